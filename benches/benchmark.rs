@@ -1,14 +1,16 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion, BenchmarkId};
 use distributed_rate_limiter::{RateLimiter, RateLimitConfig};
 use distributed_rate_limiter::algorithms::*;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 fn benchmark_token_bucket(c: &mut Criterion) {
     let mut group = c.benchmark_group("token_bucket");
     
     for rate in [100, 1000, 10000, 100000].iter() {
         let config = RateLimitConfig::per_second(*rate);
-        let mut limiter = TokenBucket::new(config);
+        let limiter = TokenBucket::new(config).unwrap();
         
         group.bench_with_input(BenchmarkId::from_parameter(rate), rate, |b, _| {
             b.iter(|| {
@@ -24,7 +26,7 @@ fn benchmark_leaky_bucket(c: &mut Criterion) {
     
     for rate in [100, 1000, 10000, 100000].iter() {
         let config = RateLimitConfig::per_second(*rate);
-        let mut limiter = LeakyBucket::new(config);
+        let limiter = LeakyBucket::new(config).unwrap();
         
         group.bench_with_input(BenchmarkId::from_parameter(rate), rate, |b, _| {
             b.iter(|| {
@@ -40,7 +42,7 @@ fn benchmark_fixed_window(c: &mut Criterion) {
     
     for rate in [100, 1000, 10000, 100000].iter() {
         let config = RateLimitConfig::per_second(*rate);
-        let mut limiter = FixedWindow::new(config);
+        let limiter = FixedWindow::new(config).unwrap();
         
         group.bench_with_input(BenchmarkId::from_parameter(rate), rate, |b, _| {
             b.iter(|| {
@@ -56,7 +58,23 @@ fn benchmark_sliding_window(c: &mut Criterion) {
     
     for rate in [100, 1000, 10000].iter() {
         let config = RateLimitConfig::per_second(*rate);
-        let mut limiter = SlidingWindow::new(config);
+        let limiter = SlidingWindow::new(config).unwrap();
+        
+        group.bench_with_input(BenchmarkId::from_parameter(rate), rate, |b, _| {
+            b.iter(|| {
+                let _ = limiter.allow_request(black_box("user1"));
+            });
+        });
+    }
+    group.finish();
+}
+
+fn benchmark_gcra(c: &mut Criterion) {
+    let mut group = c.benchmark_group("gcra");
+    
+    for rate in [100, 1000, 10000].iter() {
+        let config = RateLimitConfig::per_second(*rate);
+        let limiter = Gcra::new(config).unwrap();
         
         group.bench_with_input(BenchmarkId::from_parameter(rate), rate, |b, _| {
             b.iter(|| {
@@ -72,28 +90,42 @@ fn benchmark_comparison(c: &mut Criterion) {
     let config = RateLimitConfig::per_second(1000);
     
     group.bench_function("token_bucket", |b| {
-        let mut limiter = TokenBucket::new(config.clone());
+        let limiter = TokenBucket::new(config.clone()).unwrap();
         b.iter(|| {
             let _ = limiter.allow_request(black_box("user1"));
         });
     });
     
     group.bench_function("leaky_bucket", |b| {
-        let mut limiter = LeakyBucket::new(config.clone());
+        let limiter = LeakyBucket::new(config.clone()).unwrap();
         b.iter(|| {
             let _ = limiter.allow_request(black_box("user1"));
         });
     });
     
     group.bench_function("fixed_window", |b| {
-        let mut limiter = FixedWindow::new(config.clone());
+        let limiter = FixedWindow::new(config.clone()).unwrap();
         b.iter(|| {
             let _ = limiter.allow_request(black_box("user1"));
         });
     });
     
     group.bench_function("sliding_window", |b| {
-        let mut limiter = SlidingWindow::new(config.clone());
+        let limiter = SlidingWindow::new(config.clone()).unwrap();
+        b.iter(|| {
+            let _ = limiter.allow_request(black_box("user1"));
+        });
+    });
+    
+    group.bench_function("sliding_window_counter", |b| {
+        let limiter = SlidingWindowCounter::new(config.clone()).unwrap();
+        b.iter(|| {
+            let _ = limiter.allow_request(black_box("user1"));
+        });
+    });
+    
+    group.bench_function("gcra", |b| {
+        let limiter = Gcra::new(config.clone()).unwrap();
         b.iter(|| {
             let _ = limiter.allow_request(black_box("user1"));
         });
@@ -102,12 +134,71 @@ fn benchmark_comparison(c: &mut Criterion) {
     group.finish();
 }
 
+/// Run `ops` checks split across `threads` threads, each thread using its own keys
+fn run_contended<F>(threads: usize, ops: u64, check: F) -> Duration
+where
+    F: Fn(&str) + Send + Sync + 'static,
+{
+    let check = Arc::new(check);
+    let per_thread = ops / threads as u64 + 1;
+    let start = Instant::now();
+    
+    let handles: Vec<_> = (0..threads)
+        .map(|t| {
+            let check = Arc::clone(&check);
+            thread::spawn(move || {
+                let keys: Vec<String> = (0..64).map(|k| format!("user{}_{}", t, k)).collect();
+                for i in 0..per_thread {
+                    check(black_box(&keys[i as usize % keys.len()]));
+                }
+            })
+        })
+        .collect();
+    
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn benchmark_contention(c: &mut Criterion) {
+    let mut group = c.benchmark_group("contention");
+    let config = RateLimitConfig::per_second(1_000_000);
+    
+    for threads in [1, 4, 8].iter() {
+        // Sharded limiter shared directly between threads
+        let limiter = Arc::new(TokenBucket::new(config.clone()).unwrap());
+        group.bench_with_input(BenchmarkId::new("sharded", threads), threads, |b, &threads| {
+            b.iter_custom(|iters| {
+                let limiter = Arc::clone(&limiter);
+                run_contended(threads, iters, move |key| {
+                    let _ = limiter.allow_request(key);
+                })
+            });
+        });
+        
+        // Same limiter behind one global lock, as callers had to do before
+        let limiter = Arc::new(Mutex::new(TokenBucket::new(config.clone()).unwrap()));
+        group.bench_with_input(BenchmarkId::new("global_mutex", threads), threads, |b, &threads| {
+            b.iter_custom(|iters| {
+                let limiter = Arc::clone(&limiter);
+                run_contended(threads, iters, move |key| {
+                    let _ = limiter.lock().unwrap().allow_request(key);
+                })
+            });
+        });
+    }
+    group.finish();
+}
+
 criterion_group!(
     benches,
     benchmark_token_bucket,
     benchmark_leaky_bucket,
     benchmark_fixed_window,
     benchmark_sliding_window,
-    benchmark_comparison
+    benchmark_gcra,
+    benchmark_comparison,
+    benchmark_contention
 );
 criterion_main!(benches);
\ No newline at end of file
@@ -0,0 +1,161 @@
+use crate::algorithms::{
+    CalendarQuota, FixedWindow, Gcra, LeakyBucket, MultiWindow, SlidingWindow, SlidingWindowCounter, TokenBucket,
+};
+use crate::{RateLimitDecision, RateLimitError, RateLimiter, Result};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Shortest sleep between attempts, so a zero `retry_after` can't spin
+const MIN_WAIT: Duration = Duration::from_millis(1);
+
+/// Limiters whose checks run in memory, so async code may call them directly
+///
+/// The Redis limiters block on the network and are left out; use
+/// `AsyncRedisRateLimiter`, which has its own `acquire`. So does a limiter
+/// given a network-backed `Storage` with `with_storage`, which this can't
+/// tell apart.
+pub trait LocalRateLimiter: RateLimiter {}
+
+impl LocalRateLimiter for TokenBucket {}
+impl LocalRateLimiter for LeakyBucket {}
+impl LocalRateLimiter for FixedWindow {}
+impl LocalRateLimiter for SlidingWindow {}
+impl LocalRateLimiter for SlidingWindowCounter {}
+impl LocalRateLimiter for Gcra {}
+impl LocalRateLimiter for MultiWindow {}
+impl LocalRateLimiter for CalendarQuota {}
+impl<T: LocalRateLimiter + ?Sized> LocalRateLimiter for Box<T> {}
+impl<T: LocalRateLimiter + ?Sized> LocalRateLimiter for Arc<T> {}
+
+/// Waiting variants of `RateLimiter` checks, for the in-memory limiters
+///
+/// Instead of polling, each attempt sleeps for the `retry_after` reported
+/// by the limiter, i.e. exactly until capacity is expected to return. Other
+/// callers may still get there first, in which case the wait repeats.
+///
+/// ```no_run
+/// use distributed_rate_limiter::{RateLimitConfig, RateLimiterExt};
+/// use distributed_rate_limiter::algorithms::TokenBucket;
+///
+/// # async fn run() -> distributed_rate_limiter::Result<()> {
+/// let limiter = TokenBucket::new(RateLimitConfig::per_second(10))?;
+/// limiter.acquire("worker", 1).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub trait RateLimiterExt: LocalRateLimiter {
+    /// Wait until `cost` units can be spent for `key`, then spend them
+    fn acquire(&self, key: &str, cost: u64) -> impl Future<Output = Result<RateLimitDecision>> + Send {
+        wait_until_allowed(move || std::future::ready(self.allow_n(key, cost)), None)
+    }
+    
+    /// Like `acquire`, but give up with `RateLimitError::Timeout` after `timeout`
+    ///
+    /// Fails right away, without sleeping, when the limiter reports that
+    /// capacity won't return before the deadline.
+    fn acquire_timeout(
+        &self,
+        key: &str,
+        cost: u64,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<RateLimitDecision>> + Send {
+        wait_until_allowed(move || std::future::ready(self.allow_n(key, cost)), Some(timeout))
+    }
+}
+
+impl<T: LocalRateLimiter + ?Sized> RateLimiterExt for T {}
+
+/// Run `check` until it allows, sleeping for each denial's `retry_after`,
+/// and failing with `RateLimitError::Timeout` once capacity won't return
+/// within `timeout`
+pub(crate) async fn wait_until_allowed<F: Future<Output = Result<RateLimitDecision>>>(
+    mut check: impl FnMut() -> F,
+    timeout: Option<Duration>,
+) -> Result<RateLimitDecision> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        let decision = check().await?;
+        if decision.allowed {
+            return Ok(decision);
+        }
+        
+        let wait = wait_time(&decision);
+        if let (Some(deadline), Some(timeout)) = (deadline, timeout) {
+            if Instant::now() + wait > deadline {
+                return Err(RateLimitError::Timeout(timeout));
+            }
+        }
+        tokio::time::sleep(wait).await;
+    }
+}
+
+fn wait_time(decision: &RateLimitDecision) -> Duration {
+    decision.retry_after.unwrap_or(MIN_WAIT).max(MIN_WAIT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::TokioClock;
+    use crate::RateLimitConfig;
+    
+    // Paused tests skip straight to the next timer, so waits are exact
+    
+    #[tokio::test(start_paused = true)]
+    async fn test_acquire_waits_for_capacity() {
+        let clock = TokioClock::new();
+        let config = RateLimitConfig::new(2, Duration::from_millis(100));
+        let limiter = TokenBucket::with_clock(config, Arc::new(clock)).unwrap();
+        
+        for _ in 0..2 {
+            assert!(limiter.acquire("worker", 1).await.unwrap().allowed);
+        }
+        assert_eq!(clock.elapsed(), Duration::ZERO);
+        
+        // Each further request waits exactly as long as the denial said
+        for _ in 0..2 {
+            let retry_after = limiter.status("worker").unwrap().retry_after.unwrap();
+            let start = clock.elapsed();
+            assert!(limiter.acquire("worker", 1).await.unwrap().allowed);
+            assert_eq!(clock.elapsed() - start, retry_after);
+        }
+        assert_eq!(clock.elapsed(), Duration::from_millis(100));
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn test_acquire_timeout_fails_fast() {
+        let clock = TokioClock::new();
+        let config = RateLimitConfig::new(1, Duration::from_secs(10));
+        let limiter = LeakyBucket::with_clock(config, Arc::new(clock)).unwrap();
+        
+        limiter.acquire("worker", 1).await.unwrap();
+        
+        // Capacity returns in 10s, so there's no point sleeping for 50ms
+        let result = limiter.acquire_timeout("worker", 1, Duration::from_millis(50)).await;
+        assert!(matches!(result, Err(RateLimitError::Timeout(_))));
+        assert_eq!(clock.elapsed(), Duration::ZERO);
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn test_acquire_timeout_succeeds_within_deadline() {
+        let clock = TokioClock::new();
+        let config = RateLimitConfig::new(1, Duration::from_millis(50));
+        let limiter: Arc<Gcra> = Arc::new(Gcra::with_clock(config, Arc::new(clock)).unwrap());
+        
+        limiter.acquire("worker", 1).await.unwrap();
+        let decision = limiter.acquire_timeout("worker", 1, Duration::from_secs(1)).await.unwrap();
+        assert!(decision.allowed);
+        assert_eq!(clock.elapsed(), Duration::from_millis(50));
+    }
+    
+    #[tokio::test]
+    async fn test_acquire_rejects_impossible_cost() {
+        let limiter = TokenBucket::new(RateLimitConfig::per_second(2)).unwrap();
+        assert!(matches!(
+            limiter.acquire("worker", 3).await,
+            Err(RateLimitError::CostExceedsCapacity { .. })
+        ));
+    }
+}
@@ -0,0 +1,354 @@
+use crate::clock::{Clock, SystemClock};
+use crate::snapshot::{KeyState, LimiterSnapshot, TimeBase};
+use crate::storage::{Storage, Store, StoredState};
+use crate::{check_cost, RateLimitError, RateLimiter, RateLimitDecision, Reservation, Result, MAX_LIMIT};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+
+const SECS_PER_DAY: i64 = 86_400;
+
+/// Calendar unit a quota resets on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaPeriod {
+    Minute,
+    Hour,
+    Day,
+    Month,
+}
+
+impl QuotaPeriod {
+    /// Start and end (UNIX seconds) of the period containing `unix_secs`,
+    /// in a timezone `utc_offset` seconds ahead of UTC
+    pub fn bounds(self, unix_secs: i64, utc_offset: i32) -> (i64, i64) {
+        let offset = i64::from(utc_offset);
+        let local = unix_secs + offset;
+        
+        let (start, end) = match self {
+            QuotaPeriod::Minute => fixed_bounds(local, 60),
+            QuotaPeriod::Hour => fixed_bounds(local, 3_600),
+            QuotaPeriod::Day => fixed_bounds(local, SECS_PER_DAY),
+            QuotaPeriod::Month => {
+                let (year, month) = year_month(local.div_euclid(SECS_PER_DAY));
+                let (next_year, next_month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+                (
+                    days_from_civil(year, month) * SECS_PER_DAY,
+                    days_from_civil(next_year, next_month) * SECS_PER_DAY,
+                )
+            }
+        };
+        
+        (start - offset, end - offset)
+    }
+    
+    /// Start (UNIX seconds) of the period containing `now`, and the time left in it
+    pub(crate) fn current(self, now: Duration, utc_offset: i32) -> (i64, Duration) {
+        let (start, end) = self.bounds(now.as_secs() as i64, utc_offset);
+        let reset_after = Duration::from_secs(end.max(0) as u64).saturating_sub(now);
+        (start, reset_after)
+    }
+}
+
+pub(crate) fn validate_limit(limit: u64) -> Result<()> {
+    if limit == 0 || limit > MAX_LIMIT {
+        return Err(RateLimitError::ConfigError(format!(
+            "quota limit must be between 1 and {}",
+            MAX_LIMIT
+        )));
+    }
+    Ok(())
+}
+
+fn fixed_bounds(local: i64, length: i64) -> (i64, i64) {
+    let start = local - local.rem_euclid(length);
+    (start, start + length)
+}
+
+/// Days since 1970-01-01 of the first day of `month` in `year`
+/// (proleptic Gregorian calendar)
+fn days_from_civil(year: i64, month: u32) -> i64 {
+    // Count years from March so the leap day is at the end
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * i64::from((month + 9) % 12) + 2) / 5;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Year and month of the day `days` since 1970-01-01
+fn year_month(days: i64) -> (i64, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let march_month = (5 * day_of_year + 2) / 153;
+    let month = if march_month < 10 { march_month + 3 } else { march_month - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct QuotaState {
+    period_start: i64, // UNIX seconds
+    count: u64,
+}
+
+impl StoredState for QuotaState {
+    const ALGORITHM: &'static str = "calendar_quota";
+    
+    fn export(&self, _: TimeBase) -> KeyState {
+        KeyState::CalendarQuota {
+            period_start: self.period_start,
+            count: self.count,
+        }
+    }
+    
+    fn import(state: &KeyState, _: TimeBase) -> Option<Self> {
+        match *state {
+            KeyState::CalendarQuota { period_start, count } => Some(Self { period_start, count }),
+            _ => None,
+        }
+    }
+}
+
+/// Quota that resets on calendar boundaries, e.g. 10,000 calls per month
+/// starting on the 1st at 00:00 UTC
+///
+/// Unlike `FixedWindow`, whose window starts at a key's first request on
+/// the monotonic clock, periods are aligned to wall-clock time (in UTC, or
+/// shifted by `with_utc_offset`). State is identified by the period it
+/// belongs to, so it remains valid across restarts; use
+/// `RedisCalendarQuota` to keep it in Redis. A key is forgotten once its
+/// period has ended.
+pub struct CalendarQuota {
+    limit: u64,
+    period: QuotaPeriod,
+    utc_offset: i32,
+    counts: Store<QuotaState>,
+    clock: Arc<dyn Clock>,
+}
+
+impl CalendarQuota {
+    pub fn new(limit: u64, period: QuotaPeriod) -> Result<Self> {
+        Self::with_clock(limit, period, Arc::new(SystemClock))
+    }
+    
+    /// Create a quota that reads wall-clock time from `clock`
+    pub fn with_clock(limit: u64, period: QuotaPeriod, clock: Arc<dyn Clock>) -> Result<Self> {
+        validate_limit(limit)?;
+        
+        Ok(Self {
+            limit,
+            period,
+            utc_offset: 0,
+            counts: Store::local(None, Default::default()),
+            clock,
+        })
+    }
+    
+    /// Align periods to a timezone `utc_offset` seconds ahead of UTC
+    /// (e.g. `-5 * 3600` for midnight in UTC-5)
+    pub fn with_utc_offset(mut self, utc_offset: i32) -> Self {
+        self.utc_offset = utc_offset;
+        self
+    }
+    
+    /// Keep per-key state in `storage` instead of in this process
+    pub fn with_storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.counts = Store::Shared { storage, clock: Arc::clone(&self.clock) };
+        self
+    }
+    
+    /// Number of keys currently tracked
+    pub fn tracked_keys(&self) -> usize {
+        self.counts.len().unwrap_or(0)
+    }
+    
+    /// Start of the current period, and the time left until it ends
+    fn current_period(&self) -> (i64, Duration) {
+        self.period.current(self.clock.unix_now(), self.utc_offset)
+    }
+    
+    fn decide(&self, state: &QuotaState, cost: u64, reset_after: Duration) -> RateLimitDecision {
+        let remaining = self.limit.saturating_sub(state.count);
+        if state.count + cost <= self.limit {
+            RateLimitDecision::allow(self.limit, remaining, reset_after)
+        } else {
+            // Nothing frees up until the next period
+            RateLimitDecision::deny(self.limit, remaining, reset_after, reset_after)
+        }
+    }
+    
+    /// Count `cost` against the current period, returning when that period started
+    fn check(&self, key: &str, cost: u64) -> Result<(RateLimitDecision, i64)> {
+        check_cost(cost, self.limit)?;
+        
+        let (period_start, reset_after) = self.current_period();
+        let init = || QuotaState { period_start, count: 0 };
+        
+        self.counts.with_entry(key, init, idle_check(period_start), |state| {
+            if state.period_start != period_start {
+                *state = QuotaState { period_start, count: 0 };
+            }
+            
+            let decision = self.decide(state, cost, reset_after);
+            if decision.allowed {
+                state.count += cost;
+            }
+            (RateLimitDecision { remaining: self.limit - state.count, ..decision }, period_start)
+        })
+    }
+}
+
+fn idle_check(period_start: i64) -> impl Fn(&str, &QuotaState) -> bool {
+    move |_, state| state.count == 0 || state.period_start != period_start
+}
+
+impl RateLimiter for CalendarQuota {
+    fn allow_n(&self, key: &str, cost: u64) -> Result<RateLimitDecision> {
+        self.check(key, cost).map(|(decision, _)| decision)
+    }
+    
+    fn reserve(&self, key: &str, cost: u64) -> Result<Reservation<'_>> {
+        let (decision, period_start) = self.check(key, cost)?;
+        let key = key.to_string();
+        Ok(Reservation::new(decision, move || {
+            // Only give calls back to the period they were taken from
+            let (current_start, _) = self.current_period();
+            self.counts.update(&key, idle_check(current_start), |state| {
+                if state.period_start == period_start {
+                    state.count = state.count.saturating_sub(cost);
+                }
+            })?;
+            Ok(())
+        }))
+    }
+    
+    fn status(&self, key: &str) -> Result<RateLimitDecision> {
+        let (period_start, reset_after) = self.current_period();
+        let state = self.counts.peek(key, |state| *state)?
+            .filter(|state| state.period_start == period_start)
+            .unwrap_or(QuotaState { period_start, count: 0 });
+        
+        Ok(self.decide(&state, 1, reset_after))
+    }
+    
+    fn reset(&self, key: &str) {
+        let _ = self.counts.remove(key);
+    }
+    
+    fn evict_idle(&self) -> usize {
+        let (period_start, _) = self.current_period();
+        self.counts.evict_idle(idle_check(period_start)).unwrap_or(0)
+    }
+    
+    fn snapshot(&self) -> Option<LimiterSnapshot> {
+        let (period_start, _) = self.current_period();
+        let time = TimeBase::new(self.clock.as_ref());
+        self.counts.snapshot(time, idle_check(period_start))
+    }
+    
+    fn restore(&self, snapshot: &LimiterSnapshot) -> Result<usize> {
+        let (period_start, _) = self.current_period();
+        let time = TimeBase::new(self.clock.as_ref());
+        self.counts.restore(snapshot, time, idle_check(period_start))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    
+    // 2024-01-31T23:00:00Z
+    const JAN_31_2300: u64 = 1_706_742_000;
+    
+    #[test]
+    fn test_quota_period_bounds() {
+        let t = JAN_31_2300 as i64;
+        
+        assert_eq!(QuotaPeriod::Hour.bounds(t + 59, 0), (t, t + 3_600));
+        assert_eq!(QuotaPeriod::Day.bounds(t, 0), (t - 23 * 3_600, t + 3_600));
+        
+        // January 2024, then leap-year February
+        let feb_1 = t + 3_600;
+        assert_eq!(QuotaPeriod::Month.bounds(t, 0), (feb_1 - 31 * SECS_PER_DAY, feb_1));
+        assert_eq!(QuotaPeriod::Month.bounds(feb_1, 0), (feb_1, feb_1 + 29 * SECS_PER_DAY));
+        
+        // In UTC+2 it is already February 1st, 01:00
+        let offset = 2 * 3_600;
+        assert_eq!(QuotaPeriod::Month.bounds(t, offset), (t - 3_600, t - 3_600 + 29 * SECS_PER_DAY));
+        assert_eq!(QuotaPeriod::Day.bounds(t, offset), (t - 3_600, t - 3_600 + SECS_PER_DAY));
+        
+        // December rolls over into the next year, before and after the epoch
+        let dec_1_2023 = 1_701_388_800;
+        assert_eq!(QuotaPeriod::Month.bounds(dec_1_2023 + 10, 0), (dec_1_2023, dec_1_2023 + 31 * SECS_PER_DAY));
+        assert_eq!(QuotaPeriod::Month.bounds(-1, 0), (-31 * SECS_PER_DAY, 0));
+    }
+    
+    #[test]
+    fn test_calendar_quota_resets_on_the_first() {
+        let clock = MockClock::starting_at(Duration::from_secs(JAN_31_2300));
+        let quota = CalendarQuota::with_clock(3, QuotaPeriod::Month, Arc::new(clock.clone())).unwrap();
+        
+        assert!(quota.allow_n("acme", 3).unwrap().allowed);
+        let denied = quota.allow_request("acme").unwrap();
+        assert!(!denied.allowed);
+        assert_eq!(denied.retry_after, Some(Duration::from_secs(3_600)));
+        
+        // A new month starts at midnight UTC, however recent the first request was
+        clock.advance(Duration::from_secs(3_600));
+        let allowed = quota.allow_request("acme").unwrap();
+        assert!(allowed.allowed);
+        assert_eq!(allowed.remaining, 2);
+        assert_eq!(allowed.reset_after, Duration::from_secs(29 * 86_400));
+    }
+    
+    #[test]
+    fn test_calendar_quota_utc_offset() {
+        let clock = MockClock::starting_at(Duration::from_secs(JAN_31_2300));
+        let quota = CalendarQuota::with_clock(2, QuotaPeriod::Day, Arc::new(clock.clone()))
+            .unwrap()
+            .with_utc_offset(-3_600);
+        
+        // 22:00 in UTC-1, so the day ends in two hours
+        let decision = quota.allow_n("acme", 2).unwrap();
+        assert_eq!(decision.reset_after, Duration::from_secs(7_200));
+        
+        clock.advance(Duration::from_secs(3_600));
+        assert!(!quota.allow_request("acme").unwrap().allowed);
+        clock.advance(Duration::from_secs(3_600));
+        assert!(quota.allow_request("acme").unwrap().allowed);
+    }
+    
+    #[test]
+    fn test_calendar_quota_status_reserve_and_eviction() {
+        let clock = MockClock::starting_at(Duration::from_secs(JAN_31_2300));
+        let quota = CalendarQuota::with_clock(5, QuotaPeriod::Hour, Arc::new(clock.clone())).unwrap();
+        
+        assert_eq!(quota.status("acme").unwrap().remaining, 5);
+        assert_eq!(quota.tracked_keys(), 0);
+        
+        quota.reserve("acme", 2).unwrap().cancel().unwrap();
+        quota.allow_n("acme", 4).unwrap();
+        let status = quota.status("acme").unwrap();
+        assert!(status.allowed);
+        assert_eq!(status.remaining, 1);
+        
+        // Calls reserved in the previous hour aren't refunded to this one
+        let reservation = quota.reserve("acme", 1).unwrap();
+        clock.advance(Duration::from_secs(3_600));
+        quota.allow_n("acme", 5).unwrap();
+        reservation.cancel().unwrap();
+        assert!(!quota.status("acme").unwrap().allowed);
+        
+        assert_eq!(quota.evict_idle(), 0);
+        clock.advance(Duration::from_secs(3_600));
+        assert_eq!(quota.evict_idle(), 1);
+        
+        assert!(CalendarQuota::new(0, QuotaPeriod::Day).is_err());
+    }
+}
@@ -1,101 +1,371 @@
-use crate::{RateLimiter, RateLimitConfig, Result};
-use std::collections::HashMap;
+use crate::clock::{Clock, SystemClock};
+use crate::overrides::{self, LimitOverrides};
+use crate::snapshot::{KeyState, LimiterSnapshot, TimeBase};
+use crate::storage::{self, Storage, Store, StoredState};
+use crate::{check_cost, RateLimiter, RateLimitConfig, RateLimitDecision, Reservation, Result};
+use std::borrow::Cow;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-#[derive(Debug)]
-struct WindowState {
-    count: u64,
-    window_start: Instant,
+#[derive(Debug, Clone, Copy)]
+pub(super) struct WindowState {
+    pub(super) count: u64,
+    pub(super) window_start: Instant,
+}
+
+impl WindowState {
+    pub(super) fn new(now: Instant) -> Self {
+        Self {
+            count: 0,
+            window_start: now,
+        }
+    }
+    
+    pub(super) fn roll(&mut self, now: Instant, window: Duration) {
+        // Check if window has expired
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed >= window {
+            // Reset window
+            self.count = 0;
+            self.window_start = now;
+        }
+    }
+    
+    /// Time until the current window rolls over
+    pub(super) fn reset_after(&self, now: Instant, window: Duration) -> Duration {
+        window.saturating_sub(now.saturating_duration_since(self.window_start))
+    }
+    
+    /// Whether the window has expired or was never used
+    pub(super) fn is_idle(&self, now: Instant, window: Duration) -> bool {
+        self.count == 0 || now.saturating_duration_since(self.window_start) >= window
+    }
+    
+    /// Whether this is the window that started at `start`
+    pub(super) fn started_at(&self, start: Instant, window: Duration) -> bool {
+        // Later windows start at least a whole window apart
+        storage::same_instant(self.window_start, start, storage::TIME_TOLERANCE.min(window / 2))
+    }
+}
+
+impl StoredState for WindowState {
+    const ALGORITHM: &'static str = "fixed_window";
+    
+    fn export(&self, time: TimeBase) -> KeyState {
+        KeyState::FixedWindow {
+            count: self.count,
+            window_start_us: time.to_unix_us(self.window_start),
+        }
+    }
+    
+    fn import(state: &KeyState, time: TimeBase) -> Option<Self> {
+        match *state {
+            KeyState::FixedWindow { count, window_start_us } => Some(Self {
+                count,
+                window_start: time.to_instant(window_start_us),
+            }),
+            _ => None,
+        }
+    }
 }
 
 /// Fixed Window algorithm implementation
 /// Counts requests in fixed time windows
 /// Simple but can have burst issues at window boundaries
+///
+/// A key is forgotten once its window has expired.
 pub struct FixedWindow {
     config: RateLimitConfig,
-    windows: HashMap<String, WindowState>,
+    overrides: Option<Arc<LimitOverrides>>,
+    windows: Store<WindowState>,
+    clock: Arc<dyn Clock>,
 }
 
 impl FixedWindow {
-    pub fn new(config: RateLimitConfig) -> Self {
-        Self {
-            config,
-            windows: HashMap::new(),
-        }
+    pub fn new(config: RateLimitConfig) -> Result<Self> {
+        Self::with_clock(config, Arc::new(SystemClock))
     }
     
-    fn check_window(&mut self, key: &str) -> &mut WindowState {
-        let now = Instant::now();
+    /// Create a limiter that reads time from `clock`
+    pub fn with_clock(config: RateLimitConfig, clock: Arc<dyn Clock>) -> Result<Self> {
+        config.validate()?;
         
-        let window = self.windows.entry(key.to_string()).or_insert(WindowState {
-            count: 0,
-            window_start: now,
-        });
+        Ok(Self {
+            windows: Store::local(config.max_keys, config.overflow_policy),
+            config,
+            overrides: None,
+            clock,
+        })
+    }
+    
+    /// Look up per-key limits in `overrides` on every check
+    pub fn with_overrides(mut self, overrides: Arc<LimitOverrides>) -> Self {
+        self.overrides = Some(overrides);
+        self
+    }
+    
+    /// Keep per-key state in `storage` instead of in this process
+    pub fn with_storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.windows = Store::Shared { storage, clock: Arc::clone(&self.clock) };
+        self
+    }
+    
+    fn config_for(&self, key: &str) -> Cow<'_, RateLimitConfig> {
+        overrides::config_for(self.overrides.as_deref(), key, &self.config)
+    }
+    
+    /// Number of keys currently tracked
+    pub fn tracked_keys(&self) -> usize {
+        self.windows.len().unwrap_or(0)
+    }
+    
+    /// Count `cost` against the current window, returning when that window started
+    fn check(&self, key: &str, cost: u64) -> Result<(RateLimitDecision, Instant)> {
+        let config = self.config_for(key);
+        let max_requests = config.max_requests;
+        check_cost(cost, max_requests)?;
         
-        // Check if window has expired
-        let elapsed = now.duration_since(window.window_start);
-        if elapsed >= self.config.window {
-            // Reset window
-            window.count = 0;
-            window.window_start = now;
-        }
+        let now = self.clock.now();
+        let window_size = config.window;
+        let init = || WindowState::new(now);
         
-        window
+        self.windows.with_entry(key, init, self.idle_check(now), |window| {
+            window.roll(now, window_size);
+            
+            let reset_after = window.reset_after(now, window_size);
+            
+            let decision = if window.count + cost <= max_requests {
+                window.count += cost;
+                RateLimitDecision::allow(max_requests, max_requests - window.count, reset_after)
+            } else {
+                // Nothing frees up until the next window starts
+                let remaining = max_requests.saturating_sub(window.count);
+                RateLimitDecision::deny(max_requests, remaining, reset_after, reset_after)
+            };
+            (decision, window.window_start)
+        })
+    }
+    
+    fn idle_check(&self, now: Instant) -> impl Fn(&str, &WindowState) -> bool + '_ {
+        move |key, state| state.is_idle(now, self.config_for(key).window)
     }
 }
 
 impl RateLimiter for FixedWindow {
-    fn allow_request(&mut self, key: &str) -> Result<bool> {
-        let max_requests = self.config.max_requests;
-        let window = self.check_window(key);
+    fn allow_n(&self, key: &str, cost: u64) -> Result<RateLimitDecision> {
+        self.check(key, cost).map(|(decision, _)| decision)
+    }
+    
+    fn reserve(&self, key: &str, cost: u64) -> Result<Reservation<'_>> {
+        let (decision, window_start) = self.check(key, cost)?;
+        let key = key.to_string();
+        Ok(Reservation::new(decision, move || {
+            // Only give slots back to the window they were taken from
+            let window_size = self.config_for(&key).window;
+            self.windows.update(&key, self.idle_check(self.clock.now()), |window| {
+                if window.started_at(window_start, window_size) {
+                    window.count = window.count.saturating_sub(cost);
+                }
+            })?;
+            Ok(())
+        }))
+    }
+    
+    fn status(&self, key: &str) -> Result<RateLimitDecision> {
+        let config = self.config_for(key);
+        let max_requests = config.max_requests;
+        let now = self.clock.now();
+        let window_size = config.window;
         
-        if window.count < max_requests {
-            window.count += 1;
-            Ok(true)
+        let mut window = self.windows.peek(key, |window| *window)?
+            .unwrap_or(WindowState::new(now));
+        window.roll(now, window_size);
+        
+        let reset_after = window.reset_after(now, window_size);
+        let remaining = max_requests.saturating_sub(window.count);
+        
+        if remaining > 0 {
+            Ok(RateLimitDecision::allow(max_requests, remaining, reset_after))
         } else {
-            Ok(false)
+            Ok(RateLimitDecision::deny(max_requests, remaining, reset_after, reset_after))
         }
     }
     
-    fn reset(&mut self, key: &str) {
-        self.windows.remove(key);
+    fn reset(&self, key: &str) {
+        let _ = self.windows.remove(key);
+    }
+    
+    fn evict_idle(&self) -> usize {
+        self.windows.evict_idle(self.idle_check(self.clock.now())).unwrap_or(0)
+    }
+    
+    fn snapshot(&self) -> Option<LimiterSnapshot> {
+        let time = TimeBase::new(self.clock.as_ref());
+        self.windows.snapshot(time, self.idle_check(time.now()))
+    }
+    
+    fn restore(&self, snapshot: &LimiterSnapshot) -> Result<usize> {
+        let time = TimeBase::new(self.clock.as_ref());
+        self.windows.restore(snapshot, time, self.idle_check(time.now()))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::thread::sleep;
+    use crate::clock::MockClock;
     
     #[test]
     fn test_fixed_window_allows_requests() {
         let config = RateLimitConfig::per_second(5);
-        let mut limiter = FixedWindow::new(config);
+        let limiter = FixedWindow::new(config).unwrap();
         
         // Should allow 5 requests
         for _ in 0..5 {
-            assert!(limiter.allow_request("user1").unwrap());
+            assert!(limiter.allow_request("user1").unwrap().allowed);
         }
         
         // 6th should be denied
-        assert!(!limiter.allow_request("user1").unwrap());
+        assert!(!limiter.allow_request("user1").unwrap().allowed);
+    }
+    
+    #[test]
+    fn test_fixed_window_decision_fields() {
+        let clock = MockClock::new();
+        let config = RateLimitConfig::new(2, Duration::from_secs(10));
+        let limiter = FixedWindow::with_clock(config, Arc::new(clock.clone())).unwrap();
+        
+        let first = limiter.allow_request("user1").unwrap();
+        assert_eq!(first.limit, 2);
+        assert_eq!(first.remaining, 1);
+        
+        limiter.allow_request("user1").unwrap();
+        let denied = limiter.allow_request("user1").unwrap();
+        assert!(!denied.allowed);
+        assert_eq!(denied.remaining, 0);
+        
+        // Retry is possible exactly when the window resets
+        assert_eq!(denied.retry_after, Some(Duration::from_secs(10)));
+        assert_eq!(denied.reset_after, Duration::from_secs(10));
+        
+        clock.advance(Duration::from_secs(4));
+        let denied = limiter.allow_request("user1").unwrap();
+        assert_eq!(denied.retry_after, Some(Duration::from_secs(6)));
+    }
+    
+    #[test]
+    fn test_fixed_window_weighted_cost() {
+        let config = RateLimitConfig::per_second(10);
+        let limiter = FixedWindow::new(config).unwrap();
+        
+        assert_eq!(limiter.allow_n("user1", 6).unwrap().remaining, 4);
+        
+        let denied = limiter.allow_n("user1", 5).unwrap();
+        assert!(!denied.allowed);
+        assert_eq!(denied.remaining, 4);
+        
+        assert!(limiter.allow_n("user1", 4).unwrap().allowed);
+        assert!(matches!(
+            limiter.allow_n("user1", 11),
+            Err(crate::RateLimitError::CostExceedsCapacity { .. })
+        ));
     }
     
     #[test]
     fn test_fixed_window_resets() {
+        let clock = MockClock::new();
         let config = RateLimitConfig::new(3, Duration::from_millis(500));
-        let mut limiter = FixedWindow::new(config);
+        let limiter = FixedWindow::with_clock(config, Arc::new(clock.clone())).unwrap();
         
         // Use up window
         for _ in 0..3 {
-            assert!(limiter.allow_request("user1").unwrap());
+            assert!(limiter.allow_request("user1").unwrap().allowed);
         }
-        assert!(!limiter.allow_request("user1").unwrap());
+        assert!(!limiter.allow_request("user1").unwrap().allowed);
         
-        // Wait for window to expire
-        sleep(Duration::from_millis(600));
+        // Still the same window 1ms before it expires
+        clock.advance(Duration::from_millis(499));
+        assert!(!limiter.allow_request("user1").unwrap().allowed);
         
-        // Should have new window
-        assert!(limiter.allow_request("user1").unwrap());
+        // Should have new window exactly at the boundary
+        clock.advance(Duration::from_millis(1));
+        assert!(limiter.allow_request("user1").unwrap().allowed);
     }
-}
\ No newline at end of file
+    
+    #[test]
+    fn test_fixed_window_evicts_expired_windows() {
+        let clock = MockClock::new();
+        let config = RateLimitConfig::per_second(2);
+        let limiter = FixedWindow::with_clock(config, Arc::new(clock.clone())).unwrap();
+        
+        limiter.allow_request("user1").unwrap();
+        
+        clock.advance(Duration::from_millis(999));
+        assert_eq!(limiter.evict_idle(), 0);
+        
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(limiter.evict_idle(), 1);
+        assert_eq!(limiter.tracked_keys(), 0);
+    }
+    
+    #[test]
+    fn test_fixed_window_status_does_not_consume() {
+        let clock = MockClock::new();
+        let config = RateLimitConfig::new(2, Duration::from_secs(10));
+        let limiter = FixedWindow::with_clock(config, Arc::new(clock.clone())).unwrap();
+        
+        assert_eq!(limiter.status("user1").unwrap().remaining, 2);
+        assert_eq!(limiter.tracked_keys(), 0);
+        
+        limiter.allow_n("user1", 2).unwrap();
+        clock.advance(Duration::from_secs(4));
+        let status = limiter.status("user1").unwrap();
+        assert!(!status.allowed);
+        assert_eq!(status.remaining, 0);
+        assert_eq!(status.retry_after, Some(Duration::from_secs(6)));
+        
+        // Status reflects the new window without starting it
+        clock.advance(Duration::from_secs(6));
+        let status = limiter.status("user1").unwrap();
+        assert!(status.allowed);
+        assert_eq!(status.remaining, 2);
+        assert_eq!(status.reset_after, Duration::from_secs(10));
+    }
+    
+    #[test]
+    fn test_fixed_window_reservation_refund() {
+        let clock = MockClock::new();
+        let config = RateLimitConfig::new(2, Duration::from_secs(10));
+        let limiter = FixedWindow::with_clock(config, Arc::new(clock.clone())).unwrap();
+        
+        let reservation = limiter.reserve("user1", 2).unwrap();
+        assert!(!limiter.allow_request("user1").unwrap().allowed);
+        reservation.cancel().unwrap();
+        assert_eq!(limiter.status("user1").unwrap().remaining, 2);
+        
+        // A refund after the window rolled over must not credit the new window
+        let stale = limiter.reserve("user1", 2).unwrap();
+        clock.advance(Duration::from_secs(10));
+        limiter.allow_n("user1", 2).unwrap();
+        stale.cancel().unwrap();
+        assert_eq!(limiter.status("user1").unwrap().remaining, 0);
+    }
+    
+    #[test]
+    fn test_fixed_window_lowered_override() {
+        let overrides = Arc::new(crate::LimitOverrides::new());
+        let limiter = FixedWindow::new(RateLimitConfig::new(5, Duration::from_secs(10)))
+            .unwrap()
+            .with_overrides(Arc::clone(&overrides));
+        
+        limiter.allow_n("user1", 4).unwrap();
+        
+        // The window already holds more than the new limit
+        overrides.set_key("user1", RateLimitConfig::new(2, Duration::from_secs(10))).unwrap();
+        let denied = limiter.allow_request("user1").unwrap();
+        assert!(!denied.allowed);
+        assert_eq!(denied.limit, 2);
+        assert_eq!(denied.remaining, 0);
+        assert_eq!(limiter.status("user1").unwrap().remaining, 0);
+    }
+}
@@ -0,0 +1,354 @@
+use crate::clock::{Clock, SystemClock};
+use crate::overrides::{self, LimitOverrides};
+use crate::snapshot::{KeyState, LimiterSnapshot, TimeBase};
+use crate::storage::{Storage, Store, StoredState};
+use crate::{check_cost, RateLimitError, RateLimiter, RateLimitConfig, RateLimitDecision, Reservation, Result};
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Generic Cell Rate Algorithm implementation
+/// Tracks a single "theoretical arrival time" (TAT) per key
+///
+/// Every admitted unit pushes the TAT back by the emission interval
+/// (`window / max_requests`). A request is allowed as long as the TAT stays
+/// within `config.capacity()` intervals of the current time, which behaves
+/// like a token bucket while storing one timestamp instead of two fields.
+///
+/// Discrete refill intervals are not supported. A key is forgotten once its
+/// TAT is no further ahead than that of a new key.
+pub struct Gcra {
+    config: RateLimitConfig,
+    arrivals: Store<Instant>,
+    params: GcraParams,
+    overrides: Option<Arc<LimitOverrides>>,
+    clock: Arc<dyn Clock>,
+}
+
+/// The theoretical arrival time is all there is to a key
+impl StoredState for Instant {
+    const ALGORITHM: &'static str = "gcra";
+    
+    fn export(&self, time: TimeBase) -> KeyState {
+        KeyState::Gcra { tat_us: time.to_unix_us(*self) }
+    }
+    
+    fn import(state: &KeyState, time: TimeBase) -> Option<Self> {
+        match *state {
+            KeyState::Gcra { tat_us } => Some(time.to_instant(tat_us)),
+            _ => None,
+        }
+    }
+}
+
+/// Timings derived from a config
+#[derive(Debug, Clone, Copy)]
+struct GcraParams {
+    capacity: u64,
+    emission_interval: Duration,
+    tolerance: Duration,
+    initial_offset: Duration, // how far ahead a new key's TAT starts
+}
+
+impl GcraParams {
+    fn new(config: &RateLimitConfig) -> Result<Self> {
+        let invalid = |msg: &str| RateLimitError::ConfigError(msg.to_string());
+        if config.refill_interval.is_some() {
+            return Err(invalid("GCRA does not support refill_interval"));
+        }
+        
+        // Whole nanoseconds, so a full burst adds up to exactly the tolerance
+        let emission_interval = config.window.as_nanos() / config.max_requests as u128;
+        let emission_interval = u64::try_from(emission_interval)
+            .ok()
+            .filter(|&nanos| nanos > 0)
+            .map(Duration::from_nanos)
+            .ok_or_else(|| invalid("GCRA emission interval must be between 1ns and u64::MAX ns"))?;
+        
+        let tolerance = intervals(emission_interval, config.capacity())
+            .ok_or_else(|| invalid("GCRA burst tolerance is too large"))?;
+        let initial_offset = intervals(emission_interval, config.capacity() - config.initial_tokens())
+            .ok_or_else(|| invalid("GCRA burst tolerance is too large"))?;
+        
+        Ok(Self {
+            capacity: config.capacity(),
+            emission_interval,
+            tolerance,
+            initial_offset,
+        })
+    }
+    
+    /// Units that still fit when the TAT is `ahead` of now
+    fn remaining(&self, ahead: Duration) -> u64 {
+        (self.tolerance.saturating_sub(ahead).as_nanos() / self.emission_interval.as_nanos()) as u64
+    }
+    
+    /// Push the TAT back by `cost` units if that stays within the tolerance
+    fn check(&self, tat: &mut Instant, now: Instant, cost: u64) -> RateLimitDecision {
+        let limit = self.capacity;
+        // Can't overflow: cost never exceeds the capacity the tolerance was built from
+        let increment = intervals(self.emission_interval, cost).unwrap_or(Duration::MAX);
+        
+        // A TAT in the past means the key is fully replenished
+        let ahead = tat.saturating_duration_since(now);
+        let new_ahead = ahead.saturating_add(increment);
+        
+        if new_ahead <= self.tolerance {
+            *tat = now + new_ahead;
+            RateLimitDecision::allow(limit, self.remaining(new_ahead), new_ahead)
+        } else {
+            let retry_after = new_ahead - self.tolerance;
+            RateLimitDecision::deny(limit, self.remaining(ahead), ahead, retry_after)
+        }
+    }
+}
+
+/// `emission_interval * count`, or `None` if it doesn't fit in a `Duration`
+fn intervals(emission_interval: Duration, count: u64) -> Option<Duration> {
+    let nanos = emission_interval.as_nanos().checked_mul(count as u128)?;
+    let secs = u64::try_from(nanos / 1_000_000_000).ok()?;
+    Some(Duration::new(secs, (nanos % 1_000_000_000) as u32))
+}
+
+impl Gcra {
+    pub fn new(config: RateLimitConfig) -> Result<Self> {
+        Self::with_clock(config, Arc::new(SystemClock))
+    }
+    
+    /// Create a limiter that reads time from `clock`
+    pub fn with_clock(config: RateLimitConfig, clock: Arc<dyn Clock>) -> Result<Self> {
+        config.validate()?;
+        let params = GcraParams::new(&config)?;
+        
+        Ok(Self {
+            arrivals: Store::local(config.max_keys, config.overflow_policy),
+            config,
+            params,
+            overrides: None,
+            clock,
+        })
+    }
+    
+    /// Look up per-key limits in `overrides` on every check
+    ///
+    /// Checks for keys whose override GCRA can't run (e.g. one with a
+    /// `refill_interval`) fail with `RateLimitError::ConfigError`.
+    pub fn with_overrides(mut self, overrides: Arc<LimitOverrides>) -> Self {
+        self.overrides = Some(overrides);
+        self
+    }
+    
+    /// Keep per-key state in `storage` instead of in this process
+    pub fn with_storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.arrivals = Store::Shared { storage, clock: Arc::clone(&self.clock) };
+        self
+    }
+    
+    /// Number of keys currently tracked
+    pub fn tracked_keys(&self) -> usize {
+        self.arrivals.len().unwrap_or(0)
+    }
+    
+    fn params_for(&self, key: &str) -> Result<GcraParams> {
+        match overrides::config_for(self.overrides.as_deref(), key, &self.config) {
+            Cow::Owned(config) => GcraParams::new(&config),
+            Cow::Borrowed(_) => Ok(self.params),
+        }
+    }
+    
+    /// Pull the TAT forward by `cost` units, as if they had never been spent
+    fn refund(&self, key: &str, cost: u64) -> Result<()> {
+        let now = self.clock.now();
+        let params = self.params_for(key)?;
+        let increment = intervals(params.emission_interval, cost).unwrap_or(Duration::MAX);
+        
+        self.arrivals.update(key, self.idle_check(now), |tat| {
+            let ahead = tat.saturating_duration_since(now);
+            *tat = now + ahead.saturating_sub(increment);
+        })?;
+        Ok(())
+    }
+    
+    fn idle_check(&self, now: Instant) -> impl Fn(&str, &Instant) -> bool + '_ {
+        move |key, tat| {
+            // Keep keys whose override is unusable rather than guess
+            self.params_for(key)
+                .is_ok_and(|params| tat.saturating_duration_since(now) <= params.initial_offset)
+        }
+    }
+}
+
+impl RateLimiter for Gcra {
+    fn allow_n(&self, key: &str, cost: u64) -> Result<RateLimitDecision> {
+        let params = self.params_for(key)?;
+        check_cost(cost, params.capacity)?;
+        
+        let now = self.clock.now();
+        let init = || now + params.initial_offset;
+        
+        self.arrivals.with_entry(key, init, self.idle_check(now), |tat| params.check(tat, now, cost))
+    }
+    
+    fn reserve(&self, key: &str, cost: u64) -> Result<Reservation<'_>> {
+        let decision = self.allow_n(key, cost)?;
+        let key = key.to_string();
+        Ok(Reservation::new(decision, move || self.refund(&key, cost)))
+    }
+    
+    fn status(&self, key: &str) -> Result<RateLimitDecision> {
+        let params = self.params_for(key)?;
+        let now = self.clock.now();
+        
+        // Check a single unit against a copy so the stored TAT is left untouched
+        let mut tat = self.arrivals.peek(key, |tat| *tat)?.unwrap_or(now + params.initial_offset);
+        let ahead = tat.saturating_duration_since(now);
+        let mut decision = params.check(&mut tat, now, 1);
+        decision.remaining = params.remaining(ahead);
+        decision.reset_after = ahead;
+        
+        Ok(decision)
+    }
+    
+    fn reset(&self, key: &str) {
+        let _ = self.arrivals.remove(key);
+    }
+    
+    fn evict_idle(&self) -> usize {
+        self.arrivals.evict_idle(self.idle_check(self.clock.now())).unwrap_or(0)
+    }
+    
+    fn snapshot(&self) -> Option<LimiterSnapshot> {
+        let time = TimeBase::new(self.clock.as_ref());
+        self.arrivals.snapshot(time, self.idle_check(time.now()))
+    }
+    
+    fn restore(&self, snapshot: &LimiterSnapshot) -> Result<usize> {
+        let time = TimeBase::new(self.clock.as_ref());
+        self.arrivals.restore(snapshot, time, self.idle_check(time.now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    
+    #[test]
+    fn test_gcra_allows_requests() {
+        let config = RateLimitConfig::per_second(3);
+        let limiter = Gcra::new(config).unwrap();
+        
+        // Full burst is available immediately
+        for _ in 0..3 {
+            assert!(limiter.allow_request("user1").unwrap().allowed);
+        }
+        
+        assert!(!limiter.allow_request("user1").unwrap().allowed);
+    }
+    
+    #[test]
+    fn test_gcra_decision_fields() {
+        let clock = MockClock::new();
+        let config = RateLimitConfig::per_second(4);
+        let limiter = Gcra::with_clock(config, Arc::new(clock.clone())).unwrap();
+        
+        let first = limiter.allow_request("user1").unwrap();
+        assert_eq!(first.limit, 4);
+        assert_eq!(first.remaining, 3);
+        assert_eq!(first.reset_after, Duration::from_millis(250));
+        
+        limiter.allow_n("user1", 3).unwrap();
+        
+        // One unit is emitted every 250ms
+        let denied = limiter.allow_request("user1").unwrap();
+        assert!(!denied.allowed);
+        assert_eq!(denied.remaining, 0);
+        assert_eq!(denied.retry_after, Some(Duration::from_millis(250)));
+        assert_eq!(denied.reset_after, Duration::from_secs(1));
+        
+        clock.advance(Duration::from_millis(249));
+        assert!(!limiter.allow_request("user1").unwrap().allowed);
+        clock.advance(Duration::from_millis(1));
+        assert!(limiter.allow_request("user1").unwrap().allowed);
+        assert!(!limiter.allow_request("user1").unwrap().allowed);
+    }
+    
+    #[test]
+    fn test_gcra_weighted_cost_and_burst() {
+        let clock = MockClock::new();
+        let config = RateLimitConfig::builder(2, Duration::from_secs(1))
+            .burst(6)
+            .initial_tokens(4)
+            .build()
+            .unwrap();
+        let limiter = Gcra::with_clock(config, Arc::new(clock.clone())).unwrap();
+        
+        // A new key starts with 4 of the 6 units available
+        assert_eq!(limiter.status("user1").unwrap().remaining, 4);
+        assert!(limiter.allow_n("user1", 4).unwrap().allowed);
+        
+        let denied = limiter.allow_n("user1", 2).unwrap();
+        assert_eq!(denied.retry_after, Some(Duration::from_secs(1)));
+        
+        clock.advance(Duration::from_secs(3));
+        assert!(limiter.allow_n("user1", 6).unwrap().allowed);
+        assert!(matches!(
+            limiter.allow_n("user1", 7),
+            Err(RateLimitError::CostExceedsCapacity { .. })
+        ));
+    }
+    
+    #[test]
+    fn test_gcra_status_reserve_and_eviction() {
+        let clock = MockClock::new();
+        let config = RateLimitConfig::per_second(2);
+        let limiter = Gcra::with_clock(config, Arc::new(clock.clone())).unwrap();
+        
+        let status = limiter.status("user1").unwrap();
+        assert!(status.allowed);
+        assert_eq!(status.remaining, 2);
+        assert_eq!(limiter.tracked_keys(), 0);
+        
+        limiter.reserve("user1", 2).unwrap().cancel().unwrap();
+        assert_eq!(limiter.status("user1").unwrap().remaining, 2);
+        
+        limiter.allow_n("user1", 2).unwrap();
+        let status = limiter.status("user1").unwrap();
+        assert!(!status.allowed);
+        assert_eq!(status.retry_after, Some(Duration::from_millis(500)));
+        assert_eq!(status.reset_after, Duration::from_secs(1));
+        
+        assert_eq!(limiter.evict_idle(), 0);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(limiter.evict_idle(), 1);
+    }
+    
+    #[test]
+    fn test_gcra_rejects_refill_interval() {
+        let config = RateLimitConfig::builder(2, Duration::from_secs(1))
+            .refill_interval(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        assert!(matches!(Gcra::new(config), Err(RateLimitError::ConfigError(_))));
+    }
+    
+    #[test]
+    fn test_gcra_overrides() {
+        let overrides = Arc::new(crate::LimitOverrides::new());
+        let limiter = Gcra::new(RateLimitConfig::per_second(2))
+            .unwrap()
+            .with_overrides(Arc::clone(&overrides));
+        
+        overrides.set_key("fast", RateLimitConfig::per_second(5)).unwrap();
+        assert_eq!(limiter.allow_n("fast", 5).unwrap().limit, 5);
+        
+        // GCRA can't honour a stepped refill, so checks for that key fail
+        let stepped = RateLimitConfig::builder(5, Duration::from_secs(1))
+            .refill_interval(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        overrides.set_key("stepped", stepped).unwrap();
+        assert!(matches!(limiter.allow_request("stepped"), Err(RateLimitError::ConfigError(_))));
+        assert!(limiter.allow_request("other").unwrap().allowed);
+    }
+}
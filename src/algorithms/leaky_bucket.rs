@@ -1,112 +1,507 @@
-use crate::{RateLimiter, RateLimitConfig, Result};
-use std::collections::HashMap;
-use std::time::Instant;
+use super::{replenish, time_to_replenish};
+use crate::clock::{Clock, SystemClock};
+use crate::overrides::{self, LimitOverrides};
+use crate::snapshot::{KeyState, LimiterSnapshot, TimeBase};
+use crate::storage::{Storage, Store, StoredState};
+use crate::{check_cost, RateLimiter, RateLimitConfig, RateLimitDecision, Reservation, Result};
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct BucketState {
     water_level: f64, // Current "water" in bucket
     last_update: Instant,
 }
 
+impl BucketState {
+    fn leak(&mut self, now: Instant, leak_rate: f64, interval: Option<Duration>) {
+        // Calculate how much has leaked since last check
+        let (leaked, last_update) = replenish(self.last_update, now, leak_rate, interval);
+        
+        // Reduce water level by leaked amount (but not below 0)
+        self.water_level = (self.water_level - leaked).max(0.0);
+        self.last_update = last_update;
+    }
+    
+    /// Whether the bucket has drained down to the level of a new key
+    fn is_idle(&self, now: Instant, leak_rate: f64, initial_level: f64, interval: Option<Duration>) -> bool {
+        let (leaked, _) = replenish(self.last_update, now, leak_rate, interval);
+        self.water_level - leaked <= initial_level
+    }
+}
+
+impl StoredState for BucketState {
+    const ALGORITHM: &'static str = "leaky_bucket";
+    
+    fn export(&self, time: TimeBase) -> KeyState {
+        KeyState::LeakyBucket {
+            water_level: self.water_level,
+            last_update_us: time.to_unix_us(self.last_update),
+        }
+    }
+    
+    fn import(state: &KeyState, time: TimeBase) -> Option<Self> {
+        match *state {
+            KeyState::LeakyBucket { water_level, last_update_us } => Some(Self {
+                water_level,
+                last_update: time.to_instant(last_update_us),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Where a request landed in a shaping `LeakyBucket`'s queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Scheduled {
+    /// The admission decision, denied when the queue is full
+    pub decision: RateLimitDecision,
+    /// How long to hold the request before sending it (zero when denied)
+    pub delay: Duration,
+}
+
 /// Leaky Bucket algorithm implementation
 /// Water (requests) fills a bucket that leaks at a constant rate
 /// If bucket overflows, requests are denied
+///
+/// The bucket holds `config.capacity()` units and leaks at the sustained
+/// rate. A new key starts with `config.initial_tokens()` units of headroom,
+/// and is forgotten again once it has drained back down to that level.
+///
+/// Besides metering via `RateLimiter`, the bucket can shape traffic with
+/// `schedule` / `shape`: every admitted request is delayed until the water
+/// ahead of it has leaked out, so requests go out at exactly the leak rate.
+/// The bucket capacity then bounds the queue depth.
 pub struct LeakyBucket {
     config: RateLimitConfig,
-    buckets: HashMap<String, BucketState>,
-    leak_rate: f64, // water units leaked per second
-    max_capacity: f64,
+    buckets: Store<BucketState>,
+    overrides: Option<Arc<LimitOverrides>>,
+    clock: Arc<dyn Clock>,
 }
 
 impl LeakyBucket {
-    pub fn new(config: RateLimitConfig) -> Self {
-        let leak_rate = config.max_requests as f64 / config.window.as_secs_f64();
-        let max_capacity = config.max_requests as f64;
+    pub fn new(config: RateLimitConfig) -> Result<Self> {
+        Self::with_clock(config, Arc::new(SystemClock))
+    }
+    
+    /// Create a limiter that reads time from `clock`
+    pub fn with_clock(config: RateLimitConfig, clock: Arc<dyn Clock>) -> Result<Self> {
+        config.validate()?;
         
-        Self {
+        Ok(Self {
+            buckets: Store::local(config.max_keys, config.overflow_policy),
             config,
-            buckets: HashMap::new(),
-            leak_rate,
-            max_capacity,
-        }
+            overrides: None,
+            clock,
+        })
     }
     
-    fn update_bucket(&mut self, key: &str) -> &mut BucketState {
-        let now = Instant::now();
-        
-        let bucket = self.buckets.entry(key.to_string()).or_insert(BucketState {
-            water_level: 0.0, // Start empty
+    /// Look up per-key limits in `overrides` on every check
+    pub fn with_overrides(mut self, overrides: Arc<LimitOverrides>) -> Self {
+        self.overrides = Some(overrides);
+        self
+    }
+    
+    /// Keep per-key state in `storage` instead of in this process
+    pub fn with_storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.buckets = Store::Shared { storage, clock: Arc::clone(&self.clock) };
+        self
+    }
+    
+    /// Number of keys currently tracked
+    pub fn tracked_keys(&self) -> usize {
+        self.buckets.len().unwrap_or(0)
+    }
+    
+    fn config_for(&self, key: &str) -> Cow<'_, RateLimitConfig> {
+        overrides::config_for(self.overrides.as_deref(), key, &self.config)
+    }
+    
+    fn new_bucket(config: &RateLimitConfig, now: Instant) -> BucketState {
+        BucketState {
+            // Start empty unless configured with less initial headroom
+            water_level: (config.capacity() - config.initial_tokens()) as f64,
             last_update: now,
-        });
+        }
+    }
+    
+    /// Take `cost` units of water back out, as if they had never been added
+    fn refund(&self, key: &str, cost: u64) -> Result<()> {
+        let now = self.clock.now();
+        let config = self.config_for(key);
+        let leak_rate = config.refill_rate();
+        let interval = config.refill_interval;
         
-        // Calculate how much has leaked since last check
-        let elapsed = now.duration_since(bucket.last_update).as_secs_f64();
-        let leaked = elapsed * self.leak_rate;
+        self.buckets.update(key, self.idle_check(now), |bucket| {
+            bucket.leak(now, leak_rate, interval);
+            bucket.water_level = (bucket.water_level - cost as f64).max(0.0);
+        })?;
+        Ok(())
+    }
+    
+    /// Queue `cost` units for `key` and report when they may be sent
+    ///
+    /// Requests are released in the order they were scheduled, one leak
+    /// interval's worth of units at a time. When the queue is full the
+    /// request is denied with a `retry_after`, like `allow_n`.
+    pub fn schedule(&self, key: &str, cost: u64) -> Result<Scheduled> {
+        let (decision, delay) = self.fill(key, cost)?;
+        Ok(Scheduled { decision, delay })
+    }
+    
+    /// Queue `cost` units for `key`, then wait until they may be sent
+    ///
+    /// Returns right away with a denied decision if the queue is full.
+    pub async fn shape(&self, key: &str, cost: u64) -> Result<RateLimitDecision> {
+        let Scheduled { decision, delay } = self.schedule(key, cost)?;
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        Ok(decision)
+    }
+    
+    /// Pour `cost` units into the bucket if they fit, also returning how long
+    /// the water already in the bucket takes to leak out
+    fn fill(&self, key: &str, cost: u64) -> Result<(RateLimitDecision, Duration)> {
+        let config = self.config_for(key);
+        let limit = config.capacity();
+        check_cost(cost, limit)?;
         
-        // Reduce water level by leaked amount (but not below 0)
-        bucket.water_level = (bucket.water_level - leaked).max(0.0);
-        bucket.last_update = now;
+        let now = self.clock.now();
+        let max_capacity = limit as f64;
+        let leak_rate = config.refill_rate();
+        let interval = config.refill_interval;
+        let init = || Self::new_bucket(&config, now);
         
-        bucket
+        self.buckets.with_entry(key, init, self.idle_check(now), |bucket| {
+            bucket.leak(now, leak_rate, interval);
+            
+            // Water already in the bucket goes out ahead of this request
+            let queued = time_to_replenish(bucket.water_level, bucket.last_update, now, leak_rate, interval);
+            
+            // Check if adding `cost` units of water would overflow
+            let cost = cost as f64;
+            let allowed = bucket.water_level + cost <= max_capacity;
+            if allowed {
+                bucket.water_level += cost;
+            }
+            
+            // Time until the bucket has drained completely
+            let reset_after = time_to_replenish(bucket.water_level, bucket.last_update, now, leak_rate, interval);
+            let remaining = (max_capacity - bucket.water_level).floor() as u64;
+            
+            if allowed {
+                (RateLimitDecision::allow(limit, remaining, reset_after), queued)
+            } else {
+                // Bucket would overflow: wait until enough has leaked to fit the cost
+                let overflow = bucket.water_level + cost - max_capacity;
+                let retry_after = time_to_replenish(overflow, bucket.last_update, now, leak_rate, interval);
+                (RateLimitDecision::deny(limit, remaining, reset_after, retry_after), Duration::ZERO)
+            }
+        })
+    }
+    
+    fn idle_check(&self, now: Instant) -> impl Fn(&str, &BucketState) -> bool + '_ {
+        move |key, bucket| {
+            let config = self.config_for(key);
+            let initial_level = (config.capacity() - config.initial_tokens()) as f64;
+            bucket.is_idle(now, config.refill_rate(), initial_level, config.refill_interval)
+        }
     }
 }
 
 impl RateLimiter for LeakyBucket {
-    fn allow_request(&mut self, key: &str) -> Result<bool> {
-        let max_capacity = self.max_capacity;
-        let bucket = self.update_bucket(key);
+    fn allow_n(&self, key: &str, cost: u64) -> Result<RateLimitDecision> {
+        let (decision, _) = self.fill(key, cost)?;
+        Ok(decision)
+    }
+    
+    fn reserve(&self, key: &str, cost: u64) -> Result<Reservation<'_>> {
+        let decision = self.allow_n(key, cost)?;
+        let key = key.to_string();
+        Ok(Reservation::new(decision, move || self.refund(&key, cost)))
+    }
+    
+    fn status(&self, key: &str) -> Result<RateLimitDecision> {
+        let config = self.config_for(key);
+        let limit = config.capacity();
+        let now = self.clock.now();
+        let max_capacity = limit as f64;
+        let leak_rate = config.refill_rate();
+        let interval = config.refill_interval;
+        
+        // Leak a copy so the stored state is left untouched
+        let mut bucket = self.buckets.peek(key, BucketState::clone)?
+            .unwrap_or_else(|| Self::new_bucket(&config, now));
+        bucket.leak(now, leak_rate, interval);
+        
+        let reset_after = time_to_replenish(bucket.water_level, bucket.last_update, now, leak_rate, interval);
+        let remaining = (max_capacity - bucket.water_level).floor() as u64;
         
-        // Check if adding 1 unit of water would overflow
         if bucket.water_level + 1.0 <= max_capacity {
-            bucket.water_level += 1.0;
-            Ok(true)
+            Ok(RateLimitDecision::allow(limit, remaining, reset_after))
         } else {
-            Ok(false) // Bucket would overflow
+            let overflow = bucket.water_level + 1.0 - max_capacity;
+            let retry_after = time_to_replenish(overflow, bucket.last_update, now, leak_rate, interval);
+            Ok(RateLimitDecision::deny(limit, remaining, reset_after, retry_after))
         }
     }
     
-    fn reset(&mut self, key: &str) {
-        self.buckets.remove(key);
+    fn reset(&self, key: &str) {
+        let _ = self.buckets.remove(key);
+    }
+    
+    fn evict_idle(&self) -> usize {
+        self.buckets.evict_idle(self.idle_check(self.clock.now())).unwrap_or(0)
+    }
+    
+    fn snapshot(&self) -> Option<LimiterSnapshot> {
+        let time = TimeBase::new(self.clock.as_ref());
+        self.buckets.snapshot(time, self.idle_check(time.now()))
+    }
+    
+    fn restore(&self, snapshot: &LimiterSnapshot) -> Result<usize> {
+        let time = TimeBase::new(self.clock.as_ref());
+        self.buckets.restore(snapshot, time, self.idle_check(time.now()))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::thread::sleep;
-    use std::time::Duration;
+    use crate::clock::{MockClock, TokioClock};
     
     #[test]
     fn test_leaky_bucket_allows_requests() {
         let config = RateLimitConfig::per_second(3);
-        let mut limiter = LeakyBucket::new(config);
+        let limiter = LeakyBucket::new(config).unwrap();
         
         // Bucket starts empty, can accept 3 requests
-        assert!(limiter.allow_request("user1").unwrap(), "Request 1 should be allowed");
-        assert!(limiter.allow_request("user1").unwrap(), "Request 2 should be allowed");
-        assert!(limiter.allow_request("user1").unwrap(), "Request 3 should be allowed");
+        assert!(limiter.allow_request("user1").unwrap().allowed, "Request 1 should be allowed");
+        assert!(limiter.allow_request("user1").unwrap().allowed, "Request 2 should be allowed");
+        assert!(limiter.allow_request("user1").unwrap().allowed, "Request 3 should be allowed");
         
         // 4th request overflows the bucket
-        assert!(!limiter.allow_request("user1").unwrap(), "4th request should be denied");
+        assert!(!limiter.allow_request("user1").unwrap().allowed, "4th request should be denied");
+    }
+    
+    #[test]
+    fn test_leaky_bucket_decision_fields() {
+        let clock = MockClock::new();
+        let config = RateLimitConfig::per_second(4);
+        let limiter = LeakyBucket::with_clock(config, Arc::new(clock)).unwrap();
+        
+        let first = limiter.allow_request("user1").unwrap();
+        assert_eq!(first.limit, 4);
+        assert_eq!(first.remaining, 3);
+        
+        for _ in 0..3 {
+            limiter.allow_request("user1").unwrap();
+        }
+        
+        // Full bucket drains one unit every 250ms
+        let denied = limiter.allow_request("user1").unwrap();
+        assert!(!denied.allowed);
+        assert_eq!(denied.remaining, 0);
+        assert_eq!(denied.retry_after, Some(Duration::from_millis(250)));
+        assert_eq!(denied.reset_after, Duration::from_secs(1));
+    }
+    
+    #[test]
+    fn test_leaky_bucket_weighted_cost() {
+        let config = RateLimitConfig::per_second(10);
+        let limiter = LeakyBucket::new(config).unwrap();
+        
+        assert!(limiter.allow_n("user1", 8).unwrap().allowed);
+        
+        // 8 + 3 would overflow a bucket of 10
+        let denied = limiter.allow_n("user1", 3).unwrap();
+        assert!(!denied.allowed);
+        assert!(denied.retry_after.unwrap() <= Duration::from_millis(100));
+        
+        assert!(limiter.allow_n("user1", 2).unwrap().allowed);
+        assert!(matches!(
+            limiter.allow_n("user1", 11),
+            Err(crate::RateLimitError::CostExceedsCapacity { .. })
+        ));
+    }
+    
+    #[test]
+    fn test_leaky_bucket_burst_and_initial_level() {
+        let clock = MockClock::new();
+        let config = RateLimitConfig::builder(2, Duration::from_secs(1))
+            .burst(6)
+            .initial_tokens(4)
+            .build()
+            .unwrap();
+        let limiter = LeakyBucket::with_clock(config, Arc::new(clock.clone())).unwrap();
+        
+        // Bucket of 6 starts with 2 units of water already in it
+        let decision = limiter.allow_n("user1", 4).unwrap();
+        assert!(decision.allowed);
+        assert_eq!(decision.limit, 6);
+        assert_eq!(decision.reset_after, Duration::from_secs(3));
+        assert!(!limiter.allow_request("user1").unwrap().allowed);
+        
+        // Leaks at the sustained rate of 2/s
+        clock.advance(Duration::from_secs(1));
+        assert!(limiter.allow_n("user1", 2).unwrap().allowed);
+        assert!(!limiter.allow_request("user1").unwrap().allowed);
+    }
+    
+    #[test]
+    fn test_leaky_bucket_leak_interval() {
+        let clock = MockClock::new();
+        let config = RateLimitConfig::builder(4, Duration::from_secs(1))
+            .refill_interval(Duration::from_millis(500))
+            .build()
+            .unwrap();
+        let limiter = LeakyBucket::with_clock(config, Arc::new(clock.clone())).unwrap();
+        
+        assert!(limiter.allow_n("user1", 4).unwrap().allowed);
+        
+        // Two units drain at once every 500ms
+        clock.advance(Duration::from_millis(400));
+        let denied = limiter.allow_request("user1").unwrap();
+        assert_eq!(denied.retry_after, Some(Duration::from_millis(100)));
+        
+        clock.advance(Duration::from_millis(100));
+        assert!(limiter.allow_n("user1", 2).unwrap().allowed);
+        assert!(!limiter.allow_request("user1").unwrap().allowed);
     }
     
     #[test]
     fn test_leaky_bucket_leaks() {
+        let clock = MockClock::new();
         let config = RateLimitConfig::per_second(2);
-        let mut limiter = LeakyBucket::new(config);
+        let limiter = LeakyBucket::with_clock(config, Arc::new(clock.clone())).unwrap();
         
         // Fill bucket to capacity
-        assert!(limiter.allow_request("user1").unwrap());
-        assert!(limiter.allow_request("user1").unwrap());
+        assert!(limiter.allow_request("user1").unwrap().allowed);
+        assert!(limiter.allow_request("user1").unwrap().allowed);
         
         // Bucket is full now
-        assert!(!limiter.allow_request("user1").unwrap());
+        assert!(!limiter.allow_request("user1").unwrap().allowed);
+        
+        // One unit leaks out every 500ms
+        clock.advance(Duration::from_millis(500));
+        assert!(limiter.allow_request("user1").unwrap().allowed);
+        assert!(!limiter.allow_request("user1").unwrap().allowed);
+        
+        // Bucket should be empty after a full second, can accept requests again
+        clock.advance(Duration::from_secs(1));
+        assert!(limiter.allow_request("user1").unwrap().allowed);
+        assert!(limiter.allow_request("user1").unwrap().allowed);
+    }
+    
+    #[test]
+    fn test_leaky_bucket_evicts_drained_keys() {
+        let clock = MockClock::new();
+        let config = RateLimitConfig::per_second(2);
+        let limiter = LeakyBucket::with_clock(config, Arc::new(clock.clone())).unwrap();
         
-        // Wait for bucket to leak (1.1 seconds = ~2.2 units leaked)
-        sleep(Duration::from_millis(1100));
+        limiter.allow_n("user1", 2).unwrap();
+        
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(limiter.evict_idle(), 0);
+        
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(limiter.evict_idle(), 1);
+        assert_eq!(limiter.tracked_keys(), 0);
+    }
+    
+    #[test]
+    fn test_leaky_bucket_status_does_not_consume() {
+        let clock = MockClock::new();
+        let config = RateLimitConfig::per_second(4);
+        let limiter = LeakyBucket::with_clock(config, Arc::new(clock.clone())).unwrap();
+        
+        assert_eq!(limiter.status("user1").unwrap().remaining, 4);
+        assert_eq!(limiter.tracked_keys(), 0);
+        
+        limiter.allow_n("user1", 4).unwrap();
+        let status = limiter.status("user1").unwrap();
+        assert!(!status.allowed);
+        assert_eq!(status.retry_after, Some(Duration::from_millis(250)));
+        assert_eq!(status.reset_after, Duration::from_secs(1));
+        
+        clock.advance(Duration::from_millis(500));
+        let status = limiter.status("user1").unwrap();
+        assert!(status.allowed);
+        assert_eq!(status.remaining, 2);
+        assert_eq!(limiter.status("user1").unwrap(), status);
+    }
+    
+    #[test]
+    fn test_leaky_bucket_schedule_spaces_requests() {
+        let clock = MockClock::new();
+        let config = RateLimitConfig::per_second(4);
+        let limiter = LeakyBucket::with_clock(config, Arc::new(clock.clone())).unwrap();
+        
+        // One request goes out every 250ms, up to 4 queued at once
+        let delays: Vec<_> = (0..4)
+            .map(|_| limiter.schedule("api", 1).unwrap().delay)
+            .collect();
+        assert_eq!(delays, [0, 250, 500, 750].map(Duration::from_millis));
+        
+        let full = limiter.schedule("api", 1).unwrap();
+        assert!(!full.decision.allowed);
+        assert_eq!(full.delay, Duration::ZERO);
+        assert_eq!(full.decision.retry_after, Some(Duration::from_millis(250)));
+        
+        // The first request has been sent, so one slot is free again
+        clock.advance(Duration::from_millis(250));
+        assert!(!limiter.schedule("api", 2).unwrap().decision.allowed);
+        let scheduled = limiter.schedule("api", 1).unwrap();
+        assert!(scheduled.decision.allowed);
+        assert_eq!(scheduled.delay, Duration::from_millis(750));
+    }
+    
+    #[test]
+    fn test_leaky_bucket_schedule_with_burst_and_cost() {
+        let clock = MockClock::new();
+        let config = RateLimitConfig::builder(2, Duration::from_secs(1))
+            .burst(10)
+            .build()
+            .unwrap();
+        let limiter = LeakyBucket::with_clock(config, Arc::new(clock.clone())).unwrap();
+        
+        // A request is released once the water ahead of it has leaked out
+        assert_eq!(limiter.schedule("api", 3).unwrap().delay, Duration::ZERO);
+        assert_eq!(limiter.schedule("api", 1).unwrap().delay, Duration::from_millis(1500));
+        
+        // Metered requests count towards the same queue
+        assert!(limiter.allow_n("api", 4).unwrap().allowed);
+        let scheduled = limiter.schedule("api", 2).unwrap();
+        assert_eq!(scheduled.delay, Duration::from_secs(4));
+        assert_eq!(scheduled.decision.remaining, 0);
+        
+        // Once idle, requests are no longer held back
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(limiter.schedule("api", 1).unwrap().delay, Duration::ZERO);
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn test_leaky_bucket_shape_waits() {
+        let clock = TokioClock::new();
+        let config = RateLimitConfig::new(2, Duration::from_millis(100));
+        let limiter = LeakyBucket::with_clock(config, Arc::new(clock)).unwrap();
+        
+        for _ in 0..2 {
+            assert!(limiter.shape("api", 1).await.unwrap().allowed);
+        }
+        
+        // The second request was held for exactly one leak interval
+        assert_eq!(clock.elapsed(), Duration::from_millis(50));
+    }
+    
+    #[test]
+    fn test_leaky_bucket_reservation_refund() {
+        let config = RateLimitConfig::new(3, Duration::from_secs(60));
+        let limiter = LeakyBucket::new(config).unwrap();
         
-        // Bucket should be empty now, can accept requests again
-        assert!(limiter.allow_request("user1").unwrap());
-        assert!(limiter.allow_request("user1").unwrap());
+        limiter.reserve("user1", 3).unwrap().cancel().unwrap();
+        assert!(limiter.allow_n("user1", 3).unwrap().allowed);
     }
 }
\ No newline at end of file
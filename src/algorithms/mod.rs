@@ -2,8 +2,63 @@ pub mod token_bucket;
 pub mod leaky_bucket;
 pub mod fixed_window;
 pub mod sliding_window;
+pub mod sliding_window_counter;
+pub mod gcra;
+pub mod multi_window;
+pub mod calendar_quota;
 
 pub use token_bucket::TokenBucket;
 pub use leaky_bucket::LeakyBucket;
 pub use fixed_window::FixedWindow;
-pub use sliding_window::SlidingWindow;
\ No newline at end of file
+pub use sliding_window::SlidingWindow;
+pub use sliding_window_counter::SlidingWindowCounter;
+pub use gcra::Gcra;
+pub use multi_window::{MultiWindow, MultiWindowDecision};
+pub use calendar_quota::{CalendarQuota, QuotaPeriod};
+
+use std::time::{Duration, Instant};
+
+/// Units replenished (refilled or leaked) between `last` and `now`
+///
+/// Returns the amount together with the new reference time. With a refill
+/// interval, only whole intervals count and the reference time advances by
+/// exactly that many intervals so partial progress is not lost.
+pub(crate) fn replenish(last: Instant, now: Instant, rate: f64, interval: Option<Duration>) -> (f64, Instant) {
+    let elapsed = now.saturating_duration_since(last);
+    
+    match interval {
+        None => (elapsed.as_secs_f64() * rate, now),
+        Some(interval) => {
+            let steps = elapsed.as_nanos() / interval.as_nanos();
+            let amount = steps as f64 * interval.as_secs_f64() * rate;
+            
+            // So many steps have passed that the bucket is saturated anyway
+            let last = match u32::try_from(steps) {
+                Ok(steps) => last + interval * steps,
+                Err(_) => now,
+            };
+            (amount, last)
+        }
+    }
+}
+
+/// Time from `now` until `amount` more units have been replenished
+///
+/// Saturates at `Duration::MAX` when a very slow rate puts it out of range.
+pub(crate) fn time_to_replenish(amount: f64, last: Instant, now: Instant, rate: f64, interval: Option<Duration>) -> Duration {
+    if amount <= 0.0 {
+        return Duration::ZERO;
+    }
+    
+    match interval {
+        None => Duration::try_from_secs_f64(amount / rate).unwrap_or(Duration::MAX),
+        Some(interval) => {
+            // Tolerate float noise so an exact multiple doesn't round up a step
+            let per_step = interval.as_secs_f64() * rate;
+            let steps = (amount / per_step - 1e-9).ceil().max(1.0);
+            
+            let until_steps = Duration::try_from_secs_f64(interval.as_secs_f64() * steps).unwrap_or(Duration::MAX);
+            until_steps.saturating_sub(now.saturating_duration_since(last))
+        }
+    }
+}
@@ -0,0 +1,339 @@
+use super::fixed_window::WindowState;
+use crate::clock::{Clock, SystemClock};
+use crate::snapshot::{KeyState, LimiterSnapshot, TimeBase};
+use crate::storage::{Storage, Store, StoredState};
+use crate::{check_cost, RateLimitError, RateLimiter, RateLimitConfig, RateLimitDecision, Reservation, Result};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Outcome of checking a request against several windows at once
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MultiWindowDecision {
+    /// Combined decision, with `limit`, `remaining` and `reset_after` taken
+    /// from the denying window, or else from the one with the least left
+    pub decision: RateLimitDecision,
+    /// Index of the window that denied the request (the one to wait longest for)
+    pub denied_by: Option<usize>,
+}
+
+impl MultiWindowDecision {
+    /// Combine per-window decisions, given in the order the limits were configured
+    pub(crate) fn combine(decisions: &[RateLimitDecision]) -> Self {
+        let denied_by = decisions.iter()
+            .enumerate()
+            .filter(|(_, decision)| !decision.allowed)
+            .max_by_key(|(_, decision)| decision.retry_after)
+            .map(|(i, _)| i);
+        
+        let reported = denied_by.unwrap_or_else(|| {
+            decisions.iter()
+                .enumerate()
+                .min_by_key(|(_, decision)| decision.remaining)
+                .map_or(0, |(i, _)| i)
+        });
+        
+        Self {
+            decision: decisions[reported],
+            denied_by,
+        }
+    }
+}
+
+/// One `WindowState` per configured limit, in order
+impl StoredState for Vec<WindowState> {
+    const ALGORITHM: &'static str = "multi_window";
+    
+    fn export(&self, time: TimeBase) -> KeyState {
+        KeyState::MultiWindow {
+            windows: self.iter()
+                .map(|window| (window.count, time.to_unix_us(window.window_start)))
+                .collect(),
+        }
+    }
+    
+    fn import(state: &KeyState, time: TimeBase) -> Option<Self> {
+        match state {
+            // The windows are matched to the configured limits on their next use
+            KeyState::MultiWindow { windows } => Some(
+                windows.iter()
+                    .map(|&(count, start_us)| WindowState {
+                        count,
+                        window_start: time.to_instant(start_us),
+                    })
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+}
+
+/// Several fixed windows enforced together on the same key
+/// (e.g. 10 per second AND 1000 per hour)
+///
+/// All windows are checked under a single lock: a request consumes from
+/// every window or from none, so a deny from one window never eats into
+/// another. Each window behaves like a `FixedWindow` with its own config;
+/// key limits (`max_keys`) are taken from the first config.
+pub struct MultiWindow {
+    limits: Vec<RateLimitConfig>,
+    windows: Store<Vec<WindowState>>,
+    capacity: u64, // largest cost every window can hold
+    clock: Arc<dyn Clock>,
+}
+
+impl MultiWindow {
+    pub fn new(limits: Vec<RateLimitConfig>) -> Result<Self> {
+        Self::with_clock(limits, Arc::new(SystemClock))
+    }
+    
+    /// Create a limiter that reads time from `clock`
+    pub fn with_clock(limits: Vec<RateLimitConfig>, clock: Arc<dyn Clock>) -> Result<Self> {
+        let first = limits.first()
+            .ok_or_else(|| RateLimitError::ConfigError("MultiWindow needs at least one limit".to_string()))?;
+        for config in &limits {
+            config.validate()?;
+        }
+        
+        let capacity = limits.iter().map(|config| config.max_requests).min().unwrap_or(0);
+        
+        Ok(Self {
+            windows: Store::local(first.max_keys, first.overflow_policy),
+            limits,
+            capacity,
+            clock,
+        })
+    }
+    
+    /// Keep per-key state in `storage` instead of in this process
+    pub fn with_storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.windows = Store::Shared { storage, clock: Arc::clone(&self.clock) };
+        self
+    }
+    
+    /// The configured limits, in the order `denied_by` refers to
+    pub fn limits(&self) -> &[RateLimitConfig] {
+        &self.limits
+    }
+    
+    /// Number of keys currently tracked
+    pub fn tracked_keys(&self) -> usize {
+        self.windows.len().unwrap_or(0)
+    }
+    
+    /// Check `cost` against every window, consuming only if all of them allow it
+    pub fn check(&self, key: &str, cost: u64) -> Result<MultiWindowDecision> {
+        self.consume(key, cost).map(|(decision, _)| decision)
+    }
+    
+    /// Like `check`, also returning when each window started
+    fn consume(&self, key: &str, cost: u64) -> Result<(MultiWindowDecision, Vec<Instant>)> {
+        check_cost(cost, self.capacity)?;
+        
+        let now = self.clock.now();
+        let init = || vec![WindowState::new(now); self.limits.len()];
+        
+        self.windows.with_entry(key, init, self.idle_check(now), |windows| {
+            windows.resize(self.limits.len(), WindowState::new(now));
+            for (window, config) in windows.iter_mut().zip(&self.limits) {
+                window.roll(now, config.window);
+            }
+            
+            let allowed = windows.iter()
+                .zip(&self.limits)
+                .all(|(window, config)| window.count + cost <= config.max_requests);
+            if allowed {
+                for window in windows.iter_mut() {
+                    window.count += cost;
+                }
+            }
+            
+            // Without consuming, windows that had room still report as allowing
+            let cost = if allowed { 0 } else { cost };
+            let decision = self.decide(windows, now, cost);
+            (decision, windows.iter().map(|window| window.window_start).collect())
+        })
+    }
+    
+    /// Decide whether `cost` more units fit in every (already rolled) window
+    fn decide(&self, windows: &[WindowState], now: Instant, cost: u64) -> MultiWindowDecision {
+        let decisions: Vec<_> = windows.iter()
+            .zip(&self.limits)
+            .map(|(window, config)| {
+                let limit = config.max_requests;
+                let reset_after = window.reset_after(now, config.window);
+                let remaining = limit - window.count;
+                
+                if window.count + cost <= limit {
+                    RateLimitDecision::allow(limit, remaining, reset_after)
+                } else {
+                    // Nothing frees up until this window starts over
+                    RateLimitDecision::deny(limit, remaining, reset_after, reset_after)
+                }
+            })
+            .collect();
+        
+        MultiWindowDecision::combine(&decisions)
+    }
+    
+    /// Current state of every window for `key`, without consuming
+    pub fn check_status(&self, key: &str) -> Result<MultiWindowDecision> {
+        let now = self.clock.now();
+        
+        let mut windows = self.windows.peek(key, Vec::clone)?.unwrap_or_default();
+        windows.resize(self.limits.len(), WindowState::new(now));
+        for (window, config) in windows.iter_mut().zip(&self.limits) {
+            window.roll(now, config.window);
+        }
+        
+        Ok(self.decide(&windows, now, 1))
+    }
+    
+    fn idle_check(&self, now: Instant) -> impl Fn(&str, &Vec<WindowState>) -> bool + '_ {
+        move |_, windows| {
+            windows.iter()
+                .zip(&self.limits)
+                .all(|(window, config)| window.is_idle(now, config.window))
+        }
+    }
+}
+
+impl RateLimiter for MultiWindow {
+    fn allow_n(&self, key: &str, cost: u64) -> Result<RateLimitDecision> {
+        self.check(key, cost).map(|result| result.decision)
+    }
+    
+    fn reserve(&self, key: &str, cost: u64) -> Result<Reservation<'_>> {
+        let (result, window_starts) = self.consume(key, cost)?;
+        let key = key.to_string();
+        Ok(Reservation::new(result.decision, move || {
+            // Only give slots back to the windows they were taken from
+            self.windows.update(&key, self.idle_check(self.clock.now()), |windows| {
+                for ((window, config), &start) in windows.iter_mut().zip(&self.limits).zip(&window_starts) {
+                    if window.started_at(start, config.window) {
+                        window.count = window.count.saturating_sub(cost);
+                    }
+                }
+            })?;
+            Ok(())
+        }))
+    }
+    
+    fn status(&self, key: &str) -> Result<RateLimitDecision> {
+        self.check_status(key).map(|result| result.decision)
+    }
+    
+    fn reset(&self, key: &str) {
+        let _ = self.windows.remove(key);
+    }
+    
+    fn evict_idle(&self) -> usize {
+        self.windows.evict_idle(self.idle_check(self.clock.now())).unwrap_or(0)
+    }
+    
+    fn snapshot(&self) -> Option<LimiterSnapshot> {
+        let time = TimeBase::new(self.clock.as_ref());
+        self.windows.snapshot(time, self.idle_check(time.now()))
+    }
+    
+    fn restore(&self, snapshot: &LimiterSnapshot) -> Result<usize> {
+        let time = TimeBase::new(self.clock.as_ref());
+        self.windows.restore(snapshot, time, self.idle_check(time.now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use std::time::Duration;
+    
+    fn limiter(clock: &MockClock) -> MultiWindow {
+        let limits = vec![
+            RateLimitConfig::new(2, Duration::from_secs(1)),
+            RateLimitConfig::new(5, Duration::from_secs(60)),
+        ];
+        MultiWindow::with_clock(limits, Arc::new(clock.clone())).unwrap()
+    }
+    
+    #[test]
+    fn test_multi_window_enforces_every_window() {
+        let clock = MockClock::new();
+        let limiter = limiter(&clock);
+        
+        // The per-second window denies first
+        assert!(limiter.check("user1", 2).unwrap().decision.allowed);
+        let denied = limiter.check("user1", 1).unwrap();
+        assert!(!denied.decision.allowed);
+        assert_eq!(denied.denied_by, Some(0));
+        assert_eq!(denied.decision.retry_after, Some(Duration::from_secs(1)));
+        
+        clock.advance(Duration::from_secs(1));
+        let allowed = limiter.check("user1", 2).unwrap();
+        assert!(allowed.decision.allowed);
+        assert_eq!(allowed.denied_by, None);
+        
+        // Reported numbers come from the tightest window
+        assert_eq!(allowed.decision.limit, 2);
+        assert_eq!(allowed.decision.remaining, 0);
+        
+        // Then the per-minute window runs out
+        clock.advance(Duration::from_secs(1));
+        let denied = limiter.check("user1", 2).unwrap();
+        assert_eq!(denied.denied_by, Some(1));
+        assert_eq!(denied.decision.limit, 5);
+        assert_eq!(denied.decision.remaining, 1);
+        assert_eq!(denied.decision.retry_after, Some(Duration::from_secs(58)));
+    }
+    
+    #[test]
+    fn test_multi_window_denial_consumes_nothing() {
+        let clock = MockClock::new();
+        let limiter = limiter(&clock);
+        
+        limiter.allow_n("user1", 2).unwrap();
+        for _ in 0..10 {
+            assert!(!limiter.allow_request("user1").unwrap().allowed);
+        }
+        
+        // Denied requests did not count against the per-minute window
+        clock.advance(Duration::from_secs(1));
+        assert!(limiter.allow_n("user1", 2).unwrap().allowed);
+        clock.advance(Duration::from_secs(1));
+        assert!(limiter.allow_request("user1").unwrap().allowed);
+        assert_eq!(limiter.check_status("user1").unwrap().denied_by, Some(1));
+    }
+    
+    #[test]
+    fn test_multi_window_status_reserve_and_eviction() {
+        let clock = MockClock::new();
+        let limiter = limiter(&clock);
+        
+        let status = limiter.status("user1").unwrap();
+        assert!(status.allowed);
+        assert_eq!(status.remaining, 2);
+        assert_eq!(limiter.tracked_keys(), 0);
+        
+        limiter.reserve("user1", 2).unwrap().cancel().unwrap();
+        clock.advance(Duration::from_secs(1));
+        for _ in 0..2 {
+            limiter.allow_n("user1", 2).unwrap();
+            clock.advance(Duration::from_secs(1));
+        }
+        
+        // The cancelled reservation was refunded to the per-minute window too
+        assert_eq!(limiter.status("user1").unwrap().remaining, 1);
+        assert!(matches!(
+            limiter.allow_n("user1", 3),
+            Err(RateLimitError::CostExceedsCapacity { cost: 3, capacity: 2 })
+        ));
+        
+        assert_eq!(limiter.evict_idle(), 0);
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(limiter.evict_idle(), 1);
+    }
+    
+    #[test]
+    fn test_multi_window_rejects_empty_limits() {
+        assert!(matches!(MultiWindow::new(Vec::new()), Err(RateLimitError::ConfigError(_))));
+    }
+}
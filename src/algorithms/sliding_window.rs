@@ -1,99 +1,389 @@
-use crate::{RateLimiter, RateLimitConfig, Result};
-use std::collections::{HashMap, VecDeque};
+use crate::clock::{Clock, SystemClock};
+use crate::overrides::{self, LimitOverrides};
+use crate::snapshot::{KeyState, LimiterSnapshot, TimeBase};
+use crate::storage::{self, Storage, Store, StoredState};
+use crate::{check_cost, RateLimiter, RateLimitConfig, RateLimitDecision, Reservation, Result};
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default)]
 struct RequestLog {
-    timestamps: VecDeque<Instant>,
+    timestamps: VecDeque<(Instant, u64)>, // (request time, cost)
+    count: u64, // Sum of all costs currently in the window
+}
+
+impl RequestLog {
+    fn clean_old_requests(&mut self, now: Instant, window: Duration) {
+        // Remove requests outside the sliding window
+        while let Some(&(timestamp, cost)) = self.timestamps.front() {
+            if now.saturating_duration_since(timestamp) > window {
+                self.timestamps.pop_front();
+                self.count -= cost;
+            } else {
+                break;
+            }
+        }
+    }
+    
+    /// Time until the newest request slides out and the log is empty
+    fn reset_after(&self, now: Instant, window: Duration) -> Duration {
+        self.timestamps.back()
+            .map(|(newest, _)| window.saturating_sub(now.saturating_duration_since(*newest)))
+            .unwrap_or(Duration::ZERO)
+    }
+    
+    /// Time until enough of the oldest requests have slid out to fit `cost`
+    fn retry_after(&self, now: Instant, window: Duration, cost: u64, max_requests: u64) -> Duration {
+        let mut freed = 0;
+        let mut retry_after = Duration::ZERO;
+        for &(timestamp, entry_cost) in &self.timestamps {
+            freed += entry_cost;
+            retry_after = window.saturating_sub(now.saturating_duration_since(timestamp));
+            if self.count - freed + cost <= max_requests {
+                break;
+            }
+        }
+        retry_after
+    }
+    
+    /// Whether every logged request has slid out of the window
+    fn is_idle(&self, now: Instant, window: Duration) -> bool {
+        self.timestamps.back()
+            .is_none_or(|&(newest, _)| now.saturating_duration_since(newest) > window)
+    }
+}
+
+impl StoredState for RequestLog {
+    const ALGORITHM: &'static str = "sliding_window";
+    
+    fn export(&self, time: TimeBase) -> KeyState {
+        KeyState::SlidingWindow {
+            requests: self.timestamps.iter()
+                .map(|&(timestamp, cost)| (time.to_unix_us(timestamp), cost))
+                .collect(),
+        }
+    }
+    
+    fn import(state: &KeyState, time: TimeBase) -> Option<Self> {
+        match state {
+            KeyState::SlidingWindow { requests } => Some(Self {
+                timestamps: requests.iter()
+                    .map(|&(timestamp_us, cost)| (time.to_instant(timestamp_us), cost))
+                    .collect(),
+                count: requests.iter().map(|&(_, cost)| cost).sum(),
+            }),
+            _ => None,
+        }
+    }
 }
 
 /// Sliding Window algorithm implementation
 /// Tracks individual request timestamps in a sliding window
 /// Most accurate but uses more memory
+///
+/// A key is forgotten once all of its requests have slid out of the window.
 pub struct SlidingWindow {
     config: RateLimitConfig,
-    logs: HashMap<String, RequestLog>,
+    overrides: Option<Arc<LimitOverrides>>,
+    logs: Store<RequestLog>,
+    clock: Arc<dyn Clock>,
 }
 
 impl SlidingWindow {
-    pub fn new(config: RateLimitConfig) -> Self {
-        Self {
+    pub fn new(config: RateLimitConfig) -> Result<Self> {
+        Self::with_clock(config, Arc::new(SystemClock))
+    }
+    
+    /// Create a limiter that reads time from `clock`
+    pub fn with_clock(config: RateLimitConfig, clock: Arc<dyn Clock>) -> Result<Self> {
+        config.validate()?;
+        
+        Ok(Self {
+            logs: Store::local(config.max_keys, config.overflow_policy),
             config,
-            logs: HashMap::new(),
-        }
+            overrides: None,
+            clock,
+        })
+    }
+    
+    /// Look up per-key limits in `overrides` on every check
+    pub fn with_overrides(mut self, overrides: Arc<LimitOverrides>) -> Self {
+        self.overrides = Some(overrides);
+        self
+    }
+    
+    /// Keep per-key state in `storage` instead of in this process
+    pub fn with_storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.logs = Store::Shared { storage, clock: Arc::clone(&self.clock) };
+        self
+    }
+    
+    fn config_for(&self, key: &str) -> Cow<'_, RateLimitConfig> {
+        overrides::config_for(self.overrides.as_deref(), key, &self.config)
+    }
+    
+    /// Number of keys currently tracked
+    pub fn tracked_keys(&self) -> usize {
+        self.logs.len().unwrap_or(0)
     }
     
-    fn clean_old_requests(&mut self, key: &str) {
-        let now = Instant::now();
+    /// Log a request of `cost` if it fits, returning the time it was logged at
+    fn check(&self, key: &str, cost: u64) -> Result<(RateLimitDecision, Instant)> {
+        let config = self.config_for(key);
+        let max_requests = config.max_requests;
+        check_cost(cost, max_requests)?;
         
-        if let Some(log) = self.logs.get_mut(key) {
-            // Remove requests outside the sliding window
-            while let Some(&timestamp) = log.timestamps.front() {
-                if now.duration_since(timestamp) > self.config.window {
-                    log.timestamps.pop_front();
-                } else {
-                    break;
-                }
+        let now = self.clock.now();
+        let window = config.window;
+        let init = RequestLog::default;
+        
+        let decision = self.logs.with_entry(key, init, self.idle_check(now), |log| {
+            log.clean_old_requests(now, window);
+            
+            let allowed = log.count + cost <= max_requests;
+            if allowed {
+                log.timestamps.push_back((now, cost));
+                log.count += cost;
             }
-        }
+            
+            let remaining = max_requests.saturating_sub(log.count);
+            let reset_after = log.reset_after(now, window);
+            
+            if allowed {
+                RateLimitDecision::allow(max_requests, remaining, reset_after)
+            } else {
+                let retry_after = log.retry_after(now, window, cost, max_requests);
+                RateLimitDecision::deny(max_requests, remaining, reset_after, retry_after)
+            }
+        })?;
+        
+        Ok((decision, now))
+    }
+    
+    fn idle_check(&self, now: Instant) -> impl Fn(&str, &RequestLog) -> bool + '_ {
+        move |key, log| log.is_idle(now, self.config_for(key).window)
     }
 }
 
 impl RateLimiter for SlidingWindow {
-    fn allow_request(&mut self, key: &str) -> Result<bool> {
-        self.clean_old_requests(key);
+    fn allow_n(&self, key: &str, cost: u64) -> Result<RateLimitDecision> {
+        self.check(key, cost).map(|(decision, _)| decision)
+    }
+    
+    fn reserve(&self, key: &str, cost: u64) -> Result<Reservation<'_>> {
+        let (decision, timestamp) = self.check(key, cost)?;
+        let key = key.to_string();
+        Ok(Reservation::new(decision, move || {
+            // Requests that already slid out have nothing left to refund
+            self.logs.update(&key, self.idle_check(self.clock.now()), |log| {
+                let logged = |&(logged_at, logged_cost): &(Instant, u64)| {
+                    logged_cost == cost && storage::same_instant(logged_at, timestamp, storage::TIME_TOLERANCE)
+                };
+                if let Some(index) = log.timestamps.iter().rposition(logged) {
+                    log.timestamps.remove(index);
+                    log.count -= cost;
+                }
+            })?;
+            Ok(())
+        }))
+    }
+    
+    fn status(&self, key: &str) -> Result<RateLimitDecision> {
+        let config = self.config_for(key);
+        let max_requests = config.max_requests;
+        let now = self.clock.now();
+        let window = config.window;
+        
+        // Drop expired requests from a copy so the stored log is left untouched
+        let mut log = self.logs.peek(key, RequestLog::clone)?.unwrap_or_default();
+        log.clean_old_requests(now, window);
         
-        let log = self.logs.entry(key.to_string()).or_insert(RequestLog {
-            timestamps: VecDeque::new(),
-        });
+        let remaining = max_requests.saturating_sub(log.count);
+        let reset_after = log.reset_after(now, window);
         
-        if log.timestamps.len() < self.config.max_requests as usize {
-            log.timestamps.push_back(Instant::now());
-            Ok(true)
+        if remaining > 0 {
+            Ok(RateLimitDecision::allow(max_requests, remaining, reset_after))
         } else {
-            Ok(false)
+            let retry_after = log.retry_after(now, window, 1, max_requests);
+            Ok(RateLimitDecision::deny(max_requests, remaining, reset_after, retry_after))
         }
     }
     
-    fn reset(&mut self, key: &str) {
-        self.logs.remove(key);
+    fn reset(&self, key: &str) {
+        let _ = self.logs.remove(key);
+    }
+    
+    fn evict_idle(&self) -> usize {
+        self.logs.evict_idle(self.idle_check(self.clock.now())).unwrap_or(0)
+    }
+    
+    fn snapshot(&self) -> Option<LimiterSnapshot> {
+        let time = TimeBase::new(self.clock.as_ref());
+        self.logs.snapshot(time, self.idle_check(time.now()))
+    }
+    
+    fn restore(&self, snapshot: &LimiterSnapshot) -> Result<usize> {
+        let time = TimeBase::new(self.clock.as_ref());
+        self.logs.restore(snapshot, time, self.idle_check(time.now()))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::thread::sleep;
+    use crate::clock::MockClock;
     
     #[test]
     fn test_sliding_window_allows_requests() {
         let config = RateLimitConfig::per_second(5);
-        let mut limiter = SlidingWindow::new(config);
+        let limiter = SlidingWindow::new(config).unwrap();
         
         // Should allow 5 requests
         for _ in 0..5 {
-            assert!(limiter.allow_request("user1").unwrap());
+            assert!(limiter.allow_request("user1").unwrap().allowed);
         }
         
         // 6th should be denied
-        assert!(!limiter.allow_request("user1").unwrap());
+        assert!(!limiter.allow_request("user1").unwrap().allowed);
+    }
+    
+    #[test]
+    fn test_sliding_window_decision_fields() {
+        let clock = MockClock::new();
+        let config = RateLimitConfig::new(2, Duration::from_secs(10));
+        let limiter = SlidingWindow::with_clock(config, Arc::new(clock.clone())).unwrap();
+        
+        let first = limiter.allow_request("user1").unwrap();
+        assert_eq!(first.limit, 2);
+        assert_eq!(first.remaining, 1);
+        
+        clock.advance(Duration::from_secs(3));
+        limiter.allow_request("user1").unwrap();
+        let denied = limiter.allow_request("user1").unwrap();
+        assert!(!denied.allowed);
+        assert_eq!(denied.remaining, 0);
+        
+        // Oldest request slides out before the newest one
+        assert_eq!(denied.retry_after, Some(Duration::from_secs(7)));
+        assert_eq!(denied.reset_after, Duration::from_secs(10));
+    }
+    
+    #[test]
+    fn test_sliding_window_weighted_cost() {
+        let config = RateLimitConfig::new(10, Duration::from_secs(10));
+        let limiter = SlidingWindow::new(config).unwrap();
+        
+        assert!(limiter.allow_n("user1", 4).unwrap().allowed);
+        assert!(limiter.allow_n("user1", 5).unwrap().allowed);
+        
+        let denied = limiter.allow_n("user1", 3).unwrap();
+        assert!(!denied.allowed);
+        assert_eq!(denied.remaining, 1);
+        
+        // Only the first entry has to slide out to fit 3 more units
+        assert!(denied.retry_after.unwrap() <= denied.reset_after);
+        
+        assert!(limiter.allow_n("user1", 1).unwrap().allowed);
+        assert!(matches!(
+            limiter.allow_n("user1", 11),
+            Err(crate::RateLimitError::CostExceedsCapacity { .. })
+        ));
     }
     
     #[test]
     fn test_sliding_window_slides() {
+        let clock = MockClock::new();
         let config = RateLimitConfig::new(3, Duration::from_millis(500));
-        let mut limiter = SlidingWindow::new(config);
+        let limiter = SlidingWindow::with_clock(config, Arc::new(clock.clone())).unwrap();
         
-        // Use up limit
-        for _ in 0..3 {
-            assert!(limiter.allow_request("user1").unwrap());
-        }
-        assert!(!limiter.allow_request("user1").unwrap());
+        // Use up limit, with the last request 200ms later
+        assert!(limiter.allow_request("user1").unwrap().allowed);
+        assert!(limiter.allow_request("user1").unwrap().allowed);
+        clock.advance(Duration::from_millis(200));
+        assert!(limiter.allow_request("user1").unwrap().allowed);
+        assert!(!limiter.allow_request("user1").unwrap().allowed);
         
-        // Wait for oldest request to slide out
-        sleep(Duration::from_millis(600));
+        // Requests exactly one window old still count
+        clock.advance(Duration::from_millis(300));
+        assert!(!limiter.allow_request("user1").unwrap().allowed);
         
         // Should allow new requests as old ones slide out
-        assert!(limiter.allow_request("user1").unwrap());
+        clock.advance(Duration::from_millis(1));
+        assert!(limiter.allow_request("user1").unwrap().allowed);
+        assert!(limiter.allow_request("user1").unwrap().allowed);
+        assert!(!limiter.allow_request("user1").unwrap().allowed);
+    }
+    
+    #[test]
+    fn test_sliding_window_evicts_empty_logs() {
+        let clock = MockClock::new();
+        let config = RateLimitConfig::per_second(2);
+        let limiter = SlidingWindow::with_clock(config, Arc::new(clock.clone())).unwrap();
+        
+        limiter.allow_request("user1").unwrap();
+        clock.advance(Duration::from_millis(500));
+        limiter.allow_request("user1").unwrap();
+        
+        // The newest request is still inside the window
+        clock.advance(Duration::from_millis(600));
+        assert_eq!(limiter.evict_idle(), 0);
+        
+        clock.advance(Duration::from_millis(401));
+        assert_eq!(limiter.evict_idle(), 1);
+        assert_eq!(limiter.tracked_keys(), 0);
+    }
+    
+    #[test]
+    fn test_sliding_window_status_does_not_consume() {
+        let clock = MockClock::new();
+        let config = RateLimitConfig::new(2, Duration::from_secs(10));
+        let limiter = SlidingWindow::with_clock(config, Arc::new(clock.clone())).unwrap();
+        
+        assert_eq!(limiter.status("user1").unwrap().remaining, 2);
+        assert_eq!(limiter.tracked_keys(), 0);
+        
+        limiter.allow_request("user1").unwrap();
+        clock.advance(Duration::from_secs(3));
+        limiter.allow_request("user1").unwrap();
+        
+        let status = limiter.status("user1").unwrap();
+        assert!(!status.allowed);
+        assert_eq!(status.retry_after, Some(Duration::from_secs(7)));
+        assert_eq!(status.reset_after, Duration::from_secs(10));
+        
+        // The oldest request slides out and frees one slot
+        clock.advance(Duration::from_millis(7001));
+        let status = limiter.status("user1").unwrap();
+        assert!(status.allowed);
+        assert_eq!(status.remaining, 1);
+        assert!(limiter.allow_request("user1").unwrap().allowed);
+        assert!(!limiter.allow_request("user1").unwrap().allowed);
+    }
+    
+    #[test]
+    fn test_sliding_window_reservation_refund() {
+        let clock = MockClock::new();
+        let config = RateLimitConfig::new(2, Duration::from_secs(10));
+        let limiter = SlidingWindow::with_clock(config, Arc::new(clock.clone())).unwrap();
+        
+        limiter.allow_request("user1").unwrap();
+        clock.advance(Duration::from_secs(2));
+        let reservation = limiter.reserve("user1", 1).unwrap();
+        clock.advance(Duration::from_secs(2));
+        
+        // Only the reserved request is removed from the log
+        reservation.cancel().unwrap();
+        let status = limiter.status("user1").unwrap();
+        assert_eq!(status.remaining, 1);
+        assert_eq!(status.reset_after, Duration::from_secs(6));
+        
+        // Requests that already slid out have nothing left to refund
+        let stale = limiter.reserve("user1", 1).unwrap();
+        clock.advance(Duration::from_millis(10_001));
+        limiter.allow_n("user1", 2).unwrap();
+        stale.cancel().unwrap();
+        assert_eq!(limiter.status("user1").unwrap().remaining, 0);
     }
 }
\ No newline at end of file
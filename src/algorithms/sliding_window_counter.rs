@@ -0,0 +1,461 @@
+use crate::clock::{Clock, SystemClock};
+use crate::overrides::{self, LimitOverrides};
+use crate::snapshot::{KeyState, LimiterSnapshot, TimeBase};
+use crate::storage::{Storage, Store, StoredState};
+use crate::{check_cost, RateLimiter, RateLimitConfig, RateLimitDecision, Reservation, Result};
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Slack for float noise when comparing weighted estimates
+const EPSILON: f64 = 1e-9;
+
+#[derive(Debug, Clone)]
+struct Counters {
+    counts: VecDeque<u64>, // Oldest first, the back is the current sub-window
+    current_start: Instant,
+}
+
+impl Counters {
+    fn new(sub_windows: usize, now: Instant) -> Self {
+        Self {
+            counts: std::iter::repeat_n(0, sub_windows + 1).collect(),
+            current_start: now,
+        }
+    }
+    
+    /// Match the ring to a changed sub-window count, moving every count
+    /// into the current sub-window so none of them expires early
+    fn resize(&mut self, sub_windows: usize) {
+        if self.counts.len() != sub_windows + 1 {
+            let total = self.counts.iter().sum();
+            self.counts = std::iter::repeat_n(0, sub_windows + 1).collect();
+            *self.counts.back_mut().expect("at least two buckets") = total;
+        }
+    }
+    
+    /// Whole sub-windows that have started since the current one
+    fn steps(&self, now: Instant, granularity: Duration) -> u128 {
+        now.saturating_duration_since(self.current_start).as_nanos() / granularity.as_nanos()
+    }
+    
+    /// Move on to the sub-window containing `now`
+    fn roll(&mut self, now: Instant, granularity: Duration) {
+        let steps = self.steps(now, granularity);
+        if steps == 0 {
+            return;
+        }
+        
+        for _ in 0..steps.min(self.counts.len() as u128) {
+            self.counts.pop_front();
+            self.counts.push_back(0);
+        }
+        
+        // So long ago that every count has expired anyway: realign on now
+        self.current_start = u32::try_from(steps)
+            .ok()
+            .and_then(|steps| granularity.checked_mul(steps))
+            .map_or(now, |offset| self.current_start + offset);
+    }
+    
+    /// Position within the current sub-window, from 0.0 to 1.0
+    fn progress(&self, now: Instant, granularity: Duration) -> f64 {
+        let elapsed = now.saturating_duration_since(self.current_start);
+        (elapsed.as_secs_f64() / granularity.as_secs_f64()).min(1.0)
+    }
+    
+    /// Requests in the sliding window ending now, assuming the oldest
+    /// sub-window's requests were spread evenly across it
+    fn estimate(&self, now: Instant, granularity: Duration) -> f64 {
+        let newer: u64 = self.counts.iter().skip(1).sum();
+        let oldest = self.counts[0] as f64 * (1.0 - self.progress(now, granularity));
+        newer as f64 + oldest
+    }
+    
+    /// Time until the estimate has dropped to `threshold`
+    fn time_until(&self, threshold: f64, now: Instant, granularity: Duration) -> Duration {
+        let width = granularity.as_secs_f64();
+        let mut offset = self.progress(now, granularity) * width;
+        let mut waited = 0.0;
+        let mut newer: u64 = self.counts.iter().skip(1).sum();
+        
+        // Walk forward one sub-window at a time; within each, only the
+        // oldest count's weight shrinks (linearly to zero)
+        for (i, &oldest) in self.counts.iter().enumerate() {
+            if newer as f64 <= threshold + EPSILON {
+                let budget = threshold - newer as f64;
+                let needed = if oldest as f64 <= budget + EPSILON {
+                    offset
+                } else {
+                    width * (1.0 - budget / oldest as f64)
+                };
+                return Duration::from_secs_f64(waited + (needed - offset).max(0.0));
+            }
+            
+            waited += width - offset;
+            offset = 0.0;
+            newer -= self.counts.get(i + 1).copied().unwrap_or(0);
+        }
+        
+        Duration::from_secs_f64(waited)
+    }
+    
+    /// Whether every count will have slid out of the window by `now`
+    fn is_idle(&self, now: Instant, granularity: Duration) -> bool {
+        let steps = self.steps(now, granularity).min(self.counts.len() as u128) as usize;
+        self.counts.iter().skip(steps).all(|&count| count == 0)
+    }
+}
+
+impl StoredState for Counters {
+    const ALGORITHM: &'static str = "sliding_window_counter";
+    
+    fn export(&self, time: TimeBase) -> KeyState {
+        KeyState::SlidingWindowCounter {
+            counts: self.counts.iter().copied().collect(),
+            current_start_us: time.to_unix_us(self.current_start),
+        }
+    }
+    
+    fn import(state: &KeyState, time: TimeBase) -> Option<Self> {
+        match state {
+            // The ring is resized to the key's config on its next use
+            KeyState::SlidingWindowCounter { counts, current_start_us } => Some(Self {
+                counts: counts.iter().copied().collect(),
+                current_start: time.to_instant(*current_start_us),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Sliding Window Counter algorithm implementation
+/// Approximates a sliding window from per-window counts
+///
+/// Each window is split into `config.sub_windows` buckets (one by default).
+/// The estimate for the sliding window is the sum of the newer buckets plus
+/// the oldest one weighted by how much of it still overlaps the window.
+/// Memory per key is constant, unlike `SlidingWindow`, and accuracy improves
+/// with more sub-windows.
+pub struct SlidingWindowCounter {
+    config: RateLimitConfig,
+    counters: Store<Counters>,
+    overrides: Option<Arc<LimitOverrides>>,
+    clock: Arc<dyn Clock>,
+}
+
+/// Number of sub-windows and the length of each
+fn sub_window_layout(config: &RateLimitConfig) -> (usize, Duration) {
+    let sub_windows = config.sub_windows.unwrap_or(1);
+    (sub_windows as usize, config.window / sub_windows)
+}
+
+impl SlidingWindowCounter {
+    pub fn new(config: RateLimitConfig) -> Result<Self> {
+        Self::with_clock(config, Arc::new(SystemClock))
+    }
+    
+    /// Create a limiter that reads time from `clock`
+    pub fn with_clock(config: RateLimitConfig, clock: Arc<dyn Clock>) -> Result<Self> {
+        config.validate()?;
+        
+        Ok(Self {
+            counters: Store::local(config.max_keys, config.overflow_policy),
+            config,
+            overrides: None,
+            clock,
+        })
+    }
+    
+    /// Look up per-key limits in `overrides` on every check
+    pub fn with_overrides(mut self, overrides: Arc<LimitOverrides>) -> Self {
+        self.overrides = Some(overrides);
+        self
+    }
+    
+    /// Keep per-key state in `storage` instead of in this process
+    pub fn with_storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.counters = Store::Shared { storage, clock: Arc::clone(&self.clock) };
+        self
+    }
+    
+    fn config_for(&self, key: &str) -> Cow<'_, RateLimitConfig> {
+        overrides::config_for(self.overrides.as_deref(), key, &self.config)
+    }
+    
+    /// Number of keys currently tracked
+    pub fn tracked_keys(&self) -> usize {
+        self.counters.len().unwrap_or(0)
+    }
+    
+    /// Describe `counters` (already rolled to `now`) for a request of `cost`
+    fn decision(
+        counters: &Counters,
+        now: Instant,
+        granularity: Duration,
+        max_requests: u64,
+        cost: u64,
+        allowed: bool,
+    ) -> RateLimitDecision {
+        let estimate = counters.estimate(now, granularity);
+        let remaining = (max_requests as f64 - estimate + EPSILON).floor().max(0.0) as u64;
+        let reset_after = counters.time_until(0.0, now, granularity);
+        
+        if allowed {
+            RateLimitDecision::allow(max_requests, remaining, reset_after)
+        } else {
+            let retry_after = counters.time_until((max_requests - cost) as f64, now, granularity);
+            RateLimitDecision::deny(max_requests, remaining, reset_after, retry_after)
+        }
+    }
+    
+    /// Count `cost` if it fits, returning when the sub-window it went into started
+    fn check(&self, key: &str, cost: u64) -> Result<(RateLimitDecision, Instant)> {
+        let config = self.config_for(key);
+        let max_requests = config.max_requests;
+        check_cost(cost, max_requests)?;
+        
+        let now = self.clock.now();
+        let (sub_windows, granularity) = sub_window_layout(&config);
+        let init = || Counters::new(sub_windows, now);
+        
+        self.counters.with_entry(key, init, self.idle_check(now), |counters| {
+            counters.resize(sub_windows);
+            counters.roll(now, granularity);
+            
+            let estimate = counters.estimate(now, granularity);
+            let allowed = estimate + cost as f64 <= max_requests as f64 + EPSILON;
+            if allowed {
+                *counters.counts.back_mut().expect("at least two buckets") += cost;
+            }
+            
+            let decision = Self::decision(counters, now, granularity, max_requests, cost, allowed);
+            (decision, counters.current_start)
+        })
+    }
+    
+    fn idle_check(&self, now: Instant) -> impl Fn(&str, &Counters) -> bool + '_ {
+        move |key, counters| {
+            let (_, granularity) = sub_window_layout(&self.config_for(key));
+            counters.is_idle(now, granularity)
+        }
+    }
+}
+
+impl RateLimiter for SlidingWindowCounter {
+    fn allow_n(&self, key: &str, cost: u64) -> Result<RateLimitDecision> {
+        self.check(key, cost).map(|(decision, _)| decision)
+    }
+    
+    fn reserve(&self, key: &str, cost: u64) -> Result<Reservation<'_>> {
+        let (decision, reserved_start) = self.check(key, cost)?;
+        let key = key.to_string();
+        Ok(Reservation::new(decision, move || {
+            let now = self.clock.now();
+            let (sub_windows, granularity) = sub_window_layout(&self.config_for(&key));
+            
+            self.counters.update(&key, self.idle_check(now), |counters| {
+                counters.resize(sub_windows);
+                counters.roll(now, granularity);
+                
+                // Take the units out of the sub-window they were counted in,
+                // unless it has slid out of the ring already. Both starts are
+                // whole sub-windows apart, give or take a round trip through
+                // shared storage, so round to the nearest one.
+                let age = counters.current_start.saturating_duration_since(reserved_start);
+                let steps = (age + granularity / 2).as_nanos() / granularity.as_nanos();
+                if let Some(index) = (counters.counts.len() - 1).checked_sub(steps.min(usize::MAX as u128) as usize) {
+                    counters.counts[index] = counters.counts[index].saturating_sub(cost);
+                }
+            })?;
+            Ok(())
+        }))
+    }
+    
+    fn status(&self, key: &str) -> Result<RateLimitDecision> {
+        let config = self.config_for(key);
+        let now = self.clock.now();
+        let (sub_windows, granularity) = sub_window_layout(&config);
+        
+        // Roll a copy so the stored counts are left untouched
+        let mut counters = self.counters.peek(key, Counters::clone)?
+            .unwrap_or_else(|| Counters::new(sub_windows, now));
+        counters.resize(sub_windows);
+        counters.roll(now, granularity);
+        
+        let allowed = counters.estimate(now, granularity) + 1.0 <= config.max_requests as f64 + EPSILON;
+        Ok(Self::decision(&counters, now, granularity, config.max_requests, 1, allowed))
+    }
+    
+    fn reset(&self, key: &str) {
+        let _ = self.counters.remove(key);
+    }
+    
+    fn evict_idle(&self) -> usize {
+        self.counters.evict_idle(self.idle_check(self.clock.now())).unwrap_or(0)
+    }
+    
+    fn snapshot(&self) -> Option<LimiterSnapshot> {
+        let time = TimeBase::new(self.clock.as_ref());
+        self.counters.snapshot(time, self.idle_check(time.now()))
+    }
+    
+    fn restore(&self, snapshot: &LimiterSnapshot) -> Result<usize> {
+        let time = TimeBase::new(self.clock.as_ref());
+        self.counters.restore(snapshot, time, self.idle_check(time.now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithms::SlidingWindow;
+    use crate::clock::MockClock;
+    
+    fn counter_with(max_requests: u64, window: Duration, sub_windows: u32, clock: &MockClock) -> SlidingWindowCounter {
+        let config = RateLimitConfig::builder(max_requests, window)
+            .sub_windows(sub_windows)
+            .build()
+            .unwrap();
+        SlidingWindowCounter::with_clock(config, Arc::new(clock.clone())).unwrap()
+    }
+    
+    #[test]
+    fn test_sliding_window_counter_allows_requests() {
+        let config = RateLimitConfig::per_second(5);
+        let limiter = SlidingWindowCounter::new(config).unwrap();
+        
+        for _ in 0..5 {
+            assert!(limiter.allow_request("user1").unwrap().allowed);
+        }
+        
+        assert!(!limiter.allow_request("user1").unwrap().allowed);
+    }
+    
+    #[test]
+    fn test_sliding_window_counter_interpolates_previous_window() {
+        let clock = MockClock::new();
+        let limiter = counter_with(10, Duration::from_secs(10), 1, &clock);
+        
+        assert!(limiter.allow_n("user1", 10).unwrap().allowed);
+        
+        // Halfway into the next window, half of the previous one still counts
+        clock.advance(Duration::from_secs(15));
+        let decision = limiter.allow_n("user1", 5).unwrap();
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 0);
+        
+        // Previous weight drops by one request per second
+        let denied = limiter.allow_request("user1").unwrap();
+        assert!(!denied.allowed);
+        assert_eq!(denied.retry_after, Some(Duration::from_secs(1)));
+        assert_eq!(denied.reset_after, Duration::from_secs(15));
+        
+        clock.advance(Duration::from_secs(1));
+        assert!(limiter.allow_request("user1").unwrap().allowed);
+        assert!(!limiter.allow_request("user1").unwrap().allowed);
+    }
+    
+    #[test]
+    fn test_sliding_window_counter_sub_windows() {
+        let clock = MockClock::new();
+        let limiter = counter_with(4, Duration::from_secs(4), 4, &clock);
+        
+        // One request per one-second sub-window
+        for _ in 0..4 {
+            assert!(limiter.allow_request("user1").unwrap().allowed);
+            clock.advance(Duration::from_secs(1));
+        }
+        
+        // Like SlidingWindow, the first request counts until it is older than the window
+        assert!(!limiter.allow_request("user1").unwrap().allowed);
+        
+        // The first sub-window is fully out, the second counts in full
+        clock.advance(Duration::from_secs(1));
+        assert!(limiter.allow_request("user1").unwrap().allowed);
+        let denied = limiter.allow_request("user1").unwrap();
+        assert!(!denied.allowed);
+        assert_eq!(denied.retry_after, Some(Duration::from_secs(1)));
+        
+        assert_eq!(limiter.evict_idle(), 0);
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(limiter.evict_idle(), 1);
+    }
+    
+    #[test]
+    fn test_sliding_window_counter_status_and_refund() {
+        let clock = MockClock::new();
+        let limiter = counter_with(2, Duration::from_secs(10), 2, &clock);
+        
+        assert_eq!(limiter.status("user1").unwrap().remaining, 2);
+        assert_eq!(limiter.tracked_keys(), 0);
+        
+        let reservation = limiter.reserve("user1", 2).unwrap();
+        assert!(!limiter.status("user1").unwrap().allowed);
+        
+        // Refunds find the sub-window the units went into after a roll
+        clock.advance(Duration::from_secs(6));
+        reservation.cancel().unwrap();
+        assert_eq!(limiter.status("user1").unwrap().remaining, 2);
+        assert!(limiter.allow_n("user1", 2).unwrap().allowed);
+    }
+    
+    /// Admitted requests per algorithm for a pseudo-random bursty workload
+    fn admitted(limiter: &dyn RateLimiter, clock: &MockClock) -> u64 {
+        let mut seed: u64 = 42;
+        let mut allowed = 0;
+        
+        for _ in 0..20_000 {
+            // Simple LCG so the workload is identical across runs
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            clock.advance(Duration::from_micros(seed >> 50));
+            if limiter.allow_request("user1").unwrap().allowed {
+                allowed += 1;
+            }
+        }
+        allowed
+    }
+    
+    #[test]
+    fn test_sliding_window_counter_accuracy_against_exact() {
+        let window = Duration::from_secs(1);
+        
+        let clock = MockClock::new();
+        let config = RateLimitConfig::new(100, window);
+        let exact = admitted(&SlidingWindow::with_clock(config, Arc::new(clock.clone())).unwrap(), &clock);
+        
+        for (sub_windows, tolerance) in [(1, 0.10), (10, 0.02)] {
+            let clock = MockClock::new();
+            let approx = admitted(&counter_with(100, window, sub_windows, &clock), &clock);
+            
+            let error = (approx as f64 - exact as f64).abs() / exact as f64;
+            assert!(
+                error <= tolerance,
+                "{} sub-windows admitted {} vs exact {} ({:.1}% off)",
+                sub_windows, approx, exact, error * 100.0
+            );
+        }
+    }
+    
+    #[test]
+    fn test_sliding_window_counter_override_resizes_ring() {
+        let clock = MockClock::new();
+        let overrides = Arc::new(crate::LimitOverrides::new());
+        let limiter = counter_with(4, Duration::from_secs(4), 4, &clock).with_overrides(Arc::clone(&overrides));
+        
+        limiter.allow_n("user1", 3).unwrap();
+        
+        // Fewer, longer sub-windows and a higher limit: the 3 requests still count
+        let config = RateLimitConfig::builder(6, Duration::from_secs(4))
+            .sub_windows(2)
+            .build()
+            .unwrap();
+        overrides.set_key("user1", config).unwrap();
+        let status = limiter.status("user1").unwrap();
+        assert_eq!(status.limit, 6);
+        assert_eq!(status.remaining, 3);
+        assert!(limiter.allow_n("user1", 3).unwrap().allowed);
+        assert!(!limiter.allow_request("user1").unwrap().allowed);
+    }
+}
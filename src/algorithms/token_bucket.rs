@@ -1,116 +1,581 @@
-use crate::{RateLimiter, RateLimitConfig, Result};
-use std::collections::HashMap;
-use std::time::Instant;
+use super::{replenish, time_to_replenish};
+use crate::clock::{Clock, SystemClock};
+use crate::overrides::{self, LimitOverrides};
+use crate::snapshot::{KeyState, LimiterSnapshot, TimeBase};
+use crate::storage::{Storage, Store, StoredState};
+use crate::{check_cost, RateLimiter, RateLimitConfig, RateLimitDecision, Reservation, Result};
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct BucketState {
     tokens: f64,
     last_refill: Instant,
 }
 
+impl BucketState {
+    fn refill(&mut self, now: Instant, refill_rate: f64, max_tokens: f64, interval: Option<Duration>) {
+        // Calculate tokens to add based on time elapsed
+        let (tokens_to_add, last_refill) = replenish(self.last_refill, now, refill_rate, interval);
+        
+        // Add tokens but don't exceed max capacity
+        self.tokens = (self.tokens + tokens_to_add).min(max_tokens);
+        self.last_refill = last_refill;
+    }
+    
+    /// Whether the bucket holds at least as many tokens as a new key would
+    fn is_idle(&self, now: Instant, refill_rate: f64, initial_tokens: f64, interval: Option<Duration>) -> bool {
+        let (tokens_to_add, _) = replenish(self.last_refill, now, refill_rate, interval);
+        self.tokens + tokens_to_add >= initial_tokens
+    }
+}
+
+impl StoredState for BucketState {
+    const ALGORITHM: &'static str = "token_bucket";
+    
+    fn export(&self, time: TimeBase) -> KeyState {
+        KeyState::TokenBucket {
+            tokens: self.tokens,
+            last_refill_us: time.to_unix_us(self.last_refill),
+        }
+    }
+    
+    fn import(state: &KeyState, time: TimeBase) -> Option<Self> {
+        match *state {
+            KeyState::TokenBucket { tokens, last_refill_us } => Some(Self {
+                tokens,
+                last_refill: time.to_instant(last_refill_us),
+            }),
+            _ => None,
+        }
+    }
+}
+
 /// Token Bucket algorithm implementation
 /// Tokens are added at a constant rate, requests consume tokens
+///
+/// The bucket holds up to `config.capacity()` tokens, so a burst capacity
+/// larger than `max_requests` allows short bursts above the sustained rate.
+///
+/// Safe to share between threads: per-key state lives in a sharded map,
+/// so checks for different keys rarely contend on the same lock.
+///
+/// A key is forgotten once its bucket has refilled to the initial level,
+/// since a fresh bucket would behave identically.
 pub struct TokenBucket {
     config: RateLimitConfig,
-    buckets: HashMap<String, BucketState>,
-    refill_rate: f64, // tokens per second
+    buckets: Store<BucketState>,
+    overrides: Option<Arc<LimitOverrides>>,
+    clock: Arc<dyn Clock>,
 }
 
 impl TokenBucket {
-    pub fn new(config: RateLimitConfig) -> Self {
-        let refill_rate = config.max_requests as f64 / config.window.as_secs_f64();
+    pub fn new(config: RateLimitConfig) -> Result<Self> {
+        Self::with_clock(config, Arc::new(SystemClock))
+    }
+    
+    /// Create a limiter that reads time from `clock`
+    pub fn with_clock(config: RateLimitConfig, clock: Arc<dyn Clock>) -> Result<Self> {
+        config.validate()?;
         
-        Self {
+        Ok(Self {
+            buckets: Store::local(config.max_keys, config.overflow_policy),
             config,
-            buckets: HashMap::new(),
-            refill_rate,
-        }
+            overrides: None,
+            clock,
+        })
     }
     
-    fn refill_tokens(&mut self, key: &str) -> &mut BucketState {
-        let now = Instant::now();
-        let max_tokens = self.config.max_requests as f64;
-        
-        let bucket = self.buckets.entry(key.to_string()).or_insert(BucketState {
-            tokens: max_tokens,
+    /// Look up per-key limits in `overrides` on every check
+    pub fn with_overrides(mut self, overrides: Arc<LimitOverrides>) -> Self {
+        self.overrides = Some(overrides);
+        self
+    }
+    
+    /// Keep per-key state in `storage` instead of in this process
+    pub fn with_storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.buckets = Store::Shared { storage, clock: Arc::clone(&self.clock) };
+        self
+    }
+    
+    /// Number of keys currently tracked
+    pub fn tracked_keys(&self) -> usize {
+        self.buckets.len().unwrap_or(0)
+    }
+    
+    fn config_for(&self, key: &str) -> Cow<'_, RateLimitConfig> {
+        overrides::config_for(self.overrides.as_deref(), key, &self.config)
+    }
+    
+    fn new_bucket(config: &RateLimitConfig, now: Instant) -> BucketState {
+        BucketState {
+            tokens: config.initial_tokens() as f64,
             last_refill: now,
-        });
-        
-        // Calculate tokens to add based on time elapsed
-        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
-        let tokens_to_add = elapsed * self.refill_rate;
-        
-        // Add tokens but don't exceed max capacity
-        bucket.tokens = (bucket.tokens + tokens_to_add).min(max_tokens);
-        bucket.last_refill = now;
+        }
+    }
+    
+    /// Put `cost` tokens back, as if they had never been taken
+    pub(crate) fn refund(&self, key: &str, cost: u64) -> Result<()> {
+        let now = self.clock.now();
+        let config = self.config_for(key);
+        let max_tokens = config.capacity() as f64;
+        let refill_rate = config.refill_rate();
+        let interval = config.refill_interval;
         
-        bucket
+        // A key that was evicted already had a full bucket
+        self.buckets.update(key, self.idle_check(now), |bucket| {
+            bucket.refill(now, refill_rate, max_tokens, interval);
+            bucket.tokens = (bucket.tokens + cost as f64).min(max_tokens);
+        })?;
+        Ok(())
+    }
+    
+    fn idle_check(&self, now: Instant) -> impl Fn(&str, &BucketState) -> bool + '_ {
+        move |key, bucket| {
+            let config = self.config_for(key);
+            bucket.is_idle(now, config.refill_rate(), config.initial_tokens() as f64, config.refill_interval)
+        }
     }
 }
 
 impl RateLimiter for TokenBucket {
-    fn allow_request(&mut self, key: &str) -> Result<bool> {
-        let bucket = self.refill_tokens(key);
+    fn allow_n(&self, key: &str, cost: u64) -> Result<RateLimitDecision> {
+        let config = self.config_for(key);
+        let limit = config.capacity();
+        check_cost(cost, limit)?;
+        
+        let now = self.clock.now();
+        let max_tokens = limit as f64;
+        let refill_rate = config.refill_rate();
+        let interval = config.refill_interval;
+        let init = || Self::new_bucket(&config, now);
+        
+        let decision = self.buckets.with_entry(key, init, self.idle_check(now), |bucket| {
+            bucket.refill(now, refill_rate, max_tokens, interval);
+            
+            let cost = cost as f64;
+            let allowed = bucket.tokens >= cost;
+            if allowed {
+                bucket.tokens -= cost;
+            }
+            
+            // Time until the bucket is full again
+            let reset_after = time_to_replenish(max_tokens - bucket.tokens, bucket.last_refill, now, refill_rate, interval);
+            let remaining = bucket.tokens.floor() as u64;
+            
+            if allowed {
+                RateLimitDecision::allow(limit, remaining, reset_after)
+            } else {
+                // Time until enough tokens have been refilled to cover the cost
+                let retry_after = time_to_replenish(cost - bucket.tokens, bucket.last_refill, now, refill_rate, interval);
+                RateLimitDecision::deny(limit, remaining, reset_after, retry_after)
+            }
+        })?;
+        
+        Ok(decision)
+    }
+    
+    fn reserve(&self, key: &str, cost: u64) -> Result<Reservation<'_>> {
+        let decision = self.allow_n(key, cost)?;
+        let key = key.to_string();
+        Ok(Reservation::new(decision, move || self.refund(&key, cost)))
+    }
+    
+    fn status(&self, key: &str) -> Result<RateLimitDecision> {
+        let config = self.config_for(key);
+        let limit = config.capacity();
+        let now = self.clock.now();
+        let max_tokens = limit as f64;
+        let refill_rate = config.refill_rate();
+        let interval = config.refill_interval;
+        
+        // Refill a copy so the stored state is left untouched
+        let mut bucket = self.buckets.peek(key, BucketState::clone)?
+            .unwrap_or_else(|| Self::new_bucket(&config, now));
+        bucket.refill(now, refill_rate, max_tokens, interval);
+        
+        let reset_after = time_to_replenish(max_tokens - bucket.tokens, bucket.last_refill, now, refill_rate, interval);
+        let remaining = bucket.tokens.floor() as u64;
         
         if bucket.tokens >= 1.0 {
-            bucket.tokens -= 1.0;
-            Ok(true)
+            Ok(RateLimitDecision::allow(limit, remaining, reset_after))
         } else {
-            Ok(false)
+            let retry_after = time_to_replenish(1.0 - bucket.tokens, bucket.last_refill, now, refill_rate, interval);
+            Ok(RateLimitDecision::deny(limit, remaining, reset_after, retry_after))
         }
     }
     
-    fn reset(&mut self, key: &str) {
-        self.buckets.remove(key);
+    fn reset(&self, key: &str) {
+        let _ = self.buckets.remove(key);
+    }
+    
+    fn evict_idle(&self) -> usize {
+        self.buckets.evict_idle(self.idle_check(self.clock.now())).unwrap_or(0)
+    }
+    
+    fn snapshot(&self) -> Option<LimiterSnapshot> {
+        let time = TimeBase::new(self.clock.as_ref());
+        self.buckets.snapshot(time, self.idle_check(time.now()))
+    }
+    
+    fn restore(&self, snapshot: &LimiterSnapshot) -> Result<usize> {
+        let time = TimeBase::new(self.clock.as_ref());
+        self.buckets.restore(snapshot, time, self.idle_check(time.now()))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::thread::sleep;
-    use std::time::Duration;
+    use crate::clock::MockClock;
+    use crate::LimitOverrides;
     
     #[test]
     fn test_token_bucket_allows_requests() {
         let config = RateLimitConfig::per_second(5);
-        let mut limiter = TokenBucket::new(config);
+        let limiter = TokenBucket::new(config).unwrap();
         
         // Should allow 5 requests immediately
         for _ in 0..5 {
-            assert!(limiter.allow_request("user1").unwrap());
+            assert!(limiter.allow_request("user1").unwrap().allowed);
         }
         
         // 6th request should be denied
-        assert!(!limiter.allow_request("user1").unwrap());
+        assert!(!limiter.allow_request("user1").unwrap().allowed);
     }
     
     #[test]
     fn test_token_bucket_refills() {
+        let clock = MockClock::new();
         let config = RateLimitConfig::per_second(2);
-        let mut limiter = TokenBucket::new(config);
+        let limiter = TokenBucket::with_clock(config, Arc::new(clock.clone())).unwrap();
         
         // Use up tokens
-        assert!(limiter.allow_request("user1").unwrap());
-        assert!(limiter.allow_request("user1").unwrap());
-        assert!(!limiter.allow_request("user1").unwrap());
+        assert!(limiter.allow_request("user1").unwrap().allowed);
+        assert!(limiter.allow_request("user1").unwrap().allowed);
+        assert!(!limiter.allow_request("user1").unwrap().allowed);
+        
+        // Half a token after 250ms is not enough
+        clock.advance(Duration::from_millis(250));
+        assert!(!limiter.allow_request("user1").unwrap().allowed);
+        
+        // A whole token is back after exactly 500ms
+        clock.advance(Duration::from_millis(250));
+        assert!(limiter.allow_request("user1").unwrap().allowed);
+        assert!(!limiter.allow_request("user1").unwrap().allowed);
+    }
+    
+    #[test]
+    fn test_token_bucket_decision_fields() {
+        let clock = MockClock::new();
+        let config = RateLimitConfig::per_second(2);
+        let limiter = TokenBucket::with_clock(config, Arc::new(clock)).unwrap();
+        
+        let first = limiter.allow_request("user1").unwrap();
+        assert_eq!(first.limit, 2);
+        assert_eq!(first.remaining, 1);
+        assert!(first.retry_after.is_none());
         
-        // Wait for refill
-        sleep(Duration::from_secs(1));
+        limiter.allow_request("user1").unwrap();
+        let denied = limiter.allow_request("user1").unwrap();
+        assert!(!denied.allowed);
+        assert_eq!(denied.remaining, 0);
         
-        // Should have new tokens
-        assert!(limiter.allow_request("user1").unwrap());
+        // One token refills every 500ms, the whole bucket in 1s
+        assert_eq!(denied.retry_after, Some(Duration::from_millis(500)));
+        assert_eq!(denied.reset_after, Duration::from_secs(1));
+    }
+    
+    #[test]
+    fn test_token_bucket_weighted_cost() {
+        let config = RateLimitConfig::per_second(10);
+        let limiter = TokenBucket::new(config).unwrap();
+        
+        let decision = limiter.allow_n("user1", 7).unwrap();
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 3);
+        
+        // Not enough tokens left for another expensive request
+        let denied = limiter.allow_n("user1", 5).unwrap();
+        assert!(!denied.allowed);
+        assert_eq!(denied.remaining, 3);
+        assert!(denied.retry_after.unwrap() <= Duration::from_millis(200));
+        
+        // A cheaper request still fits
+        assert!(limiter.allow_n("user1", 3).unwrap().allowed);
+    }
+    
+    #[test]
+    fn test_token_bucket_cost_exceeds_capacity() {
+        let config = RateLimitConfig::per_second(10);
+        let limiter = TokenBucket::new(config).unwrap();
+        
+        assert!(matches!(
+            limiter.allow_n("user1", 11),
+            Err(crate::RateLimitError::CostExceedsCapacity { cost: 11, capacity: 10 })
+        ));
+    }
+    
+    #[test]
+    fn test_token_bucket_burst_above_sustained_rate() {
+        let clock = MockClock::new();
+        let config = RateLimitConfig::builder(10, Duration::from_secs(1))
+            .burst(50)
+            .build()
+            .unwrap();
+        let limiter = TokenBucket::with_clock(config, Arc::new(clock.clone())).unwrap();
+        
+        // A full bucket absorbs a burst of 50
+        let decision = limiter.allow_n("user1", 50).unwrap();
+        assert_eq!(decision.limit, 50);
+        assert_eq!(decision.reset_after, Duration::from_secs(5));
+        assert!(!limiter.allow_request("user1").unwrap().allowed);
+        
+        // Refill happens at the sustained rate of 10/s
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(limiter.allow_n("user1", 10).unwrap().remaining, 0);
+        assert!(matches!(
+            limiter.allow_n("user1", 51),
+            Err(crate::RateLimitError::CostExceedsCapacity { capacity: 50, .. })
+        ));
+    }
+    
+    #[test]
+    fn test_token_bucket_initial_tokens() {
+        let clock = MockClock::new();
+        let config = RateLimitConfig::builder(10, Duration::from_secs(1))
+            .initial_tokens(0)
+            .build()
+            .unwrap();
+        let limiter = TokenBucket::with_clock(config, Arc::new(clock.clone())).unwrap();
+        
+        // New keys start empty and have to wait for the first token
+        let denied = limiter.allow_request("user1").unwrap();
+        assert!(!denied.allowed);
+        assert_eq!(denied.retry_after, Some(Duration::from_millis(100)));
+        
+        clock.advance(Duration::from_millis(100));
+        assert!(limiter.allow_request("user1").unwrap().allowed);
+    }
+    
+    #[test]
+    fn test_token_bucket_refill_interval() {
+        let clock = MockClock::new();
+        let config = RateLimitConfig::builder(10, Duration::from_secs(1))
+            .refill_interval(Duration::from_millis(500))
+            .build()
+            .unwrap();
+        let limiter = TokenBucket::with_clock(config, Arc::new(clock.clone())).unwrap();
+        
+        assert!(limiter.allow_n("user1", 10).unwrap().allowed);
+        
+        // Tokens arrive in chunks of 5 every 500ms, not continuously
+        clock.advance(Duration::from_millis(300));
+        let denied = limiter.allow_request("user1").unwrap();
+        assert!(!denied.allowed);
+        assert_eq!(denied.retry_after, Some(Duration::from_millis(200)));
+        assert_eq!(denied.reset_after, Duration::from_millis(700));
+        
+        clock.advance(Duration::from_millis(200));
+        assert_eq!(limiter.allow_request("user1").unwrap().remaining, 4);
+        
+        // Partial progress towards the next step is kept
+        clock.advance(Duration::from_millis(499));
+        assert_eq!(limiter.allow_request("user1").unwrap().remaining, 3);
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(limiter.allow_request("user1").unwrap().remaining, 7);
+    }
+    
+    #[test]
+    fn test_token_bucket_saturates_waits_beyond_duration_range() {
+        // One token per ~146 billion years, with room for 1000 of them
+        let config = RateLimitConfig::builder(1, Duration::MAX / 2).burst(1000).build().unwrap();
+        let limiter = TokenBucket::new(config).unwrap();
+        let decision = limiter.allow_n("user1", 1000).unwrap();
+        assert!(decision.allowed);
+        assert_eq!(decision.reset_after, Duration::MAX);
+        
+        // The first step is already longer than a `Duration` can hold
+        let config = RateLimitConfig::builder(1, Duration::from_secs(1)).refill_interval(Duration::MAX).build().unwrap();
+        let limiter = TokenBucket::with_clock(config, Arc::new(MockClock::new())).unwrap();
+        assert!(limiter.allow_request("user1").unwrap().allowed);
+        let denied = limiter.allow_request("user1").unwrap();
+        assert!(!denied.allowed);
+        assert_eq!(denied.retry_after, Some(Duration::MAX));
+    }
+    
+    #[test]
+    fn test_token_bucket_rejects_invalid_config() {
+        // Would otherwise divide by a zero window and refill at an infinite rate
+        assert!(matches!(
+            TokenBucket::new(RateLimitConfig::new(10, Duration::ZERO)),
+            Err(crate::RateLimitError::ConfigError(_))
+        ));
+        assert!(TokenBucket::new(RateLimitConfig::new(0, Duration::from_secs(1))).is_err());
     }
     
     #[test]
     fn test_token_bucket_different_keys() {
         let config = RateLimitConfig::per_second(2);
-        let mut limiter = TokenBucket::new(config);
+        let limiter = TokenBucket::new(config).unwrap();
         
         // Different keys have independent limits
-        assert!(limiter.allow_request("user1").unwrap());
-        assert!(limiter.allow_request("user2").unwrap());
-        assert!(limiter.allow_request("user1").unwrap());
-        assert!(limiter.allow_request("user2").unwrap());
+        assert!(limiter.allow_request("user1").unwrap().allowed);
+        assert!(limiter.allow_request("user2").unwrap().allowed);
+        assert!(limiter.allow_request("user1").unwrap().allowed);
+        assert!(limiter.allow_request("user2").unwrap().allowed);
+    }
+    
+    #[test]
+    fn test_token_bucket_evicts_refilled_keys() {
+        let clock = MockClock::new();
+        let config = RateLimitConfig::per_second(2);
+        let limiter = TokenBucket::with_clock(config, Arc::new(clock.clone())).unwrap();
+        
+        limiter.allow_n("user1", 2).unwrap();
+        limiter.allow_request("user2").unwrap();
+        
+        // user2 is back to a full bucket after 500ms, user1 needs a full second
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(limiter.evict_idle(), 1);
+        assert_eq!(limiter.tracked_keys(), 1);
+        
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(limiter.evict_idle(), 1);
+        assert_eq!(limiter.tracked_keys(), 0);
+    }
+    
+    #[test]
+    fn test_token_bucket_key_limit() {
+        let clock = MockClock::new();
+        let config = RateLimitConfig::builder(1, Duration::from_secs(1))
+            .max_keys(1, crate::OverflowPolicy::Reject)
+            .build()
+            .unwrap();
+        let limiter = TokenBucket::with_clock(config, Arc::new(clock.clone())).unwrap();
+        
+        assert!(limiter.allow_request("user1").unwrap().allowed);
+        assert!(matches!(
+            limiter.allow_request("user2"),
+            Err(crate::RateLimitError::TooManyKeys { max_keys: 1 })
+        ));
+        
+        // Room frees up once user1 is indistinguishable from a new key
+        clock.advance(Duration::from_secs(1));
+        assert!(limiter.allow_request("user2").unwrap().allowed);
+        assert_eq!(limiter.tracked_keys(), 1);
+    }
+    
+    #[test]
+    fn test_token_bucket_evicts_least_recent_key() {
+        let config = RateLimitConfig::builder(1, Duration::from_secs(60))
+            .max_keys(1, crate::OverflowPolicy::EvictLeastRecent)
+            .build()
+            .unwrap();
+        let limiter = TokenBucket::new(config).unwrap();
+        
+        assert!(limiter.allow_request("user1").unwrap().allowed);
+        assert!(limiter.allow_request("user2").unwrap().allowed);
+        
+        // user1 was forgotten to make room, so it starts over
+        assert!(limiter.allow_request("user1").unwrap().allowed);
+        assert_eq!(limiter.tracked_keys(), 1);
+    }
+    
+    #[test]
+    fn test_token_bucket_status_does_not_consume() {
+        let clock = MockClock::new();
+        let config = RateLimitConfig::per_second(2);
+        let limiter = TokenBucket::with_clock(config, Arc::new(clock.clone())).unwrap();
+        
+        // Unknown keys report a full bucket and are not tracked
+        let status = limiter.status("user1").unwrap();
+        assert!(status.allowed);
+        assert_eq!(status.remaining, 2);
+        assert_eq!(limiter.tracked_keys(), 0);
+        
+        limiter.allow_n("user1", 2).unwrap();
+        for _ in 0..3 {
+            let status = limiter.status("user1").unwrap();
+            assert!(!status.allowed);
+            assert_eq!(status.remaining, 0);
+            assert_eq!(status.retry_after, Some(Duration::from_millis(500)));
+        }
+        
+        clock.advance(Duration::from_millis(500));
+        let status = limiter.status("user1").unwrap();
+        assert!(status.allowed);
+        assert_eq!(status.remaining, 1);
+        assert_eq!(status.reset_after, Duration::from_millis(500));
+        assert!(limiter.allow_request("user1").unwrap().allowed);
+    }
+    
+    #[test]
+    fn test_token_bucket_reservation_refund() {
+        let clock = MockClock::new();
+        let config = RateLimitConfig::per_second(4);
+        let limiter = TokenBucket::with_clock(config, Arc::new(clock.clone())).unwrap();
+        
+        let kept = limiter.reserve("user1", 2).unwrap();
+        let cancelled = limiter.reserve("user1", 2).unwrap();
+        assert!(kept.is_allowed() && cancelled.is_allowed());
+        assert_eq!(limiter.status("user1").unwrap().remaining, 0);
+        
+        kept.commit();
+        cancelled.cancel().unwrap();
+        assert_eq!(limiter.status("user1").unwrap().remaining, 2);
+        
+        // Refunds never push the bucket over capacity
+        clock.advance(Duration::from_millis(250));
+        let late = limiter.reserve("user1", 3).unwrap();
+        clock.advance(Duration::from_secs(1));
+        late.cancel().unwrap();
+        assert_eq!(limiter.status("user1").unwrap().remaining, 4);
+        
+        // Denied reservations have nothing to give back
+        limiter.allow_n("user1", 4).unwrap();
+        let denied = limiter.reserve("user1", 1).unwrap();
+        assert!(!denied.is_allowed());
+        denied.cancel().unwrap();
+        assert_eq!(limiter.status("user1").unwrap().remaining, 0);
+    }
+    
+    #[test]
+    fn test_token_bucket_overrides_keep_state() {
+        let clock = MockClock::new();
+        let overrides = Arc::new(LimitOverrides::new());
+        overrides.set_plan("pro", RateLimitConfig::per_second(10)).unwrap();
+        let limiter = TokenBucket::with_clock(RateLimitConfig::per_second(2), Arc::new(clock.clone()))
+            .unwrap()
+            .with_overrides(Arc::clone(&overrides));
+        
+        assert!(limiter.allow_n("acme", 2).unwrap().allowed);
+        assert!(!limiter.allow_request("acme").unwrap().allowed);
+        
+        // Upgrading keeps the empty bucket but refills it at the new rate
+        overrides.assign_plan("acme", "pro").unwrap();
+        let denied = limiter.allow_request("acme").unwrap();
+        assert_eq!(denied.limit, 10);
+        assert_eq!(denied.retry_after, Some(Duration::from_millis(100)));
+        
+        clock.advance(Duration::from_secs(1));
+        assert!(limiter.allow_n("acme", 10).unwrap().allowed);
+        
+        // Other keys keep the default
+        assert_eq!(limiter.status("other").unwrap().limit, 2);
+        
+        // Downgrading caps the tokens at the new capacity
+        clock.advance(Duration::from_secs(1));
+        overrides.remove_key("acme");
+        assert_eq!(limiter.status("acme").unwrap().remaining, 2);
+        
+        // Idle checks use each key's own capacity, not the default of 2
+        overrides.assign_plan("acme", "pro").unwrap();
+        limiter.allow_n("acme", 5).unwrap();
+        clock.advance(Duration::from_millis(400));
+        assert_eq!(limiter.evict_idle(), 0);
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(limiter.evict_idle(), 1);
     }
 }
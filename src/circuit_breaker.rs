@@ -0,0 +1,388 @@
+use crate::clock::{Clock, SystemClock};
+use crate::metrics;
+use crate::{RateLimitError, Result};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// State of a `CircuitBreaker`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Calls go through; failures are counted
+    Closed,
+    /// Calls fail fast until the open interval has passed
+    Open,
+    /// Probe calls test whether the backend has recovered
+    HalfOpen,
+}
+
+impl CircuitState {
+    /// Value of the `rate_limiter_circuit_breaker_state` gauge
+    pub fn as_gauge(self) -> i64 {
+        match self {
+            CircuitState::Closed => 0,
+            CircuitState::Open => 1,
+            CircuitState::HalfOpen => 2,
+        }
+    }
+}
+
+impl fmt::Display for CircuitState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half_open",
+        })
+    }
+}
+
+/// When a `CircuitBreaker` opens, and how it recovers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive failures that open the circuit
+    pub failure_threshold: u32,
+    /// How long the circuit stays open before probing the backend
+    pub open_interval: Duration,
+    /// Successful probes in a row that close the circuit again
+    pub success_threshold: u32,
+    /// Longest a single call may take before it counts as failed
+    pub call_timeout: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            open_interval: Duration::from_secs(10),
+            success_threshold: 2,
+            call_timeout: Duration::from_millis(200),
+        }
+    }
+}
+
+impl CircuitBreakerConfig {
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |msg: &str| Err(RateLimitError::ConfigError(msg.to_string()));
+        
+        if self.failure_threshold == 0 {
+            return invalid("failure_threshold must be greater than zero");
+        }
+        if self.success_threshold == 0 {
+            return invalid("success_threshold must be greater than zero");
+        }
+        if self.open_interval.is_zero() {
+            return invalid("open_interval must be greater than zero");
+        }
+        if self.call_timeout.is_zero() {
+            return invalid("call_timeout must be greater than zero");
+        }
+        Ok(())
+    }
+}
+
+/// Permission for one call, from `CircuitBreaker::acquire`
+#[derive(Debug, Clone, Copy)]
+#[must_use = "report how the call went with `CircuitBreaker::record`"]
+pub struct CallPermit {
+    generation: u64,
+}
+
+#[derive(Debug)]
+struct Circuit {
+    state: CircuitState,
+    generation: u64, // bumped on every transition, so stale results can be told apart
+    failures: u32,  // consecutive, while closed
+    successes: u32, // consecutive probes, while half-open
+    opened_at: Instant,
+    probe_started: Option<Instant>, // probe in flight, while half-open
+}
+
+/// Circuit breaker guarding calls to a remote backend such as Redis
+///
+/// After `failure_threshold` consecutive failures the circuit opens and
+/// calls fail fast with `RateLimitError::CircuitOpen` instead of waiting on
+/// a dead backend. Once `open_interval` has passed it turns half-open and
+/// lets one probe call through at a time: a failed probe opens it again,
+/// `success_threshold` successful ones close it. A probe that never reports
+/// back (e.g. a dropped future) is given up on after `call_timeout`.
+///
+/// Transitions are counted in the `rate_limiter_circuit_breaker_*` metrics,
+/// labelled with the breaker's name. Share one breaker (via `Arc`) between
+/// limiters talking to the same backend, and name each backend's differently.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    name: String,
+    config: CircuitBreakerConfig,
+    circuit: Mutex<Circuit>,
+    clock: Arc<dyn Clock>,
+}
+
+impl CircuitBreaker {
+    /// Create a circuit breaker reported in metrics as `name`
+    pub fn new(name: impl Into<String>, config: CircuitBreakerConfig) -> Result<Self> {
+        Self::with_clock(name, config, Arc::new(SystemClock))
+    }
+    
+    /// Create a circuit breaker that reads time from `clock`
+    pub fn with_clock(name: impl Into<String>, config: CircuitBreakerConfig, clock: Arc<dyn Clock>) -> Result<Self> {
+        config.validate()?;
+        let name = name.into();
+        metrics::CIRCUIT_BREAKER_STATE.with_label_values(&[&name]).set(CircuitState::Closed.as_gauge());
+        
+        Ok(Self {
+            name,
+            circuit: Mutex::new(Circuit {
+                state: CircuitState::Closed,
+                generation: 0,
+                failures: 0,
+                successes: 0,
+                opened_at: clock.now(),
+                probe_started: None,
+            }),
+            config,
+            clock,
+        })
+    }
+    
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    
+    pub fn config(&self) -> &CircuitBreakerConfig {
+        &self.config
+    }
+    
+    /// Current state; an open circuit only turns half-open on the next call
+    pub fn state(&self) -> CircuitState {
+        self.lock().state
+    }
+    
+    fn lock(&self) -> std::sync::MutexGuard<'_, Circuit> {
+        self.circuit.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    
+    fn transition(&self, circuit: &mut Circuit, state: CircuitState) {
+        circuit.state = state;
+        circuit.generation += 1;
+        circuit.failures = 0;
+        circuit.successes = 0;
+        circuit.probe_started = None;
+        if state == CircuitState::Open {
+            circuit.opened_at = self.clock.now();
+        }
+        metrics::record_circuit_transition(&self.name, state);
+    }
+    
+    /// Permission to make a call, which must then be reported with `record`
+    ///
+    /// Fails with `RateLimitError::CircuitOpen` while the circuit is open or
+    /// another probe is in flight.
+    pub fn acquire(&self) -> Result<CallPermit> {
+        let now = self.clock.now();
+        let mut circuit = self.lock();
+        
+        if circuit.state == CircuitState::Open {
+            let open_for = now.saturating_duration_since(circuit.opened_at);
+            if open_for < self.config.open_interval {
+                metrics::CIRCUIT_BREAKER_REJECTED.with_label_values(&[&self.name]).inc();
+                return Err(RateLimitError::CircuitOpen(self.config.open_interval - open_for));
+            }
+            self.transition(&mut circuit, CircuitState::HalfOpen);
+        }
+        
+        if circuit.state == CircuitState::HalfOpen {
+            let probing = circuit.probe_started
+                .is_some_and(|started| now.saturating_duration_since(started) < self.config.call_timeout);
+            if probing {
+                metrics::CIRCUIT_BREAKER_REJECTED.with_label_values(&[&self.name]).inc();
+                return Err(RateLimitError::CircuitOpen(self.config.call_timeout));
+            }
+            circuit.probe_started = Some(now);
+        }
+        Ok(CallPermit { generation: circuit.generation })
+    }
+    
+    /// Report how a call allowed by `acquire` went
+    ///
+    /// Calls that started before the circuit last changed state are ignored,
+    /// so e.g. a slow call from before an outage can't decide a probe.
+    pub fn record(&self, permit: CallPermit, success: bool) {
+        let mut circuit = self.lock();
+        if permit.generation != circuit.generation {
+            return;
+        }
+        
+        match (circuit.state, success) {
+            (CircuitState::Closed, true) => circuit.failures = 0,
+            (CircuitState::Closed, false) => {
+                circuit.failures += 1;
+                if circuit.failures >= self.config.failure_threshold {
+                    self.transition(&mut circuit, CircuitState::Open);
+                }
+            }
+            (CircuitState::HalfOpen, true) => {
+                circuit.successes += 1;
+                circuit.probe_started = None;
+                if circuit.successes >= self.config.success_threshold {
+                    self.transition(&mut circuit, CircuitState::Closed);
+                }
+            }
+            (CircuitState::HalfOpen, false) => self.transition(&mut circuit, CircuitState::Open),
+            // No permits are handed out while open
+            (CircuitState::Open, _) => {}
+        }
+    }
+    
+    /// Run `f` if the circuit allows it, recording whether it failed
+    ///
+    /// Only `RateLimitError::Backend` counts as a failure: any other error
+    /// means the backend answered.
+    pub fn call<T>(&self, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        let permit = self.acquire()?;
+        let result = f();
+        self.record(permit, !matches!(result, Err(RateLimitError::Backend(_))));
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    
+    fn breaker(clock: &Arc<MockClock>) -> CircuitBreaker {
+        let config = CircuitBreakerConfig {
+            failure_threshold: 3,
+            open_interval: Duration::from_secs(5),
+            success_threshold: 2,
+            call_timeout: Duration::from_millis(100),
+        };
+        CircuitBreaker::with_clock("test", config, clock.clone()).unwrap()
+    }
+    
+    fn fail() -> Result<()> {
+        Err(RateLimitError::Backend("Redis connection failed".into()))
+    }
+    
+    #[test]
+    fn test_circuit_opens_after_consecutive_failures() {
+        let clock = Arc::new(MockClock::new());
+        let breaker = breaker(&clock);
+        
+        // A success in between starts the count over
+        for _ in 0..2 {
+            assert!(breaker.call(fail).is_err());
+        }
+        breaker.call(|| Ok(())).unwrap();
+        for _ in 0..2 {
+            assert!(breaker.call(fail).is_err());
+        }
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        
+        assert!(breaker.call(fail).is_err());
+        assert_eq!(breaker.state(), CircuitState::Open);
+        
+        // Open: fails fast without calling
+        clock.advance(Duration::from_secs(2));
+        let result = breaker.call(|| -> Result<()> { panic!("called while open") });
+        assert!(matches!(result, Err(RateLimitError::CircuitOpen(d)) if d == Duration::from_secs(3)));
+    }
+    
+    #[test]
+    fn test_answered_errors_are_not_failures() {
+        let clock = Arc::new(MockClock::new());
+        let breaker = breaker(&clock);
+        
+        for _ in 0..5 {
+            let result = breaker.call(|| -> Result<()> { Err(RateLimitError::ConfigError("WRONGTYPE".into())) });
+            assert!(matches!(result, Err(RateLimitError::ConfigError(_))));
+        }
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+    
+    #[test]
+    fn test_half_open_probes_close_or_reopen_the_circuit() {
+        let clock = Arc::new(MockClock::new());
+        let breaker = breaker(&clock);
+        for _ in 0..3 {
+            let _ = breaker.call(fail);
+        }
+        
+        // A failed probe opens it for another interval
+        clock.advance(Duration::from_secs(5));
+        assert!(matches!(breaker.call(fail), Err(RateLimitError::Backend(_))));
+        assert_eq!(breaker.state(), CircuitState::Open);
+        clock.advance(Duration::from_secs(4));
+        assert!(matches!(breaker.acquire(), Err(RateLimitError::CircuitOpen(_))));
+        
+        // One probe at a time, and closing takes two successes
+        clock.advance(Duration::from_secs(1));
+        let probe = breaker.acquire().unwrap();
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(matches!(breaker.acquire(), Err(RateLimitError::CircuitOpen(_))));
+        breaker.record(probe, true);
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        breaker.call(|| Ok(())).unwrap();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+    
+    #[test]
+    fn test_lost_probe_is_given_up_after_call_timeout() {
+        let clock = Arc::new(MockClock::new());
+        let breaker = breaker(&clock);
+        for _ in 0..3 {
+            let _ = breaker.call(fail);
+        }
+        clock.advance(Duration::from_secs(5));
+        
+        // Never recorded, e.g. a cancelled request
+        let _ = breaker.acquire().unwrap();
+        assert!(breaker.acquire().is_err());
+        clock.advance(Duration::from_millis(100));
+        let _ = breaker.acquire().unwrap();
+    }
+    
+    #[test]
+    fn test_results_from_before_a_transition_are_ignored() {
+        let clock = Arc::new(MockClock::new());
+        let breaker = breaker(&clock);
+        let slow_failure = breaker.acquire().unwrap();
+        let slow_success = breaker.acquire().unwrap();
+        for _ in 0..3 {
+            let _ = breaker.call(fail);
+        }
+        clock.advance(Duration::from_secs(5));
+        let probe = breaker.acquire().unwrap();
+        
+        // Started while closed, so they neither reopen nor close the circuit
+        breaker.record(slow_failure, false);
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        breaker.record(slow_success, true);
+        breaker.record(probe, true);
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        breaker.call(|| Ok(())).unwrap();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+    
+    #[test]
+    fn test_each_breaker_reports_its_own_state() {
+        let clock = Arc::new(MockClock::new());
+        let config = CircuitBreakerConfig { failure_threshold: 1, ..Default::default() };
+        let primary = CircuitBreaker::with_clock("gauge-primary", config.clone(), clock.clone()).unwrap();
+        let _ = primary.call(fail);
+        let _secondary = CircuitBreaker::with_clock("gauge-secondary", config, clock.clone()).unwrap();
+        
+        let gauge = |name: &str| metrics::CIRCUIT_BREAKER_STATE.with_label_values(&[name]).get();
+        assert_eq!(gauge("gauge-primary"), CircuitState::Open.as_gauge());
+        assert_eq!(gauge("gauge-secondary"), CircuitState::Closed.as_gauge());
+    }
+    
+    #[test]
+    fn test_circuit_breaker_rejects_invalid_config() {
+        let config = CircuitBreakerConfig { failure_threshold: 0, ..Default::default() };
+        assert!(CircuitBreaker::new("test", config).is_err());
+        let config = CircuitBreakerConfig { call_timeout: Duration::ZERO, ..Default::default() };
+        assert!(CircuitBreaker::new("test", config).is_err());
+    }
+}
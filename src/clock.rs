@@ -0,0 +1,135 @@
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Source of time for rate limiters
+///
+/// In-memory algorithms use the monotonic `now`, while distributed backends
+/// that share state between machines use the wall-clock `unix_now`.
+pub trait Clock: Send + Sync + Debug {
+    /// Current monotonic time
+    fn now(&self) -> Instant;
+    
+    /// Current wall-clock time as a duration since the UNIX epoch
+    fn unix_now(&self) -> Duration;
+}
+
+/// Clock backed by the operating system
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+    
+    fn unix_now(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO)
+    }
+}
+
+/// Manually advanced clock for deterministic tests
+///
+/// Time only moves when `advance` is called. Clones share the same time,
+/// so a test can keep one handle while the limiter owns another.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    start: Instant,
+    unix_start: Duration,
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+impl MockClock {
+    /// Create a mock clock starting at 2023-11-14T22:13:20Z
+    pub fn new() -> Self {
+        Self::starting_at(Duration::from_secs(1_700_000_000))
+    }
+    
+    /// Create a mock clock whose wall-clock time starts at `unix_time`
+    pub fn starting_at(unix_time: Duration) -> Self {
+        Self {
+            start: Instant::now(),
+            unix_start: unix_time,
+            elapsed: Arc::new(Mutex::new(Duration::ZERO)),
+        }
+    }
+    
+    /// Move time forward
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+    
+    /// Total time advanced since creation
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+    
+    fn unix_now(&self) -> Duration {
+        self.unix_start + self.elapsed()
+    }
+}
+
+/// Clock following tokio's, so limiters see the time a paused test runtime
+/// skips ahead when every task sleeps
+#[cfg(test)]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TokioClock {
+    start: tokio::time::Instant,
+}
+
+#[cfg(test)]
+impl TokioClock {
+    pub(crate) fn new() -> Self {
+        Self { start: tokio::time::Instant::now() }
+    }
+    
+    /// Time since creation, as tokio sees it
+    pub(crate) fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+#[cfg(test)]
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+    
+    fn unix_now(&self) -> Duration {
+        Duration::from_secs(1_700_000_000) + self.elapsed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_mock_clock_advances() {
+        let clock = MockClock::new();
+        let handle = clock.clone();
+        
+        let start = clock.now();
+        let unix_start = clock.unix_now();
+        
+        handle.advance(Duration::from_millis(1500));
+        
+        assert_eq!(clock.now() - start, Duration::from_millis(1500));
+        assert_eq!(clock.unix_now() - unix_start, Duration::from_millis(1500));
+        assert_eq!(clock.elapsed(), Duration::from_millis(1500));
+    }
+}
\ No newline at end of file
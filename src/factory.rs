@@ -0,0 +1,105 @@
+use crate::algorithms::{FixedWindow, Gcra, LeakyBucket, SlidingWindow, SlidingWindowCounter, TokenBucket};
+use crate::clock::{Clock, SystemClock};
+use crate::redis_limiter::RedisRateLimiter;
+use crate::{AlgorithmType, RateLimitConfig, RateLimitError, RateLimiter, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Where limiter state is kept
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Backend {
+    /// Per-process state (the default)
+    #[default]
+    Memory,
+    /// Shared state in Redis, for limits enforced across several instances
+    Redis { url: String },
+}
+
+/// Build a limiter for `algorithm` with the given config and backend
+///
+/// ```
+/// use distributed_rate_limiter::{AlgorithmType, RateLimitConfig, RateLimiter};
+/// use distributed_rate_limiter::factory::{build_limiter, Backend};
+///
+/// let algorithm: AlgorithmType = "sliding_window".parse().unwrap();
+/// let limiter = build_limiter(algorithm, RateLimitConfig::per_second(10), &Backend::Memory).unwrap();
+/// assert!(limiter.allow_request("user1").unwrap().allowed);
+/// ```
+pub fn build_limiter(
+    algorithm: AlgorithmType,
+    config: RateLimitConfig,
+    backend: &Backend,
+) -> Result<Box<dyn RateLimiter>> {
+    build_limiter_with_clock(algorithm, config, backend, Arc::new(SystemClock))
+}
+
+/// Like `build_limiter`, but the limiter reads time from `clock`
+pub fn build_limiter_with_clock(
+    algorithm: AlgorithmType,
+    config: RateLimitConfig,
+    backend: &Backend,
+    clock: Arc<dyn Clock>,
+) -> Result<Box<dyn RateLimiter>> {
+    match backend {
+        Backend::Memory => Ok(match algorithm {
+            AlgorithmType::TokenBucket => Box::new(TokenBucket::with_clock(config, clock)?),
+            AlgorithmType::LeakyBucket => Box::new(LeakyBucket::with_clock(config, clock)?),
+            AlgorithmType::FixedWindow => Box::new(FixedWindow::with_clock(config, clock)?),
+            AlgorithmType::SlidingWindow => Box::new(SlidingWindow::with_clock(config, clock)?),
+            AlgorithmType::SlidingWindowCounter => Box::new(SlidingWindowCounter::with_clock(config, clock)?),
+            AlgorithmType::Gcra => Box::new(Gcra::with_clock(config, clock)?),
+        }),
+        Backend::Redis { url } => {
+            let limiter = RedisRateLimiter::with_algorithm(url, config, algorithm, clock)
+                .map_err(|e| RateLimitError::ConfigError(e.to_string()))?;
+            Ok(Box::new(limiter))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    
+    #[test]
+    fn test_factory_builds_every_memory_algorithm() {
+        for algorithm in AlgorithmType::ALL {
+            let limiter = build_limiter(algorithm, RateLimitConfig::per_second(2), &Backend::Memory).unwrap();
+            
+            assert!(limiter.allow_request("user1").unwrap().allowed);
+            assert!(limiter.allow_request("user1").unwrap().allowed);
+            assert!(!limiter.allow_request("user1").unwrap().allowed, "{} should deny", algorithm);
+        }
+    }
+    
+    #[test]
+    fn test_factory_validates_config() {
+        let config = RateLimitConfig::new(0, Duration::from_secs(1));
+        assert!(build_limiter(AlgorithmType::FixedWindow, config, &Backend::Memory).is_err());
+    }
+    
+    #[test]
+    fn test_factory_builds_every_redis_algorithm() {
+        // Building doesn't connect, so this runs without Redis
+        let backend = Backend::Redis { url: "redis://127.0.0.1/".into() };
+        for algorithm in AlgorithmType::ALL {
+            assert!(build_limiter(algorithm, RateLimitConfig::per_second(2), &backend).is_ok(), "{}", algorithm);
+        }
+        
+        let result = build_limiter(AlgorithmType::SlidingWindow, RateLimitConfig::new(0, Duration::from_secs(1)), &backend);
+        assert!(matches!(result, Err(RateLimitError::ConfigError(_))));
+        let result = build_limiter(AlgorithmType::FixedWindow, RateLimitConfig::per_second(2), &Backend::Redis { url: "http://cache/".into() });
+        assert!(matches!(result, Err(RateLimitError::ConfigError(_))));
+    }
+    
+    #[test]
+    fn test_backend_serde() {
+        let backend: Backend = serde_json::from_str(r#"{"type": "redis", "url": "redis://cache/"}"#).unwrap();
+        assert_eq!(backend, Backend::Redis { url: "redis://cache/".into() });
+        
+        let backend: Backend = serde_json::from_str(r#"{"type": "memory"}"#).unwrap();
+        assert_eq!(backend, Backend::Memory);
+    }
+}
\ No newline at end of file
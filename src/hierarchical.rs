@@ -0,0 +1,334 @@
+use crate::{RateLimitDecision, RateLimiter, Reservation, Result};
+use std::fmt;
+
+type KeyFn<C> = Box<dyn Fn(&C) -> Option<String> + Send + Sync>;
+
+struct Tier<C: ?Sized> {
+    name: String,
+    limiter: Box<dyn RateLimiter>,
+    key: KeyFn<C>,
+}
+
+/// Outcome of checking a request against every tier
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HierarchicalDecision {
+    /// Decision of the tier that denied the request, or else of the
+    /// applicable tier with the least left
+    pub decision: RateLimitDecision,
+    /// Name of the tier that denied the request
+    pub denied_by: Option<String>,
+    /// Why giving units back to earlier tiers failed after a denial; they
+    /// stay spent, but the denial is still reported
+    pub rollback_error: Option<String>,
+}
+
+/// Ordered chain of limits applied to the same request, e.g.
+/// user → API key → endpoint → global
+///
+/// Each tier pairs a limiter (any `RateLimiter`, in memory or in Redis)
+/// with a function that picks the tier's key from a request context `C`,
+/// or returns `None` when the tier doesn't apply. Tiers are checked in
+/// order and the request consumes from all of them or from none: when a
+/// tier denies, units already taken from earlier tiers are refunded and
+/// later tiers are not checked. Limiters without refund support (see
+/// `RateLimiter::reserve`) keep units taken for denied requests.
+///
+/// Keys are prefixed with the tier name, so tiers can share a backend.
+///
+/// ```
+/// use distributed_rate_limiter::{HierarchicalLimiter, RateLimitConfig};
+/// use distributed_rate_limiter::algorithms::{FixedWindow, TokenBucket};
+///
+/// struct Request { user: String, endpoint: &'static str }
+///
+/// let limiter = HierarchicalLimiter::new()
+///     .tier("user", TokenBucket::new(RateLimitConfig::per_second(5)).unwrap(), |r: &Request| Some(r.user.clone()))
+///     .tier("endpoint", FixedWindow::new(RateLimitConfig::per_minute(100)).unwrap(), |r: &Request| Some(r.endpoint.to_string()))
+///     .tier("global", TokenBucket::new(RateLimitConfig::per_second(1000)).unwrap(), |_: &Request| Some(String::new()));
+///
+/// let request = Request { user: "alice".to_string(), endpoint: "/search" };
+/// let result = limiter.check(&request, 1).unwrap();
+/// assert!(result.decision.allowed);
+/// assert_eq!(result.denied_by, None);
+/// ```
+pub struct HierarchicalLimiter<C: ?Sized> {
+    tiers: Vec<Tier<C>>,
+}
+
+impl<C: ?Sized> HierarchicalLimiter<C> {
+    /// Create a limiter without any tiers (which allows everything)
+    pub fn new() -> Self {
+        Self { tiers: Vec::new() }
+    }
+    
+    /// Add a tier, checked after all tiers added before it
+    pub fn tier(
+        mut self,
+        name: impl Into<String>,
+        limiter: impl RateLimiter + 'static,
+        key: impl Fn(&C) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.tiers.push(Tier {
+            name: name.into(),
+            limiter: Box::new(limiter),
+            key: Box::new(key),
+        });
+        self
+    }
+    
+    /// Names of the tiers, in the order they are checked
+    pub fn tier_names(&self) -> impl Iterator<Item = &str> {
+        self.tiers.iter().map(|tier| tier.name.as_str())
+    }
+    
+    /// Check `cost` against every applicable tier, consuming only if all allow it
+    pub fn check(&self, ctx: &C, cost: u64) -> Result<HierarchicalDecision> {
+        let (result, reservations) = self.reserve_tiers(ctx, cost)?;
+        for reservation in reservations {
+            reservation.commit();
+        }
+        Ok(result)
+    }
+    
+    /// Like `check`, returning a handle that refunds every tier when cancelled
+    pub fn reserve(&self, ctx: &C, cost: u64) -> Result<(HierarchicalDecision, Reservation<'_>)> {
+        let (result, reservations) = self.reserve_tiers(ctx, cost)?;
+        let reservation = Reservation::new(result.decision, move || cancel_all(reservations));
+        Ok((result, reservation))
+    }
+    
+    /// Current quota across all applicable tiers, without consuming
+    pub fn status(&self, ctx: &C) -> Result<HierarchicalDecision> {
+        let mut decisions = Vec::new();
+        for (tier, key) in self.applicable(ctx) {
+            let decision = tier.limiter.status(&key)?;
+            if !decision.allowed {
+                return Ok(HierarchicalDecision {
+                    decision,
+                    denied_by: Some(tier.name.clone()),
+                    rollback_error: None,
+                });
+            }
+            decisions.push(decision);
+        }
+        
+        Ok(Self::allowed(decisions))
+    }
+    
+    /// Reset every applicable tier for this context
+    pub fn reset(&self, ctx: &C) {
+        for (tier, key) in self.applicable(ctx) {
+            tier.limiter.reset(&key);
+        }
+    }
+    
+    /// Forget idle keys in every tier, returning how many were removed
+    pub fn evict_idle(&self) -> usize {
+        self.tiers.iter().map(|tier| tier.limiter.evict_idle()).sum()
+    }
+    
+    /// Tiers that apply to `ctx`, with their (prefixed) keys
+    fn applicable(&self, ctx: &C) -> Vec<(&Tier<C>, String)> {
+        self.tiers.iter()
+            .filter_map(|tier| (tier.key)(ctx).map(|key| (tier, format!("{}:{}", tier.name, key))))
+            .collect()
+    }
+    
+    /// Reserve from each tier in order, rolling back as soon as one denies or fails
+    fn reserve_tiers(&self, ctx: &C, cost: u64) -> Result<(HierarchicalDecision, Vec<Reservation<'_>>)> {
+        let mut reservations = Vec::new();
+        for (tier, key) in self.applicable(ctx) {
+            let reservation = match tier.limiter.reserve(&key, cost) {
+                Ok(reservation) => reservation,
+                Err(e) => {
+                    // Report the tier's own error rather than one from the rollback
+                    let _ = cancel_all(reservations);
+                    return Err(e);
+                }
+            };
+            
+            if !reservation.is_allowed() {
+                let decision = *reservation.decision();
+                return Ok((
+                    HierarchicalDecision {
+                        decision,
+                        denied_by: Some(tier.name.clone()),
+                        rollback_error: cancel_all(reservations).err().map(|e| e.to_string()),
+                    },
+                    Vec::new(),
+                ));
+            }
+            reservations.push(reservation);
+        }
+        
+        let decisions = reservations.iter().map(|reservation| *reservation.decision()).collect();
+        Ok((Self::allowed(decisions), reservations))
+    }
+    
+    /// Combine the decisions of tiers that all allowed the request
+    fn allowed(decisions: Vec<RateLimitDecision>) -> HierarchicalDecision {
+        // With no applicable tier there is nothing to limit
+        let decision = decisions.into_iter()
+            .min_by_key(|decision| decision.remaining)
+            .unwrap_or(RateLimitDecision::allow(u64::MAX, u64::MAX, std::time::Duration::ZERO));
+        
+        HierarchicalDecision {
+            decision,
+            denied_by: None,
+            rollback_error: None,
+        }
+    }
+}
+
+/// Cancel reservations newest first, returning the first error once all were tried
+fn cancel_all(reservations: Vec<Reservation<'_>>) -> Result<()> {
+    reservations.into_iter()
+        .rev()
+        .map(Reservation::cancel)
+        .fold(Ok(()), Result::and)
+}
+
+impl<C: ?Sized> Default for HierarchicalLimiter<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C: ?Sized> fmt::Debug for HierarchicalLimiter<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HierarchicalLimiter")
+            .field("tiers", &self.tier_names().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithms::{FixedWindow, TokenBucket};
+    use crate::{RateLimitConfig, RateLimitError};
+    
+    struct Request {
+        user: &'static str,
+        api_key: Option<&'static str>,
+    }
+    
+    fn limiter() -> HierarchicalLimiter<Request> {
+        HierarchicalLimiter::new()
+            .tier("user", FixedWindow::new(RateLimitConfig::per_minute(2)).unwrap(), |r: &Request| Some(r.user.to_string()))
+            .tier("api_key", FixedWindow::new(RateLimitConfig::per_minute(10)).unwrap(), |r: &Request| r.api_key.map(str::to_string))
+            .tier("global", TokenBucket::new(RateLimitConfig::per_minute(3)).unwrap(), |_: &Request| Some(String::new()))
+    }
+    
+    #[test]
+    fn test_hierarchical_reports_denying_tier() {
+        let limiter = limiter();
+        let alice = Request { user: "alice", api_key: Some("k1") };
+        let bob = Request { user: "bob", api_key: None };
+        
+        assert!(limiter.check(&alice, 2).unwrap().decision.allowed);
+        let denied = limiter.check(&alice, 1).unwrap();
+        assert!(!denied.decision.allowed);
+        assert_eq!(denied.denied_by.as_deref(), Some("user"));
+        assert_eq!(denied.decision.limit, 2);
+        
+        // Alice's denied request didn't use up the global quota
+        let allowed = limiter.check(&bob, 1).unwrap();
+        assert!(allowed.decision.allowed);
+        assert_eq!(allowed.decision.remaining, 0);
+        
+        let denied = limiter.check(&bob, 1).unwrap();
+        assert_eq!(denied.denied_by.as_deref(), Some("global"));
+        let carol = Request { user: "carol", api_key: None };
+        assert_eq!(limiter.status(&carol).unwrap().denied_by.as_deref(), Some("global"));
+    }
+    
+    #[test]
+    fn test_hierarchical_rolls_back_earlier_tiers() {
+        let limiter = limiter();
+        let alice = Request { user: "alice", api_key: Some("k1") };
+        
+        // The global tier denies, so the user and API key tiers are refunded
+        limiter.check(&Request { user: "bob", api_key: None }, 2).unwrap();
+        assert_eq!(limiter.check(&alice, 2).unwrap().denied_by.as_deref(), Some("global"));
+        
+        limiter.reset(&Request { user: "bob", api_key: None });
+        let status = limiter.status(&alice).unwrap();
+        assert!(status.decision.allowed);
+        assert_eq!(status.decision.remaining, 2);
+        
+        // Errors roll back too
+        let limiter: HierarchicalLimiter<str> = HierarchicalLimiter::new()
+            .tier("wide", TokenBucket::new(RateLimitConfig::per_second(5)).unwrap(), |key: &str| Some(key.to_string()))
+            .tier("narrow", TokenBucket::new(RateLimitConfig::per_second(2)).unwrap(), |key: &str| Some(key.to_string()));
+        assert!(matches!(
+            limiter.check("user1", 3),
+            Err(RateLimitError::CostExceedsCapacity { .. })
+        ));
+        assert_eq!(limiter.status("user1").unwrap().decision.remaining, 2);
+        assert!(limiter.check("user1", 2).unwrap().decision.allowed);
+    }
+    
+    /// Allows everything, but can't give units back
+    struct Unrefundable;
+    
+    impl RateLimiter for Unrefundable {
+        fn allow_n(&self, _: &str, _: u64) -> Result<RateLimitDecision> {
+            Ok(RateLimitDecision::allow(10, 10, std::time::Duration::ZERO))
+        }
+        
+        fn reserve(&self, key: &str, cost: u64) -> Result<Reservation<'_>> {
+            let decision = self.allow_n(key, cost)?;
+            Ok(Reservation::new(decision, || Err(RateLimitError::Backend("refund failed".into()))))
+        }
+        
+        fn status(&self, key: &str) -> Result<RateLimitDecision> {
+            self.allow_n(key, 0)
+        }
+        
+        fn reset(&self, _: &str) {}
+    }
+    
+    #[test]
+    fn test_hierarchical_reports_denial_despite_failed_rollback() {
+        let limiter: HierarchicalLimiter<str> = HierarchicalLimiter::new()
+            .tier("remote", Unrefundable, |key: &str| Some(key.to_string()))
+            .tier("local", TokenBucket::new(RateLimitConfig::per_second(1)).unwrap(), |key: &str| Some(key.to_string()));
+        
+        assert!(limiter.check("user1", 1).unwrap().decision.allowed);
+        let denied = limiter.check("user1", 1).unwrap();
+        assert!(!denied.decision.allowed);
+        assert_eq!(denied.denied_by.as_deref(), Some("local"));
+        assert!(denied.rollback_error.unwrap().contains("refund failed"));
+    }
+    
+    #[test]
+    fn test_hierarchical_reservation_refunds_every_tier() {
+        let limiter = limiter();
+        let alice = Request { user: "alice", api_key: Some("k1") };
+        
+        let (result, reservation) = limiter.reserve(&alice, 2).unwrap();
+        assert!(result.decision.allowed);
+        reservation.cancel().unwrap();
+        
+        assert!(limiter.check(&alice, 2).unwrap().decision.allowed);
+        assert_eq!(
+            limiter.tier_names().collect::<Vec<_>>(),
+            ["user", "api_key", "global"]
+        );
+    }
+    
+    #[test]
+    fn test_hierarchical_without_applicable_tiers() {
+        let limiter: HierarchicalLimiter<str> = HierarchicalLimiter::new()
+            .tier("admin", TokenBucket::new(RateLimitConfig::per_second(1)).unwrap(), |user: &str| {
+                (user == "admin").then(|| user.to_string())
+            });
+        
+        for _ in 0..5 {
+            assert!(limiter.check("guest", 1).unwrap().decision.allowed);
+        }
+        assert!(limiter.check("admin", 1).unwrap().decision.allowed);
+        assert!(!limiter.check("admin", 1).unwrap().decision.allowed);
+    }
+}
@@ -1,10 +1,35 @@
+pub mod acquire;
 pub mod algorithms;
+pub mod circuit_breaker;
+pub mod clock;
+pub mod factory;
+pub mod hierarchical;
+#[cfg(test)]
+mod lua_redis;
 pub mod redis_limiter;
+pub mod reservation;
 pub mod metrics; 
+pub mod overrides;
+pub mod sharded;
+pub mod snapshot;
+pub mod storage;
 
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
 use thiserror::Error;
 
+pub use acquire::{LocalRateLimiter, RateLimiterExt};
+pub use circuit_breaker::{CallPermit, CircuitBreaker, CircuitBreakerConfig, CircuitState};
+pub use hierarchical::{HierarchicalDecision, HierarchicalLimiter};
+pub use overrides::LimitOverrides;
+pub use reservation::Reservation;
+pub use sharded::OverflowPolicy;
+pub use snapshot::{KeyState, LimiterSnapshot};
+pub use storage::{MemoryStorage, Storage};
+
 #[derive(Error, Debug)]
 pub enum RateLimitError {
     #[error("Rate limit exceeded")]
@@ -12,15 +37,52 @@ pub enum RateLimitError {
     
     #[error("Configuration error: {0}")]
     ConfigError(String),
+    
+    #[error("Request cost {cost} exceeds limiter capacity {capacity}")]
+    CostExceedsCapacity { cost: u64, capacity: u64 },
+    
+    #[error("Too many tracked keys (limit {max_keys})")]
+    TooManyKeys { max_keys: usize },
+    
+    #[error("Timed out after {0:?} waiting for rate limit capacity")]
+    Timeout(Duration),
+    
+    #[error("Circuit breaker open, retry after {0:?}")]
+    CircuitOpen(Duration),
+    
+    #[error("Backend unavailable: {0}")]
+    Backend(String),
 }
 
 pub type Result<T> = std::result::Result<T, RateLimitError>;
 
+/// Largest request count or capacity a config may use
+///
+/// Bucket algorithms track fractional tokens as `f64`, which represents
+/// integers exactly only up to 2^53.
+pub const MAX_LIMIT: u64 = 1 << 53;
+
 /// Configuration for rate limiter
-#[derive(Debug, Clone)]
+///
+/// `max_requests` per `window` is the sustained rate. Bucket algorithms can
+/// additionally allow bursts above that rate via `burst`; use
+/// `RateLimitConfig::builder` to set the optional fields.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct RateLimitConfig {
     pub max_requests: u64,
     pub window: Duration,
+    /// Bucket capacity (defaults to `max_requests`)
+    pub burst: Option<u64>,
+    /// Requests a brand new key may make immediately (defaults to the capacity)
+    pub initial_tokens: Option<u64>,
+    /// Refill in discrete steps of this interval instead of continuously
+    pub refill_interval: Option<Duration>,
+    /// Sub-windows the sliding window counter splits each window into (defaults to 1)
+    pub sub_windows: Option<u32>,
+    /// Most keys an in-memory limiter tracks at once (unbounded if `None`)
+    pub max_keys: Option<usize>,
+    /// What happens to new keys once `max_keys` is reached
+    pub overflow_policy: OverflowPolicy,
 }
 
 impl RateLimitConfig {
@@ -28,6 +90,12 @@ impl RateLimitConfig {
         Self {
             max_requests,
             window,
+            burst: None,
+            initial_tokens: None,
+            refill_interval: None,
+            sub_windows: None,
+            max_keys: None,
+            overflow_policy: OverflowPolicy::default(),
         }
     }
     
@@ -38,22 +106,500 @@ impl RateLimitConfig {
     pub fn per_minute(max_requests: u64) -> Self {
         Self::new(max_requests, Duration::from_secs(60))
     }
+    
+    /// Start building a config with a sustained rate of `max_requests` per `window`
+    pub fn builder(max_requests: u64, window: Duration) -> RateLimitConfigBuilder {
+        RateLimitConfigBuilder {
+            config: Self::new(max_requests, window),
+        }
+    }
+    
+    /// Maximum number of units a bucket can hold
+    pub fn capacity(&self) -> u64 {
+        self.burst.unwrap_or(self.max_requests)
+    }
+    
+    /// Units available to a key the first time it is seen
+    pub fn initial_tokens(&self) -> u64 {
+        self.initial_tokens.unwrap_or_else(|| self.capacity())
+    }
+    
+    /// Sustained rate in units per second
+    pub fn refill_rate(&self) -> f64 {
+        self.max_requests as f64 / self.window.as_secs_f64()
+    }
+    
+    /// Check that the config describes a usable limit
+    ///
+    /// Every limiter constructor calls this, so a limiter never ends up with
+    /// a zero window, an infinite refill rate or similar nonsense.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |msg: String| Err(RateLimitError::ConfigError(msg));
+        
+        if self.max_requests == 0 {
+            return invalid("max_requests must be greater than zero".into());
+        }
+        if self.max_requests > MAX_LIMIT {
+            return invalid(format!("max_requests must be at most {}", MAX_LIMIT));
+        }
+        if self.window.is_zero() {
+            return invalid("window must be greater than zero".into());
+        }
+        // Backends keep state for a multiple of the window
+        if self.window.checked_mul(2).is_none() {
+            return invalid(format!("window {:?} is too large", self.window));
+        }
+        if let Some(burst) = self.burst {
+            if burst == 0 {
+                return invalid("burst must be greater than zero".into());
+            }
+            if burst > MAX_LIMIT {
+                return invalid(format!("burst must be at most {}", MAX_LIMIT));
+            }
+        }
+        if let Some(initial_tokens) = self.initial_tokens {
+            if initial_tokens > self.capacity() {
+                return invalid(format!(
+                    "initial_tokens ({}) must not exceed capacity ({})",
+                    initial_tokens,
+                    self.capacity()
+                ));
+            }
+        }
+        if let Some(interval) = self.refill_interval {
+            if interval.is_zero() {
+                return invalid("refill_interval must be greater than zero".into());
+            }
+        }
+        if let Some(sub_windows) = self.sub_windows {
+            if sub_windows == 0 {
+                return invalid("sub_windows must be greater than zero".into());
+            }
+            if self.window.as_nanos() < sub_windows as u128 {
+                return invalid(format!("window {:?} is too short for {} sub-windows", self.window, sub_windows));
+            }
+        }
+        if self.max_keys == Some(0) {
+            return invalid("max_keys must be greater than zero".into());
+        }
+        
+        let refill_rate = self.refill_rate();
+        if !refill_rate.is_finite() || refill_rate <= 0.0 {
+            return invalid(format!("refill rate {} tokens/s is not usable", refill_rate));
+        }
+        
+        Ok(())
+    }
+}
+
+/// Builder for `RateLimitConfig`
+///
+/// ```
+/// use distributed_rate_limiter::RateLimitConfig;
+/// use std::time::Duration;
+///
+/// // 10 req/s sustained, bursts of up to 50
+/// let config = RateLimitConfig::builder(10, Duration::from_secs(1))
+///     .burst(50)
+///     .build()
+///     .unwrap();
+/// assert_eq!(config.capacity(), 50);
+/// ```
+#[derive(Debug, Clone)]
+pub struct RateLimitConfigBuilder {
+    config: RateLimitConfig,
+}
+
+impl RateLimitConfigBuilder {
+    /// Allow bursts of up to `burst` units above the sustained rate
+    pub fn burst(mut self, burst: u64) -> Self {
+        self.config.burst = Some(burst);
+        self
+    }
+    
+    /// Number of units a new key starts with
+    pub fn initial_tokens(mut self, initial_tokens: u64) -> Self {
+        self.config.initial_tokens = Some(initial_tokens);
+        self
+    }
+    
+    /// Add tokens in discrete steps every `interval` instead of continuously
+    pub fn refill_interval(mut self, interval: Duration) -> Self {
+        self.config.refill_interval = Some(interval);
+        self
+    }
+    
+    /// Split each window into `sub_windows` buckets for the sliding window counter
+    pub fn sub_windows(mut self, sub_windows: u32) -> Self {
+        self.config.sub_windows = Some(sub_windows);
+        self
+    }
+    
+    /// Track at most `max_keys` keys, handling new keys beyond that per `policy`
+    pub fn max_keys(mut self, max_keys: usize, policy: OverflowPolicy) -> Self {
+        self.config.max_keys = Some(max_keys);
+        self.config.overflow_policy = policy;
+        self
+    }
+    
+    /// Validate and return the config
+    pub fn build(self) -> Result<RateLimitConfig> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+/// Outcome of a single rate limit check
+///
+/// Carries everything a caller needs to build `X-RateLimit-*` / `Retry-After`
+/// style responses without re-deriving the limiter's internal state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitDecision {
+    /// Whether the request was admitted
+    pub allowed: bool,
+    /// Maximum number of requests allowed by the limiter
+    pub limit: u64,
+    /// Requests that can still be made right now
+    pub remaining: u64,
+    /// Time until the limiter is fully replenished for this key
+    pub reset_after: Duration,
+    /// Time to wait before the next request can succeed (only set when denied)
+    pub retry_after: Option<Duration>,
+}
+
+impl RateLimitDecision {
+    /// Build a decision for an admitted request
+    pub fn allow(limit: u64, remaining: u64, reset_after: Duration) -> Self {
+        Self {
+            allowed: true,
+            limit,
+            remaining,
+            reset_after,
+            retry_after: None,
+        }
+    }
+    
+    /// Build a decision for a rejected request
+    pub fn deny(limit: u64, remaining: u64, reset_after: Duration, retry_after: Duration) -> Self {
+        Self {
+            allowed: false,
+            limit,
+            remaining,
+            reset_after,
+            retry_after: Some(retry_after),
+        }
+    }
+}
+
+/// Reject costs that no amount of waiting could ever satisfy
+pub(crate) fn check_cost(cost: u64, capacity: u64) -> Result<()> {
+    if cost > capacity {
+        Err(RateLimitError::CostExceedsCapacity { cost, capacity })
+    } else {
+        Ok(())
+    }
 }
 
 /// Trait that all rate limiting algorithms must implement
+///
+/// Methods take `&self` so a single limiter can be shared across threads
+/// (e.g. behind an `Arc`) without an external lock.
 pub trait RateLimiter: Send + Sync {
+    /// Check if a request consuming `cost` units is allowed
+    ///
+    /// Returns `RateLimitError::CostExceedsCapacity` when `cost` could never
+    /// be admitted, even by a completely idle limiter.
+    fn allow_n(&self, key: &str, cost: u64) -> Result<RateLimitDecision>;
+    
     /// Check if a request is allowed
-    fn allow_request(&mut self, key: &str) -> Result<bool>;
+    fn allow_request(&self, key: &str) -> Result<RateLimitDecision> {
+        self.allow_n(key, 1)
+    }
+    
+    /// Spend `cost` units like `allow_n`, returning a handle to give them back
+    ///
+    /// Cancelling the reservation restores the state as if the request had
+    /// never been made, as far as time has not already done so (e.g. a
+    /// window that has since rolled over is left alone). The default
+    /// implementation's reservations can't be refunded.
+    fn reserve(&self, key: &str, cost: u64) -> Result<Reservation<'_>> {
+        Ok(Reservation::non_refundable(self.allow_n(key, cost)?))
+    }
+    
+    /// Report the current quota for `key` without consuming any of it
+    ///
+    /// `remaining` is what could be spent right now and `allowed` tells
+    /// whether a single request would currently succeed; if not,
+    /// `retry_after` is the time until it would. Keys that have never been
+    /// seen report a fresh quota and are not added to the limiter.
+    fn status(&self, key: &str) -> Result<RateLimitDecision>;
     
     /// Reset the rate limiter for a specific key
-    fn reset(&mut self, key: &str);
+    fn reset(&self, key: &str);
+    
+    /// Forget keys whose state is no different from a brand new key
+    ///
+    /// Returns the number of keys removed. In-memory limiters also do this
+    /// lazily as they grow; call it periodically (see `spawn_sweeper`) to
+    /// release memory sooner. Backends that expire state on their own
+    /// return 0.
+    fn evict_idle(&self) -> usize {
+        0
+    }
+    
+    /// Export the state of every non-idle key, e.g. to persist across restarts
+    ///
+    /// Returns `None` for backends whose state already outlives the process.
+    fn snapshot(&self) -> Option<LimiterSnapshot> {
+        None
+    }
+    
+    /// Replace the state of the keys in `snapshot` with their exported state
+    ///
+    /// Returns the number of keys restored. Fails without restoring
+    /// anything if the snapshot holds another algorithm's state.
+    fn restore(&self, snapshot: &LimiterSnapshot) -> Result<usize> {
+        let _ = snapshot;
+        Err(RateLimitError::ConfigError("this limiter does not support restoring snapshots".to_string()))
+    }
+}
+
+impl<T: RateLimiter + ?Sized> RateLimiter for Box<T> {
+    fn allow_n(&self, key: &str, cost: u64) -> Result<RateLimitDecision> {
+        (**self).allow_n(key, cost)
+    }
+    
+    fn allow_request(&self, key: &str) -> Result<RateLimitDecision> {
+        (**self).allow_request(key)
+    }
+    
+    fn reserve(&self, key: &str, cost: u64) -> Result<Reservation<'_>> {
+        (**self).reserve(key, cost)
+    }
+    
+    fn status(&self, key: &str) -> Result<RateLimitDecision> {
+        (**self).status(key)
+    }
+    
+    fn reset(&self, key: &str) {
+        (**self).reset(key)
+    }
+    
+    fn evict_idle(&self) -> usize {
+        (**self).evict_idle()
+    }
+    
+    fn snapshot(&self) -> Option<LimiterSnapshot> {
+        (**self).snapshot()
+    }
+    
+    fn restore(&self, snapshot: &LimiterSnapshot) -> Result<usize> {
+        (**self).restore(snapshot)
+    }
+}
+
+impl<T: RateLimiter + ?Sized> RateLimiter for Arc<T> {
+    fn allow_n(&self, key: &str, cost: u64) -> Result<RateLimitDecision> {
+        (**self).allow_n(key, cost)
+    }
+    
+    fn allow_request(&self, key: &str) -> Result<RateLimitDecision> {
+        (**self).allow_request(key)
+    }
+    
+    fn reserve(&self, key: &str, cost: u64) -> Result<Reservation<'_>> {
+        (**self).reserve(key, cost)
+    }
+    
+    fn status(&self, key: &str) -> Result<RateLimitDecision> {
+        (**self).status(key)
+    }
+    
+    fn reset(&self, key: &str) {
+        (**self).reset(key)
+    }
+    
+    fn evict_idle(&self) -> usize {
+        (**self).evict_idle()
+    }
+    
+    fn snapshot(&self) -> Option<LimiterSnapshot> {
+        (**self).snapshot()
+    }
+    
+    fn restore(&self, snapshot: &LimiterSnapshot) -> Result<usize> {
+        (**self).restore(snapshot)
+    }
+}
+
+/// Periodically call `evict_idle` on `limiter` from a background task
+///
+/// The task only holds a weak reference and stops once the limiter has
+/// been dropped. Must be called from within a Tokio runtime.
+pub fn spawn_sweeper<L>(limiter: &Arc<L>, every: Duration) -> tokio::task::JoinHandle<()>
+where
+    L: RateLimiter + ?Sized + 'static,
+{
+    let limiter = Arc::downgrade(limiter);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(every);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            match limiter.upgrade() {
+                Some(limiter) => {
+                    limiter.evict_idle();
+                }
+                None => break,
+            }
+        }
+    })
 }
 
 /// Enum for selecting rate limiting algorithm
-#[derive(Debug, Clone, Copy)]
+///
+/// Serializes as snake_case (`"token_bucket"`), which is also what
+/// `FromStr` accepts, so config files, the HTTP API and the CLI all
+/// name algorithms the same way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum AlgorithmType {
     TokenBucket,
     LeakyBucket,
     FixedWindow,
     SlidingWindow,
+    SlidingWindowCounter,
+    Gcra,
+}
+
+impl AlgorithmType {
+    /// Every supported algorithm
+    pub const ALL: [AlgorithmType; 6] = [
+        AlgorithmType::TokenBucket,
+        AlgorithmType::LeakyBucket,
+        AlgorithmType::FixedWindow,
+        AlgorithmType::SlidingWindow,
+        AlgorithmType::SlidingWindowCounter,
+        AlgorithmType::Gcra,
+    ];
+    
+    /// Canonical snake_case name
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AlgorithmType::TokenBucket => "token_bucket",
+            AlgorithmType::LeakyBucket => "leaky_bucket",
+            AlgorithmType::FixedWindow => "fixed_window",
+            AlgorithmType::SlidingWindow => "sliding_window",
+            AlgorithmType::SlidingWindowCounter => "sliding_window_counter",
+            AlgorithmType::Gcra => "gcra",
+        }
+    }
+}
+
+impl fmt::Display for AlgorithmType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for AlgorithmType {
+    type Err = RateLimitError;
+    
+    /// Parse a name like `token_bucket`, `token-bucket` or `TokenBucket`
+    fn from_str(s: &str) -> Result<Self> {
+        let normalized: String = s
+            .chars()
+            .filter(|c| !matches!(c, '_' | '-' | ' '))
+            .collect::<String>()
+            .to_ascii_lowercase();
+        
+        AlgorithmType::ALL
+            .into_iter()
+            .find(|alg| alg.as_str().replace('_', "") == normalized)
+            .ok_or_else(|| RateLimitError::ConfigError(format!("unknown algorithm '{}'", s)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn config_error(config: RateLimitConfig) -> String {
+        match config.validate() {
+            Err(RateLimitError::ConfigError(msg)) => msg,
+            other => panic!("expected ConfigError, got {:?}", other),
+        }
+    }
+    
+    #[test]
+    fn test_config_validation_accepts_sane_configs() {
+        assert!(RateLimitConfig::per_second(1).validate().is_ok());
+        assert!(RateLimitConfig::new(5, Duration::from_millis(1)).validate().is_ok());
+        assert!(RateLimitConfig::builder(10, Duration::from_secs(1))
+            .burst(50)
+            .initial_tokens(0)
+            .refill_interval(Duration::from_millis(100))
+            .build()
+            .is_ok());
+    }
+    
+    #[test]
+    fn test_algorithm_type_parsing() {
+        for alg in AlgorithmType::ALL {
+            assert_eq!(alg.to_string().parse::<AlgorithmType>().unwrap(), alg);
+        }
+        
+        assert_eq!("token-bucket".parse::<AlgorithmType>().unwrap(), AlgorithmType::TokenBucket);
+        assert_eq!("SlidingWindow".parse::<AlgorithmType>().unwrap(), AlgorithmType::SlidingWindow);
+        assert!(matches!("gcra2".parse::<AlgorithmType>(), Err(RateLimitError::ConfigError(_))));
+    }
+    
+    #[test]
+    fn test_algorithm_type_serde() {
+        let json = serde_json::to_string(&AlgorithmType::LeakyBucket).unwrap();
+        assert_eq!(json, "\"leaky_bucket\"");
+        
+        let alg: AlgorithmType = serde_json::from_str("\"fixed_window\"").unwrap();
+        assert_eq!(alg, AlgorithmType::FixedWindow);
+    }
+    
+    #[test]
+    fn test_config_validation_rejects_nonsense() {
+        assert!(config_error(RateLimitConfig::new(0, Duration::from_secs(1))).contains("max_requests"));
+        assert!(config_error(RateLimitConfig::new(10, Duration::ZERO)).contains("window"));
+        assert!(config_error(RateLimitConfig::new(MAX_LIMIT + 1, Duration::from_secs(1))).contains("max_requests"));
+        assert!(config_error(RateLimitConfig::new(10, Duration::MAX)).contains("too large"));
+        
+        let builder = || RateLimitConfig::builder(10, Duration::from_secs(1));
+        assert!(builder().burst(0).build().is_err());
+        assert!(builder().burst(5).initial_tokens(6).build().is_err());
+        assert!(builder().refill_interval(Duration::ZERO).build().is_err());
+        assert!(builder().max_keys(0, OverflowPolicy::Reject).build().is_err());
+        assert!(builder().sub_windows(0).build().is_err());
+        assert!(RateLimitConfig::builder(10, Duration::from_nanos(5)).sub_windows(6).build().is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_sweeper_evicts_idle_keys() {
+        use crate::algorithms::FixedWindow;
+        use crate::clock::MockClock;
+        
+        let clock = MockClock::new();
+        let config = RateLimitConfig::per_second(5);
+        let limiter = Arc::new(FixedWindow::with_clock(config, Arc::new(clock.clone())).unwrap());
+        
+        for i in 0..10 {
+            limiter.allow_request(&format!("user{}", i)).unwrap();
+        }
+        assert_eq!(limiter.tracked_keys(), 10);
+        
+        let sweeper = spawn_sweeper(&limiter, Duration::from_millis(5));
+        clock.advance(Duration::from_secs(1));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(limiter.tracked_keys(), 0);
+        
+        // The sweeper stops on its own once the limiter is gone
+        drop(limiter);
+        tokio::time::timeout(Duration::from_secs(1), sweeper).await.unwrap().unwrap();
+    }
 }
\ No newline at end of file
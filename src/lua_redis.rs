@@ -0,0 +1,529 @@
+//! An in-process Redis stand-in that runs the limiters' Lua scripts
+//!
+//! Tests plug it into the Redis limiters in place of a server, so the
+//! scripts run on every `cargo test` instead of only where Redis is
+//! reachable. It implements just the commands the scripts use, with Redis's
+//! types, WRONGTYPE errors, expiry and number formatting, on the same
+//! `Clock` as the limiter under test.
+
+use crate::clock::Clock;
+use mlua::{Lua, MultiValue, Value as LuaValue};
+use redis::{ConnectionLike, ErrorKind, RedisError, RedisResult, Script, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// The keyspace and `redis.call` of the stand-in
+const REDIS_LUA: &str = r#"
+    local db = {}
+    
+    local function now_ms()
+        return math.floor(now_us / 1000)
+    end
+    
+    local function fail(message)
+        error(message, 0)
+    end
+    
+    local function wrongtype()
+        fail('WRONGTYPE Operation against a key holding the wrong kind of value')
+    end
+    
+    -- The live entry at `key`, checked to be of `kind` if there is one
+    local function lookup(key, kind)
+        local entry = db[key]
+        if entry and entry.expires and now_ms() > entry.expires then
+            db[key] = nil
+            entry = nil
+        end
+        if entry and kind and entry.kind ~= kind then
+            wrongtype()
+        end
+        return entry
+    end
+    
+    local function create(key, kind)
+        local entry = lookup(key, kind)
+        if not entry then
+            entry = { kind = kind, value = kind == 'string' and '' or {} }
+            db[key] = entry
+        end
+        return entry
+    end
+    
+    local function integer(arg)
+        local n = tonumber(arg)
+        if not n or n ~= math.floor(n) or string.find(arg, '[%.eE]') then
+            fail('ERR value is not an integer or out of range')
+        end
+        return n
+    end
+    
+    -- Redis formats numbers with %.17g, both as arguments and as scores
+    local function format(n)
+        if n == math.huge then return 'inf' end
+        if n == -math.huge then return '-inf' end
+        return string.format('%.17g', n)
+    end
+    
+    local function score(arg)
+        local n = tonumber(arg)
+        if arg == '+inf' or arg == 'inf' then n = math.huge end
+        if arg == '-inf' then n = -math.huge end
+        if not n then fail('ERR value is not a valid float') end
+        return n
+    end
+    
+    -- `arg` as a ZCOUNT-style bound: a test that a score lies above (or below) it
+    local function bound(arg, above)
+        local exclusive = string.sub(arg, 1, 1) == '('
+        local limit = score(exclusive and string.sub(arg, 2) or arg)
+        if above then
+            return function(s) return exclusive and s > limit or not exclusive and s >= limit end
+        end
+        return function(s) return exclusive and s < limit or not exclusive and s <= limit end
+    end
+    
+    -- Members of a sorted set by score, then member
+    local function sorted(entry)
+        local items = {}
+        for member, s in pairs(entry.value) do
+            items[#items + 1] = { member, s }
+        end
+        table.sort(items, function(a, b)
+            if a[2] == b[2] then return a[1] < b[1] end
+            return a[2] < b[2]
+        end)
+        return items
+    end
+    
+    local function in_range(entry, min, max)
+        local above, below = bound(min, true), bound(max, false)
+        local items = {}
+        for _, item in ipairs(sorted(entry)) do
+            if above(item[2]) and below(item[2]) then
+                items[#items + 1] = item
+            end
+        end
+        return items
+    end
+    
+    local function reply(items, with_scores)
+        local out = {}
+        for _, item in ipairs(items) do
+            out[#out + 1] = item[1]
+            if with_scores then out[#out + 1] = format(item[2]) end
+        end
+        return out
+    end
+    
+    -- Redis drops sorted sets and hashes that become empty
+    local function drop_if_empty(key, entry)
+        if next(entry.value) == nil then db[key] = nil end
+    end
+    
+    local function expire_at(key, at)
+        local entry = lookup(key)
+        if not entry then return 0 end
+        if at <= now_ms() then
+            db[key] = nil
+        else
+            entry.expires = at
+        end
+        return 1
+    end
+    
+    local commands = {}
+    
+    function commands.TIME()
+        return { tostring(math.floor(now_us / 1000000)), tostring(now_us % 1000000) }
+    end
+    
+    function commands.DEL(...)
+        local deleted = 0
+        for _, key in ipairs({...}) do
+            if lookup(key) then
+                db[key] = nil
+                deleted = deleted + 1
+            end
+        end
+        return deleted
+    end
+    
+    -- The whole keyspace in one batch, for MATCH patterns using only `*` and `?`
+    function commands.SCAN(cursor, match, pattern)
+        if match and string.upper(match) ~= 'MATCH' then fail('ERR syntax error') end
+        local lua_pattern = '^' .. string.gsub(pattern or '*', '.', function(c)
+            if c == '*' then return '.*' end
+            if c == '?' then return '.' end
+            if string.find(c, '%w') then return c end
+            return '%' .. c
+        end) .. '$'
+        local keys = {}
+        for key in pairs(db) do
+            if lookup(key) and string.find(key, lua_pattern) then
+                keys[#keys + 1] = key
+            end
+        end
+        return { '0', keys }
+    end
+    
+    function commands.GET(key)
+        local entry = lookup(key, 'string')
+        return entry and entry.value or false
+    end
+    
+    function commands.SET(key, value, px, ms)
+        db[key] = { kind = 'string', value = value }
+        if px then
+            if string.upper(px) ~= 'PX' then fail('ERR syntax error') end
+            local ms = integer(ms)
+            if ms <= 0 then fail('ERR invalid expire time in \'set\' command') end
+            db[key].expires = now_ms() + ms
+        end
+        return { ok = 'OK' }
+    end
+    
+    local function incrby(key, by)
+        local entry = lookup(key, 'string')
+        local current = entry and integer(entry.value) or 0
+        local value = format(current + integer(by))
+        if entry then
+            entry.value = value
+        else
+            db[key] = { kind = 'string', value = value }
+        end
+        return tonumber(value)
+    end
+    
+    function commands.INCRBY(key, by)
+        return incrby(key, by)
+    end
+    
+    function commands.DECRBY(key, by)
+        return incrby(key, format(-integer(by)))
+    end
+    
+    function commands.PTTL(key)
+        local entry = lookup(key)
+        if not entry then return -2 end
+        if not entry.expires then return -1 end
+        return entry.expires - now_ms()
+    end
+    
+    function commands.PEXPIRE(key, ms)
+        return expire_at(key, now_ms() + integer(ms))
+    end
+    
+    function commands.PEXPIREAT(key, at)
+        return expire_at(key, integer(at))
+    end
+    
+    function commands.HMGET(key, ...)
+        local entry = lookup(key, 'hash')
+        local values = {}
+        for i, field in ipairs({...}) do
+            values[i] = entry and entry.value[field] or false
+        end
+        return values
+    end
+    
+    function commands.HMSET(key, ...)
+        local args = {...}
+        if #args == 0 or #args % 2 ~= 0 then
+            fail('ERR wrong number of arguments for \'hmset\' command')
+        end
+        local entry = create(key, 'hash')
+        for i = 1, #args, 2 do
+            entry.value[args[i]] = args[i + 1]
+        end
+        return { ok = 'OK' }
+    end
+    
+    function commands.ZADD(key, ...)
+        local args = {...}
+        if #args == 0 or #args % 2 ~= 0 then fail('ERR syntax error') end
+        local entry = create(key, 'zset')
+        local added = 0
+        for i = 1, #args, 2 do
+            if entry.value[args[i + 1]] == nil then added = added + 1 end
+            entry.value[args[i + 1]] = score(args[i])
+        end
+        return added
+    end
+    
+    function commands.ZCARD(key)
+        local entry = lookup(key, 'zset')
+        return entry and #sorted(entry) or 0
+    end
+    
+    function commands.ZCOUNT(key, min, max)
+        local entry = lookup(key, 'zset')
+        return entry and #in_range(entry, min, max) or 0
+    end
+    
+    function commands.ZRANGE(key, start, stop, with_scores)
+        local entry = lookup(key, 'zset')
+        if not entry then return {} end
+        local items = sorted(entry)
+        start, stop = integer(start), integer(stop)
+        if start < 0 then start = math.max(#items + start, 0) end
+        if stop < 0 then stop = #items + stop end
+        local range = {}
+        for i = start + 1, math.min(stop + 1, #items) do
+            range[#range + 1] = items[i]
+        end
+        return reply(range, with_scores and string.upper(with_scores) == 'WITHSCORES')
+    end
+    
+    function commands.ZRANGEBYSCORE(key, min, max, ...)
+        local entry = lookup(key, 'zset')
+        if not entry then return {} end
+        local items = in_range(entry, min, max)
+        local with_scores, offset, count = false, 0, -1
+        local options = {...}
+        local i = 1
+        while i <= #options do
+            local option = string.upper(options[i])
+            if option == 'WITHSCORES' then
+                with_scores = true
+                i = i + 1
+            elseif option == 'LIMIT' then
+                offset, count = integer(options[i + 1]), integer(options[i + 2])
+                i = i + 3
+            else
+                fail('ERR syntax error')
+            end
+        end
+        local range = {}
+        for j = offset + 1, #items do
+            if count >= 0 and #range >= count then break end
+            range[#range + 1] = items[j]
+        end
+        return reply(range, with_scores)
+    end
+    
+    function commands.ZREM(key, ...)
+        local entry = lookup(key, 'zset')
+        if not entry then return 0 end
+        local removed = 0
+        for _, member in ipairs({...}) do
+            if entry.value[member] ~= nil then
+                entry.value[member] = nil
+                removed = removed + 1
+            end
+        end
+        drop_if_empty(key, entry)
+        return removed
+    end
+    
+    function commands.ZREMRANGEBYSCORE(key, min, max)
+        local entry = lookup(key, 'zset')
+        if not entry then return 0 end
+        local items = in_range(entry, min, max)
+        for _, item in ipairs(items) do
+            entry.value[item[1]] = nil
+        end
+        drop_if_empty(key, entry)
+        return #items
+    end
+    
+    redis = {}
+    
+    function redis.replicate_commands()
+        return true
+    end
+    
+    function redis.call(name, ...)
+        local args = {}
+        for i, arg in ipairs({...}) do
+            if type(arg) == 'number' then
+                args[i] = format(arg)
+            elseif type(arg) == 'string' then
+                args[i] = arg
+            else
+                fail('ERR Lua redis lib command arguments must be strings or integers')
+            end
+        end
+        local command = commands[string.upper(name)]
+        if not command then
+            fail('ERR unknown command \'' .. name .. '\'')
+        end
+        return command(unpack(args))
+    end
+"#;
+
+/// Redis's keyspace in a Lua interpreter, running scripts the way Redis does
+pub(crate) struct LuaRedis {
+    lua: Mutex<Lua>,
+    scripts: Mutex<HashMap<String, String>>,
+    clock: Mutex<Arc<dyn Clock>>,
+}
+
+impl LuaRedis {
+    /// An empty keyspace whose server clock is `clock`
+    pub(crate) fn new(clock: Arc<dyn Clock>) -> Arc<Self> {
+        let lua = Lua::new();
+        lua.load(REDIS_LUA).exec().expect("the Redis stand-in loads");
+        Arc::new(Self {
+            lua: Mutex::new(lua),
+            scripts: Mutex::new(HashMap::new()),
+            clock: Mutex::new(clock),
+        })
+    }
+    
+    /// Read server time from `clock` from now on, as after its clock was
+    /// set back or a failover to a server whose clock differs
+    pub(crate) fn set_clock(&self, clock: Arc<dyn Clock>) {
+        *self.clock.lock().unwrap() = clock;
+    }
+    
+    /// A connection to this server
+    pub(crate) fn connection(&self) -> LuaConnection<'_> {
+        LuaConnection { redis: self }
+    }
+    
+    fn execute(&self, args: Vec<Vec<u8>>) -> RedisResult<Value> {
+        let lua = self.lua.lock().unwrap();
+        let now_us = self.clock.lock().unwrap().unix_now().as_micros() as f64;
+        lua.globals().set("now_us", now_us).map_err(lua_failed)?;
+        
+        let mut args = args.into_iter().map(|arg| String::from_utf8(arg).expect("commands are UTF-8"));
+        let name = args.next().unwrap_or_default().to_uppercase();
+        let args: Vec<String> = args.collect();
+        match name.as_str() {
+            "EVAL" => {
+                let script = args[0].clone();
+                self.scripts.lock().unwrap().insert(Script::new(&script).get_hash().to_string(), script.clone());
+                run_script(&lua, &script, &args[1..])
+            }
+            "EVALSHA" => {
+                let script = self.scripts.lock().unwrap().get(&args[0]).cloned();
+                match script {
+                    Some(script) => run_script(&lua, &script, &args[1..]),
+                    None => Err((ErrorKind::NoScriptError, "NOSCRIPT No matching script").into()),
+                }
+            }
+            "SCRIPT" if args.first().is_some_and(|sub| sub.eq_ignore_ascii_case("LOAD")) => {
+                let hash = Script::new(&args[1]).get_hash().to_string();
+                self.scripts.lock().unwrap().insert(hash.clone(), args[1].clone());
+                Ok(Value::Data(hash.into_bytes()))
+            }
+            _ => {
+                let call: mlua::Function = lua.globals()
+                    .get::<_, mlua::Table>("redis")
+                    .and_then(|redis| redis.get("call"))
+                    .map_err(lua_failed)?;
+                let mut call_args = vec![name];
+                call_args.extend(args);
+                call.call::<_, LuaValue>(MultiValue::from_vec(
+                    call_args.into_iter().map(|arg| LuaValue::String(lua.create_string(&arg).unwrap())).collect(),
+                ))
+                .map_err(lua_failed)
+                .and_then(to_redis)
+            }
+        }
+    }
+}
+
+/// Run `script` with `args` laid out as after EVAL's script argument
+fn run_script(lua: &Lua, script: &str, args: &[String]) -> RedisResult<Value> {
+    let num_keys: usize = args[0].parse().expect("EVAL takes a key count");
+    let (keys, argv) = args[1..].split_at(num_keys);
+    let globals = lua.globals();
+    globals.set("KEYS", keys.to_vec()).map_err(lua_failed)?;
+    globals.set("ARGV", argv.to_vec()).map_err(lua_failed)?;
+    
+    let value: LuaValue = lua.load(script).set_name("user_script").eval().map_err(lua_failed)?;
+    to_redis(value)
+}
+
+/// A Lua value as Redis replies with it
+fn to_redis(value: LuaValue) -> RedisResult<Value> {
+    Ok(match value {
+        LuaValue::Nil | LuaValue::Boolean(false) => Value::Nil,
+        LuaValue::Boolean(true) => Value::Int(1),
+        // Redis truncates numbers to integers
+        LuaValue::Integer(n) => Value::Int(n),
+        LuaValue::Number(n) => Value::Int(n as i64),
+        LuaValue::String(s) => Value::Data(s.as_bytes().to_vec()),
+        LuaValue::Table(table) => {
+            if let Ok(Some(err)) = table.raw_get::<_, Option<String>>("err") {
+                return Err(RedisError::from((ErrorKind::ResponseError, "script error", err)));
+            }
+            if let Ok(Some(ok)) = table.raw_get::<_, Option<String>>("ok") {
+                return Ok(if ok == "OK" { Value::Okay } else { Value::Status(ok) });
+            }
+            // Arrays end at the first nil
+            let mut items = Vec::new();
+            for i in 1.. {
+                match table.raw_get::<_, LuaValue>(i).map_err(lua_failed)? {
+                    LuaValue::Nil => break,
+                    item => items.push(to_redis(item)?),
+                }
+            }
+            Value::Bulk(items)
+        }
+        other => panic!("scripts can't reply with {}", other.type_name()),
+    })
+}
+
+/// Errors raised in scripts reach the client as error replies
+fn lua_failed(e: mlua::Error) -> RedisError {
+    RedisError::from((ErrorKind::ResponseError, "script error", e.to_string()))
+}
+
+/// Parse the RESP arrays of bulk strings that clients send commands as
+fn parse_commands(mut packed: &[u8]) -> Vec<Vec<Vec<u8>>> {
+    fn line<'a>(packed: &mut &'a [u8]) -> &'a str {
+        let end = packed.windows(2).position(|w| w == b"\r\n").expect("RESP lines end in CRLF");
+        let line = std::str::from_utf8(&packed[..end]).expect("RESP headers are ASCII");
+        *packed = &packed[end + 2..];
+        line
+    }
+    
+    let mut commands = Vec::new();
+    while !packed.is_empty() {
+        let count: usize = line(&mut packed)[1..].parse().expect("commands are arrays");
+        let mut args = Vec::with_capacity(count);
+        for _ in 0..count {
+            let len: usize = line(&mut packed)[1..].parse().expect("arguments are bulk strings");
+            args.push(packed[..len].to_vec());
+            packed = &packed[len + 2..];
+        }
+        commands.push(args);
+    }
+    commands
+}
+
+/// A connection to a `LuaRedis`
+pub(crate) struct LuaConnection<'a> {
+    redis: &'a LuaRedis,
+}
+
+impl ConnectionLike for LuaConnection<'_> {
+    fn req_packed_command(&mut self, cmd: &[u8]) -> RedisResult<Value> {
+        let mut commands = parse_commands(cmd);
+        assert_eq!(commands.len(), 1, "one command at a time");
+        self.redis.execute(commands.remove(0))
+    }
+    
+    fn req_packed_commands(&mut self, cmd: &[u8], offset: usize, count: usize) -> RedisResult<Vec<Value>> {
+        let replies = parse_commands(cmd)
+            .into_iter()
+            .map(|args| self.redis.execute(args))
+            .collect::<RedisResult<Vec<_>>>()?;
+        Ok(replies.into_iter().skip(offset).take(count).collect())
+    }
+    
+    fn get_db(&self) -> i64 {
+        0
+    }
+    
+    fn check_connection(&mut self) -> bool {
+        true
+    }
+    
+    fn is_open(&self) -> bool {
+        true
+    }
+}
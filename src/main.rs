@@ -1,5 +1,5 @@
-use distributed_rate_limiter::{RateLimiter, RateLimitConfig};
-use distributed_rate_limiter::algorithms::*;
+use distributed_rate_limiter::{AlgorithmType, RateLimiter, RateLimitConfig};
+use distributed_rate_limiter::factory::{build_limiter, Backend};
 use distributed_rate_limiter::redis_limiter::RedisRateLimiter;
 use distributed_rate_limiter::metrics::{self, record_request};
 use std::time::{Duration, Instant};
@@ -17,6 +17,36 @@ fn print_performance_info() {
     println!("💡 Run load tests: cargo test --release -- --nocapture");
 }
 
+/// Algorithms named on the command line, or all of them
+fn parse_algorithms() -> distributed_rate_limiter::Result<Vec<AlgorithmType>> {
+    let algorithms = std::env::args()
+        .skip(1)
+        .map(|arg| arg.parse())
+        .collect::<distributed_rate_limiter::Result<Vec<AlgorithmType>>>()?;
+    
+    if algorithms.is_empty() {
+        Ok(AlgorithmType::ALL.to_vec())
+    } else {
+        Ok(algorithms)
+    }
+}
+
+fn describe(algorithm: AlgorithmType) -> (&'static str, &'static str) {
+    match algorithm {
+        AlgorithmType::TokenBucket => ("TOKEN BUCKET", "Tokens refill over time"),
+        AlgorithmType::LeakyBucket => ("LEAKY BUCKET", "Queue that leaks at constant rate"),
+        AlgorithmType::FixedWindow => ("FIXED WINDOW", "Counter resets every window"),
+        AlgorithmType::SlidingWindow => ("SLIDING WINDOW", "Tracks exact timestamps"),
+        AlgorithmType::SlidingWindowCounter => ("SLIDING WINDOW COUNTER", "Weighted counts of the previous window"),
+        AlgorithmType::Gcra => ("GCRA", "One theoretical arrival time per key"),
+    }
+}
+
+/// Keycap emoji for a single digit (1️⃣, 2️⃣, ...)
+fn keycap(n: usize) -> String {
+    format!("{}\u{fe0f}\u{20e3}", n % 10)
+}
+
 #[tokio::main]
 async fn main() {
     // Initialize metrics
@@ -27,43 +57,31 @@ async fn main() {
     
     let config = RateLimitConfig::per_second(3);
     
-    println!("Testing all 4 rate limiting algorithms WITH METRICS");
-    println!("Config: 3 requests per second\n");
-    
-    // Test 1: Token Bucket
-    println!("1️⃣  TOKEN BUCKET");
-    println!("   (Tokens refill over time)");
-    println!("   ----------------------------");
-    test_algorithm_with_metrics("Token Bucket", TokenBucket::new(config.clone())).await;
-    
-    println!("\n");
-    
-    // Test 2: Leaky Bucket
-    println!("2️⃣  LEAKY BUCKET");
-    println!("   (Queue that leaks at constant rate)");
-    println!("   ----------------------------");
-    test_algorithm_with_metrics("Leaky Bucket", LeakyBucket::new(config.clone())).await;
-    
-    println!("\n");
-    
-    // Test 3: Fixed Window
-    println!("3️⃣  FIXED WINDOW");
-    println!("   (Counter resets every window)");
-    println!("   ----------------------------");
-    test_algorithm_with_metrics("Fixed Window", FixedWindow::new(config.clone())).await;
-    
-    println!("\n");
+    // Algorithms can be picked on the command line, e.g. `cargo run -- token_bucket`
+    let algorithms = match parse_algorithms() {
+        Ok(algorithms) => algorithms,
+        Err(e) => {
+            eprintln!("❌ {}", e);
+            std::process::exit(2);
+        }
+    };
     
-    // Test 4: Sliding Window
-    println!("4️⃣  SLIDING WINDOW");
-    println!("   (Tracks exact timestamps)");
-    println!("   ----------------------------");
-    test_algorithm_with_metrics("Sliding Window", SlidingWindow::new(config.clone())).await;
+    println!("Testing {} rate limiting algorithms WITH METRICS", algorithms.len());
+    println!("Config: 3 requests per second\n");
     
-    println!("\n");
+    for (i, algorithm) in algorithms.iter().enumerate() {
+        let (title, description) = describe(*algorithm);
+        println!("{}  {}", keycap(i + 1), title);
+        println!("   ({})", description);
+        println!("   ----------------------------");
+        let limiter = build_limiter(*algorithm, config.clone(), &Backend::Memory).unwrap();
+        test_algorithm_with_metrics(title, limiter).await;
+        
+        println!("\n");
+    }
     
-    // Test 5: Redis Distributed
-    println!("5️⃣  REDIS DISTRIBUTED");
+    // Redis Distributed
+    println!("{}  REDIS DISTRIBUTED", keycap(algorithms.len() + 1));
     println!("   (Token Bucket with Redis backend)");
     println!("   ----------------------------");
     test_redis().await;
@@ -84,10 +102,10 @@ async fn main() {
     println!("{}", metrics_output);
 }
 
-async fn test_algorithm_with_metrics<T: RateLimiter>(_name: &str, mut limiter: T) {
+async fn test_algorithm_with_metrics<T: RateLimiter>(_name: &str, limiter: T) {
     for i in 1..=5 {
         let start = Instant::now();
-        let allowed = limiter.allow_request("user_test").unwrap();
+        let allowed = limiter.allow_request("user_test").unwrap().allowed;
         record_request(allowed, start);
         
         println!("   Request {}: {}", i, if allowed { "✅ ALLOWED" } else { "❌ BLOCKED" });
@@ -98,7 +116,7 @@ async fn test_algorithm_with_metrics<T: RateLimiter>(_name: &str, mut limiter: T
     
     for i in 1..=2 {
         let start = Instant::now();
-        let allowed = limiter.allow_request("user_test").unwrap();
+        let allowed = limiter.allow_request("user_test").unwrap().allowed;
         record_request(allowed, start);
         
         println!("   Request {}: {}", i, if allowed { "✅ ALLOWED" } else { "❌ BLOCKED" });
@@ -110,13 +128,14 @@ async fn test_redis() {
     let config = RateLimitConfig::per_second(3);
     
     match RedisRateLimiter::new(redis_url, config) {
-        Ok(mut limiter) => {
+        Ok(limiter) => {
             println!("   ✅ Connected to Redis\n");
             limiter.reset("user_redis");
             
             for i in 1..=5 {
                 match limiter.allow_request("user_redis") {
-                    Ok(allowed) => {
+                    Ok(decision) => {
+                        let allowed = decision.allowed;
                         let start = Instant::now();
                         record_request(allowed, start);
                         println!("   Request {}: {}", i, if allowed { "✅ ALLOWED" } else { "❌ BLOCKED" });
@@ -1,4 +1,6 @@
-use prometheus::{Counter, Histogram, IntCounter, Registry, Encoder, TextEncoder};
+use crate::circuit_breaker::CircuitState;
+use crate::redis_limiter::DegradedMode;
+use prometheus::{Histogram, IntCounter, IntCounterVec, IntGaugeVec, Opts, Registry, Encoder, TextEncoder};
 use lazy_static::lazy_static;
 use std::time::Instant;
 
@@ -31,6 +33,51 @@ lazy_static! {
             "Request processing latency in seconds"
         ).buckets(vec![0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1])
     ).expect("metric can be created");
+    
+    /// Circuit breaker state changes, by breaker and the state entered
+    pub static ref CIRCUIT_BREAKER_TRANSITIONS: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "rate_limiter_circuit_breaker_transitions_total",
+            "Number of circuit breaker state transitions"
+        ),
+        &["breaker", "state"]
+    ).expect("metric can be created");
+    
+    /// State of each circuit breaker: 0 closed, 1 open, 2 half-open
+    pub static ref CIRCUIT_BREAKER_STATE: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "rate_limiter_circuit_breaker_state",
+            "Circuit breaker state (0 closed, 1 open, 2 half-open)"
+        ),
+        &["breaker"]
+    ).expect("metric can be created");
+    
+    /// Calls failed fast by an open circuit breaker, by breaker
+    pub static ref CIRCUIT_BREAKER_REJECTED: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "rate_limiter_circuit_breaker_rejected_total",
+            "Number of calls rejected by an open circuit breaker"
+        ),
+        &["breaker"]
+    ).expect("metric can be created");
+    
+    /// 1 for the mode each Redis limiter is answering in: `normal` or its degraded mode
+    pub static ref DEGRADED_MODE: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "rate_limiter_degraded_mode",
+            "Mode in effect while Redis is unavailable (1 = active)"
+        ),
+        &["limiter", "mode"]
+    ).expect("metric can be created");
+    
+    /// Checks answered without Redis, by limiter and degraded mode
+    pub static ref DEGRADED_REQUESTS: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "rate_limiter_degraded_requests_total",
+            "Number of checks answered in a degraded mode"
+        ),
+        &["limiter", "mode"]
+    ).expect("metric can be created");
 }
 
 /// Initialize metrics registry
@@ -43,6 +90,16 @@ pub fn init_metrics() {
         .expect("collector can be registered");
     REGISTRY.register(Box::new(REQUEST_LATENCY.clone()))
         .expect("collector can be registered");
+    REGISTRY.register(Box::new(CIRCUIT_BREAKER_TRANSITIONS.clone()))
+        .expect("collector can be registered");
+    REGISTRY.register(Box::new(CIRCUIT_BREAKER_STATE.clone()))
+        .expect("collector can be registered");
+    REGISTRY.register(Box::new(CIRCUIT_BREAKER_REJECTED.clone()))
+        .expect("collector can be registered");
+    REGISTRY.register(Box::new(DEGRADED_MODE.clone()))
+        .expect("collector can be registered");
+    REGISTRY.register(Box::new(DEGRADED_REQUESTS.clone()))
+        .expect("collector can be registered");
 }
 
 /// Record a rate limit check
@@ -58,6 +115,19 @@ pub fn record_request(allowed: bool, start: Instant) {
     REQUEST_LATENCY.observe(duration);
 }
 
+/// Record the circuit breaker named `breaker` entering `state`
+pub fn record_circuit_transition(breaker: &str, state: CircuitState) {
+    CIRCUIT_BREAKER_TRANSITIONS.with_label_values(&[breaker, &state.to_string()]).inc();
+    CIRCUIT_BREAKER_STATE.with_label_values(&[breaker]).set(state.as_gauge());
+}
+
+/// Record the Redis limiter named `limiter` answering in its degraded `mode`
+/// if `active`, or normally
+pub fn record_degraded_mode(limiter: &str, mode: DegradedMode, active: bool) {
+    DEGRADED_MODE.with_label_values(&[limiter, "normal"]).set(!active as i64);
+    DEGRADED_MODE.with_label_values(&[limiter, &mode.to_string()]).set(active as i64);
+}
+
 /// Get metrics in Prometheus format
 pub fn get_metrics() -> String {
     let encoder = TextEncoder::new();
@@ -0,0 +1,155 @@
+use crate::{RateLimitConfig, RateLimitError, Result};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+#[derive(Debug, Default)]
+struct Table {
+    plans: HashMap<String, RateLimitConfig>,
+    key_plans: HashMap<String, String>,
+    keys: HashMap<String, RateLimitConfig>,
+}
+
+/// Per-key and per-plan limits that replace a limiter's default config
+///
+/// A key's config is looked up on every check: an explicit key override
+/// wins, then the config of the plan the key is assigned to, then the
+/// limiter's own config. Overrides can be changed at any time; existing
+/// per-key state is kept and simply measured against the new limits from
+/// the next check on (e.g. a bucket keeps its tokens, up to the new
+/// capacity). `max_keys` and `overflow_policy` always come from the
+/// limiter's own config.
+///
+/// Share one table between limiters with an `Arc`, and attach it with the
+/// limiter's `with_overrides`.
+///
+/// ```
+/// use distributed_rate_limiter::{LimitOverrides, RateLimitConfig, RateLimiter};
+/// use distributed_rate_limiter::algorithms::TokenBucket;
+/// use std::sync::Arc;
+///
+/// let overrides = Arc::new(LimitOverrides::new());
+/// overrides.set_plan("pro", RateLimitConfig::per_second(100)).unwrap();
+/// overrides.assign_plan("acme", "pro").unwrap();
+///
+/// let limiter = TokenBucket::new(RateLimitConfig::per_second(10))
+///     .unwrap()
+///     .with_overrides(Arc::clone(&overrides));
+///
+/// assert_eq!(limiter.allow_request("acme").unwrap().limit, 100);
+/// assert_eq!(limiter.allow_request("someone_else").unwrap().limit, 10);
+/// ```
+#[derive(Debug, Default)]
+pub struct LimitOverrides {
+    table: RwLock<Table>,
+}
+
+impl LimitOverrides {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Define (or redefine) a plan, e.g. "free", "pro" or "enterprise"
+    pub fn set_plan(&self, plan: impl Into<String>, config: RateLimitConfig) -> Result<()> {
+        config.validate()?;
+        self.write().plans.insert(plan.into(), config);
+        Ok(())
+    }
+    
+    /// Remove a plan; keys assigned to it fall back to the default config
+    pub fn remove_plan(&self, plan: &str) -> Option<RateLimitConfig> {
+        self.write().plans.remove(plan)
+    }
+    
+    /// Put `key` on a plan that has been defined with `set_plan`
+    pub fn assign_plan(&self, key: impl Into<String>, plan: &str) -> Result<()> {
+        let mut table = self.write();
+        if !table.plans.contains_key(plan) {
+            return Err(RateLimitError::ConfigError(format!("unknown plan '{}'", plan)));
+        }
+        table.key_plans.insert(key.into(), plan.to_string());
+        Ok(())
+    }
+    
+    /// Give `key` its own config, taking precedence over its plan
+    pub fn set_key(&self, key: impl Into<String>, config: RateLimitConfig) -> Result<()> {
+        config.validate()?;
+        self.write().keys.insert(key.into(), config);
+        Ok(())
+    }
+    
+    /// Drop the key's own config and plan, returning it to the default config
+    pub fn remove_key(&self, key: &str) {
+        let mut table = self.write();
+        table.keys.remove(key);
+        table.key_plans.remove(key);
+    }
+    
+    /// The plan `key` is assigned to, if any
+    pub fn plan_of(&self, key: &str) -> Option<String> {
+        self.read().key_plans.get(key).cloned()
+    }
+    
+    /// The config overriding the default for `key`, if any
+    pub fn resolve(&self, key: &str) -> Option<RateLimitConfig> {
+        let table = self.read();
+        table.keys.get(key)
+            .or_else(|| table.key_plans.get(key).and_then(|plan| table.plans.get(plan)))
+            .cloned()
+    }
+    
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, Table> {
+        // The table is always left consistent, so keep serving after poisoning
+        self.table.read().unwrap_or_else(|e| e.into_inner())
+    }
+    
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, Table> {
+        self.table.write().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The config that applies to `key`: its override, or else `default`
+pub(crate) fn config_for<'a>(
+    overrides: Option<&LimitOverrides>,
+    key: &str,
+    default: &'a RateLimitConfig,
+) -> Cow<'a, RateLimitConfig> {
+    match overrides.and_then(|overrides| overrides.resolve(key)) {
+        Some(config) => Cow::Owned(config),
+        None => Cow::Borrowed(default),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    
+    #[test]
+    fn test_overrides_resolution_order() {
+        let overrides = LimitOverrides::new();
+        overrides.set_plan("pro", RateLimitConfig::per_second(100)).unwrap();
+        assert!(overrides.assign_plan("acme", "missing").is_err());
+        assert!(overrides.set_key("bad", RateLimitConfig::new(0, Duration::from_secs(1))).is_err());
+        
+        assert_eq!(overrides.resolve("acme"), None);
+        overrides.assign_plan("acme", "pro").unwrap();
+        assert_eq!(overrides.resolve("acme").unwrap().max_requests, 100);
+        assert_eq!(overrides.plan_of("acme").as_deref(), Some("pro"));
+        
+        // Key overrides win over plans
+        overrides.set_key("acme", RateLimitConfig::per_second(500)).unwrap();
+        assert_eq!(overrides.resolve("acme").unwrap().max_requests, 500);
+        
+        overrides.remove_key("acme");
+        assert_eq!(overrides.resolve("acme"), None);
+        
+        // Keys on a removed plan fall back to the default
+        overrides.assign_plan("acme", "pro").unwrap();
+        overrides.remove_plan("pro");
+        assert_eq!(overrides.resolve("acme"), None);
+        
+        let default = RateLimitConfig::per_second(10);
+        assert!(matches!(config_for(None, "acme", &default), Cow::Borrowed(_)));
+    }
+}
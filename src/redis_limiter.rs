@@ -1,114 +1,2922 @@
-use crate::{RateLimiter, RateLimitConfig, Result};
-use redis::{Client, Commands, Script};
-use std::time::SystemTime;
+use crate::acquire;
+use crate::algorithms::calendar_quota::{self, QuotaPeriod};
+use crate::algorithms::{MultiWindowDecision, TokenBucket};
+use crate::circuit_breaker::CircuitBreaker;
+use crate::clock::{Clock, SystemClock};
+#[cfg(test)]
+use crate::lua_redis::LuaRedis;
+use crate::metrics;
+use crate::overrides::{self, LimitOverrides};
+use crate::snapshot::KeyState;
+use crate::storage::Storage;
+use crate::{check_cost, AlgorithmType, RateLimitError, RateLimiter, RateLimitConfig, RateLimitDecision, Reservation, Result};
+use redis::aio::MultiplexedConnection;
+use redis::{AsyncCommands, Client, ConnectionLike, Script, ScriptInvocation};
+use std::borrow::Cow;
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Prepended to scripts that take `now` as an argument: an empty `now`
+/// means "use the Redis server's clock"
+const SERVER_TIME_PRELUDE: &str = r#"
+    -- TIME differs between master and replicas, so replicate the script's
+    -- effects rather than the script itself (the default from Redis 5 on)
+    local function server_time_us()
+        redis.replicate_commands()
+        local time = redis.call('TIME')
+        return tonumber(time[1]) * 1000000 + tonumber(time[2])
+    end
+"#;
+
+/// Lua script for atomic token bucket check
+/// This ensures race conditions don't occur in distributed systems
+const TOKEN_BUCKET_SCRIPT: &str = r#"
+    local key = KEYS[1]
+    local max_tokens = tonumber(ARGV[1])
+    local refill_rate = tonumber(ARGV[2])
+    local now = tonumber(ARGV[3]) or server_time_us() / 1000000 -- seconds since the epoch
+    local cost = tonumber(ARGV[5])
+    local initial_tokens = tonumber(ARGV[6])
+    local interval = tonumber(ARGV[7]) -- refill step in seconds, 0 = continuous
+    local mode = ARGV[8] -- 'consume', 'status' (read only) or 'refund'
+    
+    -- Get current state
+    local state = redis.call('HMGET', key, 'tokens', 'last_refill')
+    if mode == 'refund' and not state[1] then
+        -- Expired state means the bucket is full already
+        return {1, max_tokens, 0, 0}
+    end
+    local tokens = tonumber(state[1]) or initial_tokens
+    local last_refill = tonumber(state[2]) or now
+    
+    -- Calculate refill; a last refill in the future (from a clock that is
+    -- ahead, or before a jump back) refills nothing until it has passed
+    local elapsed = math.max(now - last_refill, 0)
+    if interval > 0 then
+        -- Only whole intervals count; keep partial progress
+        local steps = math.floor(elapsed / interval)
+        tokens = math.min(tokens + steps * interval * refill_rate, max_tokens)
+        last_refill = last_refill + steps * interval
+    else
+        tokens = math.min(tokens + elapsed * refill_rate, max_tokens)
+        last_refill = math.max(last_refill, now)
+    end
+    
+    -- Time (ms) until `amount` more tokens have been refilled
+    local function time_to_refill(amount)
+        if amount <= 0 then
+            return 0
+        end
+        if interval > 0 then
+            local steps = math.max(math.ceil(amount / (interval * refill_rate) - 1e-9), 1)
+            return math.ceil((steps * interval - (now - last_refill)) * 1000)
+        end
+        return math.ceil(amount / refill_rate * 1000)
+    end
+    
+    -- Status query: would a single request fit right now?
+    if mode == 'status' then
+        local reset_after_ms = time_to_refill(max_tokens - tokens)
+        if tokens >= 1 then
+            return {1, math.floor(tokens), reset_after_ms, 0}
+        end
+        return {0, math.floor(tokens), reset_after_ms, time_to_refill(1 - tokens)}
+    end
+    
+    -- Check if request allowed (refunds put tokens back instead)
+    local allowed = 0
+    local retry_after_ms = 0
+    if mode == 'refund' then
+        tokens = math.min(tokens + cost, max_tokens)
+        allowed = 1
+    elseif tokens >= cost then
+        tokens = tokens - cost
+        allowed = 1
+    else
+        retry_after_ms = time_to_refill(cost - tokens)
+    end
+    
+    -- Save state with expiration (long enough to refill completely)
+    redis.call('HMSET', key, 'tokens', tokens, 'last_refill', last_refill)
+    redis.call('PEXPIRE', key, ARGV[4])
+    
+    -- Lua numbers are truncated to integers in replies, so return
+    -- remaining tokens and durations (ms) as whole numbers
+    local reset_after_ms = time_to_refill(max_tokens - tokens)
+    return {allowed, math.floor(tokens), reset_after_ms, retry_after_ms}
+    "#;
+
+/// Lua script for atomic GCRA check, storing one timestamp per key
+const GCRA_SCRIPT: &str = r#"
+    local key = KEYS[1]
+    local emission_interval = tonumber(ARGV[1]) -- microseconds per unit
+    local capacity = tonumber(ARGV[2])
+    local now = tonumber(ARGV[3]) or server_time_us() -- microseconds since the epoch
+    local cost = tonumber(ARGV[4])
+    local initial_offset = tonumber(ARGV[5]) -- how far ahead a new key's TAT starts
+    local mode = ARGV[6] -- 'consume', 'status' (read only) or 'refund'
+    local tolerance = emission_interval * capacity
+    
+    -- The whole state is a single theoretical arrival time (TAT)
+    local tat = tonumber(redis.call('GET', key))
+    if not tat then
+        if mode == 'refund' then
+            -- Expired state means the key is fully replenished already
+            return {1, capacity, 0, 0}
+        end
+        tat = now + initial_offset
+    end
+    
+    -- A TAT in the past means the key is fully replenished
+    local ahead = math.max(tat - now, 0)
+    local function ms(us)
+        return math.ceil(us / 1000)
+    end
+    local function remaining(ahead)
+        return math.floor((tolerance - ahead) / emission_interval)
+    end
+    
+    if mode == 'status' then
+        if ahead + emission_interval <= tolerance then
+            return {1, remaining(ahead), ms(ahead), 0}
+        end
+        return {0, remaining(ahead), ms(ahead), ms(ahead + emission_interval - tolerance)}
+    end
+    
+    if mode == 'refund' then
+        ahead = math.max(ahead - emission_interval * cost, 0)
+    else
+        local new_ahead = ahead + emission_interval * cost
+        if new_ahead > tolerance then
+            return {0, remaining(ahead), ms(ahead), ms(new_ahead - tolerance)}
+        end
+        ahead = new_ahead
+    end
+    
+    -- Keep the key until the TAT has passed; format explicitly because
+    -- tostring() would round microsecond timestamps to 14 digits
+    redis.call('SET', key, string.format('%.0f', now + ahead), 'PX', math.max(ms(ahead), 1))
+    return {1, remaining(ahead), ms(ahead), 0}
+"#;
+
+/// Lua script for atomic leaky bucket check; same arguments as the token bucket
+const LEAKY_BUCKET_SCRIPT: &str = r#"
+    local key = KEYS[1]
+    local capacity = tonumber(ARGV[1])
+    local leak_rate = tonumber(ARGV[2])
+    local now = tonumber(ARGV[3]) or server_time_us() / 1000000 -- seconds since the epoch
+    local cost = tonumber(ARGV[5])
+    local initial_tokens = tonumber(ARGV[6]) -- headroom of a new key
+    local interval = tonumber(ARGV[7]) -- leak step in seconds, 0 = continuous
+    local mode = ARGV[8] -- 'consume', 'status' (read only) or 'refund'
+    
+    local state = redis.call('HMGET', key, 'water', 'last_update')
+    if mode == 'refund' and not state[1] then
+        -- Expired state means the bucket has drained already
+        return {1, capacity, 0, 0}
+    end
+    local water = tonumber(state[1]) or (capacity - initial_tokens)
+    local last_update = tonumber(state[2]) or now
+    
+    -- Leak what has drained since the last update, which may be in the
+    -- future if written by a clock that is ahead
+    local elapsed = math.max(now - last_update, 0)
+    if interval > 0 then
+        -- Only whole intervals count; keep partial progress
+        local steps = math.floor(elapsed / interval)
+        water = math.max(water - steps * interval * leak_rate, 0)
+        last_update = last_update + steps * interval
+    else
+        water = math.max(water - elapsed * leak_rate, 0)
+        last_update = math.max(last_update, now)
+    end
+    
+    -- Time (ms) until `amount` more water has leaked out
+    local function time_to_leak(amount)
+        if amount <= 0 then
+            return 0
+        end
+        if interval > 0 then
+            local steps = math.max(math.ceil(amount / (interval * leak_rate) - 1e-9), 1)
+            return math.ceil((steps * interval - (now - last_update)) * 1000)
+        end
+        return math.ceil(amount / leak_rate * 1000)
+    end
+    
+    if mode == 'status' then
+        cost = 1
+    end
+    local allowed = 0
+    local retry_after_ms = 0
+    if mode == 'refund' then
+        water = math.max(water - cost, 0)
+        allowed = 1
+    elseif water + cost <= capacity then
+        if mode == 'consume' then
+            water = water + cost
+        end
+        allowed = 1
+    else
+        retry_after_ms = time_to_leak(water + cost - capacity)
+    end
+    
+    if mode ~= 'status' then
+        redis.call('HMSET', key, 'water', water, 'last_update', last_update)
+        redis.call('PEXPIRE', key, ARGV[4])
+    end
+    return {allowed, math.floor(capacity - water), time_to_leak(water), retry_after_ms}
+"#;
+
+/// Lua script for atomic fixed window check: a counter that expires with its window
+const FIXED_WINDOW_SCRIPT: &str = r#"
+    local key = KEYS[1]
+    local limit = tonumber(ARGV[1])
+    local window = tonumber(ARGV[2]) -- milliseconds
+    local cost = tonumber(ARGV[3])
+    local mode = ARGV[4] -- 'consume', 'status' (read only) or 'refund'
+    -- Refunds only go to the window that had this much time left (ms)
+    -- by now, or to the current one if empty
+    local expected_ttl = tonumber(ARGV[5])
+    
+    -- The window starts with its first request and ends when the key
+    -- expires; Redis keeps a key through its last millisecond, which by
+    -- then belongs to the next window
+    local count = tonumber(redis.call('GET', key)) or 0
+    local ttl = redis.call('PTTL', key)
+    local exists = ttl > 0
+    if not exists then
+        count = 0
+        ttl = window
+    end
+    
+    if mode == 'refund' then
+        -- A later window ends at least a whole window later
+        if exists and count > 0 and (not expected_ttl or math.abs(ttl - expected_ttl) * 2 < window) then
+            redis.call('DECRBY', key, math.min(cost, count))
+        end
+        return {1, 0, 0, 0}
+    end
+    
+    if mode == 'consume' and count + cost <= limit then
+        if exists then
+            redis.call('INCRBY', key, cost)
+        else
+            redis.call('SET', key, cost, 'PX', window)
+        end
+        count = count + cost
+        cost = 0
+    end
+    
+    -- Nothing frees up until the window starts over
+    if count + cost <= limit then
+        return {1, limit - count, ttl, 0}
+    end
+    return {0, limit - count, ttl, ttl}
+"#;
+
+/// Lua script for atomic sliding window log check: a sorted set with one
+/// member per unit, scored by when it was logged
+const SLIDING_WINDOW_SCRIPT: &str = r#"
+    local key = KEYS[1]
+    local limit = tonumber(ARGV[1])
+    local window = tonumber(ARGV[2]) -- milliseconds
+    local now = tonumber(ARGV[3]) or math.floor(server_time_us() / 1000) -- milliseconds since the epoch
+    local cost = tonumber(ARGV[4])
+    local mode = ARGV[5] -- 'consume', 'status' (read only) or 'refund'
+    local id = ARGV[6] -- unique per request, names its units ('' on refunds: the newest)
+    
+    -- Units logged before the window started have slid out
+    local oldest_allowed = now - window
+    local count = redis.call('ZCOUNT', key, oldest_allowed, '+inf')
+    
+    -- unpack() is limited by the Lua stack, so send members in batches
+    local function each_batch(n, member, f)
+        local batch = {}
+        for i = 1, n do
+            member(batch, i)
+            if #batch >= 1000 or i == n then
+                f(batch)
+                batch = {}
+            end
+        end
+    end
+    
+    if mode == 'refund' then
+        if id == '' then
+            local newest = redis.call('ZRANGE', key, -cost, -1)
+            if #newest > 0 then
+                redis.call('ZREM', key, unpack(newest))
+            end
+        else
+            each_batch(cost, function(batch, i)
+                batch[#batch + 1] = id .. ':' .. i
+            end, function(batch)
+                redis.call('ZREM', key, unpack(batch))
+            end)
+        end
+        return {1, 0, 0, 0}
+    end
+    
+    if mode == 'consume' and count + cost <= limit then
+        redis.call('ZREMRANGEBYSCORE', key, '-inf', '(' .. oldest_allowed)
+        each_batch(cost, function(batch, i)
+            batch[#batch + 1] = now
+            batch[#batch + 1] = id .. ':' .. i
+        end, function(batch)
+            redis.call('ZADD', key, unpack(batch))
+        end)
+        -- A unit still counts when exactly `window` old
+        redis.call('PEXPIRE', key, window + 1)
+        count = count + cost
+        cost = 0
+    end
+    
+    -- Time (ms) until the unit at `index` (0 = oldest) slides out
+    local function slides_out(index)
+        local entry = redis.call('ZRANGEBYSCORE', key, oldest_allowed, '+inf', 'WITHSCORES', 'LIMIT', index, 1)
+        return math.max(tonumber(entry[2]) + window - now, 0)
+    end
+    
+    local reset_after_ms = 0
+    if count > 0 then
+        reset_after_ms = slides_out(count - 1)
+    end
+    if count + cost <= limit then
+        return {1, limit - count, reset_after_ms, 0}
+    end
+    return {0, limit - count, reset_after_ms, slides_out(count + cost - limit - 1)}
+"#;
+
+/// Lua script for atomic sliding window counter check, with the
+/// sub-window counts (oldest first) kept as one comma-separated field
+const SLIDING_WINDOW_COUNTER_SCRIPT: &str = r#"
+    local key = KEYS[1]
+    local limit = tonumber(ARGV[1])
+    local granularity = tonumber(ARGV[2]) -- milliseconds per sub-window
+    local n = tonumber(ARGV[3]) + 1 -- the oldest only partly overlaps the window
+    local now = tonumber(ARGV[4]) or math.floor(server_time_us() / 1000) -- milliseconds since the epoch
+    local cost = tonumber(ARGV[5])
+    local mode = ARGV[6] -- 'consume', 'status' (read only) or 'refund'
+    local counted_at = now - (tonumber(ARGV[7]) or 0) -- refunds: how long ago (ms) the units were counted
+    local epsilon = 1e-9
+    
+    local state = redis.call('HMGET', key, 'start', 'counts')
+    if mode == 'refund' and not state[1] then
+        -- Expired state means every count has slid out already
+        return {1, limit, 0, 0}
+    end
+    local start = tonumber(state[1]) or now
+    local counts = {}
+    for count in string.gmatch(state[2] or '', '%d+') do
+        counts[#counts + 1] = tonumber(count)
+    end
+    
+    if #counts ~= n then
+        -- The sub-window count changed: move every count into the current
+        -- sub-window so none of them expires early
+        local total = 0
+        for i = 1, #counts do
+            total = total + counts[i]
+        end
+        counts = {}
+        for i = 1, n do
+            counts[i] = 0
+        end
+        counts[n] = total
+    end
+    
+    -- Move on to the sub-window containing now
+    local steps = math.floor((now - start) / granularity)
+    if steps > 0 then
+        local rolled = {}
+        for i = 1, n do
+            rolled[i] = counts[i + steps] or 0
+        end
+        counts = rolled
+        start = start + steps * granularity
+    end
+    local progress = math.min(math.max((now - start) / granularity, 0), 1)
+    
+    -- Requests in the sliding window ending now, assuming the oldest
+    -- sub-window's requests were spread evenly across it
+    local function estimate()
+        local total = counts[1] * (1 - progress)
+        for i = 2, n do
+            total = total + counts[i]
+        end
+        return total
+    end
+    
+    -- Time (ms) until the estimate has dropped to `threshold`
+    local function time_until(threshold)
+        local offset = progress * granularity
+        local waited = 0
+        local newer = 0
+        for i = 2, n do
+            newer = newer + counts[i]
+        end
+        for i = 1, n do
+            local oldest = counts[i]
+            if newer <= threshold + epsilon then
+                local budget = threshold - newer
+                local needed = offset
+                if oldest > budget + epsilon then
+                    needed = granularity * (1 - budget / oldest)
+                end
+                return math.ceil(waited + math.max(needed - offset, 0))
+            end
+            waited = waited + granularity - offset
+            offset = 0
+            newer = newer - (counts[i + 1] or 0)
+        end
+        return math.ceil(waited)
+    end
+    
+    local changed = false
+    if mode == 'refund' then
+        -- Take the units out of the sub-window they were counted in,
+        -- unless it has slid out of the ring already
+        local index = n - math.max(math.ceil((start - counted_at) / granularity), 0)
+        if index >= 1 then
+            counts[index] = math.max(counts[index] - cost, 0)
+            changed = true
+        end
+        cost = 0
+    elseif mode == 'consume' and estimate() + cost <= limit + epsilon then
+        counts[n] = counts[n] + cost
+        changed = true
+        cost = 0
+    end
+    
+    if changed then
+        local formatted = {}
+        for i = 1, n do
+            formatted[i] = string.format('%.0f', counts[i])
+        end
+        redis.call('HMSET', key, 'start', start, 'counts', table.concat(formatted, ','))
+        redis.call('PEXPIRE', key, n * granularity)
+    end
+    
+    local remaining = math.max(math.floor(limit - estimate() + epsilon), 0)
+    if mode == 'status' then
+        cost = 1
+    end
+    if estimate() + cost <= limit + epsilon then
+        return {1, remaining, time_until(0), 0}
+    end
+    return {0, remaining, time_until(0), time_until(limit - cost)}
+"#;
+
+/// Lua script for atomic multi-window check, all windows in one hash
+const MULTI_WINDOW_SCRIPT: &str = r#"
+    local key = KEYS[1]
+    local now = tonumber(ARGV[1]) or math.floor(server_time_us() / 1000) -- ms since the epoch
+    local cost = tonumber(ARGV[2])
+    local mode = ARGV[3] -- 'consume', 'status' (read only) or 'refund'
+    local since = tonumber(ARGV[4]) -- refunds only go to windows started by then
+    local n = (#ARGV - 4) / 2 -- followed by one (limit, window ms) pair per window
+    
+    -- Each window keeps its count and start time in the same hash
+    local fields = {}
+    for i = 1, n do
+        fields[#fields + 1] = 'count' .. i
+        fields[#fields + 1] = 'start' .. i
+    end
+    local state = redis.call('HMGET', key, unpack(fields))
+    if mode == 'refund' and not state[2] then
+        -- Expired state means every window has started over already
+        return {now}
+    end
+    
+    local limits, windows, counts, starts = {}, {}, {}, {}
+    local fits = true
+    for i = 1, n do
+        limits[i] = tonumber(ARGV[3 + 2 * i])
+        windows[i] = tonumber(ARGV[4 + 2 * i])
+        counts[i] = tonumber(state[2 * i - 1]) or 0
+        starts[i] = tonumber(state[2 * i]) or now
+        
+        -- Start a new window once the current one has expired
+        if now - starts[i] >= windows[i] then
+            counts[i] = 0
+            starts[i] = now
+        end
+        if counts[i] + cost > limits[i] then
+            fits = false
+        end
+    end
+    
+    -- Consume from every window or from none
+    local changed = false
+    if mode == 'refund' then
+        for i = 1, n do
+            if starts[i] <= since then
+                counts[i] = math.max(counts[i] - cost, 0)
+            end
+        end
+        changed = true
+    elseif mode == 'consume' and fits then
+        for i = 1, n do
+            counts[i] = counts[i] + cost
+        end
+        changed = true
+        cost = 0
+    end
+    
+    if changed then
+        local values, ttl = {}, 1
+        for i = 1, n do
+            values[#values + 1] = 'count' .. i
+            values[#values + 1] = counts[i]
+            values[#values + 1] = 'start' .. i
+            values[#values + 1] = starts[i]
+            ttl = math.max(ttl, starts[i] + windows[i] - now)
+        end
+        redis.call('HMSET', key, unpack(values))
+        redis.call('PEXPIRE', key, ttl)
+    end
+    
+    -- The time used, then per window: whether `cost` more fits, remaining, reset (ms)
+    local result = {now}
+    for i = 1, n do
+        result[#result + 1] = (counts[i] + cost <= limits[i]) and 1 or 0
+        result[#result + 1] = limits[i] - counts[i]
+        result[#result + 1] = starts[i] + windows[i] - now
+    end
+    return result
+"#;
+
+/// Lua script for calendar quotas; each key holds one period's count
+///
+/// KEYS are the candidate periods, each with its start and end in ARGV.
+/// Checks use the one containing `now`: with the server's clock the caller
+/// can't tell which that is, so it sends the periods around its own.
+const CALENDAR_QUOTA_SCRIPT: &str = r#"
+    local limit = tonumber(ARGV[1])
+    local cost = tonumber(ARGV[2])
+    local mode = ARGV[3] -- 'consume', 'status' (read only) or 'refund'
+    local now = tonumber(ARGV[4]) or math.floor(server_time_us() / 1000) -- ms since the epoch
+    
+    -- Refunds go back to the period the units were taken from, the only one sent
+    local period = mode == 'refund' and 1 or nil
+    for i = 1, #KEYS do
+        if not period and tonumber(ARGV[3 + 2 * i]) <= now and now < tonumber(ARGV[4 + 2 * i]) then
+            period = i
+        end
+    end
+    if not period then
+        return {err = 'ERR the time is outside every quota period sent'}
+    end
+    local key = KEYS[period]
+    local expire_at = tonumber(ARGV[4 + 2 * period]) -- end of the period, ms since the epoch
+    
+    local stored = redis.call('GET', key)
+    if mode == 'refund' and not stored then
+        -- The period is over, so there is nothing to give back to
+        return {1, limit, 0, period}
+    end
+    local count = tonumber(stored) or 0
+    
+    local changed = false
+    if mode == 'refund' then
+        count = math.max(count - cost, 0)
+        changed = true
+    elseif mode == 'consume' and count + cost <= limit then
+        count = count + cost
+        changed = true
+        cost = 0
+    end
+    
+    if changed then
+        redis.call('SET', key, count)
+        redis.call('PEXPIREAT', key, expire_at)
+    end
+    
+    return {(count + cost <= limit) and 1 or 0, limit - count, expire_at - now, period}
+"#;
+
+/// Lua script writing a `RedisStorage` key only if it still holds what was read
+const STORAGE_UPDATE_SCRIPT: &str = r#"
+    local key = KEYS[1]
+    local existed = ARGV[1] == '1'
+    local expected = ARGV[2]
+    local state = ARGV[3] -- new JSON, or '' to delete the key
+    local ttl = ARGV[4] -- ms, or '' to keep the key until deleted
+    
+    local current = redis.call('GET', key)
+    if existed ~= (current ~= false) or (current and current ~= expected) then
+        return 0
+    end
+    
+    if state == '' then
+        redis.call('DEL', key)
+    elseif ttl == '' then
+        redis.call('SET', key, state)
+    else
+        redis.call('SET', key, state, 'PX', ttl)
+    end
+    return 1
+"#;
+
+/// How often `RedisStorage::update` reads a key again after another instance
+/// wrote it first, before giving up
+const MAX_STORAGE_UPDATE_ATTEMPTS: usize = 10;
+
+/// Microseconds between GCRA units (0 if the rate is too high to represent)
+fn gcra_emission_interval_us(config: &RateLimitConfig) -> u64 {
+    (config.window.as_micros() / config.max_requests as u128) as u64
+}
+
+/// What a run of the Lua script does with the key's state
+#[derive(Debug, Clone, Copy)]
+enum ScriptMode {
+    Consume,
+    Status,
+    Refund,
+}
+
+impl ScriptMode {
+    fn as_arg(self) -> &'static str {
+        match self {
+            ScriptMode::Consume => "consume",
+            ScriptMode::Status => "status",
+            ScriptMode::Refund => "refund",
+        }
+    }
+}
+
+/// Which units a refund gives back; the default is the most recently consumed
+#[derive(Debug, Clone, Copy, Default)]
+struct Consumed {
+    /// This process's clock at the consume call, in ms since the epoch
+    at_ms: Option<u64>,
+    /// Time left in the window, as the consume call reported it
+    reset_after: Option<Duration>,
+    /// Id the units were logged under
+    id: Option<u64>,
+    /// Answered in the degraded mode (from the local fallback, if any) rather than by Redis
+    degraded: bool,
+}
+
+/// How long fail-closed denials ask clients to wait, unless the circuit
+/// breaker knows when it will probe Redis again
+const FAIL_CLOSED_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// What a `RedisRateLimiter` answers while Redis is unavailable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DegradedMode {
+    /// Allow every request
+    FailOpen,
+    /// Deny every request
+    FailClosed,
+    /// Limit in memory to this node's share of the limit, assuming `nodes`
+    /// instances split the traffic evenly
+    LocalFallback { nodes: u32 },
+}
+
+impl fmt::Display for DegradedMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DegradedMode::FailOpen => "fail_open",
+            DegradedMode::FailClosed => "fail_closed",
+            DegradedMode::LocalFallback { .. } => "local_fallback",
+        })
+    }
+}
+
+/// The degraded mode of one limiter, and whether it is in effect
+struct Degraded {
+    /// Labels this limiter's degraded-mode metrics
+    name: String,
+    mode: DegradedMode,
+    fallback: Option<TokenBucket>,
+    active: AtomicBool,
+}
+
+/// `config` cut down to a `1/nodes` share, rounding up
+fn node_share(config: &RateLimitConfig, nodes: u32) -> RateLimitConfig {
+    let share = |n: u64| n.div_ceil(nodes as u64);
+    RateLimitConfig {
+        max_requests: share(config.max_requests),
+        burst: config.burst.map(share),
+        initial_tokens: config.initial_tokens.map(share),
+        ..config.clone()
+    }
+}
+
+/// `(allowed, remaining, reset_after_ms, retry_after_ms)` as returned by the scripts
+type ScriptReply = (i32, u64, u64, u64);
+
+fn script_decision(limit: u64, (allowed, remaining, reset_after_ms, retry_after_ms): ScriptReply) -> RateLimitDecision {
+    let reset_after = Duration::from_millis(reset_after_ms);
+    
+    if allowed == 1 {
+        RateLimitDecision::allow(limit, remaining, reset_after)
+    } else {
+        RateLimitDecision::deny(limit, remaining, reset_after, Duration::from_millis(retry_after_ms))
+    }
+}
 
 /// Redis-backed distributed rate limiter using Lua scripts for atomicity
+///
+/// Each `AlgorithmType` has its own script, behaving like the in-memory
+/// algorithm of the same name; the sliding window log keeps one sorted set
+/// member per unit in the window. Window-based algorithms work in whole
+/// milliseconds.
+///
+/// Calls block on a pooled connection; in async servers use
+/// `AsyncRedisRateLimiter`, which shares one multiplexed connection instead.
+/// A pooled connection broken by a Redis restart fails the call it was used
+/// for and is then dropped.
+///
+/// By default the scripts run on each client's clock, so instances whose
+/// clocks drift apart disagree about elapsed time; `with_server_time` makes
+/// them all use the Redis server's clock.
+///
+/// Give it a `CircuitBreaker` with `with_circuit_breaker` to fail fast
+/// instead of waiting on Redis while it is down, and a `DegradedMode` with
+/// `with_degraded_mode` to choose what checks answer meanwhile.
 pub struct RedisRateLimiter {
-    client: Client,
+    connector: Connector,
     config: RateLimitConfig,
+    algorithm: AlgorithmType,
+    lua_script: Script,
+    overrides: Option<Arc<LimitOverrides>>,
+    clock: Arc<dyn Clock>,
+    server_time: bool,
+    degraded: Option<Degraded>,
+    instance_id: u64, // with `next_request`, names logged units uniquely across instances
+    next_request: AtomicU64,
+}
+
+/// Why `algorithm` can't run `config` in Redis, if it can't
+fn unsupported(algorithm: AlgorithmType, config: &RateLimitConfig) -> Option<&'static str> {
+    match algorithm {
+        AlgorithmType::Gcra if config.refill_interval.is_some() => Some("GCRA does not support refill_interval"),
+        AlgorithmType::Gcra if gcra_emission_interval_us(config) == 0 => {
+            Some("GCRA in Redis supports at most one unit per microsecond")
+        }
+        AlgorithmType::FixedWindow | AlgorithmType::SlidingWindow if config.window < Duration::from_millis(1) => {
+            Some("windows in Redis must be at least 1ms long")
+        }
+        AlgorithmType::SlidingWindowCounter if sub_window_ms(config) == 0 => {
+            Some("sub-windows in Redis must be at least 1ms long")
+        }
+        _ => None,
+    }
+}
+
+/// Length of each sliding window counter sub-window, in whole milliseconds
+fn sub_window_ms(config: &RateLimitConfig) -> u64 {
+    (config.window / config.sub_windows.unwrap_or(1)).as_millis() as u64
+}
+
+impl RedisRateLimiter {
+    /// Create a new Redis rate limiter
+    pub fn new(redis_url: &str, config: RateLimitConfig) -> anyhow::Result<Self> {
+        Self::with_clock(redis_url, config, Arc::new(SystemClock))
+    }
+    
+    /// Create a Redis rate limiter that timestamps requests with `clock`
+    pub fn with_clock(redis_url: &str, config: RateLimitConfig, clock: Arc<dyn Clock>) -> anyhow::Result<Self> {
+        Self::with_algorithm(redis_url, config, AlgorithmType::TokenBucket, clock)
+    }
+    
+    /// Create a Redis rate limiter running `algorithm`
+    pub fn with_algorithm(
+        redis_url: &str,
+        config: RateLimitConfig,
+        algorithm: AlgorithmType,
+        clock: Arc<dyn Clock>,
+    ) -> anyhow::Result<Self> {
+        config.validate()?;
+        if let Some(reason) = unsupported(algorithm, &config) {
+            anyhow::bail!(reason);
+        }
+        let connector = Connector::new(Client::open(redis_url)?);
+        
+        let script = match algorithm {
+            AlgorithmType::TokenBucket => TOKEN_BUCKET_SCRIPT,
+            AlgorithmType::LeakyBucket => LEAKY_BUCKET_SCRIPT,
+            AlgorithmType::FixedWindow => FIXED_WINDOW_SCRIPT,
+            AlgorithmType::SlidingWindow => SLIDING_WINDOW_SCRIPT,
+            AlgorithmType::SlidingWindowCounter => SLIDING_WINDOW_COUNTER_SCRIPT,
+            AlgorithmType::Gcra => GCRA_SCRIPT,
+        };
+        let lua_script = Script::new(&format!("{}{}", SERVER_TIME_PRELUDE, script));
+        
+        Ok(Self {
+            connector,
+            config,
+            algorithm,
+            lua_script,
+            overrides: None,
+            clock,
+            server_time: false,
+            degraded: None,
+            instance_id: RandomState::new().build_hasher().finish(),
+            next_request: AtomicU64::new(0),
+        })
+    }
+    
+    /// Look up per-key limits in `overrides` on every check
+    ///
+    /// Checks for keys whose override the algorithm can't run fail with
+    /// `RateLimitError::ConfigError`.
+    pub fn with_overrides(mut self, overrides: Arc<LimitOverrides>) -> Self {
+        self.overrides = Some(overrides);
+        self
+    }
+    
+    /// Timestamp requests with the Redis server's clock (`TIME`) instead of `clock`
+    ///
+    /// One clock then governs every instance, whatever their drift. `clock`
+    /// still times how long reservations are held before a refund. Needs
+    /// Redis 3.2 or later for script effects replication.
+    pub fn with_server_time(mut self) -> Self {
+        self.server_time = true;
+        self
+    }
+    
+    /// Send every Redis call through `circuit_breaker`, bounded by its `call_timeout`
+    ///
+    /// The timeout covers the whole call, connecting and every round trip
+    /// included. While the circuit is open, calls fail with
+    /// `RateLimitError::CircuitOpen` without touching Redis.
+    pub fn with_circuit_breaker(mut self, circuit_breaker: Arc<CircuitBreaker>) -> Self {
+        self.connector.circuit_breaker = Some(circuit_breaker);
+        self
+    }
+    
+    /// Run scripts on `redis` instead of a server
+    #[cfg(test)]
+    fn with_lua_redis(mut self, redis: Arc<LuaRedis>) -> Self {
+        self.connector.lua_redis = Some(redis);
+        self
+    }
+    
+    /// Answer checks in `mode` while Redis is unavailable, instead of with an error
+    ///
+    /// Every check still tries Redis first (unless the circuit breaker is
+    /// open), so the limiter switches back as soon as Redis recovers. The
+    /// `rate_limiter_degraded_mode` gauge shows which mode is in effect,
+    /// labelled with `name`. A local fallback enforces the default config's
+    /// share, not overrides.
+    pub fn with_degraded_mode(mut self, name: impl Into<String>, mode: DegradedMode) -> Result<Self> {
+        let fallback = match mode {
+            DegradedMode::LocalFallback { nodes: 0 } => {
+                return Err(RateLimitError::ConfigError("local fallback needs at least one node".into()));
+            }
+            DegradedMode::LocalFallback { nodes } => {
+                Some(TokenBucket::with_clock(node_share(&self.config, nodes), self.clock.clone())?)
+            }
+            _ => None,
+        };
+        
+        let name = name.into();
+        metrics::record_degraded_mode(&name, mode, false);
+        self.degraded = Some(Degraded { name, mode, fallback, active: AtomicBool::new(false) });
+        Ok(self)
+    }
+    
+    /// The degraded mode checks are answered in while Redis is unavailable,
+    /// or `None` while Redis answers them (or there is no degraded mode)
+    pub fn degraded_mode(&self) -> Option<DegradedMode> {
+        self.degraded
+            .as_ref()
+            .filter(|degraded| degraded.active.load(Ordering::Relaxed))
+            .map(|degraded| degraded.mode)
+    }
+    
+    /// Whether checks are being answered in the degraded mode
+    pub fn is_degraded(&self) -> bool {
+        self.degraded_mode().is_some()
+    }
+    
+    /// Clear `key` in Redis (and in the local fallback), reporting whether Redis did
+    pub fn try_reset(&self, key: &str) -> Result<()> {
+        if let Some(fallback) = self.fallback() {
+            fallback.reset(key);
+        }
+        self.connector.run(|conn| redis::cmd("DEL").arg(self.redis_key(key)).query(conn).map_err(command_failed))
+    }
+    
+    fn fallback(&self) -> Option<&TokenBucket> {
+        self.degraded.as_ref().and_then(|degraded| degraded.fallback.as_ref())
+    }
+    
+    /// Note whether Redis answered, updating the gauge on a change of mode
+    fn set_degraded(&self, active: bool) {
+        if let Some(degraded) = &self.degraded {
+            if degraded.active.swap(active, Ordering::Relaxed) != active {
+                metrics::record_degraded_mode(&degraded.name, degraded.mode, active);
+            }
+        }
+    }
+    
+    /// Answer a check Redis failed with `error` according to the degraded mode
+    ///
+    /// Only an unavailable Redis (`Backend` or `CircuitOpen`) is answered
+    /// for; errors Redis replied with, such as a key of the wrong type, say
+    /// nothing about whether it is up and are returned as they are.
+    fn degrade(&self, key: &str, cost: u64, mode: ScriptMode, error: RateLimitError) -> Result<RateLimitDecision> {
+        let Some(degraded) = &self.degraded else {
+            return Err(error);
+        };
+        if !matches!(error, RateLimitError::Backend(_) | RateLimitError::CircuitOpen(_)) {
+            return Err(error);
+        }
+        // Units taken in Redis can only be given back there
+        if let ScriptMode::Refund = mode {
+            return Err(error);
+        }
+        
+        self.set_degraded(true);
+        metrics::DEGRADED_REQUESTS.with_label_values(&[&degraded.name, &degraded.mode.to_string()]).inc();
+        let limit = self.limit_for(key)?;
+        match (&degraded.fallback, mode) {
+            (Some(fallback), ScriptMode::Consume) => match fallback.allow_n(key, cost) {
+                // The share is smaller than what Redis would take
+                Err(RateLimitError::CostExceedsCapacity { capacity, .. }) => {
+                    Ok(RateLimitDecision::deny(capacity, 0, Duration::ZERO, FAIL_CLOSED_RETRY_AFTER))
+                }
+                result => result,
+            },
+            (Some(fallback), _) => fallback.status(key),
+            (None, _) if degraded.mode == DegradedMode::FailOpen => {
+                Ok(RateLimitDecision::allow(limit, limit, Duration::ZERO))
+            }
+            (None, _) => {
+                let retry_after = match error {
+                    RateLimitError::CircuitOpen(retry_after) => retry_after,
+                    _ => FAIL_CLOSED_RETRY_AFTER,
+                };
+                Ok(RateLimitDecision::deny(limit, 0, retry_after, retry_after))
+            }
+        }
+    }
+    
+    /// `now` for the script, or empty to have it read the server's clock
+    fn time_arg(&self, now: impl ToString) -> String {
+        if self.server_time {
+            String::new()
+        } else {
+            now.to_string()
+        }
+    }
+    
+    /// The config for `key`, if the algorithm can run it
+    fn config_for(&self, key: &str) -> Result<Cow<'_, RateLimitConfig>> {
+        let config = overrides::config_for(self.overrides.as_deref(), key, &self.config);
+        if let Cow::Owned(config) = &config {
+            if let Some(reason) = unsupported(self.algorithm, config) {
+                return Err(crate::RateLimitError::ConfigError(reason.to_string()));
+            }
+        }
+        Ok(config)
+    }
+    
+    /// Largest cost `config` admits: buckets allow bursts, windows don't
+    fn limit(&self, config: &RateLimitConfig) -> u64 {
+        match self.algorithm {
+            AlgorithmType::TokenBucket | AlgorithmType::LeakyBucket | AlgorithmType::Gcra => config.capacity(),
+            _ => config.max_requests,
+        }
+    }
+    
+    fn limit_for(&self, key: &str) -> Result<u64> {
+        self.config_for(key).map(|config| self.limit(&config))
+    }
+    
+    /// Key holding the state for `key`, distinct per algorithm since the layouts differ
+    fn redis_key(&self, key: &str) -> String {
+        match self.algorithm {
+            AlgorithmType::TokenBucket => format!("rate_limit:{}", key),
+            other => format!("rate_limit_{}:{}", other, key),
+        }
+    }
+    
+    /// Token and leaky bucket script arguments (ARGV[1..=7])
+    fn bucket_args(&self, invocation: &mut ScriptInvocation<'_>, config: &RateLimitConfig, cost: u64, now: Duration) {
+        let max_tokens = config.capacity() as f64;
+        let refill_rate = config.refill_rate();
+        let now = now.as_secs_f64();
+        let interval = config.refill_interval.map_or(0.0, |i| i.as_secs_f64());
+        
+        // Keep state (in ms) at least until a drained bucket would be full again,
+        // so sub-second windows don't round down to an immediate expiry
+        let time_to_full_ms = ((max_tokens / refill_rate + interval) * 1000.0).ceil() as u64;
+        let ttl_ms = (config.window.as_millis() as u64 * 2).max(time_to_full_ms).max(1);
+        
+        invocation
+            .arg(max_tokens)
+            .arg(refill_rate)
+            .arg(self.time_arg(now))
+            .arg(ttl_ms)
+            .arg(cost)
+            .arg(config.initial_tokens())
+            .arg(interval);
+    }
+    
+    /// GCRA script arguments (ARGV[1..=5]), all integers in microseconds
+    fn gcra_args(&self, invocation: &mut ScriptInvocation<'_>, config: &RateLimitConfig, cost: u64, now: Duration) {
+        let emission_interval = gcra_emission_interval_us(config);
+        let capacity = config.capacity();
+        let initial_offset = emission_interval * (capacity - config.initial_tokens());
+        
+        invocation
+            .arg(emission_interval)
+            .arg(capacity)
+            .arg(self.time_arg(now.as_micros() as u64))
+            .arg(cost)
+            .arg(initial_offset);
+    }
+    
+    /// The script call for `cost` units in the given mode, the limit it
+    /// checks against, and what refunding it would take
+    ///
+    /// Refunds give back the units described by `consumed`.
+    fn prepare(&self, key: &str, cost: u64, mode: ScriptMode, consumed: Consumed) -> Result<(ScriptInvocation<'_>, u64, Consumed)> {
+        let config = self.config_for(key)?;
+        let limit = self.limit(&config);
+        let now = self.clock.unix_now();
+        let now_ms = now.as_millis() as u64;
+        let window_ms = config.window.as_millis() as u64;
+        
+        let mut invocation = self.lua_script.prepare_invoke();
+        invocation.key(self.redis_key(key));
+        match self.algorithm {
+            AlgorithmType::TokenBucket | AlgorithmType::LeakyBucket => self.bucket_args(&mut invocation, &config, cost, now),
+            AlgorithmType::Gcra => self.gcra_args(&mut invocation, &config, cost, now),
+            AlgorithmType::FixedWindow => {
+                invocation.arg(limit).arg(window_ms).arg(cost);
+            }
+            AlgorithmType::SlidingWindow => {
+                invocation.arg(limit).arg(window_ms).arg(self.time_arg(now_ms)).arg(cost);
+            }
+            AlgorithmType::SlidingWindowCounter => {
+                let sub_windows = config.sub_windows.unwrap_or(1);
+                invocation
+                    .arg(limit)
+                    .arg(sub_window_ms(&config))
+                    .arg(sub_windows)
+                    .arg(self.time_arg(now_ms))
+                    .arg(cost);
+            }
+        }
+        invocation.arg(mode.as_arg());
+        
+        // Where the units go, or which ones a refund gives back; empty
+        // arguments stand for the most recent
+        let id = match mode {
+            ScriptMode::Consume => Some(self.next_request.fetch_add(1, Ordering::Relaxed)),
+            ScriptMode::Status => None,
+            ScriptMode::Refund => consumed.id,
+        };
+        match self.algorithm {
+            AlgorithmType::FixedWindow => {
+                // Time the window the units were taken from has left by now
+                let expected_ttl = consumed.at_ms.zip(consumed.reset_after)
+                    .map(|(at_ms, reset_after)| reset_after.as_millis() as i64 - now_ms.saturating_sub(at_ms) as i64);
+                invocation.arg(expected_ttl.map_or(String::new(), |ttl| ttl.to_string()));
+            }
+            AlgorithmType::SlidingWindow => {
+                invocation.arg(id.map_or(String::new(), |id| format!("{:x}.{:x}", self.instance_id, id)));
+            }
+            AlgorithmType::SlidingWindowCounter => {
+                // How long ago, so it holds on either clock
+                let counted_ago = consumed.at_ms.map(|at_ms| now_ms.saturating_sub(at_ms));
+                invocation.arg(counted_ago.map_or(String::new(), |ago| ago.to_string()));
+            }
+            _ => {}
+        }
+        
+        let sent = Consumed { at_ms: Some(now_ms), reset_after: None, id, degraded: false };
+        Ok((invocation, limit, sent))
+    }
+    
+    /// Run the algorithm's script for `cost` units in the given mode,
+    /// also returning what refunding it would take
+    fn run_script(&self, key: &str, cost: u64, mode: ScriptMode, consumed: Consumed) -> Result<(RateLimitDecision, Consumed)> {
+        if let (ScriptMode::Refund, true) = (mode, consumed.degraded) {
+            return self.refund_degraded(key, cost, consumed);
+        }
+        let (invocation, limit, sent) = self.prepare(key, cost, mode, consumed)?;
+        
+        let reply = self.connector.run(|conn| {
+            invocation
+                .invoke(conn)
+                .map_err(|e| redis_failed("Lua script failed", e))
+        });
+        self.answer(key, cost, mode, (limit, sent), reply)
+    }
+    
+    /// Give back units a degraded check took: to the local fallback if it
+    /// has one, and never to Redis, which didn't see them even if it is back
+    fn refund_degraded(&self, key: &str, cost: u64, consumed: Consumed) -> Result<(RateLimitDecision, Consumed)> {
+        match self.fallback() {
+            Some(fallback) => {
+                fallback.refund(key, cost)?;
+                Ok((fallback.status(key)?, consumed))
+            }
+            None => {
+                let limit = self.limit_for(key)?;
+                Ok((RateLimitDecision::allow(limit, limit, Duration::ZERO), consumed))
+            }
+        }
+    }
+    
+    /// The decision for a script call `prepare`d as `(limit, sent)`, from
+    /// Redis's reply or else the degraded mode
+    fn answer(
+        &self,
+        key: &str,
+        cost: u64,
+        mode: ScriptMode,
+        (limit, sent): (u64, Consumed),
+        reply: Result<ScriptReply>,
+    ) -> Result<(RateLimitDecision, Consumed)> {
+        match reply {
+            Ok(reply) => {
+                self.set_degraded(false);
+                let decision = script_decision(limit, reply);
+                Ok((decision, Consumed { reset_after: Some(decision.reset_after), ..sent }))
+            }
+            Err(e) => {
+                let decision = self.degrade(key, cost, mode, e)?;
+                Ok((decision, Consumed { degraded: true, ..Consumed::default() }))
+            }
+        }
+    }
+    
+    /// Check, answering in the degraded mode if Redis fails or the circuit
+    /// breaker is open: fail-open unless `with_degraded_mode` set another
+    ///
+    /// Invalid costs and overrides are still reported as errors.
+    pub fn check_with_fallback(&self, key: &str) -> Result<RateLimitDecision> {
+        let limit = self.limit_for(key)?;
+        match self.allow_request(key) {
+            Err(RateLimitError::Backend(_) | RateLimitError::CircuitOpen(_)) if self.degraded.is_none() => {
+                Ok(RateLimitDecision::allow(limit, limit, Duration::ZERO))
+            }
+            result => result,
+        }
+    }
+}
+
+impl RateLimiter for RedisRateLimiter {
+    fn allow_n(&self, key: &str, cost: u64) -> Result<RateLimitDecision> {
+        check_cost(cost, self.limit_for(key)?)?;
+        self.run_script(key, cost, ScriptMode::Consume, Consumed::default()).map(|(decision, _)| decision)
+    }
+    
+    fn reserve(&self, key: &str, cost: u64) -> Result<Reservation<'_>> {
+        check_cost(cost, self.limit_for(key)?)?;
+        let (decision, consumed) = self.run_script(key, cost, ScriptMode::Consume, Consumed::default())?;
+        let key = key.to_string();
+        Ok(Reservation::new(decision, move || {
+            self.run_script(&key, cost, ScriptMode::Refund, consumed).map(|_| ())
+        }))
+    }
+    
+    fn status(&self, key: &str) -> Result<RateLimitDecision> {
+        self.run_script(key, 1, ScriptMode::Status, Consumed::default()).map(|(decision, _)| decision)
+    }
+    
+    /// Like `try_reset`, but a Redis failure goes unreported
+    fn reset(&self, key: &str) {
+        let _ = self.try_reset(key);
+    }
+}
+
+/// Async counterpart of `RedisRateLimiter`, sharing one connection across all checks
+///
+/// Runs the same scripts on the same keys, so both can enforce the same
+/// limits. Checks from any number of tasks are pipelined over a single
+/// multiplexed connection without blocking the executor. The script is
+/// loaded when the connection opens and then run with EVALSHA, loading it
+/// again if Redis answers NOSCRIPT (e.g. after a restart or SCRIPT FLUSH).
+/// A connection that fails is dropped and reopened by the next check.
+///
+/// ```no_run
+/// use distributed_rate_limiter::redis_limiter::AsyncRedisRateLimiter;
+/// use distributed_rate_limiter::RateLimitConfig;
+///
+/// # async fn run() -> anyhow::Result<()> {
+/// let limiter = AsyncRedisRateLimiter::new("redis://127.0.0.1/", RateLimitConfig::per_second(100))?;
+/// if !limiter.allow_request("user1").await?.allowed {
+///     // reject
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct AsyncRedisRateLimiter {
+    limiter: RedisRateLimiter,
+    connection: tokio::sync::Mutex<Option<MultiplexedConnection>>,
+}
+
+impl AsyncRedisRateLimiter {
+    pub fn new(redis_url: &str, config: RateLimitConfig) -> anyhow::Result<Self> {
+        Self::with_algorithm(redis_url, config, AlgorithmType::TokenBucket, Arc::new(SystemClock))
+    }
+    
+    /// Create an async Redis rate limiter running `algorithm`
+    pub fn with_algorithm(
+        redis_url: &str,
+        config: RateLimitConfig,
+        algorithm: AlgorithmType,
+        clock: Arc<dyn Clock>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            limiter: RedisRateLimiter::with_algorithm(redis_url, config, algorithm, clock)?,
+            connection: tokio::sync::Mutex::new(None),
+        })
+    }
+    
+    /// Look up per-key limits in `overrides` on every check
+    pub fn with_overrides(mut self, overrides: Arc<LimitOverrides>) -> Self {
+        self.limiter = self.limiter.with_overrides(overrides);
+        self
+    }
+    
+    /// Timestamp requests with the Redis server's clock; see `RedisRateLimiter::with_server_time`
+    pub fn with_server_time(mut self) -> Self {
+        self.limiter = self.limiter.with_server_time();
+        self
+    }
+    
+    /// Send every Redis call through `circuit_breaker`; see `RedisRateLimiter::with_circuit_breaker`
+    pub fn with_circuit_breaker(mut self, circuit_breaker: Arc<CircuitBreaker>) -> Self {
+        self.limiter = self.limiter.with_circuit_breaker(circuit_breaker);
+        self
+    }
+    
+    /// Answer checks in `mode` while Redis is unavailable; see `RedisRateLimiter::with_degraded_mode`
+    ///
+    /// `refund` only gives units back to Redis; to give back units whatever
+    /// answered the check, take them with `reserve`.
+    pub fn with_degraded_mode(mut self, name: impl Into<String>, mode: DegradedMode) -> Result<Self> {
+        self.limiter = self.limiter.with_degraded_mode(name, mode)?;
+        Ok(self)
+    }
+    
+    /// See `RedisRateLimiter::degraded_mode`
+    pub fn degraded_mode(&self) -> Option<DegradedMode> {
+        self.limiter.degraded_mode()
+    }
+    
+    /// See `RedisRateLimiter::is_degraded`
+    pub fn is_degraded(&self) -> bool {
+        self.limiter.is_degraded()
+    }
+    
+    /// The shared connection, opening it (and loading the script) if needed
+    async fn connection(&self) -> Result<MultiplexedConnection> {
+        // Held while connecting, so concurrent checks wait for one connection
+        let mut connection = self.connection.lock().await;
+        if let Some(conn) = connection.as_ref() {
+            return Ok(conn.clone());
+        }
+        
+        let mut conn = self.limiter.connector.client.get_multiplexed_tokio_connection().await.map_err(connection_failed)?;
+        self.limiter
+            .lua_script
+            .prepare_invoke()
+            .load_async(&mut conn)
+            .await
+            .map_err(|e| redis_failed("Loading the Lua script failed", e))?;
+        *connection = Some(conn.clone());
+        Ok(conn)
+    }
+    
+    /// Run `call` through the circuit breaker, if there is one, and within its `call_timeout`
+    async fn guarded<T>(&self, call: impl Future<Output = Result<T>>) -> Result<T> {
+        let Some(breaker) = &self.limiter.connector.circuit_breaker else {
+            return call.await;
+        };
+        
+        let permit = breaker.acquire()?;
+        let timeout = breaker.config().call_timeout;
+        let result = match tokio::time::timeout(timeout, call).await {
+            Ok(result) => result,
+            Err(_) => {
+                // The connection may be stuck; open a new one next time
+                *self.connection.lock().await = None;
+                Err(RateLimitError::Backend(format!("Redis call timed out after {:?}", timeout)))
+            }
+        };
+        breaker.record(permit, !matches!(result, Err(RateLimitError::Backend(_))));
+        result
+    }
+    
+    async fn run_script(&self, key: &str, cost: u64, mode: ScriptMode, consumed: Consumed) -> Result<(RateLimitDecision, Consumed)> {
+        if let (ScriptMode::Refund, true) = (mode, consumed.degraded) {
+            return self.limiter.refund_degraded(key, cost, consumed);
+        }
+        let (invocation, limit, sent) = self.limiter.prepare(key, cost, mode, consumed)?;
+        
+        let reply = self.guarded(async {
+            let mut conn = self.connection().await?;
+            match invocation.invoke_async::<_, ScriptReply>(&mut conn).await {
+                Ok(reply) => Ok(reply),
+                Err(e) => {
+                    if is_unavailable(&e) {
+                        // Reconnect on the next check
+                        *self.connection.lock().await = None;
+                    }
+                    Err(redis_failed("Lua script failed", e))
+                }
+            }
+        })
+        .await;
+        self.limiter.answer(key, cost, mode, (limit, sent), reply)
+    }
+    
+    /// Check and consume `cost` units for `key`
+    pub async fn allow_n(&self, key: &str, cost: u64) -> Result<RateLimitDecision> {
+        check_cost(cost, self.limiter.limit_for(key)?)?;
+        self.run_script(key, cost, ScriptMode::Consume, Consumed::default()).await.map(|(decision, _)| decision)
+    }
+    
+    pub async fn allow_request(&self, key: &str) -> Result<RateLimitDecision> {
+        self.allow_n(key, 1).await
+    }
+    
+    /// Wait until `cost` units can be spent for `key`, then spend them; see
+    /// `RateLimiterExt::acquire`
+    pub async fn acquire(&self, key: &str, cost: u64) -> Result<RateLimitDecision> {
+        acquire::wait_until_allowed(|| self.allow_n(key, cost), None).await
+    }
+    
+    /// Like `acquire`, but give up with `RateLimitError::Timeout` after `timeout`
+    pub async fn acquire_timeout(&self, key: &str, cost: u64, timeout: Duration) -> Result<RateLimitDecision> {
+        acquire::wait_until_allowed(|| self.allow_n(key, cost), Some(timeout)).await
+    }
+    
+    /// Check and consume `cost` units for `key`, to be committed or cancelled
+    /// like `RateLimiter::reserve`
+    pub async fn reserve(&self, key: &str, cost: u64) -> Result<AsyncReservation<'_>> {
+        check_cost(cost, self.limiter.limit_for(key)?)?;
+        let (decision, consumed) = self.run_script(key, cost, ScriptMode::Consume, Consumed::default()).await?;
+        Ok(AsyncReservation {
+            limiter: self,
+            key: key.to_string(),
+            cost,
+            decision,
+            consumed: decision.allowed.then_some(consumed),
+        })
+    }
+    
+    /// Give back `cost` of the most recently consumed units in Redis, e.g.
+    /// when the request they were spent on failed
+    ///
+    /// Units a degraded check answered for were never taken in Redis, so
+    /// refunding them here would inflate its count; use `reserve` for checks
+    /// that may need to be given back.
+    pub async fn refund(&self, key: &str, cost: u64) -> Result<()> {
+        self.run_script(key, cost, ScriptMode::Refund, Consumed::default()).await.map(|_| ())
+    }
+    
+    /// Current state for `key`, without consuming
+    pub async fn status(&self, key: &str) -> Result<RateLimitDecision> {
+        self.run_script(key, 1, ScriptMode::Status, Consumed::default()).await.map(|(decision, _)| decision)
+    }
+    
+    pub async fn reset(&self, key: &str) -> Result<()> {
+        self.guarded(async {
+            let mut conn = self.connection().await?;
+            conn.del::<_, ()>(self.limiter.redis_key(key)).await.map_err(command_failed)
+        })
+        .await
+    }
+}
+
+/// Units taken by `AsyncRedisRateLimiter::reserve`, to be kept or given back
+///
+/// Like `Reservation`, but cancelling is async. A cancelled reservation
+/// gives its units back to whatever took them: Redis, or the degraded mode
+/// (the local fallback, if any) when Redis was unavailable.
+#[must_use = "a reservation should be committed or cancelled"]
+pub struct AsyncReservation<'a> {
+    limiter: &'a AsyncRedisRateLimiter,
+    key: String,
+    cost: u64,
+    decision: RateLimitDecision,
+    consumed: Option<Consumed>, // None when denied: nothing to give back
+}
+
+impl AsyncReservation<'_> {
+    /// The check that made this reservation
+    pub fn decision(&self) -> &RateLimitDecision {
+        &self.decision
+    }
+    
+    /// Whether the units were actually reserved
+    pub fn is_allowed(&self) -> bool {
+        self.decision.allowed
+    }
+    
+    /// Keep the reserved units spent
+    pub fn commit(self) {}
+    
+    /// Give the reserved units back (a no-op for denied reservations)
+    pub async fn cancel(self) -> Result<()> {
+        let Some(consumed) = self.consumed else {
+            return Ok(());
+        };
+        self.limiter.run_script(&self.key, self.cost, ScriptMode::Refund, consumed).await.map(|_| ())
+    }
+}
+
+impl fmt::Debug for AsyncReservation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AsyncReservation")
+            .field("key", &self.key)
+            .field("decision", &self.decision)
+            .field("refundable", &self.consumed.is_some())
+            .finish()
+    }
+}
+
+/// Redis-backed `MultiWindow`: several fixed windows on the same key
+///
+/// All windows for a key live in one hash and are checked and updated by a
+/// single Lua call, so a request consumes from every window or from none.
+///
+/// Calls go through the same pooled connections as `RedisRateLimiter`, and
+/// `with_circuit_breaker` bounds them the same way. There is no degraded
+/// mode: while Redis is down, checks fail with `RateLimitError::Backend` or
+/// `CircuitOpen`. Windows follow this process's clock unless
+/// `with_server_time` is set, as with `RedisRateLimiter`.
+pub struct RedisMultiWindow {
+    connector: Connector,
+    limits: Vec<RateLimitConfig>,
+    capacity: u64, // largest cost every window can hold
     lua_script: Script,
+    clock: Arc<dyn Clock>,
+    server_time: bool,
+}
+
+impl RedisMultiWindow {
+    /// Create a new Redis multi-window limiter
+    pub fn new(redis_url: &str, limits: Vec<RateLimitConfig>) -> anyhow::Result<Self> {
+        Self::with_clock(redis_url, limits, Arc::new(SystemClock))
+    }
+    
+    /// Create a Redis multi-window limiter that timestamps requests with `clock`
+    pub fn with_clock(redis_url: &str, limits: Vec<RateLimitConfig>, clock: Arc<dyn Clock>) -> anyhow::Result<Self> {
+        if limits.is_empty() {
+            anyhow::bail!("MultiWindow needs at least one limit");
+        }
+        for config in &limits {
+            config.validate()?;
+            if config.window.as_millis() == 0 {
+                anyhow::bail!("windows in Redis must be at least 1ms long");
+            }
+        }
+        
+        let capacity = limits.iter().map(|config| config.max_requests).min().unwrap_or(0);
+        
+        Ok(Self {
+            connector: Connector::new(Client::open(redis_url)?),
+            limits,
+            capacity,
+            lua_script: Script::new(&format!("{}{}", SERVER_TIME_PRELUDE, MULTI_WINDOW_SCRIPT)),
+            clock,
+            server_time: false,
+        })
+    }
+    
+    /// Time windows with the Redis server's clock; see `RedisRateLimiter::with_server_time`
+    pub fn with_server_time(mut self) -> Self {
+        self.server_time = true;
+        self
+    }
+    
+    /// Send every Redis call through `circuit_breaker`; see `RedisRateLimiter::with_circuit_breaker`
+    pub fn with_circuit_breaker(mut self, circuit_breaker: Arc<CircuitBreaker>) -> Self {
+        self.connector.circuit_breaker = Some(circuit_breaker);
+        self
+    }
+    
+    /// Run scripts on `redis` instead of a server
+    #[cfg(test)]
+    fn with_lua_redis(mut self, redis: Arc<LuaRedis>) -> Self {
+        self.connector.lua_redis = Some(redis);
+        self
+    }
+    
+    /// The configured limits, in the order `denied_by` refers to
+    pub fn limits(&self) -> &[RateLimitConfig] {
+        &self.limits
+    }
+    
+    /// Clear every window for `key`, reporting whether Redis did
+    pub fn try_reset(&self, key: &str) -> Result<()> {
+        self.connector.run(|conn| {
+            redis::cmd("DEL").arg(format!("rate_limit_multi:{}", key)).query(conn).map_err(command_failed)
+        })
+    }
+    
+    /// Check `cost` against every window, consuming only if all of them allow it
+    pub fn check(&self, key: &str, cost: u64) -> Result<MultiWindowDecision> {
+        check_cost(cost, self.capacity)?;
+        self.run_script(key, cost, ScriptMode::Consume, 0).map(|(result, _)| result)
+    }
+    
+    /// Current state of every window for `key`, without consuming
+    pub fn check_status(&self, key: &str) -> Result<MultiWindowDecision> {
+        self.run_script(key, 1, ScriptMode::Status, 0).map(|(result, _)| result)
+    }
+    
+    /// Run the script, also returning the time (ms) it ran at; refunds skip
+    /// windows started after `since`
+    fn run_script(&self, key: &str, cost: u64, mode: ScriptMode, since: u64) -> Result<(MultiWindowDecision, u64)> {
+        let now = match self.server_time {
+            true => String::new(),
+            false => self.clock.unix_now().as_millis().to_string(),
+        };
+        let mut invocation = self.lua_script.prepare_invoke();
+        invocation
+            .key(format!("rate_limit_multi:{}", key))
+            .arg(now)
+            .arg(cost)
+            .arg(mode.as_arg())
+            .arg(since);
+        for config in &self.limits {
+            invocation.arg(config.max_requests).arg(config.window.as_millis() as u64);
+        }
+        
+        let (now, windows): (u64, Vec<(i32, u64, u64)>) = self.connector.run(|conn| {
+            let reply: Vec<u64> = invocation
+                .invoke(conn)
+                .map_err(|e| redis_failed("Lua script failed", e))?;
+            let windows = reply[1..].chunks(3).map(|window| (window[0] as i32, window[1], window[2])).collect();
+            Ok((reply[0], windows))
+        })?;
+        
+        let decisions: Vec<_> = windows.into_iter()
+            .zip(&self.limits)
+            .map(|((fits, remaining, reset_after_ms), config)| {
+                let reset_after = Duration::from_millis(reset_after_ms);
+                if fits == 1 {
+                    RateLimitDecision::allow(config.max_requests, remaining, reset_after)
+                } else {
+                    RateLimitDecision::deny(config.max_requests, remaining, reset_after, reset_after)
+                }
+            })
+            .collect();
+        
+        // Refunds of expired state report nothing
+        if decisions.is_empty() {
+            let limit = self.capacity;
+            let result = MultiWindowDecision {
+                decision: RateLimitDecision::allow(limit, limit, Duration::ZERO),
+                denied_by: None,
+            };
+            return Ok((result, now));
+        }
+        Ok((MultiWindowDecision::combine(&decisions), now))
+    }
+}
+
+impl RateLimiter for RedisMultiWindow {
+    fn allow_n(&self, key: &str, cost: u64) -> Result<RateLimitDecision> {
+        self.check(key, cost).map(|result| result.decision)
+    }
+    
+    fn reserve(&self, key: &str, cost: u64) -> Result<Reservation<'_>> {
+        check_cost(cost, self.capacity)?;
+        let (result, reserved_at) = self.run_script(key, cost, ScriptMode::Consume, 0)?;
+        let key = key.to_string();
+        Ok(Reservation::new(result.decision, move || {
+            // Only give slots back to the windows they were taken from
+            self.run_script(&key, cost, ScriptMode::Refund, reserved_at).map(|_| ())
+        }))
+    }
+    
+    fn status(&self, key: &str) -> Result<RateLimitDecision> {
+        self.check_status(key).map(|result| result.decision)
+    }
+    
+    /// Like `try_reset`, but a Redis failure goes unreported
+    fn reset(&self, key: &str) {
+        let _ = self.try_reset(key);
+    }
+}
+
+/// Redis-backed `CalendarQuota`: counts that reset on calendar boundaries
+///
+/// Each period is counted under its own key, which expires when the period
+/// ends. Periods follow this process's clock unless `with_server_time` is
+/// set. Like `RedisMultiWindow`, it can be given a circuit breaker but has no
+/// degraded mode.
+pub struct RedisCalendarQuota {
+    connector: Connector,
+    limit: u64,
+    period: QuotaPeriod,
+    utc_offset: i32,
+    lua_script: Script,
+    clock: Arc<dyn Clock>,
+    server_time: bool,
+}
+
+impl RedisCalendarQuota {
+    /// Create a new Redis calendar quota
+    pub fn new(redis_url: &str, limit: u64, period: QuotaPeriod) -> anyhow::Result<Self> {
+        Self::with_clock(redis_url, limit, period, Arc::new(SystemClock))
+    }
+    
+    /// Create a Redis calendar quota that reads wall-clock time from `clock`
+    pub fn with_clock(redis_url: &str, limit: u64, period: QuotaPeriod, clock: Arc<dyn Clock>) -> anyhow::Result<Self> {
+        calendar_quota::validate_limit(limit)?;
+        
+        Ok(Self {
+            connector: Connector::new(Client::open(redis_url)?),
+            limit,
+            period,
+            utc_offset: 0,
+            lua_script: Script::new(&format!("{}{}", SERVER_TIME_PRELUDE, CALENDAR_QUOTA_SCRIPT)),
+            clock,
+            server_time: false,
+        })
+    }
+    
+    /// Pick the period with the Redis server's clock; see `RedisRateLimiter::with_server_time`
+    ///
+    /// The server's clock may put a check in the period before or after the
+    /// one this process's clock is in, but not further off.
+    pub fn with_server_time(mut self) -> Self {
+        self.server_time = true;
+        self
+    }
+    
+    /// Align periods to a timezone `utc_offset` seconds ahead of UTC
+    pub fn with_utc_offset(mut self, utc_offset: i32) -> Self {
+        self.utc_offset = utc_offset;
+        self
+    }
+    
+    /// Send every Redis call through `circuit_breaker`; see `RedisRateLimiter::with_circuit_breaker`
+    pub fn with_circuit_breaker(mut self, circuit_breaker: Arc<CircuitBreaker>) -> Self {
+        self.connector.circuit_breaker = Some(circuit_breaker);
+        self
+    }
+    
+    /// Run scripts on `redis` instead of a server
+    #[cfg(test)]
+    fn with_lua_redis(mut self, redis: Arc<LuaRedis>) -> Self {
+        self.connector.lua_redis = Some(redis);
+        self
+    }
+    
+    /// Clear the current period's count for `key`, reporting whether Redis did
+    pub fn try_reset(&self, key: &str) -> Result<()> {
+        let keys: Vec<_> = self.candidate_periods().into_iter().map(|start| self.redis_key(key, start)).collect();
+        self.connector.run(|conn| redis::cmd("DEL").arg(&keys).query(conn).map_err(command_failed))
+    }
+    
+    fn redis_key(&self, key: &str, period_start: i64) -> String {
+        format!("rate_limit_quota:{}:{}", key, period_start)
+    }
+    
+    /// Starts (UNIX seconds) of the periods a check may fall in: the current
+    /// one, and with the server's clock also the ones either side of it
+    fn candidate_periods(&self) -> Vec<i64> {
+        let (start, end) = self.period.bounds(self.clock.unix_now().as_secs() as i64, self.utc_offset);
+        match self.server_time {
+            true => vec![self.period.bounds(start - 1, self.utc_offset).0, start, end],
+            false => vec![start],
+        }
+    }
+    
+    /// Run the script against whichever of `periods` the time falls in (the
+    /// first, for refunds), also returning that period's start
+    fn run_script(&self, key: &str, cost: u64, mode: ScriptMode, periods: &[i64]) -> Result<(RateLimitDecision, i64)> {
+        let now = match self.server_time {
+            true => String::new(),
+            false => self.clock.unix_now().as_millis().to_string(),
+        };
+        let mut invocation = self.lua_script.prepare_invoke();
+        invocation.arg(self.limit).arg(cost).arg(mode.as_arg()).arg(now);
+        for &start in periods {
+            let (_, end) = self.period.bounds(start, self.utc_offset);
+            invocation.key(self.redis_key(key, start)).arg(start.saturating_mul(1000)).arg(end.saturating_mul(1000));
+        }
+        
+        let (fits, remaining, reset_after_ms, period): (i32, u64, i64, usize) = self.connector.run(|conn| {
+            invocation
+                .invoke(conn)
+                .map_err(|e| redis_failed("Lua script failed", e))
+        })?;
+        
+        let reset_after = Duration::from_millis(reset_after_ms.max(0) as u64);
+        let decision = if fits == 1 {
+            RateLimitDecision::allow(self.limit, remaining, reset_after)
+        } else {
+            // Nothing frees up until the next period
+            RateLimitDecision::deny(self.limit, remaining, reset_after, reset_after)
+        };
+        Ok((decision, periods[period - 1]))
+    }
+}
+
+impl RateLimiter for RedisCalendarQuota {
+    fn allow_n(&self, key: &str, cost: u64) -> Result<RateLimitDecision> {
+        check_cost(cost, self.limit)?;
+        self.run_script(key, cost, ScriptMode::Consume, &self.candidate_periods()).map(|(decision, _)| decision)
+    }
+    
+    fn reserve(&self, key: &str, cost: u64) -> Result<Reservation<'_>> {
+        check_cost(cost, self.limit)?;
+        let (decision, period_start) = self.run_script(key, cost, ScriptMode::Consume, &self.candidate_periods())?;
+        let key = key.to_string();
+        Ok(Reservation::new(decision, move || {
+            // Only give calls back to the period they were taken from
+            self.run_script(&key, cost, ScriptMode::Refund, &[period_start]).map(|_| ())
+        }))
+    }
+    
+    fn status(&self, key: &str) -> Result<RateLimitDecision> {
+        self.run_script(key, 1, ScriptMode::Status, &self.candidate_periods()).map(|(decision, _)| decision)
+    }
+    
+    /// Like `try_reset`, but a Redis failure goes unreported
+    fn reset(&self, key: &str) {
+        let _ = self.try_reset(key);
+    }
+}
+
+/// `Storage` in Redis, making every in-memory algorithm distributed
+///
+/// Each key's state is kept as JSON under `{prefix}:{key}`. An update reads
+/// it, then writes with a script that checks nothing changed in between,
+/// reading again if another instance wrote first (up to
+/// `MAX_STORAGE_UPDATE_ATTEMPTS` times, then failing with
+/// `RateLimitError::Backend`). That takes two round trips per check where
+/// the Lua scripts of `RedisRateLimiter` take one, but works for any
+/// algorithm. Times are stored as wall-clock time, so instances need
+/// reasonably synced clocks.
+///
+/// Calls block on a pooled connection, like `RedisRateLimiter`'s.
+///
+/// ```no_run
+/// use distributed_rate_limiter::algorithms::SlidingWindow;
+/// use distributed_rate_limiter::redis_limiter::RedisStorage;
+/// use distributed_rate_limiter::RateLimitConfig;
+/// use std::sync::Arc;
+///
+/// let storage = Arc::new(RedisStorage::new("redis://127.0.0.1/").unwrap());
+/// let limiter = SlidingWindow::new(RateLimitConfig::per_minute(100)).unwrap().with_storage(storage);
+/// ```
+pub struct RedisStorage {
+    connector: Connector,
+    update_script: Script,
+    prefix: String,
+    ttl: Option<Duration>,
+}
+
+impl RedisStorage {
+    pub fn new(redis_url: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            connector: Connector::new(Client::open(redis_url)?),
+            update_script: Script::new(STORAGE_UPDATE_SCRIPT),
+            prefix: "rate_limit_state".to_string(),
+            ttl: None,
+        })
+    }
+    
+    /// Keep keys under `{prefix}:` instead of `rate_limit_state:`, e.g. to
+    /// keep limiters with different algorithms apart
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+    
+    /// Have Redis drop keys not updated for `ttl`, so keys no one calls
+    /// `evict_idle` for still expire; it must exceed the longest window
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+    
+    /// Send every Redis call through `circuit_breaker`; see
+    /// `RedisRateLimiter::with_circuit_breaker`
+    pub fn with_circuit_breaker(mut self, circuit_breaker: Arc<CircuitBreaker>) -> Self {
+        self.connector.circuit_breaker = Some(circuit_breaker);
+        self
+    }
+    
+    /// Keep state in `redis` instead of a server
+    #[cfg(test)]
+    fn with_lua_redis(mut self, redis: Arc<LuaRedis>) -> Self {
+        self.connector.lua_redis = Some(redis);
+        self
+    }
+    
+    fn redis_key(&self, key: &str) -> String {
+        format!("{}:{}", self.prefix, key)
+    }
+    
+    /// Every stored key, without the prefix
+    fn keys(&self) -> Result<Vec<String>> {
+        let keys: Vec<String> = self.connector.run(|conn| {
+            let mut scan = redis::cmd("SCAN");
+            scan.cursor_arg(0).arg("MATCH").arg(format!("{}:*", self.prefix));
+            scan.iter(conn).map(|keys| keys.collect()).map_err(command_failed)
+        })?;
+        Ok(keys.into_iter().map(|key| key[self.prefix.len() + 1..].to_string()).collect())
+    }
+    
+    /// One read and conditional write of `key`, returning whether the write
+    /// went through (or nothing needed writing)
+    fn try_update(
+        &self,
+        conn: &mut dyn ConnectionLike,
+        key: &str,
+        f: &mut dyn FnMut(Option<KeyState>) -> Option<KeyState>,
+    ) -> Result<bool> {
+        let redis_key = self.redis_key(key);
+        let json: Option<String> = redis::cmd("GET").arg(&redis_key).query(conn).map_err(command_failed)?;
+        let current = json.as_deref().map(|json| decode_state(key, json)).transpose()?;
+        
+        let next = f(current.clone());
+        if next == current {
+            return Ok(true);
+        }
+        let state = next.map_or(String::new(), |state| {
+            serde_json::to_string(&state).expect("key state serializes to JSON")
+        });
+        let ttl = self.ttl.map_or(String::new(), |ttl| ttl.as_millis().max(1).to_string());
+        let written: bool = self.update_script
+            .key(&redis_key)
+            .arg(if json.is_some() { "1" } else { "0" })
+            .arg(json.unwrap_or_default())
+            .arg(state)
+            .arg(ttl)
+            .invoke(conn)
+            .map_err(command_failed)?;
+        Ok(written)
+    }
+}
+
+fn connection_failed(e: redis::RedisError) -> RateLimitError {
+    RateLimitError::Backend(format!("Redis connection failed: {}", e))
+}
+
+fn command_failed(e: redis::RedisError) -> RateLimitError {
+    redis_failed("Redis command failed", e)
+}
+
+/// `RateLimitError::Backend` if Redis couldn't be reached in time; anything
+/// Redis answered with (e.g. a script error or WRONGTYPE) is a `ConfigError`
+fn redis_failed(context: &str, e: redis::RedisError) -> RateLimitError {
+    if is_unavailable(&e) {
+        RateLimitError::Backend(format!("{}: {}", context, e))
+    } else {
+        RateLimitError::ConfigError(format!("{}: {}", context, e))
+    }
+}
+
+fn is_unavailable(e: &redis::RedisError) -> bool {
+    e.is_io_error() || e.is_connection_dropped() || e.is_connection_refusal() || e.is_timeout()
+}
+
+/// Pooled blocking connections to Redis, optionally behind a circuit breaker
+///
+/// redis-rs offers no timeout for the handshake that follows connecting, so a
+/// server that accepts connections but never answers can still hold up the
+/// call that opens one.
+struct Connector {
+    client: Client,
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
+    idle: Mutex<Vec<redis::Connection>>,
+    #[cfg(test)]
+    lua_redis: Option<Arc<LuaRedis>>, // stands in for the server when set
+}
+
+impl Connector {
+    fn new(client: Client) -> Self {
+        Self {
+            client,
+            circuit_breaker: None,
+            idle: Mutex::new(Vec::new()),
+            #[cfg(test)]
+            lua_redis: None,
+        }
+    }
+    
+    /// Run `f` on a pooled connection, through the circuit breaker if there is one
+    ///
+    /// Under a breaker, connecting and all of `f`'s round trips share one
+    /// `call_timeout`.
+    fn run<T>(&self, f: impl FnOnce(&mut dyn ConnectionLike) -> Result<T>) -> Result<T> {
+        #[cfg(test)]
+        if let Some(redis) = &self.lua_redis {
+            return f(&mut redis.connection());
+        }
+        
+        let Some(breaker) = &self.circuit_breaker else {
+            return self.run_until(None, f);
+        };
+        
+        let permit = breaker.acquire()?;
+        let timeout = breaker.config().call_timeout;
+        let result = self.run_until(Some(Instant::now() + timeout), f);
+        breaker.record(permit, !matches!(result, Err(RateLimitError::Backend(_))));
+        result
+    }
+    
+    fn run_until<T>(
+        &self,
+        deadline: Option<Instant>,
+        f: impl FnOnce(&mut dyn ConnectionLike) -> Result<T>,
+    ) -> Result<T> {
+        let mut conn = self.checkout(deadline)?;
+        let result = f(&mut DeadlineConnection { conn: &mut conn, deadline });
+        
+        // After a timeout a reply may still be on its way; don't reuse the connection
+        if !matches!(result, Err(RateLimitError::Backend(_))) && conn.is_open() {
+            self.idle.lock().unwrap_or_else(|e| e.into_inner()).push(conn);
+        }
+        result
+    }
+    
+    fn checkout(&self, deadline: Option<Instant>) -> Result<redis::Connection> {
+        let idle = self.idle.lock().unwrap_or_else(|e| e.into_inner()).pop();
+        if let Some(conn) = idle {
+            return Ok(conn);
+        }
+        
+        match deadline {
+            Some(deadline) => {
+                let left = time_left(deadline).map_err(connection_failed)?;
+                self.client.get_connection_with_timeout(left).map_err(connection_failed)
+            }
+            None => self.client.get_connection().map_err(connection_failed),
+        }
+    }
+}
+
+/// Time until `deadline`, or a timeout error once it has passed
+fn time_left(deadline: Instant) -> redis::RedisResult<Duration> {
+    let left = deadline.saturating_duration_since(Instant::now());
+    if left.is_zero() {
+        return Err((redis::ErrorKind::IoError, "Redis call timed out").into());
+    }
+    Ok(left)
+}
+
+/// A connection whose round trips must all finish by `deadline`
+struct DeadlineConnection<'a> {
+    conn: &'a mut redis::Connection,
+    deadline: Option<Instant>,
+}
+
+impl DeadlineConnection<'_> {
+    /// Bound the next round trip by the time left until the deadline
+    fn arm(&mut self) -> redis::RedisResult<()> {
+        let Some(deadline) = self.deadline else {
+            return Ok(());
+        };
+        let left = time_left(deadline)?;
+        self.conn.set_read_timeout(Some(left))?;
+        self.conn.set_write_timeout(Some(left))
+    }
+}
+
+impl ConnectionLike for DeadlineConnection<'_> {
+    fn req_packed_command(&mut self, cmd: &[u8]) -> redis::RedisResult<redis::Value> {
+        self.arm()?;
+        self.conn.req_packed_command(cmd)
+    }
+    
+    fn req_packed_commands(&mut self, cmd: &[u8], offset: usize, count: usize) -> redis::RedisResult<Vec<redis::Value>> {
+        self.arm()?;
+        self.conn.req_packed_commands(cmd, offset, count)
+    }
+    
+    fn get_db(&self) -> i64 {
+        self.conn.get_db()
+    }
+    
+    fn check_connection(&mut self) -> bool {
+        self.conn.check_connection()
+    }
+    
+    fn is_open(&self) -> bool {
+        self.conn.is_open()
+    }
+}
+
+fn decode_state(key: &str, json: &str) -> Result<KeyState> {
+    serde_json::from_str(json)
+        .map_err(|e| RateLimitError::ConfigError(format!("invalid state for '{}' in Redis: {}", key, e)))
+}
+
+impl Storage for RedisStorage {
+    fn get(&self, key: &str) -> Result<Option<KeyState>> {
+        let json: Option<String> = self.connector.run(|conn| {
+            redis::cmd("GET").arg(self.redis_key(key)).query(conn).map_err(command_failed)
+        })?;
+        json.map(|json| decode_state(key, &json)).transpose()
+    }
+    
+    fn update(&self, key: &str, f: &mut dyn FnMut(Option<KeyState>) -> Option<KeyState>) -> Result<()> {
+        self.connector.run(|conn| {
+            for _ in 0..MAX_STORAGE_UPDATE_ATTEMPTS {
+                if self.try_update(conn, key, f)? {
+                    return Ok(());
+                }
+            }
+            Err(RateLimitError::Backend(format!(
+                "gave up updating '{}' in Redis after {} conflicting writes",
+                key, MAX_STORAGE_UPDATE_ATTEMPTS,
+            )))
+        })
+    }
+    
+    fn remove(&self, key: &str) -> Result<()> {
+        self.connector.run(|conn| redis::cmd("DEL").arg(self.redis_key(key)).query(conn).map_err(command_failed))
+    }
+    
+    fn evict(&self, is_idle: &mut dyn FnMut(&str, &KeyState) -> bool) -> Result<usize> {
+        let keys = self.keys()?;
+        
+        let mut removed = 0;
+        for key in keys {
+            // Only the last run of the update counts
+            let mut evicted = false;
+            self.update(&key, &mut |state| {
+                evicted = state.as_ref().is_some_and(|state| is_idle(&key, state));
+                state.filter(|_| !evicted)
+            })?;
+            removed += usize::from(evicted);
+        }
+        Ok(removed)
+    }
+    
+    fn key_count(&self) -> Result<usize> {
+        self.keys().map(|keys| keys.len())
+    }
 }
 
-impl RedisRateLimiter {
-    /// Create a new Redis rate limiter
-    pub fn new(redis_url: &str, config: RateLimitConfig) -> anyhow::Result<Self> {
-        let client = Client::open(redis_url)?;
-        
-        // Lua script for atomic token bucket check
-        // This ensures race conditions don't occur in distributed systems
-        let lua_script = Script::new(
-            r#"
-            local key = KEYS[1]
-            local max_tokens = tonumber(ARGV[1])
-            local refill_rate = tonumber(ARGV[2])
-            local now = tonumber(ARGV[3])
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit_breaker::{CircuitBreakerConfig, CircuitState};
+    use crate::clock::MockClock;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    
+    /// A fake Redis answering every command with `reply`, after `delay` for
+    /// all but the handshake; returns its URL and a count of the connections
+    /// it accepted
+    fn stub_redis(reply: &'static str, delay: Duration) -> (String, Arc<AtomicU64>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("redis://{}/", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicU64::new(0));
+        let accepted = connections.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { return };
+                accepted.fetch_add(1, Ordering::SeqCst);
+                std::thread::spawn(move || {
+                    let mut buf = [0; 65536];
+                    while let Ok(n @ 1..) = stream.read(&mut buf) {
+                        let read = &buf[..n];
+                        if !read.windows(6).any(|w| w == b"CLIENT") {
+                            std::thread::sleep(delay);
+                        }
+                        let commands = (0..n).filter(|&i| read[i] == b'*' && (i == 0 || read[i - 1] == b'\n')).count();
+                        if stream.write_all(reply.repeat(commands).as_bytes()).is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        (url, connections)
+    }
+    
+    /// A step in a run of checks compared between Redis and memory
+    #[derive(Debug, Clone, Copy)]
+    enum Step {
+        Consume(u64),
+        Status,
+        Reserve(u64),
+        /// Cancel the oldest reservation still held
+        Cancel,
+        Advance(Duration),
+    }
+    
+    /// Checks every script should answer like its in-memory algorithm:
+    /// partial and whole spending, a cost over the limit, refunds within
+    /// the window and after it has rolled over
+    fn edge_case_steps(window: Duration) -> Vec<Step> {
+        use Step::*;
+        vec![
+            Consume(2), Consume(3), Consume(1), Status,
+            Consume(6),
+            Advance(window / 4), Consume(1), Status,
+            Reserve(1), Cancel, Status,
+            Advance(window), Consume(4), Consume(2), Status,
+            Reserve(1), Advance(window + window / 2), Cancel, Status,
+            Reserve(2), Advance(window), Consume(1), Cancel, Status,
+            Consume(5), Advance(window / 10), Reserve(1), Status,
+            Advance(window * 3), Cancel, Status, Consume(5), Consume(1),
+        ]
+    }
+    
+    fn assert_same_decision(redis: &Result<RateLimitDecision>, memory: &Result<RateLimitDecision>, context: &str) {
+        let close = |a: Duration, b: Duration| a.abs_diff(b) <= Duration::from_millis(1);
+        match (redis, memory) {
+            (Ok(redis), Ok(memory)) => {
+                assert_eq!(
+                    (redis.allowed, redis.limit, redis.remaining),
+                    (memory.allowed, memory.limit, memory.remaining),
+                    "{}: {:?} vs {:?}", context, redis, memory,
+                );
+                assert!(close(redis.reset_after, memory.reset_after), "{}: {:?} vs {:?}", context, redis, memory);
+                match (redis.retry_after, memory.retry_after) {
+                    (Some(a), Some(b)) => assert!(close(a, b), "{}: {:?} vs {:?}", context, redis, memory),
+                    (a, b) => assert_eq!(a, b, "{}", context),
+                }
+            }
+            (redis, memory) => assert_eq!(format!("{:?}", redis), format!("{:?}", memory), "{}", context),
+        }
+    }
+    
+    /// Run `steps` for "key" on `redis` and `memory`, which share `clock`;
+    /// `redis` keeps its state on `server` under `redis_key`
+    fn assert_steps_match(
+        redis: &dyn RateLimiter,
+        server: &LuaRedis,
+        redis_key: &str,
+        memory: &dyn RateLimiter,
+        clock: &MockClock,
+        steps: &[Step],
+        name: &str,
+    ) {
+        let mut held = std::collections::VecDeque::new();
+        for (i, step) in steps.iter().enumerate() {
+            let context = format!("{} step {} ({:?})", name, i, step);
+            match *step {
+                Step::Consume(cost) => assert_same_decision(&redis.allow_n("key", cost), &memory.allow_n("key", cost), &context),
+                Step::Status => assert_same_decision(&redis.status("key"), &memory.status("key"), &context),
+                Step::Reserve(cost) => {
+                    let (r, m) = (redis.reserve("key", cost), memory.reserve("key", cost));
+                    assert_same_decision(
+                        &r.as_ref().map(|r| *r.decision()).map_err(|e| RateLimitError::ConfigError(e.to_string())),
+                        &m.as_ref().map(|m| *m.decision()).map_err(|e| RateLimitError::ConfigError(e.to_string())),
+                        &context,
+                    );
+                    held.push_back((r.unwrap(), m.unwrap()));
+                }
+                Step::Cancel => {
+                    let (r, m) = held.pop_front().expect("a reservation is held");
+                    r.cancel().unwrap();
+                    m.cancel().unwrap();
+                }
+                Step::Advance(by) => {
+                    // Forget idle keys in memory when Redis expires them, not before
+                    clock.advance(by);
+                    let ttl: i64 = redis::cmd("PTTL").arg(redis_key).query(&mut server.connection()).unwrap();
+                    if ttl == -2 {
+                        memory.evict_idle();
+                    }
+                }
+            }
+        }
+    }
+    
+    #[test]
+    fn test_redis_scripts_match_memory() {
+        for algorithm in AlgorithmType::ALL {
+            let window = Duration::from_secs(1);
+            let config = match algorithm {
+                AlgorithmType::SlidingWindowCounter => RateLimitConfig::builder(5, window).sub_windows(4).build().unwrap(),
+                _ => RateLimitConfig::new(5, window),
+            };
+            let clock = Arc::new(MockClock::new());
+            let server = LuaRedis::new(clock.clone());
+            let redis = RedisRateLimiter::with_algorithm("redis://127.0.0.1:1/", config.clone(), algorithm, clock.clone())
+                .unwrap()
+                .with_lua_redis(server.clone());
+            let memory = crate::factory::build_limiter_with_clock(algorithm, config, &crate::factory::Backend::Memory, clock.clone())
+                .unwrap();
+            
+            let steps = edge_case_steps(window);
+            assert_steps_match(&redis, &server, &redis.redis_key("key"), memory.as_ref(), &clock, &steps, &algorithm.to_string());
+        }
+    }
+    
+    #[test]
+    fn test_redis_bucket_scripts_match_memory_with_bursts_and_steps() {
+        let algorithms = [AlgorithmType::TokenBucket, AlgorithmType::LeakyBucket, AlgorithmType::Gcra];
+        let configs = [
+            RateLimitConfig::builder(4, Duration::from_secs(2)).burst(8).build().unwrap(),
+            RateLimitConfig::builder(4, Duration::from_secs(2)).initial_tokens(2).build().unwrap(),
+            RateLimitConfig::builder(4, Duration::from_secs(2)).refill_interval(Duration::from_millis(500)).build().unwrap(),
+        ];
+        for algorithm in algorithms {
+            for config in &configs {
+                let clock = Arc::new(MockClock::new());
+                let Ok(redis) = RedisRateLimiter::with_algorithm("redis://127.0.0.1:1/", config.clone(), algorithm, clock.clone()) else {
+                    // GCRA has no discrete refill
+                    continue;
+                };
+                let server = LuaRedis::new(clock.clone());
+                let redis = redis.with_lua_redis(server.clone());
+                let memory = crate::factory::build_limiter_with_clock(algorithm, config.clone(), &crate::factory::Backend::Memory, clock.clone())
+                    .unwrap();
+                
+                let name = format!("{} {:?}", algorithm, config);
+                let steps = edge_case_steps(config.window);
+                assert_steps_match(&redis, &server, &redis.redis_key("key"), memory.as_ref(), &clock, &steps, &name);
+            }
+        }
+    }
+    
+    #[test]
+    fn test_redis_multi_window_script_matches_memory() {
+        use crate::algorithms::MultiWindow;
+        
+        let limits = vec![
+            RateLimitConfig::new(3, Duration::from_secs(1)),
+            RateLimitConfig::new(5, Duration::from_secs(10)),
+        ];
+        let clock = Arc::new(MockClock::new());
+        let server = LuaRedis::new(clock.clone());
+        let redis = RedisMultiWindow::with_clock("redis://127.0.0.1:1/", limits.clone(), clock.clone())
+            .unwrap()
+            .with_lua_redis(server.clone());
+        let memory = MultiWindow::with_clock(limits, clock.clone()).unwrap();
+        
+        // Each window denies in turn, and refunds skip windows that rolled over
+        let mut steps = edge_case_steps(Duration::from_secs(1));
+        steps.extend(edge_case_steps(Duration::from_secs(10)));
+        assert_steps_match(&redis, &server, "rate_limit_multi:key", &memory, &clock, &steps, "multi window");
+        
+        let (redis, memory) = (redis.check("key", 1).unwrap(), memory.check("key", 1).unwrap());
+        assert_eq!(redis.denied_by, memory.denied_by);
+    }
+    
+    #[test]
+    fn test_redis_calendar_quota_script_matches_memory() {
+        use crate::algorithms::CalendarQuota;
+        
+        let clock = Arc::new(MockClock::new());
+        let server = LuaRedis::new(clock.clone());
+        let redis = RedisCalendarQuota::with_clock("redis://127.0.0.1:1/", 5, QuotaPeriod::Day, clock.clone())
+            .unwrap()
+            .with_lua_redis(server.clone());
+        let memory = CalendarQuota::with_clock(5, QuotaPeriod::Day, clock.clone()).unwrap();
+        
+        // The mock clock starts late in the day, so the period soon rolls over
+        // Each period has its own key, so memory forgets no earlier than Redis
+        let steps = edge_case_steps(Duration::from_secs(3 * 3600));
+        assert_steps_match(&redis, &server, "none", &memory, &clock, &steps, "calendar quota");
+    }
+    
+    #[test]
+    fn test_lua_redis_reports_script_errors() {
+        let clock = Arc::new(MockClock::new());
+        let server = LuaRedis::new(clock.clone());
+        let bucket = RedisRateLimiter::with_algorithm("redis://127.0.0.1:1/", RateLimitConfig::per_second(5), AlgorithmType::TokenBucket, clock.clone())
+            .unwrap()
+            .with_lua_redis(server.clone());
+        
+        // A string where the token bucket expects a hash
+        let _: () = redis::cmd("SET").arg(bucket.redis_key("key")).arg("x").query(&mut server.connection()).unwrap();
+        assert!(matches!(bucket.allow_request("key"), Err(RateLimitError::ConfigError(_))));
+        
+        // Redis is up, so a degraded mode doesn't answer for it
+        let bucket = bucket.with_degraded_mode("bucket", DegradedMode::FailOpen).unwrap();
+        assert!(matches!(bucket.allow_request("key"), Err(RateLimitError::ConfigError(_))));
+        assert!(matches!(bucket.status("key"), Err(RateLimitError::ConfigError(_))));
+    }
+    
+    #[tokio::test]
+    async fn test_async_redis_script_errors_are_not_degraded() {
+        let (url, _) = stub_redis("-WRONGTYPE Operation against a key holding the wrong kind of value\r\n", Duration::ZERO);
+        let limiter = AsyncRedisRateLimiter::new(&url, RateLimitConfig::per_second(5))
+            .unwrap()
+            .with_degraded_mode("async", DegradedMode::LocalFallback { nodes: 1 })
+            .unwrap();
+        assert!(matches!(limiter.allow_request("user1").await, Err(RateLimitError::ConfigError(_))));
+        assert!(matches!(limiter.reserve("user1", 1).await, Err(RateLimitError::ConfigError(_))));
+    }
+    
+    #[test]
+    fn test_redis_rate_limiter_rejects_invalid_config() {
+        // Validation happens before any connection attempt
+        let config = RateLimitConfig::new(10, std::time::Duration::ZERO);
+        assert!(RedisRateLimiter::new("redis://127.0.0.1/", config).is_err());
+    }
+    
+    #[test]
+    fn test_redis_rate_limiter_algorithm_support() {
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        let url = "redis://127.0.0.1/";
+        
+        let config = RateLimitConfig::per_second(5);
+        for algorithm in AlgorithmType::ALL {
+            assert!(RedisRateLimiter::with_algorithm(url, config.clone(), algorithm, clock.clone()).is_ok(), "{}", algorithm);
+        }
+        
+        // Windows work in whole milliseconds
+        let short = RateLimitConfig::new(5, Duration::from_micros(500));
+        assert!(RedisRateLimiter::with_algorithm(url, short.clone(), AlgorithmType::SlidingWindow, clock.clone()).is_err());
+        assert!(RedisRateLimiter::with_algorithm(url, short, AlgorithmType::TokenBucket, clock.clone()).is_ok());
+        let fine = RateLimitConfig::builder(5, Duration::from_millis(10)).sub_windows(20).build().unwrap();
+        assert!(RedisRateLimiter::with_algorithm(url, fine, AlgorithmType::SlidingWindowCounter, clock.clone()).is_err());
+        
+        // GCRA has no discrete refill and works in whole microseconds
+        let stepped = RateLimitConfig::builder(5, Duration::from_secs(1))
+            .refill_interval(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        assert!(RedisRateLimiter::with_algorithm(url, stepped, AlgorithmType::Gcra, clock.clone()).is_err());
+        let too_fast = RateLimitConfig::new(2_000, Duration::from_millis(1));
+        assert!(RedisRateLimiter::with_algorithm(url, too_fast, AlgorithmType::Gcra, clock).is_err());
+    }
+    
+    #[test]
+    fn test_redis_every_algorithm() {
+        // Skip if Redis not available
+        for algorithm in AlgorithmType::ALL {
+            let limiter = RedisRateLimiter::with_algorithm(
+                "redis://127.0.0.1/",
+                RateLimitConfig::per_minute(3),
+                algorithm,
+                Arc::new(SystemClock),
+            ).unwrap();
+            limiter.reset("every_user");
             
-            -- Get current state
-            local state = redis.call('HMGET', key, 'tokens', 'last_refill')
-            local tokens = tonumber(state[1]) or max_tokens
-            local last_refill = tonumber(state[2]) or now
+            match limiter.allow_n("every_user", 2) {
+                Ok(decision) => assert!(decision.allowed, "{}", algorithm),
+                Err(_) => {
+                    println!("Redis not available, skipping test");
+                    return;
+                }
+            }
             
-            -- Calculate refill
-            local elapsed = now - last_refill
-            local tokens_to_add = elapsed * refill_rate
-            tokens = math.min(tokens + tokens_to_add, max_tokens)
+            // A cancelled reservation is given back to where it was taken from
+            limiter.reserve("every_user", 1).unwrap().cancel().unwrap();
+            assert_eq!(limiter.status("every_user").unwrap().remaining, 1, "{}", algorithm);
             
-            -- Check if request allowed
-            local allowed = 0
-            if tokens >= 1.0 then
-                tokens = tokens - 1.0
-                allowed = 1
-            end
+            assert!(limiter.allow_request("every_user").unwrap().allowed, "{}", algorithm);
+            let denied = limiter.allow_request("every_user").unwrap();
+            assert!(!denied.allowed, "{} should deny", algorithm);
+            assert!(denied.retry_after.unwrap() > Duration::ZERO);
+            
+            limiter.reset("every_user");
+        }
+    }
+    
+    #[test]
+    fn test_redis_server_time_ignores_client_clocks() {
+        // Skip if Redis not available
+        let behind = Arc::new(MockClock::starting_at(Duration::from_secs(1_000_000)));
+        let ahead = Arc::new(MockClock::starting_at(Duration::from_secs(2_000_000_000)));
+        for algorithm in AlgorithmType::ALL {
+            let limiter = |clock: Arc<MockClock>| {
+                RedisRateLimiter::with_algorithm("redis://127.0.0.1/", RateLimitConfig::per_minute(2), algorithm, clock)
+                    .unwrap()
+                    .with_server_time()
+            };
+            let (behind, ahead) = (limiter(behind.clone()), limiter(ahead.clone()));
+            behind.reset("server_time_user");
+            
+            match ahead.allow_request("server_time_user") {
+                Ok(decision) => assert!(decision.allowed, "{}", algorithm),
+                Err(_) => {
+                    println!("Redis not available, skipping test");
+                    return;
+                }
+            }
+            // Neither clock's idea of elapsed time reaches Redis
+            assert!(behind.allow_request("server_time_user").unwrap().allowed, "{}", algorithm);
+            assert!(!ahead.allow_request("server_time_user").unwrap().allowed, "{}", algorithm);
             
-            -- Save state with expiration (2x window duration)
-            redis.call('HMSET', key, 'tokens', tokens, 'last_refill', now)
-            redis.call('EXPIRE', key, ARGV[4])
+            behind.reset("server_time_user");
+        }
+    }
+    
+    #[test]
+    fn test_lua_redis_server_time_ignores_client_clocks() {
+        let server = LuaRedis::new(Arc::new(MockClock::new()));
+        let behind = Arc::new(MockClock::starting_at(Duration::from_secs(1_000_000)));
+        let ahead = Arc::new(MockClock::starting_at(Duration::from_secs(2_000_000_000)));
+        for algorithm in AlgorithmType::ALL {
+            let limiter = |clock: Arc<MockClock>| {
+                RedisRateLimiter::with_algorithm("redis://127.0.0.1:1/", RateLimitConfig::per_minute(2), algorithm, clock)
+                    .unwrap()
+                    .with_server_time()
+                    .with_lua_redis(server.clone())
+            };
+            let (behind, ahead) = (limiter(behind.clone()), limiter(ahead.clone()));
+            
+            // Neither clock's idea of elapsed time reaches Redis
+            assert!(ahead.allow_request("key").unwrap().allowed, "{}", algorithm);
+            assert!(behind.allow_request("key").unwrap().allowed, "{}", algorithm);
+            assert!(!ahead.allow_request("key").unwrap().allowed, "{}", algorithm);
+            
+            // Refunds find the units on the server's clock too
+            let reservation = behind.reserve("key", 1).unwrap();
+            assert!(!reservation.decision().allowed, "{}", algorithm);
+            behind.try_reset("key").unwrap();
+        }
+    }
+    
+    #[test]
+    fn test_lua_redis_multi_window_and_calendar_use_server_time() {
+        // The server is late on 2023-11-14; one client is an hour behind it,
+        // the other three hours ahead, on 2023-11-15
+        let server = LuaRedis::new(Arc::new(MockClock::new()));
+        let behind = Arc::new(MockClock::starting_at(Duration::from_secs(1_700_000_000 - 3600)));
+        let ahead = Arc::new(MockClock::starting_at(Duration::from_secs(1_700_000_000 + 3 * 3600)));
+        
+        let limits = vec![RateLimitConfig::per_minute(3), RateLimitConfig::new(10, Duration::from_secs(3600))];
+        let multi = |clock: Arc<MockClock>| {
+            RedisMultiWindow::with_clock("redis://127.0.0.1:1/", limits.clone(), clock)
+                .unwrap()
+                .with_server_time()
+                .with_lua_redis(server.clone())
+        };
+        let calendar = |clock: Arc<MockClock>| {
+            RedisCalendarQuota::with_clock("redis://127.0.0.1:1/", 3, QuotaPeriod::Day, clock)
+                .unwrap()
+                .with_server_time()
+                .with_lua_redis(server.clone())
+        };
+        let limiters: [(Box<dyn RateLimiter>, Box<dyn RateLimiter>); 2] = [
+            (Box::new(multi(behind.clone())), Box::new(multi(ahead.clone()))),
+            (Box::new(calendar(behind.clone())), Box::new(calendar(ahead.clone()))),
+        ];
+        for (behind, ahead) in limiters {
+            // Both clients count against the server's window and period
+            assert!(ahead.allow_request("key").unwrap().allowed);
+            let reservation = behind.reserve("key", 1).unwrap();
+            assert!(reservation.decision().allowed);
+            assert!(ahead.allow_request("key").unwrap().allowed);
+            assert!(!behind.allow_request("key").unwrap().allowed);
             
-            return allowed
-            "#,
+            // A cancelled reservation goes back to where it was taken from
+            reservation.cancel().unwrap();
+            assert_eq!(ahead.status("key").unwrap().remaining, 1);
+            assert!(ahead.allow_request("key").unwrap().allowed);
+            assert!(!behind.allow_request("key").unwrap().allowed);
+        }
+    }
+    
+    #[test]
+    fn test_lua_redis_buckets_clamp_future_refill_times() {
+        for algorithm in [AlgorithmType::TokenBucket, AlgorithmType::LeakyBucket] {
+            for server_time in [false, true] {
+                let ahead = Arc::new(MockClock::starting_at(Duration::from_secs(1_700_000_010)));
+                let behind = Arc::new(MockClock::new());
+                let server = LuaRedis::new(if server_time { ahead.clone() } else { behind.clone() });
+                let limiter = |clock: Arc<MockClock>| {
+                    let limiter = RedisRateLimiter::with_algorithm("redis://127.0.0.1:1/", RateLimitConfig::per_second(2), algorithm, clock)
+                        .unwrap()
+                        .with_lua_redis(server.clone());
+                    if server_time { limiter.with_server_time() } else { limiter }
+                };
+                let (first, second) = (limiter(ahead.clone()), limiter(behind.clone()));
+                let context = format!("{} server time: {}", algorithm, server_time);
+                
+                // Drained at a time 10s later than the next check sees: another
+                // client's clock, or the server's before it was set back
+                assert!(first.allow_n("key", 2).unwrap().allowed, "{}", context);
+                if server_time {
+                    server.set_clock(behind.clone());
+                }
+                let denied = second.allow_request("key").unwrap();
+                assert!(!denied.allowed && denied.remaining == 0, "{}: {:?}", context, denied);
+                
+                // The earlier clock refills nothing until it has passed the last
+                // refill, and doesn't move it back
+                ahead.advance(Duration::from_millis(500));
+                behind.advance(Duration::from_millis(500));
+                assert!(!second.allow_request("key").unwrap().allowed, "{}", context);
+                if !server_time {
+                    assert!(first.allow_request("key").unwrap().allowed, "{}", context);
+                    assert!(!first.allow_request("key").unwrap().allowed, "{}", context);
+                }
+            }
+        }
+    }
+    
+    #[test]
+    fn test_redis_gcra() {
+        // Skip if Redis not available
+        let config = RateLimitConfig::per_second(3);
+        let limiter = RedisRateLimiter::with_algorithm("redis://127.0.0.1/", config, AlgorithmType::Gcra, Arc::new(SystemClock)).unwrap();
+        limiter.reset("gcra_user");
+        
+        for _ in 0..3 {
+            match limiter.allow_request("gcra_user") {
+                Ok(decision) => assert!(decision.allowed),
+                Err(_) => {
+                    println!("Redis not available, skipping test");
+                    return;
+                }
+            }
+        }
+        
+        let denied = limiter.allow_request("gcra_user").unwrap();
+        assert!(!denied.allowed);
+        assert!(denied.retry_after.unwrap() <= Duration::from_millis(334));
+        
+        limiter.reset("gcra_user");
+    }
+    
+    #[test]
+    fn test_redis_storage_shares_state() {
+        let clock = Arc::new(MockClock::new());
+        let server = LuaRedis::new(clock.clone());
+        let storage: Arc<dyn Storage> = Arc::new(RedisStorage::new("redis://127.0.0.1:1/").unwrap().with_lua_redis(server));
+        let instance = || {
+            crate::algorithms::SlidingWindow::with_clock(RateLimitConfig::per_second(3), clock.clone())
+                .unwrap()
+                .with_storage(Arc::clone(&storage))
+        };
+        let (first, second) = (instance(), instance());
+        
+        assert!(first.allow_n("user1", 2).unwrap().allowed);
+        assert!(second.allow_request("user1").unwrap().allowed);
+        assert!(!first.allow_request("user1").unwrap().allowed);
+        assert!(second.allow_request("user2").unwrap().allowed);
+        assert_eq!(storage.key_count().unwrap(), 2);
+        
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(first.evict_idle(), 2);
+        assert_eq!(storage.key_count().unwrap(), 0);
+    }
+    
+    #[test]
+    fn test_redis_storage_gives_up_on_contended_key() {
+        let clock = Arc::new(MockClock::new());
+        let server = LuaRedis::new(clock.clone());
+        let storage = RedisStorage::new("redis://127.0.0.1:1/").unwrap().with_lua_redis(server.clone());
+        let redis_key = storage.redis_key("hot");
+        
+        // Another instance writes the key between every read and write
+        let mut attempts = 0;
+        let result = storage.update("hot", &mut |_| {
+            attempts += 1;
+            let other = serde_json::to_string(&KeyState::Gcra { tat_us: attempts }).unwrap();
+            let _: () = redis::cmd("SET").arg(&redis_key).arg(other).query(&mut server.connection()).unwrap();
+            Some(KeyState::Gcra { tat_us: 0 })
+        });
+        assert!(matches!(result, Err(RateLimitError::Backend(_))));
+        assert_eq!(attempts, MAX_STORAGE_UPDATE_ATTEMPTS as u64);
+        
+        // Uncontended, the first write goes through
+        storage.update("hot", &mut |_| Some(KeyState::Gcra { tat_us: 0 })).unwrap();
+        assert_eq!(storage.get("hot").unwrap(), Some(KeyState::Gcra { tat_us: 0 }));
+        storage.update("hot", &mut |_| None).unwrap();
+        assert_eq!(storage.get("hot").unwrap(), None);
+        
+        let storage = storage.with_ttl(Duration::from_secs(1));
+        storage.update("hot", &mut |_| Some(KeyState::Gcra { tat_us: 0 })).unwrap();
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(storage.get("hot").unwrap(), None);
+    }
+    
+    #[test]
+    fn test_redis_storage_fails_fast_and_reuses_connections() {
+        // Nothing listens on port 1
+        let config = CircuitBreakerConfig { failure_threshold: 1, ..Default::default() };
+        let breaker = Arc::new(CircuitBreaker::new("storage", config).unwrap());
+        let storage = RedisStorage::new("redis://127.0.0.1:1/").unwrap().with_circuit_breaker(breaker);
+        assert!(matches!(storage.get("user1"), Err(RateLimitError::Backend(_))));
+        assert!(matches!(storage.update("user1", &mut |state| state), Err(RateLimitError::CircuitOpen(_))));
+        
+        let (url, connections) = stub_redis("$-1\r\n", Duration::ZERO);
+        let storage = RedisStorage::new(&url).unwrap();
+        for _ in 0..3 {
+            assert_eq!(storage.get("user1").unwrap(), None);
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+    
+    #[tokio::test]
+    async fn test_async_redis_rate_limiter() {
+        // Skip if Redis not available
+        let config = RateLimitConfig::per_minute(3);
+        let limiter = AsyncRedisRateLimiter::new("redis://127.0.0.1/", config.clone()).unwrap();
+        if limiter.reset("async_user").await.is_err() {
+            println!("Redis not available, skipping test");
+            return;
+        }
+        
+        // Concurrent checks share the connection
+        let (a, b, c, d) = tokio::join!(
+            limiter.allow_request("async_user"),
+            limiter.allow_request("async_user"),
+            limiter.allow_request("async_user"),
+            limiter.allow_request("async_user"),
         );
+        let allowed = [a, b, c, d].into_iter().filter(|decision| decision.as_ref().unwrap().allowed).count();
+        assert_eq!(allowed, 3);
         
-        Ok(Self {
-            client,
-            config,
-            lua_script,
-        })
+        // Same keys as the blocking limiter
+        limiter.refund("async_user", 1).await.unwrap();
+        let blocking = RedisRateLimiter::new("redis://127.0.0.1/", config).unwrap();
+        assert_eq!(blocking.status("async_user").unwrap().remaining, 1);
+        
+        limiter.reset("async_user").await.unwrap();
     }
     
-    /// Check with circuit breaker pattern
-    pub fn check_with_fallback(&mut self, key: &str) -> Result<bool> {
-        match self.allow_request(key) {
-            Ok(result) => Ok(result),
+    #[tokio::test]
+    async fn test_async_redis_rate_limiter_reports_connection_failure() {
+        // Nothing listens on port 1; every check tries to connect again
+        let limiter = AsyncRedisRateLimiter::new("redis://127.0.0.1:1/", RateLimitConfig::per_second(5)).unwrap();
+        for _ in 0..2 {
+            assert!(matches!(limiter.allow_request("user1").await, Err(RateLimitError::Backend(_))));
+        }
+        
+        // Cost is checked before connecting
+        assert!(matches!(limiter.allow_n("user1", 6).await, Err(RateLimitError::CostExceedsCapacity { .. })));
+    }
+    
+    #[test]
+    fn test_redis_circuit_breaker_fails_fast() {
+        // Nothing listens on port 1
+        let config = CircuitBreakerConfig { failure_threshold: 2, ..Default::default() };
+        let breaker = Arc::new(CircuitBreaker::new("redis", config).unwrap());
+        let limiter = RedisRateLimiter::new("redis://127.0.0.1:1/", RateLimitConfig::per_second(5))
+            .unwrap()
+            .with_circuit_breaker(breaker.clone());
+        
+        for _ in 0..2 {
+            assert!(matches!(limiter.allow_request("user1"), Err(RateLimitError::Backend(_))));
+        }
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(matches!(limiter.allow_request("user1"), Err(RateLimitError::CircuitOpen(_))));
+        
+        // The fallback still allows, without trying Redis
+        let decision = limiter.check_with_fallback("user1").unwrap();
+        assert!(decision.allowed);
+    }
+    
+    #[test]
+    fn test_redis_call_timeout_covers_every_round_trip() {
+        // EVALSHA is answered with NOSCRIPT in time, but the SCRIPT LOAD that
+        // follows would finish past the one deadline for the whole call
+        let (url, _) = stub_redis("-NOSCRIPT No matching script\r\n", Duration::from_millis(200));
+        let config = CircuitBreakerConfig { call_timeout: Duration::from_millis(300), ..Default::default() };
+        let breaker = Arc::new(CircuitBreaker::new("redis", config).unwrap());
+        let limiter = RedisRateLimiter::new(&url, RateLimitConfig::per_second(5))
+            .unwrap()
+            .with_circuit_breaker(breaker);
+        
+        let started = Instant::now();
+        assert!(matches!(limiter.allow_request("user1"), Err(RateLimitError::Backend(_))));
+        assert!(started.elapsed() < Duration::from_millis(600));
+    }
+    
+    #[test]
+    fn test_redis_connections_are_reused() {
+        let (url, connections) = stub_redis(":1\r\n", Duration::ZERO);
+        let breaker = Arc::new(CircuitBreaker::new("redis", CircuitBreakerConfig::default()).unwrap());
+        let limiter = RedisRateLimiter::new(&url, RateLimitConfig::per_second(5))
+            .unwrap()
+            .with_circuit_breaker(breaker);
+        
+        for _ in 0..3 {
+            limiter.reset("user1");
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+    
+    #[test]
+    fn test_redis_degraded_modes() {
+        // Nothing listens on port 1
+        let limiter = |mode| {
+            RedisRateLimiter::new("redis://127.0.0.1:1/", RateLimitConfig::per_second(5))
+                .unwrap()
+                .with_degraded_mode("modes", mode)
+        };
+        
+        let fail_open = limiter(DegradedMode::FailOpen).unwrap();
+        for _ in 0..10 {
+            assert!(fail_open.allow_request("user1").unwrap().allowed);
+        }
+        // Nothing was taken in Redis, so there is nothing to give back
+        fail_open.reserve("user1", 2).unwrap().cancel().unwrap();
+        
+        let fail_closed = limiter(DegradedMode::FailClosed).unwrap();
+        let denied = fail_closed.check_with_fallback("user1").unwrap();
+        assert!(!denied.allowed);
+        assert_eq!(denied.retry_after, Some(FAIL_CLOSED_RETRY_AFTER));
+        
+        // Two nodes: this one may take 3 of the 5
+        let local = limiter(DegradedMode::LocalFallback { nodes: 2 }).unwrap();
+        for _ in 0..3 {
+            assert!(local.allow_request("user1").unwrap().allowed);
+        }
+        assert!(!local.allow_request("user1").unwrap().allowed);
+        assert!(!local.allow_n("user1", 5).unwrap().allowed);
+        assert!(local.allow_request("user2").unwrap().allowed);
+        
+        // Local units go back to the local bucket
+        local.reset("user2");
+        let reservation = local.reserve("user2", 3).unwrap();
+        assert!(reservation.decision().allowed);
+        assert_eq!(local.status("user2").unwrap().remaining, 0);
+        reservation.cancel().unwrap();
+        assert_eq!(local.status("user2").unwrap().remaining, 3);
+        
+        assert!(limiter(DegradedMode::LocalFallback { nodes: 0 }).is_err());
+    }
+    
+    #[test]
+    fn test_each_limiter_reports_its_own_degraded_mode() {
+        let gauge = |limiter, mode| metrics::DEGRADED_MODE.with_label_values(&[limiter, mode]).get();
+        
+        // Nothing listens on port 1
+        let down = RedisRateLimiter::new("redis://127.0.0.1:1/", RateLimitConfig::per_second(5))
+            .unwrap()
+            .with_degraded_mode("down", DegradedMode::FailOpen)
+            .unwrap();
+        assert_eq!(down.degraded_mode(), None);
+        assert!(down.allow_request("user1").unwrap().allowed);
+        assert_eq!(down.degraded_mode(), Some(DegradedMode::FailOpen));
+        assert_eq!((gauge("down", "normal"), gauge("down", "fail_open")), (0, 1));
+        
+        // Setting up and checking another limiter leaves the first's state alone
+        let clock = Arc::new(MockClock::new());
+        let up = RedisRateLimiter::with_algorithm("redis://127.0.0.1:1/", RateLimitConfig::per_second(5), AlgorithmType::TokenBucket, clock.clone())
+            .unwrap()
+            .with_lua_redis(LuaRedis::new(clock))
+            .with_degraded_mode("up", DegradedMode::FailClosed)
+            .unwrap();
+        assert!(up.allow_request("user1").unwrap().allowed);
+        assert!(!up.is_degraded());
+        assert_eq!((gauge("up", "normal"), gauge("up", "fail_closed")), (1, 0));
+        
+        assert!(down.is_degraded());
+        assert_eq!((gauge("down", "normal"), gauge("down", "fail_open")), (0, 1));
+    }
+    
+    #[test]
+    fn test_redis_fail_closed_waits_for_circuit_breaker() {
+        let config = CircuitBreakerConfig { failure_threshold: 1, ..Default::default() };
+        let breaker = Arc::new(CircuitBreaker::new("redis", config).unwrap());
+        let limiter = RedisRateLimiter::new("redis://127.0.0.1:1/", RateLimitConfig::per_second(5))
+            .unwrap()
+            .with_circuit_breaker(breaker)
+            .with_degraded_mode("fail_closed", DegradedMode::FailClosed)
+            .unwrap();
+        
+        assert!(!limiter.allow_request("user1").unwrap().allowed);
+        let denied = limiter.allow_request("user1").unwrap();
+        assert!(denied.retry_after.unwrap() > FAIL_CLOSED_RETRY_AFTER);
+    }
+    
+    #[tokio::test]
+    async fn test_async_redis_circuit_breaker_fails_fast() {
+        let config = CircuitBreakerConfig { failure_threshold: 1, ..Default::default() };
+        let breaker = Arc::new(CircuitBreaker::new("redis", config).unwrap());
+        let limiter = AsyncRedisRateLimiter::new("redis://127.0.0.1:1/", RateLimitConfig::per_second(5))
+            .unwrap()
+            .with_circuit_breaker(breaker.clone());
+        
+        assert!(matches!(limiter.allow_request("user1").await, Err(RateLimitError::Backend(_))));
+        assert!(matches!(limiter.allow_request("user1").await, Err(RateLimitError::CircuitOpen(_))));
+        assert!(matches!(limiter.reset("user1").await, Err(RateLimitError::CircuitOpen(_))));
+        
+        let limiter = limiter.with_degraded_mode("breaker", DegradedMode::LocalFallback { nodes: 5 }).unwrap();
+        assert!(limiter.allow_request("user1").await.unwrap().allowed);
+        assert!(!limiter.allow_request("user1").await.unwrap().allowed);
+        assert!(limiter.refund("user1", 1).await.is_err());
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn test_async_redis_acquire() {
+        // Nothing listens on port 1
+        let limiter = AsyncRedisRateLimiter::new("redis://127.0.0.1:1/", RateLimitConfig::per_second(5)).unwrap();
+        assert!(matches!(limiter.acquire("user1", 1).await, Err(RateLimitError::Backend(_))));
+        
+        // Fail-closed denials ask for a wait longer than the timeout
+        let limiter = limiter.with_degraded_mode("acquire", DegradedMode::FailClosed).unwrap();
+        let start = tokio::time::Instant::now();
+        let result = limiter.acquire_timeout("user1", 1, FAIL_CLOSED_RETRY_AFTER / 2).await;
+        assert!(matches!(result, Err(RateLimitError::Timeout(_))));
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        
+        let limiter = limiter.with_degraded_mode("acquire", DegradedMode::FailOpen).unwrap();
+        assert!(limiter.acquire("user1", 1).await.unwrap().allowed);
+    }
+    
+    #[tokio::test]
+    async fn test_async_redis_degraded_reservation_refunds_locally() {
+        // Nothing listens on port 1; the fallback gives each node one unit
+        let limiter = AsyncRedisRateLimiter::new("redis://127.0.0.1:1/", RateLimitConfig::per_second(5))
+            .unwrap()
+            .with_degraded_mode("reservations", DegradedMode::LocalFallback { nodes: 5 })
+            .unwrap();
+        
+        let reservation = limiter.reserve("user1", 1).await.unwrap();
+        assert!(reservation.is_allowed());
+        assert!(!limiter.allow_request("user1").await.unwrap().allowed);
+        
+        // Cancelling gives the unit back to the fallback without trying Redis
+        reservation.cancel().await.unwrap();
+        let reservation = limiter.reserve("user1", 1).await.unwrap();
+        assert!(reservation.is_allowed());
+        reservation.commit();
+        
+        // Denied reservations have nothing to give back
+        let denied = limiter.reserve("user1", 1).await.unwrap();
+        assert!(!denied.is_allowed());
+        denied.cancel().await.unwrap();
+        assert!(!limiter.allow_request("user1").await.unwrap().allowed);
+    }
+    
+    #[test]
+    fn test_redis_multi_window() {
+        // Skip if Redis not available
+        let limits = vec![
+            RateLimitConfig::new(2, Duration::from_secs(1)),
+            RateLimitConfig::new(3, Duration::from_secs(60)),
+        ];
+        assert!(RedisMultiWindow::new("redis://127.0.0.1/", Vec::new()).is_err());
+        let limiter = RedisMultiWindow::new("redis://127.0.0.1/", limits).unwrap();
+        limiter.reset("multi_user");
+        
+        match limiter.check("multi_user", 2) {
+            Ok(result) => assert!(result.decision.allowed),
             Err(_) => {
-                // Fallback: allow request but log error
-                eprintln!("⚠️  Redis connection failed, allowing request (circuit breaker open)");
-                Ok(true)
+                println!("Redis not available, skipping test");
+                return;
             }
         }
+        
+        let denied = limiter.check("multi_user", 1).unwrap();
+        assert_eq!(denied.denied_by, Some(0));
+        
+        // A cancelled reservation leaves both windows as they were
+        limiter.reset("multi_user");
+        limiter.reserve("multi_user", 2).unwrap().cancel().unwrap();
+        let status = limiter.check_status("multi_user").unwrap();
+        assert!(status.decision.allowed);
+        assert_eq!(status.decision.remaining, 2);
+        
+        limiter.reset("multi_user");
     }
-}
-
-impl RateLimiter for RedisRateLimiter {
-    fn allow_request(&mut self, key: &str) -> Result<bool> {
-        let mut conn = self.client.get_connection()
-            .map_err(|e| crate::RateLimitError::ConfigError(format!("Redis connection failed: {}", e)))?;
-        
-        let redis_key = format!("rate_limit:{}", key);
-        let max_tokens = self.config.max_requests as f64;
-        let refill_rate = max_tokens / self.config.window.as_secs_f64();
-        let now = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
+    
+    #[test]
+    fn test_redis_multi_window_fails_fast() {
+        // Nothing listens on port 1
+        let config = CircuitBreakerConfig { failure_threshold: 2, ..Default::default() };
+        let breaker = Arc::new(CircuitBreaker::new("redis", config).unwrap());
+        let limits = vec![RateLimitConfig::per_second(2), RateLimitConfig::per_minute(3)];
+        let limiter = RedisMultiWindow::new("redis://127.0.0.1:1/", limits)
             .unwrap()
-            .as_secs_f64();
-        let ttl = (self.config.window.as_secs() * 2) as usize;
-        
-        let allowed: i32 = self.lua_script
-            .key(&redis_key)
-            .arg(max_tokens)
-            .arg(refill_rate)
-            .arg(now)
-            .arg(ttl)
-            .invoke(&mut conn)
-            .map_err(|e| crate::RateLimitError::ConfigError(format!("Lua script failed: {}", e)))?;
+            .with_circuit_breaker(breaker.clone());
         
-        Ok(allowed == 1)
+        assert!(matches!(limiter.check("user1", 1), Err(RateLimitError::Backend(_))));
+        assert!(matches!(limiter.try_reset("user1"), Err(RateLimitError::Backend(_))));
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(matches!(limiter.check_status("user1"), Err(RateLimitError::CircuitOpen(_))));
     }
     
-    fn reset(&mut self, key: &str) {
-        if let Ok(mut conn) = self.client.get_connection() {
-            let redis_key = format!("rate_limit:{}", key);
-            let res: redis::RedisResult<i32> = conn.del(&redis_key);
-            let _ = res;
-
-
-
+    #[test]
+    fn test_redis_calendar_quota() {
+        // Skip if Redis not available
+        assert!(RedisCalendarQuota::new("redis://127.0.0.1/", 0, QuotaPeriod::Day).is_err());
+        let quota = RedisCalendarQuota::new("redis://127.0.0.1/", 3, QuotaPeriod::Month).unwrap();
+        quota.reset("quota_user");
+        
+        match quota.allow_n("quota_user", 3) {
+            Ok(decision) => assert!(decision.allowed),
+            Err(_) => {
+                println!("Redis not available, skipping test");
+                return;
+            }
         }
+        
+        let denied = quota.allow_request("quota_user").unwrap();
+        assert!(!denied.allowed);
+        assert!(denied.retry_after.unwrap() <= Duration::from_secs(31 * 86_400));
+        
+        // Cancelled reservations go back to this month's count
+        quota.reset("quota_user");
+        quota.reserve("quota_user", 2).unwrap().cancel().unwrap();
+        assert_eq!(quota.status("quota_user").unwrap().remaining, 3);
+        
+        quota.reset("quota_user");
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
     
     #[test]
     fn test_redis_rate_limiter() {
@@ -116,20 +2924,37 @@ mod tests {
         let config = RateLimitConfig::per_second(5);
         
         match RedisRateLimiter::new("redis://127.0.0.1/", config) {
-            Ok(mut limiter) => {
+            Ok(limiter) => {
                 // Clean slate
                 limiter.reset("test_user");
                 
+                // Status queries never spend tokens
+                if let Ok(status) = limiter.status("test_user") {
+                    assert!(status.allowed);
+                    assert_eq!(status.remaining, 5);
+                }
+                
                 // Should allow 5 requests
                 for _ in 0..5 {
-                    if let Ok(allowed) = limiter.allow_request("test_user") {
-                        assert!(allowed);
+                    if let Ok(decision) = limiter.allow_request("test_user") {
+                        assert!(decision.allowed);
                     }
                 }
                 
                 // 6th should be denied
-                if let Ok(allowed) = limiter.allow_request("test_user") {
-                    assert!(!allowed);
+                if let Ok(decision) = limiter.allow_request("test_user") {
+                    assert!(!decision.allowed);
+                    assert_eq!(decision.remaining, 0);
+                    assert!(decision.retry_after.is_some());
+                }
+                
+                // Cancelled reservations give their tokens back
+                limiter.reset("test_user");
+                if let Ok(reservation) = limiter.reserve("test_user", 5) {
+                    reservation.cancel().unwrap();
+                    if let Ok(status) = limiter.status("test_user") {
+                        assert_eq!(status.remaining, 5);
+                    }
                 }
                 
                 // Cleanup
@@ -0,0 +1,77 @@
+use crate::{RateLimitDecision, Result};
+use std::fmt;
+
+type Refund<'a> = Box<dyn FnOnce() -> Result<()> + Send + 'a>;
+
+/// Capacity taken by `RateLimiter::reserve`, to be kept or given back
+///
+/// Call `commit` once the request has done real work, or `cancel` to
+/// refund the units (e.g. when validation fails first). Dropping a
+/// reservation without either keeps the units spent.
+///
+/// ```
+/// use distributed_rate_limiter::{RateLimitConfig, RateLimiter};
+/// use distributed_rate_limiter::algorithms::TokenBucket;
+///
+/// let limiter = TokenBucket::new(RateLimitConfig::per_second(1)).unwrap();
+///
+/// let reservation = limiter.reserve("user1", 1).unwrap();
+/// assert!(reservation.is_allowed());
+/// reservation.cancel().unwrap();
+///
+/// // The token was given back
+/// assert!(limiter.allow_request("user1").unwrap().allowed);
+/// ```
+#[must_use = "a reservation should be committed or cancelled"]
+pub struct Reservation<'a> {
+    decision: RateLimitDecision,
+    refund: Option<Refund<'a>>,
+}
+
+impl<'a> Reservation<'a> {
+    /// Wrap an admitted decision with the action that gives its units back
+    pub fn new(decision: RateLimitDecision, refund: impl FnOnce() -> Result<()> + Send + 'a) -> Self {
+        Self {
+            decision,
+            refund: decision.allowed.then(|| Box::new(refund) as Refund<'a>),
+        }
+    }
+    
+    /// A reservation whose units can't be given back
+    pub fn non_refundable(decision: RateLimitDecision) -> Self {
+        Self {
+            decision,
+            refund: None,
+        }
+    }
+    
+    /// The check that made this reservation
+    pub fn decision(&self) -> &RateLimitDecision {
+        &self.decision
+    }
+    
+    /// Whether the units were actually reserved
+    pub fn is_allowed(&self) -> bool {
+        self.decision.allowed
+    }
+    
+    /// Keep the reserved units spent
+    pub fn commit(self) {}
+    
+    /// Give the reserved units back (a no-op for denied reservations)
+    pub fn cancel(mut self) -> Result<()> {
+        match self.refund.take() {
+            Some(refund) => refund(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Debug for Reservation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Reservation")
+            .field("decision", &self.decision)
+            .field("refundable", &self.refund.is_some())
+            .finish()
+    }
+}
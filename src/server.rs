@@ -1,14 +1,35 @@
 use actix_web::{get, post, web, App, HttpResponse, HttpServer, Result};
+use actix_web::error::ErrorBadRequest;
 use actix_cors::Cors;
 use actix_files as fs;
 use serde::{Deserialize, Serialize};
+use std::path::Path;
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-use distributed_rate_limiter::{RateLimiter, RateLimitConfig, AlgorithmType};
-use distributed_rate_limiter::algorithms::*;
+use distributed_rate_limiter::{AlgorithmType, LimiterSnapshot, RateLimitConfig, RateLimiter};
+use distributed_rate_limiter::algorithms::TokenBucket;
+use distributed_rate_limiter::factory::{build_limiter, Backend};
 use distributed_rate_limiter::metrics;
 
+/// Where the shared limiter's state is kept between runs
+const SNAPSHOT_PATH: &str = "rate_limiter_snapshot.json";
+
+/// How often the snapshot is saved while running, bounding what a crash loses
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Keeps the periodic and the shutdown save from writing the file at once
+static SNAPSHOT_LOCK: Mutex<()> = Mutex::new(());
+
+/// Most checks one `/api/test` call may run
+const MAX_TEST_REQUESTS: u32 = 10_000;
+
+/// Set to a `redis://` URL to run `/api/test` against Redis instead of memory
+const REDIS_URL_VAR: &str = "RATE_LIMITER_REDIS_URL";
+
+/// Long-lived limiter behind `/api/check`, shared by all workers
+type SharedLimiter = Arc<dyn RateLimiter>;
+
 #[derive(Serialize)]
 struct MetricsResponse {
     total: u64,
@@ -19,12 +40,32 @@ struct MetricsResponse {
 
 #[derive(Deserialize)]
 struct TestRequest {
-    algorithm: String,
+    algorithm: AlgorithmType,
     max_requests: u64,
     window_seconds: u64,
     num_requests: u32,
 }
 
+#[derive(Deserialize)]
+struct CheckRequest {
+    key: String,
+    #[serde(default = "default_cost")]
+    cost: u64,
+}
+
+fn default_cost() -> u64 {
+    1
+}
+
+#[derive(Serialize)]
+struct CheckResponse {
+    allowed: bool,
+    limit: u64,
+    remaining: u64,
+    reset_after_ms: u128,
+    retry_after_ms: Option<u128>,
+}
+
 #[derive(Serialize)]
 struct TestResponse {
     allowed: u32,
@@ -60,96 +101,44 @@ async fn get_metrics() -> Result<HttpResponse> {
 }
 
 #[post("/api/test")]
-async fn test_rate_limiter(req: web::Json<TestRequest>) -> Result<HttpResponse> {
+async fn test_rate_limiter(backend: web::Data<Backend>, req: web::Json<TestRequest>) -> Result<HttpResponse> {
+    if req.num_requests > MAX_TEST_REQUESTS {
+        return Err(ErrorBadRequest(format!("num_requests may be at most {}", MAX_TEST_REQUESTS)));
+    }
     let config = RateLimitConfig::new(
         req.max_requests,
         std::time::Duration::from_secs(req.window_seconds),
     );
     
     let start = Instant::now();
-    let mut allowed_count = 0;
-    let mut blocked_count = 0;
-    let mut results = Vec::new();
+    let limiter = build_limiter(req.algorithm, config, &backend).map_err(ErrorBadRequest)?;
     
-    match req.algorithm.as_str() {
-        "token_bucket" => {
-            let mut limiter = TokenBucket::new(config);
-            for _ in 0..req.num_requests {
-                let req_start = Instant::now();
-                match limiter.allow_request("test_user") {
-                    Ok(true) => {
-                        allowed_count += 1;
-                        results.push(true);
-                        metrics::record_request(true, req_start);
-                    }
-                    Ok(false) => {
-                        blocked_count += 1;
-                        results.push(false);
-                        metrics::record_request(false, req_start);
-                    }
-                    Err(_) => {}
-                }
-            }
-        }
-        "leaky_bucket" => {
-            let mut limiter = LeakyBucket::new(config);
-            for _ in 0..req.num_requests {
-                let req_start = Instant::now();
-                match limiter.allow_request("test_user") {
-                    Ok(true) => {
-                        allowed_count += 1;
-                        results.push(true);
-                        metrics::record_request(true, req_start);
-                    }
-                    Ok(false) => {
-                        blocked_count += 1;
-                        results.push(false);
-                        metrics::record_request(false, req_start);
-                    }
-                    Err(_) => {}
-                }
-            }
-        }
-        "fixed_window" => {
-            let mut limiter = FixedWindow::new(config);
-            for _ in 0..req.num_requests {
-                let req_start = Instant::now();
-                match limiter.allow_request("test_user") {
-                    Ok(true) => {
-                        allowed_count += 1;
-                        results.push(true);
-                        metrics::record_request(true, req_start);
-                    }
-                    Ok(false) => {
-                        blocked_count += 1;
-                        results.push(false);
-                        metrics::record_request(false, req_start);
-                    }
-                    Err(_) => {}
+    // The Redis backend blocks on every check, so keep it off the worker thread
+    let num_requests = req.num_requests;
+    let (allowed_count, blocked_count, results) = web::block(move || {
+        let mut allowed_count = 0;
+        let mut blocked_count = 0;
+        let mut results = Vec::new();
+        
+        for _ in 0..num_requests {
+            let req_start = Instant::now();
+            match limiter.allow_request("test_user") {
+                Ok(decision) if decision.allowed => {
+                    allowed_count += 1;
+                    results.push(true);
+                    metrics::record_request(true, req_start);
                 }
-            }
-        }
-        "sliding_window" => {
-            let mut limiter = SlidingWindow::new(config);
-            for _ in 0..req.num_requests {
-                let req_start = Instant::now();
-                match limiter.allow_request("test_user") {
-                    Ok(true) => {
-                        allowed_count += 1;
-                        results.push(true);
-                        metrics::record_request(true, req_start);
-                    }
-                    Ok(false) => {
-                        blocked_count += 1;
-                        results.push(false);
-                        metrics::record_request(false, req_start);
-                    }
-                    Err(_) => {}
+                Ok(_) => {
+                    blocked_count += 1;
+                    results.push(false);
+                    metrics::record_request(false, req_start);
                 }
+                Err(_) => {}
             }
         }
-        _ => {}
-    }
+        (allowed_count, blocked_count, results)
+    })
+    .await?;
     
     let duration = start.elapsed();
     let duration_ms = duration.as_secs_f64() * 1000.0;
@@ -164,27 +153,118 @@ async fn test_rate_limiter(req: web::Json<TestRequest>) -> Result<HttpResponse>
     }))
 }
 
+#[post("/api/check")]
+async fn check(limiter: web::Data<SharedLimiter>, req: web::Json<CheckRequest>) -> Result<HttpResponse> {
+    let req_start = Instant::now();
+    let decision = limiter.allow_n(&req.key, req.cost).map_err(ErrorBadRequest)?;
+    metrics::record_request(decision.allowed, req_start);
+    
+    Ok(HttpResponse::Ok().json(CheckResponse {
+        allowed: decision.allowed,
+        limit: decision.limit,
+        remaining: decision.remaining,
+        reset_after_ms: decision.reset_after.as_millis(),
+        retry_after_ms: decision.retry_after.map(|retry_after| retry_after.as_millis()),
+    }))
+}
+
+/// Restore the limiter from the last snapshot, if there is one
+fn load_snapshot(limiter: &dyn RateLimiter, path: &Path) {
+    let json = match std::fs::read_to_string(path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+        Err(e) => {
+            eprintln!("⚠️  Could not read {}: {}", path.display(), e);
+            return;
+        }
+    };
+    
+    let restored = serde_json::from_str::<LimiterSnapshot>(&json)
+        .map_err(|e| e.to_string())
+        .and_then(|snapshot| limiter.restore(&snapshot).map_err(|e| e.to_string()));
+    match restored {
+        Ok(keys) => println!("♻️  Restored {} keys from {}", keys, path.display()),
+        Err(e) => eprintln!("⚠️  Ignoring snapshot {}: {}", path.display(), e),
+    }
+}
+
+/// Write the limiter's state next to the previous snapshot, then swap it in,
+/// returning how many keys were saved
+fn save_snapshot(limiter: &dyn RateLimiter, path: &Path) -> std::io::Result<usize> {
+    let Some(snapshot) = limiter.snapshot() else {
+        return Ok(0);
+    };
+    
+    let _guard = SNAPSHOT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_vec(&snapshot)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(snapshot.len())
+}
+
+/// Save a snapshot every `SNAPSHOT_INTERVAL` until the server stops
+async fn save_periodically(limiter: SharedLimiter, path: &'static Path) {
+    let mut interval = actix_web::rt::time::interval(SNAPSHOT_INTERVAL);
+    interval.tick().await; // the first tick is immediate
+    loop {
+        interval.tick().await;
+        let limiter = Arc::clone(&limiter);
+        let saved = web::block(move || save_snapshot(limiter.as_ref(), path))
+            .await
+            .map_err(std::io::Error::other)
+            .and_then(|saved| saved);
+        if let Err(e) = saved {
+            eprintln!("⚠️  Could not save {}: {}", path.display(), e);
+        }
+    }
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     // Initialize metrics
     metrics::init_metrics();
     
+    let limiter: SharedLimiter = Arc::new(
+        TokenBucket::new(RateLimitConfig::per_minute(60)).map_err(std::io::Error::other)?,
+    );
+    let snapshot_path = Path::new(SNAPSHOT_PATH);
+    load_snapshot(limiter.as_ref(), snapshot_path);
+    let data = web::Data::new(Arc::clone(&limiter));
+    
+    // Chosen here, not per request: any page the operator visits could
+    // otherwise point the server at hosts of its choosing
+    let backend = match std::env::var(REDIS_URL_VAR) {
+        Ok(url) => Backend::Redis { url },
+        Err(_) => Backend::Memory,
+    };
+    let backend = web::Data::new(backend);
+    let periodic_save = actix_web::rt::spawn(save_periodically(Arc::clone(&limiter), snapshot_path));
+    
     println!("🚀 Starting Rate Limiter Web Dashboard");
     println!("📊 Dashboard: http://localhost:3001");
     println!("📈 Metrics API: http://localhost:3001/api/metrics");
     println!("\nPress Ctrl+C to stop\n");
     
-    HttpServer::new(|| {
+    HttpServer::new(move || {
         let cors = Cors::permissive();
         
         App::new()
             .wrap(cors)
+            .app_data(data.clone())
+            .app_data(backend.clone())
             .service(index)
             .service(get_metrics)
             .service(test_rate_limiter)
+            .service(check)
             .service(fs::Files::new("/", "web").index_file("index.html"))
     })
     .bind(("127.0.0.1", 3001))?
     .run()
-    .await
+    .await?;
+    
+    // Ctrl+C / SIGTERM: requests have drained, so the state is final
+    periodic_save.abort();
+    let keys = save_snapshot(limiter.as_ref(), snapshot_path)?;
+    println!("💾 Saved {} keys to {}", keys, snapshot_path.display());
+    Ok(())
 }
\ No newline at end of file
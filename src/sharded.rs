@@ -0,0 +1,558 @@
+use crate::{RateLimitError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+/// Shards are swept for idle keys once they grow to this size
+const MIN_SWEEP_THRESHOLD: usize = 64;
+
+/// What to do with a new key when the key limit has been reached
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Fail checks for new keys with `RateLimitError::TooManyKeys`
+    #[default]
+    Reject,
+    /// Forget the least recently used key to make room
+    EvictLeastRecent,
+}
+
+#[derive(Debug)]
+struct Slot<V> {
+    value: V,
+    last_access: u64,
+}
+
+#[derive(Debug)]
+struct Shard<V> {
+    entries: HashMap<String, Slot<V>>,
+    /// Keys by last access, kept only for `OverflowPolicy::EvictLeastRecent`
+    recency: Option<BTreeMap<u64, String>>,
+    next_sweep: usize,
+}
+
+impl<V> Shard<V> {
+    /// The value for `key`, counting this as a use at `access`
+    fn touch(&mut self, key: &str, access: u64) -> Option<&mut V> {
+        let slot = self.entries.get_mut(key)?;
+        if let Some(recency) = &mut self.recency {
+            if let Some(key) = recency.remove(&slot.last_access) {
+                recency.insert(access, key);
+            }
+        }
+        slot.last_access = access;
+        Some(&mut slot.value)
+    }
+    
+    /// Add `key`, which must not be present yet
+    fn insert_new(&mut self, key: String, value: V, access: u64) -> &mut V {
+        if let Some(recency) = &mut self.recency {
+            recency.insert(access, key.clone());
+        }
+        &mut self.entries.entry(key).or_insert(Slot { value, last_access: access }).value
+    }
+    
+    fn remove(&mut self, key: &str) -> Option<V> {
+        let slot = self.entries.remove(key)?;
+        if let Some(recency) = &mut self.recency {
+            recency.remove(&slot.last_access);
+        }
+        Some(slot.value)
+    }
+    
+    /// Drop entries that are no different from a fresh key
+    fn sweep(&mut self, is_idle: &impl Fn(&str, &V) -> bool) -> usize {
+        let before = self.entries.len();
+        let recency = &mut self.recency;
+        self.entries.retain(|key, slot| {
+            let idle = is_idle(key, &slot.value);
+            if let (true, Some(recency)) = (idle, recency.as_mut()) {
+                recency.remove(&slot.last_access);
+            }
+            !idle
+        });
+        
+        // Amortize: don't sweep again until the shard has doubled
+        self.next_sweep = (self.entries.len() * 2).max(MIN_SWEEP_THRESHOLD);
+        before - self.entries.len()
+    }
+    
+    /// When the least recently used entry was last used
+    fn oldest(&self) -> Option<u64> {
+        self.recency.as_ref()?.first_key_value().map(|(&access, _)| access)
+    }
+    
+    /// Forget the least recently used entry, if it was last used at `access`
+    fn evict_oldest(&mut self, access: u64) -> bool {
+        if self.oldest() != Some(access) {
+            return false;
+        }
+        let key = self.recency.as_mut().and_then(|recency| recency.pop_first()).map(|(_, key)| key);
+        key.is_some_and(|key| self.entries.remove(&key).is_some())
+    }
+}
+
+/// Lock-striped map from keys to per-key limiter state
+///
+/// Keys are hashed onto a fixed number of independently locked shards, so
+/// threads checking different keys rarely contend on the same lock.
+///
+/// Idle keys (whose state is equivalent to a fresh key) are swept lazily
+/// whenever a shard doubles in size, and can be swept explicitly with
+/// `evict_idle`. An optional key limit caps the total across all shards.
+/// Once it is reached the whole map is swept to make room, but after a sweep
+/// that freed nothing not again until `max_keys` more uses, so a flood of
+/// new keys doesn't scan the map for each one.
+#[derive(Debug)]
+pub struct ShardedMap<V> {
+    shards: Box<[Mutex<Shard<V>>]>,
+    hasher: RandomState,
+    overflow_policy: OverflowPolicy,
+    max_keys: Option<usize>,
+    /// Keys held, plus slots reserved for keys about to be inserted
+    len: AtomicUsize,
+    tick: AtomicU64,
+    /// The tick before which a full map isn't swept again
+    sweep_after: AtomicU64,
+}
+
+impl<V> ShardedMap<V> {
+    /// Create a map with a shard count sized for this machine
+    pub fn new() -> Self {
+        Self::with_shards(Self::default_shards())
+    }
+    
+    /// Create a map with a specific number of shards (rounded up to a power of two)
+    pub fn with_shards(shards: usize) -> Self {
+        Self::build(shards, None, OverflowPolicy::default())
+    }
+    
+    /// Create a map holding at most `max_keys` keys (unbounded if `None`)
+    pub fn with_limit(max_keys: Option<usize>, overflow_policy: OverflowPolicy) -> Self {
+        Self::build(Self::default_shards(), max_keys, overflow_policy)
+    }
+    
+    fn default_shards() -> usize {
+        let parallelism = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(4);
+        
+        parallelism * 4
+    }
+    
+    fn build(shards: usize, max_keys: Option<usize>, overflow_policy: OverflowPolicy) -> Self {
+        let track_recency = max_keys.is_some() && overflow_policy == OverflowPolicy::EvictLeastRecent;
+        Self {
+            shards: (0..shards.max(1).next_power_of_two())
+                .map(|_| Mutex::new(Shard {
+                    entries: HashMap::new(),
+                    recency: track_recency.then(BTreeMap::new),
+                    next_sweep: MIN_SWEEP_THRESHOLD,
+                }))
+                .collect(),
+            hasher: RandomState::new(),
+            overflow_policy,
+            max_keys,
+            len: AtomicUsize::new(0),
+            tick: AtomicU64::new(0),
+            sweep_after: AtomicU64::new(0),
+        }
+    }
+    
+    /// Number of shards the keyspace is split into
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+    
+    fn index(&self, key: &str) -> usize {
+        self.hasher.hash_one(key) as usize & (self.shards.len() - 1)
+    }
+    
+    fn shard(&self, key: &str) -> MutexGuard<'_, Shard<V>> {
+        Self::lock(&self.shards[self.index(key)])
+    }
+    
+    fn lock(shard: &Mutex<Shard<V>>) -> MutexGuard<'_, Shard<V>> {
+        // A panic while holding the lock cannot leave limiter state half
+        // updated in a way that matters, so keep serving after poisoning
+        shard.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    
+    /// Take a slot for a new key, unless the key limit has been reached
+    fn reserve_slot(&self) -> bool {
+        match self.max_keys {
+            Some(max_keys) => self.len
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |len| (len < max_keys).then_some(len + 1))
+                .is_ok(),
+            None => {
+                self.len.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+        }
+    }
+    
+    /// Run `f` on the state for `key`, creating it with `init` if missing
+    ///
+    /// `is_idle` tells which existing entries can be forgotten to make room.
+    /// Fails with `RateLimitError::TooManyKeys` if the key is new, the key
+    /// limit has been reached and the overflow policy is `Reject`.
+    pub fn with_entry<R>(
+        &self,
+        key: &str,
+        init: impl FnOnce() -> V,
+        is_idle: impl Fn(&str, &V) -> bool,
+        f: impl FnOnce(&mut V) -> R,
+    ) -> Result<R> {
+        let access = self.tick.fetch_add(1, Ordering::Relaxed);
+        let index = self.index(key);
+        {
+            let mut shard = Self::lock(&self.shards[index]);
+            
+            // Avoid allocating an owned key on the hot path when the entry exists
+            if let Some(value) = shard.touch(key, access) {
+                return Ok(f(value));
+            }
+            
+            if shard.entries.len() >= shard.next_sweep {
+                let freed = shard.sweep(&is_idle);
+                self.len.fetch_sub(freed, Ordering::Relaxed);
+            }
+            if self.reserve_slot() {
+                return Ok(f(shard.insert_new(key.to_string(), init(), access)));
+            }
+        }
+        
+        // Making room visits other shards, so it can't hold this one's lock
+        self.make_room(access, &is_idle)?;
+        let mut shard = Self::lock(&self.shards[index]);
+        if let Some(value) = shard.touch(key, access) {
+            // Added by another thread meanwhile
+            self.len.fetch_sub(1, Ordering::Relaxed);
+            return Ok(f(value));
+        }
+        Ok(f(shard.insert_new(key.to_string(), init(), access)))
+    }
+    
+    /// Reserve a slot in a full map, by sweeping idle keys or per the overflow policy
+    fn make_room(&self, access: u64, is_idle: &impl Fn(&str, &V) -> bool) -> Result<()> {
+        let max_keys = self.max_keys.unwrap_or(usize::MAX);
+        loop {
+            if self.reserve_slot() {
+                return Ok(());
+            }
+            if access >= self.sweep_after.load(Ordering::Relaxed) {
+                let freed = self.evict_idle(is_idle);
+                if freed > 0 {
+                    continue;
+                }
+                self.sweep_after.store(access.saturating_add(max_keys as u64), Ordering::Relaxed);
+            }
+            match self.overflow_policy {
+                OverflowPolicy::Reject => return Err(RateLimitError::TooManyKeys { max_keys }),
+                OverflowPolicy::EvictLeastRecent => self.evict_least_recent(),
+            }
+        }
+    }
+    
+    /// Forget the least recently used key across all shards
+    fn evict_least_recent(&self) {
+        let oldest = self.shards
+            .iter()
+            .enumerate()
+            .filter_map(|(i, shard)| Self::lock(shard).oldest().map(|access| (access, i)))
+            .min();
+        
+        // If that key was used or removed meanwhile, the caller looks again
+        if let Some((access, i)) = oldest {
+            if Self::lock(&self.shards[i]).evict_oldest(access) {
+                self.len.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+    
+    /// Set the state of every key in `entries` at once
+    ///
+    /// All shards are locked throughout, so no check sees some keys written
+    /// and others not. If the new keys don't fit and the overflow policy is
+    /// `Reject`, fails with `RateLimitError::TooManyKeys` without writing any.
+    pub fn insert_all(&self, entries: Vec<(String, V)>, is_idle: impl Fn(&str, &V) -> bool) -> Result<()> {
+        // Always in index order, and `with_entry` holds only one, so no deadlock
+        let mut shards: Vec<_> = self.shards.iter().map(Self::lock).collect();
+        
+        if let Some(max_keys) = self.max_keys {
+            let new_keys = entries
+                .iter()
+                .map(|(key, _)| key.as_str())
+                .filter(|key| !shards[self.index(key)].entries.contains_key(*key))
+                .collect::<HashSet<_>>()
+                .len();
+            if new_keys > 0 && self.len.load(Ordering::Relaxed) + new_keys > max_keys {
+                let freed: usize = shards.iter_mut().map(|shard| shard.sweep(&is_idle)).sum();
+                self.len.fetch_sub(freed, Ordering::Relaxed);
+            }
+            if self.overflow_policy == OverflowPolicy::Reject && self.len.load(Ordering::Relaxed) + new_keys > max_keys {
+                return Err(RateLimitError::TooManyKeys { max_keys });
+            }
+        }
+        
+        for (key, value) in entries {
+            let access = self.tick.fetch_add(1, Ordering::Relaxed);
+            let shard = self.index(&key);
+            if let Some(existing) = shards[shard].touch(&key, access) {
+                *existing = value;
+                continue;
+            }
+            if !self.reserve_slot() {
+                // Only `EvictLeastRecent` gets here
+                let oldest = shards
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, shard)| shard.oldest().map(|access| (access, i)))
+                    .min();
+                // Its slot goes to this key; otherwise the slots are all
+                // reserved by checks yet to insert, so go over the limit
+                if !oldest.is_some_and(|(oldest, i)| shards[i].evict_oldest(oldest)) {
+                    self.len.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            shards[shard].insert_new(key, value, access);
+        }
+        Ok(())
+    }
+    
+    /// Run `f` on the state for `key` if it exists, without creating it
+    ///
+    /// Unlike `with_entry` this does not count as a use of the key for
+    /// least-recently-used eviction.
+    pub fn peek<R>(&self, key: &str, f: impl FnOnce(&V) -> R) -> Option<R> {
+        self.shard(key).entries.get(key).map(|slot| f(&slot.value))
+    }
+    
+    /// Run `f` on the state for `key` if it exists, without creating it
+    pub fn update<R>(&self, key: &str, f: impl FnOnce(&mut V) -> R) -> Option<R> {
+        self.shard(key).entries.get_mut(key).map(|slot| f(&mut slot.value))
+    }
+    
+    /// Remove the state for `key`
+    pub fn remove(&self, key: &str) -> Option<V> {
+        let value = self.shard(key).remove(key)?;
+        self.len.fetch_sub(1, Ordering::Relaxed);
+        Some(value)
+    }
+    
+    /// Remove every idle entry across all shards, returning how many were removed
+    pub fn evict_idle(&self, is_idle: impl Fn(&str, &V) -> bool) -> usize {
+        let freed = self.shards
+            .iter()
+            .map(|shard| Self::lock(shard).sweep(&is_idle))
+            .sum();
+        self.len.fetch_sub(freed, Ordering::Relaxed);
+        freed
+    }
+    
+    /// Run `f` on every entry, one shard at a time
+    pub fn for_each(&self, mut f: impl FnMut(&str, &V)) {
+        for shard in self.shards.iter() {
+            for (key, slot) in &Self::lock(shard).entries {
+                f(key, &slot.value);
+            }
+        }
+    }
+    
+    /// Total number of tracked keys across all shards
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| Self::lock(shard).entries.len())
+            .sum()
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<V> Default for ShardedMap<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    
+    fn never_idle(_: &str, _: &u64) -> bool {
+        false
+    }
+    
+    #[test]
+    fn test_sharded_map_entries() {
+        let map: ShardedMap<u64> = ShardedMap::with_shards(3);
+        assert_eq!(map.shard_count(), 4);
+        
+        map.with_entry("a", || 0, never_idle, |v| *v += 1).unwrap();
+        map.with_entry("a", || 0, never_idle, |v| *v += 1).unwrap();
+        map.with_entry("b", || 10, never_idle, |v| *v += 1).unwrap();
+        
+        assert_eq!(map.with_entry("a", || 0, never_idle, |v| *v).unwrap(), 2);
+        assert_eq!(map.with_entry("b", || 0, never_idle, |v| *v).unwrap(), 11);
+        assert_eq!(map.len(), 2);
+        
+        assert_eq!(map.peek("a", |v| *v), Some(2));
+        assert_eq!(map.peek("missing", |v| *v), None);
+        assert_eq!(map.update("a", |v| { *v += 1; *v }), Some(3));
+        assert_eq!(map.update("missing", |v| *v), None);
+        assert_eq!(map.len(), 2);
+        
+        assert_eq!(map.remove("a"), Some(3));
+        assert_eq!(map.len(), 1);
+    }
+    
+    #[test]
+    fn test_sharded_map_concurrent_updates() {
+        let map: Arc<ShardedMap<u64>> = Arc::new(ShardedMap::new());
+        
+        let handles: Vec<_> = (0..8)
+            .map(|t| {
+                let map = Arc::clone(&map);
+                thread::spawn(move || {
+                    for i in 0..1000 {
+                        map.with_entry(&format!("key{}", (t + i) % 16), || 0, never_idle, |v| *v += 1).unwrap();
+                    }
+                })
+            })
+            .collect();
+        
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        
+        let total: u64 = (0..16)
+            .map(|i| map.with_entry(&format!("key{}", i), || 0, never_idle, |v| *v).unwrap())
+            .sum();
+        assert_eq!(total, 8000);
+    }
+    
+    #[test]
+    fn test_sharded_map_lazily_sweeps_idle_keys() {
+        let map: ShardedMap<u64> = ShardedMap::with_shards(1);
+        
+        // Zero means "same as a fresh key" here
+        for i in 0..10_000 {
+            map.with_entry(&format!("key{}", i), || 0, |_, v| *v == 0, |_| ()).unwrap();
+        }
+        assert!(map.len() <= MIN_SWEEP_THRESHOLD);
+        
+        // Active keys survive sweeps
+        map.with_entry("active", || 0, |_, v| *v == 0, |v| *v = 1).unwrap();
+        let tracked = map.len();
+        assert_eq!(map.evict_idle(|_, v| *v == 0), tracked - 1);
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.with_entry("active", || 0, never_idle, |v| *v).unwrap(), 1);
+    }
+    
+    #[test]
+    fn test_sharded_map_rejects_keys_over_limit() {
+        let map: ShardedMap<u64> = ShardedMap::with_limit(Some(8), OverflowPolicy::Reject);
+        
+        let mut accepted = 0;
+        for i in 0..100 {
+            if map.with_entry(&format!("key{}", i), || 1, never_idle, |_| ()).is_ok() {
+                accepted += 1;
+            }
+        }
+        
+        // The limit is on the whole map, whichever shards the keys land in
+        assert_eq!(accepted, 8);
+        assert_eq!(map.len(), 8);
+        assert!(matches!(
+            map.with_entry("one_more", || 1, never_idle, |_| ()),
+            Err(RateLimitError::TooManyKeys { max_keys: 8 })
+        ));
+        
+        // Once keys turn idle there is room again
+        map.evict_idle(|_, _| true);
+        assert!(map.with_entry("one_more", || 1, never_idle, |_| ()).is_ok());
+    }
+    
+    #[test]
+    fn test_sharded_map_inserts_all_or_nothing() {
+        let map: ShardedMap<u64> = ShardedMap::with_limit(Some(4), OverflowPolicy::Reject);
+        map.with_entry("kept", || 1, never_idle, |_| ()).unwrap();
+        
+        let entries: Vec<_> = (0..10).map(|i| (format!("key{}", i), i)).collect();
+        assert!(matches!(
+            map.insert_all(entries, never_idle),
+            Err(RateLimitError::TooManyKeys { max_keys: 4 })
+        ));
+        assert_eq!(map.len(), 1);
+        
+        // Existing keys are overwritten without needing room
+        map.insert_all(vec![("kept".to_string(), 7)], never_idle).unwrap();
+        assert_eq!(map.peek("kept", |v| *v), Some(7));
+    }
+    
+    #[test]
+    fn test_sharded_map_evicts_least_recent_over_limit() {
+        let map: ShardedMap<u64> = ShardedMap::with_limit(Some(1), OverflowPolicy::EvictLeastRecent);
+        
+        map.with_entry("old", || 1, never_idle, |_| ()).unwrap();
+        map.with_entry("new", || 2, never_idle, |_| ()).unwrap();
+        
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.remove("old"), None);
+        assert_eq!(map.remove("new"), Some(2));
+    }
+    
+    #[test]
+    fn test_sharded_map_evicts_least_recent_across_shards() {
+        let map: ShardedMap<u64> = ShardedMap::with_limit(Some(3), OverflowPolicy::EvictLeastRecent);
+        assert!(map.shard_count() > 1);
+        
+        for key in ["a", "b", "c"] {
+            map.with_entry(key, || 1, never_idle, |_| ()).unwrap();
+        }
+        map.with_entry("a", || 1, never_idle, |_| ()).unwrap();
+        
+        // "b" is the oldest wherever the keys were hashed to
+        map.with_entry("d", || 1, never_idle, |_| ()).unwrap();
+        assert_eq!(map.len(), 3);
+        assert_eq!(map.peek("b", |v| *v), None);
+        for key in ["a", "c", "d"] {
+            assert_eq!(map.peek(key, |v| *v), Some(1));
+        }
+        
+        // Restores make room the same way
+        map.insert_all(vec![("e".to_string(), 2), ("a".to_string(), 3)], never_idle).unwrap();
+        assert_eq!(map.len(), 3);
+        assert_eq!(map.peek("c", |v| *v), None);
+        assert_eq!(map.peek("a", |v| *v), Some(3));
+    }
+    
+    #[test]
+    fn test_sharded_map_does_not_rescan_when_full() {
+        let map: ShardedMap<u64> = ShardedMap::with_limit(Some(1000), OverflowPolicy::Reject);
+        for i in 0..1000 {
+            map.with_entry(&format!("key{}", i), || 1, never_idle, |_| ()).unwrap();
+        }
+        
+        // A flood of new keys: after a sweep frees nothing, the next waits
+        // for as many uses as the map holds keys (shards reaching their own
+        // sweep threshold may add one pass each)
+        let checked = AtomicUsize::new(0);
+        let is_idle = |_: &str, _: &u64| {
+            checked.fetch_add(1, Ordering::Relaxed);
+            false
+        };
+        for i in 0..2000 {
+            assert!(map.with_entry(&format!("new{}", i), || 1, is_idle, |_| ()).is_err());
+        }
+        assert!(checked.load(Ordering::Relaxed) <= 3000);
+    }
+}
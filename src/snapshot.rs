@@ -0,0 +1,284 @@
+use crate::clock::Clock;
+use crate::sharded::ShardedMap;
+use crate::storage::{mismatch, StoredState};
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// Serializable state of one key, tagged with the algorithm it belongs to
+///
+/// Points in time are stored as microseconds since the UNIX epoch, so a
+/// snapshot stays meaningful in a process with a different monotonic clock.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "algorithm", rename_all = "snake_case")]
+pub enum KeyState {
+    TokenBucket { tokens: f64, last_refill_us: u64 },
+    LeakyBucket { water_level: f64, last_update_us: u64 },
+    FixedWindow { count: u64, window_start_us: u64 },
+    /// `(request time, cost)` pairs, oldest first
+    SlidingWindow { requests: Vec<(u64, u64)> },
+    /// Sub-window counts, oldest first, the last one starting at `current_start_us`
+    SlidingWindowCounter { counts: Vec<u64>, current_start_us: u64 },
+    Gcra { tat_us: u64 },
+    /// `(count, window start)` per configured window
+    MultiWindow { windows: Vec<(u64, u64)> },
+    /// Period start in UNIX seconds
+    CalendarQuota { period_start: i64, count: u64 },
+}
+
+impl KeyState {
+    /// Name of the algorithm this state belongs to
+    pub fn algorithm(&self) -> &'static str {
+        match self {
+            KeyState::TokenBucket { .. } => "token_bucket",
+            KeyState::LeakyBucket { .. } => "leaky_bucket",
+            KeyState::FixedWindow { .. } => "fixed_window",
+            KeyState::SlidingWindow { .. } => "sliding_window",
+            KeyState::SlidingWindowCounter { .. } => "sliding_window_counter",
+            KeyState::Gcra { .. } => "gcra",
+            KeyState::MultiWindow { .. } => "multi_window",
+            KeyState::CalendarQuota { .. } => "calendar_quota",
+        }
+    }
+}
+
+/// Per-key state of an in-memory limiter, as exported by `RateLimiter::snapshot`
+///
+/// Only keys that differ from a brand new key are included. Restoring a
+/// snapshot with `RateLimiter::restore` picks up where the state left off:
+/// time that passed in between counts, so buckets refill and windows
+/// expire as if the process had kept running. Times older than the
+/// monotonic clock can represent (e.g. from before a reboot) are clamped
+/// to the oldest time it can.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LimiterSnapshot {
+    /// When the snapshot was taken, in microseconds since the UNIX epoch
+    pub taken_at_us: u64,
+    pub keys: BTreeMap<String, KeyState>,
+}
+
+impl LimiterSnapshot {
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+/// A monotonic and a wall-clock reading taken together, for converting between them
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TimeBase {
+    now: Instant,
+    unix_now: Duration,
+}
+
+impl TimeBase {
+    pub(crate) fn new(clock: &dyn Clock) -> Self {
+        Self {
+            now: clock.now(),
+            unix_now: clock.unix_now(),
+        }
+    }
+    
+    pub(crate) fn now(self) -> Instant {
+        self.now
+    }
+    
+    /// Wall-clock time of `instant`, in microseconds since the UNIX epoch
+    pub(crate) fn to_unix_us(self, instant: Instant) -> u64 {
+        let unix = if instant <= self.now {
+            self.unix_now.saturating_sub(self.now - instant)
+        } else {
+            self.unix_now + (instant - self.now)
+        };
+        unix.as_micros() as u64
+    }
+    
+    /// Monotonic time of `unix_us`, as far back as the monotonic clock goes
+    pub(crate) fn to_instant(self, unix_us: u64) -> Instant {
+        let unix = Duration::from_micros(unix_us);
+        if unix >= self.unix_now {
+            return self.now + (unix - self.unix_now);
+        }
+        
+        let mut age = self.unix_now - unix;
+        loop {
+            if let Some(instant) = self.now.checked_sub(age) {
+                return instant;
+            }
+            age /= 2;
+        }
+    }
+}
+
+/// Export every non-idle entry of `map`
+pub(crate) fn take<V: StoredState>(
+    map: &ShardedMap<V>,
+    time: TimeBase,
+    is_idle: impl Fn(&str, &V) -> bool,
+) -> LimiterSnapshot {
+    let mut keys = BTreeMap::new();
+    map.for_each(|key, value| {
+        if !is_idle(key, value) {
+            keys.insert(key.to_string(), value.export(time));
+        }
+    });
+    
+    LimiterSnapshot {
+        taken_at_us: time.unix_now.as_micros() as u64,
+        keys,
+    }
+}
+
+/// Replace the state of every key in `snapshot`, returning how many were restored
+///
+/// Nothing is restored unless every key holds this algorithm's state and,
+/// with `OverflowPolicy::Reject`, they all fit under the key limit.
+pub(crate) fn restore<V: StoredState>(
+    map: &ShardedMap<V>,
+    snapshot: &LimiterSnapshot,
+    time: TimeBase,
+    is_idle: impl Fn(&str, &V) -> bool,
+) -> Result<usize> {
+    let entries = snapshot.keys.iter()
+        .map(|(key, state)| {
+            V::import(state, time)
+                .map(|value| (key.clone(), value))
+                .ok_or_else(|| mismatch::<V>(key, state))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    
+    let restored = entries.len();
+    map.insert_all(entries, is_idle)?;
+    Ok(restored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithms::{CalendarQuota, FixedWindow, MultiWindow, QuotaPeriod, TokenBucket};
+    use crate::clock::MockClock;
+    use crate::factory::{build_limiter_with_clock, Backend};
+    use crate::redis_limiter::RedisRateLimiter;
+    use crate::{AlgorithmType, OverflowPolicy, RateLimitConfig, RateLimitError, RateLimiter};
+    use std::sync::Arc;
+    
+    #[test]
+    fn test_time_base_round_trip() {
+        let clock = MockClock::new();
+        clock.advance(Duration::from_secs(10));
+        let time = TimeBase::new(&clock);
+        
+        let past = time.now() - Duration::from_millis(1500);
+        let future = time.now() + Duration::from_millis(250);
+        assert_eq!(time.to_unix_us(past), 1_700_000_008_500_000);
+        assert_eq!(time.to_instant(time.to_unix_us(past)), past);
+        assert_eq!(time.to_instant(time.to_unix_us(future)), future);
+        
+        // Far beyond the monotonic clock's range, clamp instead of panicking
+        assert!(time.to_instant(0) <= time.now());
+    }
+    
+    #[test]
+    fn test_snapshot_survives_restart_as_json() {
+        let clock = MockClock::new();
+        let limiter = TokenBucket::with_clock(RateLimitConfig::per_second(5), Arc::new(clock.clone())).unwrap();
+        limiter.allow_n("busy", 5).unwrap();
+        limiter.allow_n("light", 2).unwrap();
+        limiter.allow_n("idle", 1).unwrap();
+        clock.advance(Duration::from_millis(200));
+        
+        // "idle" has refilled, so there is nothing to keep
+        let snapshot = limiter.snapshot().unwrap();
+        assert_eq!(snapshot.keys.keys().collect::<Vec<_>>(), ["busy", "light"]);
+        let json = serde_json::to_string(&snapshot).unwrap();
+        
+        // A new process with its own monotonic clock, 400ms later
+        let clock = MockClock::starting_at(clock.unix_now() + Duration::from_millis(400));
+        let restored = TokenBucket::with_clock(RateLimitConfig::per_second(5), Arc::new(clock)).unwrap();
+        assert_eq!(restored.restore(&serde_json::from_str(&json).unwrap()).unwrap(), 2);
+        
+        // Refill continues from the bucket's last refill, 0.6s before
+        assert_eq!(restored.status("busy").unwrap().remaining, 3);
+        assert_eq!(restored.status("light").unwrap().remaining, 5);
+    }
+    
+    #[test]
+    fn test_every_algorithm_round_trips() {
+        let clock = MockClock::new();
+        let config = RateLimitConfig::per_minute(4);
+        let mut limiters: Vec<Box<dyn RateLimiter>> = AlgorithmType::ALL.iter()
+            .map(|&algorithm| build_limiter_with_clock(algorithm, config.clone(), &Backend::Memory, Arc::new(clock.clone())).unwrap())
+            .collect();
+        limiters.push(Box::new(MultiWindow::with_clock(vec![config.clone(), RateLimitConfig::per_second(3)], Arc::new(clock.clone())).unwrap()));
+        limiters.push(Box::new(CalendarQuota::with_clock(4, QuotaPeriod::Day, Arc::new(clock.clone())).unwrap()));
+        
+        for limiter in &limiters {
+            limiter.allow_n("user1", 3).unwrap();
+        }
+        clock.advance(Duration::from_secs(1));
+        let snapshots: Vec<_> = limiters.iter().map(|limiter| limiter.snapshot().unwrap()).collect();
+        
+        // Fresh limiters in a process whose monotonic clock started elsewhere
+        let restarted = MockClock::starting_at(clock.unix_now());
+        let restored: Vec<Box<dyn RateLimiter>> = AlgorithmType::ALL.iter()
+            .map(|&algorithm| build_limiter_with_clock(algorithm, config.clone(), &Backend::Memory, Arc::new(restarted.clone())).unwrap())
+            .chain([
+                Box::new(MultiWindow::with_clock(vec![config.clone(), RateLimitConfig::per_second(3)], Arc::new(restarted.clone())).unwrap()) as Box<dyn RateLimiter>,
+                Box::new(CalendarQuota::with_clock(4, QuotaPeriod::Day, Arc::new(restarted.clone())).unwrap()),
+            ])
+            .collect();
+        
+        for ((limiter, snapshot), original) in restored.iter().zip(&snapshots).zip(&limiters) {
+            assert_eq!(snapshot.len(), 1);
+            assert_eq!(limiter.restore(snapshot).unwrap(), 1);
+            assert_eq!(limiter.status("user1").unwrap(), original.status("user1").unwrap());
+            assert_eq!(limiter.snapshot().as_ref(), Some(snapshot));
+        }
+        
+        // State in Redis outlives the process, so there is nothing to snapshot
+        let redis = RedisRateLimiter::new("redis://127.0.0.1/", config).unwrap();
+        assert!(redis.snapshot().is_none());
+        assert!(redis.restore(&snapshots[0]).is_err());
+    }
+    
+    #[test]
+    fn test_restore_rejects_other_algorithms() {
+        let clock = MockClock::new();
+        let windows = FixedWindow::with_clock(RateLimitConfig::per_second(5), Arc::new(clock.clone())).unwrap();
+        windows.allow_n("user1", 3).unwrap();
+        let snapshot = windows.snapshot().unwrap();
+        
+        let buckets = TokenBucket::with_clock(RateLimitConfig::per_second(5), Arc::new(clock)).unwrap();
+        assert!(matches!(buckets.restore(&snapshot), Err(RateLimitError::ConfigError(_))));
+        assert_eq!(buckets.tracked_keys(), 0);
+        
+        // Restoring replaces existing state
+        windows.allow_n("user1", 2).unwrap();
+        windows.restore(&snapshot).unwrap();
+        assert_eq!(windows.status("user1").unwrap().remaining, 2);
+    }
+    
+    #[test]
+    fn test_restore_is_all_or_nothing_over_key_limit() {
+        let clock = MockClock::new();
+        let large = TokenBucket::with_clock(RateLimitConfig::per_second(5), Arc::new(clock.clone())).unwrap();
+        for i in 0..10 {
+            large.allow_n(&format!("user{}", i), 3).unwrap();
+        }
+        let snapshot = large.snapshot().unwrap();
+        
+        let config = RateLimitConfig::builder(5, Duration::from_secs(1)).max_keys(4, OverflowPolicy::Reject).build().unwrap();
+        let small = TokenBucket::with_clock(config, Arc::new(clock)).unwrap();
+        small.allow_n("local", 1).unwrap();
+        assert!(matches!(small.restore(&snapshot), Err(RateLimitError::TooManyKeys { max_keys: 4 })));
+        
+        // Neither half restored nor the existing key lost
+        assert_eq!(small.tracked_keys(), 1);
+        assert_eq!(small.status("user0").unwrap().remaining, 5);
+        assert_eq!(small.status("local").unwrap().remaining, 4);
+    }
+}
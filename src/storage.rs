@@ -0,0 +1,365 @@
+use crate::clock::Clock;
+use crate::sharded::{OverflowPolicy, ShardedMap};
+use crate::snapshot::{self, KeyState, LimiterSnapshot, TimeBase};
+use crate::{RateLimitError, Result};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How far apart two readings of the same stored instant may be
+///
+/// Shared storage keeps times as wall-clock microseconds, so converting
+/// them back depends on when the clocks were read; compare instants that
+/// may have made that round trip with `same_instant`.
+pub(crate) const TIME_TOLERANCE: Duration = Duration::from_millis(1);
+
+/// Whether `a` and `b` are the same instant, give or take `tolerance`
+pub(crate) fn same_instant(a: Instant, b: Instant, tolerance: Duration) -> bool {
+    a.max(b) - a.min(b) < tolerance
+}
+
+/// Backend holding per-key limiter state, shared by every algorithm
+///
+/// Algorithms are written once against their own state type and convert
+/// to and from `KeyState` at this boundary, so any store that can keep a
+/// `KeyState` per key and update it atomically makes every algorithm
+/// distributed-capable (see `RedisStorage`). Attach one to a limiter with
+/// its `with_storage`; without one, limiters keep state in a local sharded
+/// map with no conversion overhead.
+///
+/// Keys whose state becomes no different from a brand new key are
+/// removed by the limiter as part of the update.
+pub trait Storage: Send + Sync {
+    /// Current state of `key`
+    fn get(&self, key: &str) -> Result<Option<KeyState>>;
+    
+    /// Atomically replace the state of `key` with what `f` returns for it
+    ///
+    /// `f` gets `None` for a missing key, and returning `None` removes the
+    /// key. Backends using optimistic concurrency call `f` again when the
+    /// key changed underneath them, so it must be free of side effects
+    /// beyond its last call.
+    fn update(&self, key: &str, f: &mut dyn FnMut(Option<KeyState>) -> Option<KeyState>) -> Result<()>;
+    
+    /// Remove the state of `key`
+    fn remove(&self, key: &str) -> Result<()>;
+    
+    /// Remove every key for which `is_idle` returns true, returning how many were removed
+    fn evict(&self, is_idle: &mut dyn FnMut(&str, &KeyState) -> bool) -> Result<usize>;
+    
+    /// Number of keys currently stored
+    fn key_count(&self) -> Result<usize>;
+}
+
+/// `Storage` in a process-local `HashMap`
+///
+/// Mostly useful as a reference implementation and for tests: limiters
+/// without a storage attached keep their state locally more efficiently.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    entries: Mutex<HashMap<String, KeyState>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    fn entries(&self) -> std::sync::MutexGuard<'_, HashMap<String, KeyState>> {
+        // Every update is a single insert or remove, so keep serving after poisoning
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, key: &str) -> Result<Option<KeyState>> {
+        Ok(self.entries().get(key).cloned())
+    }
+    
+    fn update(&self, key: &str, f: &mut dyn FnMut(Option<KeyState>) -> Option<KeyState>) -> Result<()> {
+        let mut entries = self.entries();
+        if let Some(state) = f(entries.remove(key)) {
+            entries.insert(key.to_string(), state);
+        }
+        Ok(())
+    }
+    
+    fn remove(&self, key: &str) -> Result<()> {
+        self.entries().remove(key);
+        Ok(())
+    }
+    
+    fn evict(&self, is_idle: &mut dyn FnMut(&str, &KeyState) -> bool) -> Result<usize> {
+        let mut entries = self.entries();
+        let before = entries.len();
+        entries.retain(|key, state| !is_idle(key, state));
+        Ok(before - entries.len())
+    }
+    
+    fn key_count(&self) -> Result<usize> {
+        Ok(self.entries().len())
+    }
+}
+
+/// Per-key state of one algorithm, convertible to the shared `KeyState` form
+pub(crate) trait StoredState: Sized {
+    /// Name of the algorithm, as reported by `KeyState::algorithm`
+    const ALGORITHM: &'static str;
+    
+    fn export(&self, time: TimeBase) -> KeyState;
+    
+    /// `None` if `state` belongs to another algorithm
+    fn import(state: &KeyState, time: TimeBase) -> Option<Self>;
+}
+
+/// Error for `state` stored under `key` that another algorithm wrote
+pub(crate) fn mismatch<V: StoredState>(key: &str, state: &KeyState) -> RateLimitError {
+    RateLimitError::ConfigError(format!(
+        "{} state for '{}' does not fit a {} limiter",
+        state.algorithm(),
+        key,
+        V::ALGORITHM
+    ))
+}
+
+/// Where an algorithm keeps its per-key state
+pub(crate) enum Store<V> {
+    Local(ShardedMap<V>),
+    Shared {
+        storage: Arc<dyn Storage>,
+        clock: Arc<dyn Clock>,
+    },
+}
+
+impl<V: StoredState> Store<V> {
+    pub(crate) fn local(max_keys: Option<usize>, overflow_policy: OverflowPolicy) -> Self {
+        Store::Local(ShardedMap::with_limit(max_keys, overflow_policy))
+    }
+    
+    fn import(key: &str, state: &KeyState, time: TimeBase) -> Result<V> {
+        V::import(state, time).ok_or_else(|| mismatch::<V>(key, state))
+    }
+    
+    /// Run `f` on the state for `key`, creating it with `init` if missing
+    ///
+    /// With shared storage, `init` and `f` may run more than once.
+    pub(crate) fn with_entry<R>(
+        &self,
+        key: &str,
+        init: impl Fn() -> V,
+        is_idle: impl Fn(&str, &V) -> bool,
+        mut f: impl FnMut(&mut V) -> R,
+    ) -> Result<R> {
+        match self {
+            Store::Local(map) => map.with_entry(key, init, is_idle, f),
+            Store::Shared { storage, clock } => {
+                let time = TimeBase::new(clock.as_ref());
+                let mut outcome = None;
+                storage.update(key, &mut |current| {
+                    let mut value = match current.as_ref().map(|state| Self::import(key, state, time)) {
+                        Some(Ok(value)) => value,
+                        Some(Err(e)) => {
+                            // Leave another algorithm's state alone
+                            outcome = Some(Err(e));
+                            return current;
+                        }
+                        None => init(),
+                    };
+                    outcome = Some(Ok(f(&mut value)));
+                    (!is_idle(key, &value)).then(|| value.export(time))
+                })?;
+                outcome.expect("storage ran the update")
+            }
+        }
+    }
+    
+    /// Run `f` on the state for `key` if it exists
+    pub(crate) fn peek<R>(&self, key: &str, f: impl FnOnce(&V) -> R) -> Result<Option<R>> {
+        match self {
+            Store::Local(map) => Ok(map.peek(key, f)),
+            Store::Shared { storage, clock } => {
+                let time = TimeBase::new(clock.as_ref());
+                match storage.get(key)? {
+                    Some(state) => Ok(Some(f(&Self::import(key, &state, time)?))),
+                    None => Ok(None),
+                }
+            }
+        }
+    }
+    
+    /// Run `f` on the state for `key` if it exists, without creating it
+    pub(crate) fn update<R>(&self, key: &str, is_idle: impl Fn(&str, &V) -> bool, mut f: impl FnMut(&mut V) -> R) -> Result<Option<R>> {
+        match self {
+            Store::Local(map) => Ok(map.update(key, f)),
+            Store::Shared { storage, clock } => {
+                let time = TimeBase::new(clock.as_ref());
+                let mut outcome = Ok(None);
+                storage.update(key, &mut |current| {
+                    let mut value = match current.as_ref().map(|state| Self::import(key, state, time)) {
+                        Some(Ok(value)) => value,
+                        Some(Err(e)) => {
+                            outcome = Err(e);
+                            return current;
+                        }
+                        None => {
+                            outcome = Ok(None);
+                            return None;
+                        }
+                    };
+                    outcome = Ok(Some(f(&mut value)));
+                    (!is_idle(key, &value)).then(|| value.export(time))
+                })?;
+                outcome
+            }
+        }
+    }
+    
+    pub(crate) fn remove(&self, key: &str) -> Result<()> {
+        match self {
+            Store::Local(map) => {
+                map.remove(key);
+                Ok(())
+            }
+            Store::Shared { storage, .. } => storage.remove(key),
+        }
+    }
+    
+    /// Remove every idle key, returning how many were removed
+    pub(crate) fn evict_idle(&self, is_idle: impl Fn(&str, &V) -> bool) -> Result<usize> {
+        match self {
+            Store::Local(map) => Ok(map.evict_idle(is_idle)),
+            Store::Shared { storage, clock } => {
+                let time = TimeBase::new(clock.as_ref());
+                storage.evict(&mut |key, state| V::import(state, time).is_some_and(|value| is_idle(key, &value)))
+            }
+        }
+    }
+    
+    pub(crate) fn len(&self) -> Result<usize> {
+        match self {
+            Store::Local(map) => Ok(map.len()),
+            Store::Shared { storage, .. } => storage.key_count(),
+        }
+    }
+    
+    /// Export every non-idle key; shared state already outlives the process
+    pub(crate) fn snapshot(&self, time: TimeBase, is_idle: impl Fn(&str, &V) -> bool) -> Option<LimiterSnapshot> {
+        match self {
+            Store::Local(map) => Some(snapshot::take(map, time, is_idle)),
+            Store::Shared { .. } => None,
+        }
+    }
+    
+    /// Replace the state of every key in `snapshot`, returning how many were restored
+    ///
+    /// Nothing is restored unless every key holds this algorithm's state and
+    /// fits under the key limit. Shared storage is written key by key, so a
+    /// storage error partway through leaves the keys before it restored.
+    pub(crate) fn restore(&self, snapshot: &LimiterSnapshot, time: TimeBase, is_idle: impl Fn(&str, &V) -> bool) -> Result<usize> {
+        match self {
+            Store::Local(map) => snapshot::restore(map, snapshot, time, is_idle),
+            Store::Shared { storage, .. } => {
+                for (key, state) in &snapshot.keys {
+                    Self::import(key, state, time)?;
+                }
+                for (key, state) in &snapshot.keys {
+                    storage.update(key, &mut |_| Some(state.clone()))?;
+                }
+                Ok(snapshot.len())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithms::{FixedWindow, Gcra, LeakyBucket, MultiWindow, SlidingWindow, SlidingWindowCounter, TokenBucket};
+    use crate::clock::MockClock;
+    use crate::{RateLimitConfig, RateLimiter};
+    
+    #[test]
+    fn test_limiters_share_memory_storage() {
+        let clock = MockClock::new();
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let instance = || {
+            FixedWindow::with_clock(RateLimitConfig::per_second(5), Arc::new(clock.clone()))
+                .unwrap()
+                .with_storage(Arc::clone(&storage))
+        };
+        let (first, second) = (instance(), instance());
+        
+        assert!(first.allow_n("user1", 3).unwrap().allowed);
+        assert!(second.allow_n("user1", 2).unwrap().allowed);
+        assert!(!first.allow_request("user1").unwrap().allowed);
+        assert_eq!(second.status("user1").unwrap().remaining, 0);
+        assert_eq!(first.tracked_keys(), 1);
+        
+        // Shared state already outlives the process
+        assert!(first.snapshot().is_none());
+        
+        second.reset("user1");
+        assert_eq!(storage.key_count().unwrap(), 0);
+        assert!(first.allow_n("user1", 5).unwrap().allowed);
+    }
+    
+    #[test]
+    fn test_every_algorithm_runs_on_shared_storage() {
+        let clock = MockClock::new();
+        let config = RateLimitConfig::per_second(2);
+        let storage = Arc::new(MemoryStorage::new());
+        let shared = || Arc::clone(&storage) as Arc<dyn Storage>;
+        let clock_arc = || Arc::new(clock.clone());
+        
+        // Each limiter uses its algorithm's name as the key
+        let limiters: Vec<(&str, Box<dyn RateLimiter>)> = vec![
+            ("token_bucket", Box::new(TokenBucket::with_clock(config.clone(), clock_arc()).unwrap().with_storage(shared()))),
+            ("leaky_bucket", Box::new(LeakyBucket::with_clock(config.clone(), clock_arc()).unwrap().with_storage(shared()))),
+            ("fixed_window", Box::new(FixedWindow::with_clock(config.clone(), clock_arc()).unwrap().with_storage(shared()))),
+            ("sliding_window", Box::new(SlidingWindow::with_clock(config.clone(), clock_arc()).unwrap().with_storage(shared()))),
+            ("sliding_window_counter", Box::new(SlidingWindowCounter::with_clock(config.clone(), clock_arc()).unwrap().with_storage(shared()))),
+            ("gcra", Box::new(Gcra::with_clock(config.clone(), clock_arc()).unwrap().with_storage(shared()))),
+            ("multi_window", Box::new(MultiWindow::with_clock(vec![config.clone()], clock_arc()).unwrap().with_storage(shared()))),
+        ];
+        
+        for (key, limiter) in &limiters {
+            assert!(limiter.allow_request(key).unwrap().allowed, "{}", key);
+            let before = storage.get(key).unwrap().unwrap();
+            assert_eq!(before.algorithm(), *key);
+            
+            // A cancelled reservation leaves the stored state as it was
+            limiter.reserve(key, 1).unwrap().cancel().unwrap();
+            assert_eq!(storage.get(key).unwrap(), Some(before), "{}", key);
+            
+            assert!(limiter.allow_request(key).unwrap().allowed, "{}", key);
+            assert!(!limiter.allow_request(key).unwrap().allowed, "{} should deny", key);
+        }
+        assert_eq!(storage.key_count().unwrap(), limiters.len());
+        
+        // Each limiter only evicts keys holding its own algorithm's state
+        clock.advance(Duration::from_secs(2));
+        for (key, limiter) in &limiters {
+            assert_eq!(limiter.evict_idle(), 1, "{}", key);
+        }
+        assert_eq!(storage.key_count().unwrap(), 0);
+    }
+    
+    #[test]
+    fn test_storage_rejects_other_algorithms_state() {
+        let clock = MockClock::new();
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let windows = FixedWindow::with_clock(RateLimitConfig::per_second(5), Arc::new(clock.clone()))
+            .unwrap()
+            .with_storage(Arc::clone(&storage));
+        let buckets = TokenBucket::with_clock(RateLimitConfig::per_second(5), Arc::new(clock))
+            .unwrap()
+            .with_storage(Arc::clone(&storage));
+        
+        windows.allow_n("user1", 3).unwrap();
+        assert!(matches!(buckets.allow_request("user1"), Err(RateLimitError::ConfigError(_))));
+        assert!(buckets.status("user1").is_err());
+        
+        // The other algorithm's state is left alone
+        assert_eq!(windows.status("user1").unwrap().remaining, 2);
+    }
+}